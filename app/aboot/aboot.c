@@ -5637,8 +5637,8 @@ normal_boot:
 	if (!boot_into_fastboot)
 	{
 #if WITH_LK2ND_BOOT
-		if (!boot_into_recovery)
-			lk2nd_boot();
+		if (!boot_into_recovery && lk2nd_boot())
+			goto fastboot;
 #endif
 
 		if (target_is_emmc_boot())
@@ -42,6 +42,11 @@ typedef struct bdev {
 	char *label;
 	bool is_leaf;
 
+	/* partition table info, all zero if unknown or not a GPT partition */
+	uint8_t type_guid[16];
+	uint8_t unique_guid[16];
+	uint64_t attributes;
+
 	/* function pointers */
 	ssize_t (*read)(struct bdev *, void *buf, off_t offset, size_t len);
 	ssize_t (*read_block)(struct bdev *, void *buf, bnum_t block, uint count);
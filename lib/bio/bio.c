@@ -390,6 +390,9 @@ void bio_initialize_bdev(bdev_t *dev, const char *name, size_t block_size, bnum_
 
 	dev->is_leaf = false;
 	dev->label = NULL;
+	memset(dev->type_guid, 0, sizeof(dev->type_guid));
+	memset(dev->unique_guid, 0, sizeof(dev->unique_guid));
+	dev->attributes = 0;
 
 	/* set up the default hooks, the sub driver should override the block operations at least */
 	dev->read = bio_default_read;
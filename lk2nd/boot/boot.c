@@ -6,9 +6,11 @@
 #include <lib/fs.h>
 #include <list.h>
 #include <stdlib.h>
+#include <string.h>
 #include <target.h>
 
 #include <lk2nd/boot.h>
+#include <lk2nd/device.h>
 #include <lk2nd/hw/bdev.h>
 #include <app/fastboot.h>
 
@@ -55,14 +57,49 @@ static void lk2nd_scan_devices(void)
 	}
 }
 
+/**
+ * lk2nd_boot_image_offset() - Where the OS boot image starts in a partition.
+ * @label: Name of the partition
+ *
+ * When lk2nd is installed at the start of the boot partition, the boot image
+ * of the OS follows it.
+ *
+ * Return: Offset of the boot image in bytes
+ */
+uint64_t lk2nd_boot_image_offset(const char *label)
+{
+#ifdef LK2ND_PARTITION_SIZE
+	if (!strcmp(label, LK2ND_PARTITION_BASE))
+		return LK2ND_PARTITION_SIZE;
+#endif
+	return 0;
+}
+
+/**
+ * lk2nd_boot_have_atags() - Check if there are ATAGs to pass on to the kernel.
+ *
+ * Only lk2nd booted as a second stage bootloader has any, lk1st has nothing
+ * to pass on.
+ */
+bool lk2nd_boot_have_atags(void)
+{
+#if WITH_LK2ND_DEVICE_2ND
+	return lk2nd_device2nd_have_atags();
+#else
+	return false;
+#endif
+}
+
 /**
  * lk2nd_boot() - Try to boot the OS.
  *
  * This method is supposed to be called from aboot.
  * If appropriate OS is found, it will be booted, and this
  * method will never return.
+ *
+ * Return: true if the misc partition asks to stay in fastboot
  */
-void lk2nd_boot(void)
+bool lk2nd_boot(void)
 {
 	static bool init_done = false;
 
@@ -72,6 +109,6 @@ void lk2nd_boot(void)
 	}
 
     //	lk2nd_scan_devices();
-    extern void boot_scan(void);
-    boot_scan();
+    extern bool boot_scan(void);
+    return boot_scan();
 }
@@ -23,6 +23,22 @@ const char *const *lk2nd_device_get_dtb_hints(void)
 	return lk2nd_dev.dtbfiles;
 }
 
+/**
+ * lk2nd_device_get_compatible() - Get the compatible of the detected device.
+ */
+const char *lk2nd_device_get_compatible(void)
+{
+	return lk2nd_dev.compatible;
+}
+
+/**
+ * lk2nd_device_get_model() - Get the model name of the detected device.
+ */
+const char *lk2nd_device_get_model(void)
+{
+	return lk2nd_dev.model;
+}
+
 static int find_device_node(const void *dtb)
 {
 	int lk2nd_node, node, ret;
@@ -15,5 +15,7 @@ void lk2nd_bdev_init(void)
 	if (IS_ENABLED(MMC_SDHCI_SUPPORT))
 		lk2nd_mmc_sdhci_bio_register();
 
+	lk2nd_liblp_publish();
+
 	lk2nd_bdev_dump_devices();
 }
@@ -1,10 +1,49 @@
 #ifndef BDEV_H
 #define BDEV_H
 
+#include <lib/bio.h>
+
 /* util.c */
 void lk2nd_bdev_dump_devices(void);
 
 void lk2nd_wrapper_bio_register(void);
 void lk2nd_mmc_sdhci_bio_register(void);
 
+/**
+ * lk2nd_partition_publish() - Publish partitions from a GPT or MBR as sub-devices.
+ * @device: Name of the block device to scan
+ *
+ * Implemented in Rust. Partitions are published as "<device>p<N>", numbered
+ * in partition table order, with their GPT name, type GUID, PARTUUID and
+ * attributes filled in.
+ *
+ * Return: Number of published partitions, <0 if no partition table was found
+ */
+int lk2nd_partition_publish(const char *device);
+
+/**
+ * struct lk2nd_bdev_extent - A run of blocks of a linear device.
+ * @parent: Name of the device the blocks are on, NULL for zeroes
+ * @start: First block on @parent, in 512 byte sectors
+ * @count: Number of 512 byte sectors
+ */
+struct lk2nd_bdev_extent {
+	const char *parent;
+	bnum_t start;
+	bnum_t count;
+};
+
+/* linear.c */
+int lk2nd_bdev_publish_linear(const char *name, const struct lk2nd_bdev_extent *extents, size_t count);
+
+/**
+ * lk2nd_liblp_publish() - Publish the logical partitions in Android super partitions.
+ *
+ * Implemented in Rust. Each logical partition is published as a linear device
+ * named and labeled after it, e.g. "system_a".
+ *
+ * Return: Number of published partitions
+ */
+int lk2nd_liblp_publish(void);
+
 #endif
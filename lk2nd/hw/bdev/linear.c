@@ -0,0 +1,190 @@
+// SPDX-License-Identifier: BSD-3-Clause
+
+#include <debug.h>
+#include <err.h>
+#include <lib/bio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "bdev.h"
+
+#define LINEAR_BLOCK_SIZE	512
+
+struct linear_extent {
+	/* NULL if the extent reads as zeroes */
+	bdev_t *parent;
+	off_t parent_offset;
+	off_t offset;
+	off_t size;
+};
+
+struct linear_bdev {
+	bdev_t dev;
+	size_t count;
+	struct linear_extent extents[];
+};
+
+static struct linear_extent *linear_find(struct linear_bdev *linear, off_t offset)
+{
+	size_t i;
+
+	for (i = 0; i < linear->count; ++i) {
+		if (offset < linear->extents[i].offset + linear->extents[i].size)
+			return &linear->extents[i];
+	}
+	return NULL;
+}
+
+static ssize_t linear_read(struct bdev *dev, void *_buf, off_t offset, size_t len)
+{
+	struct linear_bdev *linear = (struct linear_bdev *)dev;
+	uint8_t *buf = _buf;
+	ssize_t done = 0;
+
+	while (len > 0) {
+		struct linear_extent *ext = linear_find(linear, offset);
+		ssize_t ret;
+		size_t chunk;
+
+		if (!ext)
+			break;
+
+		chunk = MIN(len, (size_t)(ext->offset + ext->size - offset));
+		if (ext->parent) {
+			ret = bio_read(ext->parent, buf, ext->parent_offset + offset - ext->offset, chunk);
+			if (ret < 0)
+				return ret;
+			if ((size_t)ret != chunk)
+				return ERR_IO;
+		} else {
+			memset(buf, 0, chunk);
+		}
+
+		buf += chunk;
+		offset += chunk;
+		len -= chunk;
+		done += chunk;
+	}
+
+	return done;
+}
+
+static ssize_t linear_read_block(struct bdev *dev, void *buf, bnum_t block, uint count)
+{
+	return linear_read(dev, buf, (off_t)block * LINEAR_BLOCK_SIZE, (size_t)count * LINEAR_BLOCK_SIZE);
+}
+
+static ssize_t linear_write(struct bdev *dev, const void *_buf, off_t offset, size_t len)
+{
+	struct linear_bdev *linear = (struct linear_bdev *)dev;
+	const uint8_t *buf = _buf;
+	ssize_t done = 0;
+
+	while (len > 0) {
+		struct linear_extent *ext = linear_find(linear, offset);
+		ssize_t ret;
+		size_t chunk;
+
+		if (!ext)
+			break;
+		if (!ext->parent)
+			return ERR_NOT_SUPPORTED;
+
+		chunk = MIN(len, (size_t)(ext->offset + ext->size - offset));
+		ret = bio_write(ext->parent, buf, ext->parent_offset + offset - ext->offset, chunk);
+		if (ret < 0)
+			return ret;
+		if ((size_t)ret != chunk)
+			return ERR_IO;
+
+		buf += chunk;
+		offset += chunk;
+		len -= chunk;
+		done += chunk;
+	}
+
+	return done;
+}
+
+static ssize_t linear_write_block(struct bdev *dev, const void *buf, bnum_t block, uint count)
+{
+	return linear_write(dev, buf, (off_t)block * LINEAR_BLOCK_SIZE, (size_t)count * LINEAR_BLOCK_SIZE);
+}
+
+static void linear_close(struct bdev *dev)
+{
+	struct linear_bdev *linear = (struct linear_bdev *)dev;
+	size_t i;
+
+	for (i = 0; i < linear->count; ++i) {
+		if (linear->extents[i].parent)
+			bio_close(linear->extents[i].parent);
+		linear->extents[i].parent = NULL;
+	}
+}
+
+/**
+ * lk2nd_bdev_publish_linear() - Publish a device made up of runs of blocks from other devices.
+ * @name: Name of the new block device
+ * @extents: The runs of blocks, in order
+ * @count: Number of @extents
+ *
+ * This is what device-mapper's "linear" and "zero" targets do. The new device
+ * is a leaf, the devices it is made of are not.
+ *
+ * Return: 0 on success, <0 if a parent device is missing or too small
+ */
+int lk2nd_bdev_publish_linear(const char *name, const struct lk2nd_bdev_extent *extents, size_t count)
+{
+	struct linear_bdev *linear;
+	off_t offset = 0;
+	bnum_t blocks = 0;
+	size_t i;
+
+	linear = calloc(1, sizeof(*linear) + count * sizeof(linear->extents[0]));
+	if (!linear)
+		return ERR_NO_MEMORY;
+
+	for (i = 0; i < count; ++i) {
+		struct linear_extent *ext = &linear->extents[i];
+		off_t size = (off_t)extents[i].count * LINEAR_BLOCK_SIZE;
+
+		ext->offset = offset;
+		ext->size = size;
+		linear->count = i + 1;
+
+		if (extents[i].parent) {
+			ext->parent = bio_open(extents[i].parent);
+			if (!ext->parent)
+				goto err;
+
+			ext->parent_offset = (off_t)extents[i].start * LINEAR_BLOCK_SIZE;
+			if (ext->parent_offset + size > ext->parent->size)
+				goto err;
+		}
+
+		offset += size;
+		blocks += extents[i].count;
+	}
+
+	bio_initialize_bdev(&linear->dev, name, LINEAR_BLOCK_SIZE, blocks);
+	linear->dev.is_leaf = true;
+	linear->dev.read = linear_read;
+	linear->dev.read_block = linear_read_block;
+	linear->dev.write = linear_write;
+	linear->dev.write_block = linear_write_block;
+	linear->dev.close = linear_close;
+
+	for (i = 0; i < count; ++i) {
+		if (linear->extents[i].parent)
+			linear->extents[i].parent->is_leaf = false;
+	}
+
+	bio_register_device(&linear->dev);
+	return 0;
+
+err:
+	linear_close(&linear->dev);
+	free(linear);
+	return ERR_INVALID_ARGS;
+}
@@ -55,6 +55,37 @@ static ssize_t lk2nd_mmc_sdhci_bdev_read_block(struct bdev *bdev, void *buf, bnu
 	return count * block_size;
 }
 
+static ssize_t lk2nd_mmc_sdhci_bdev_write_block(struct bdev *bdev, const void *buf, bnum_t block, uint count)
+{
+	struct mmc_bdev *dev = container_of(bdev, struct mmc_bdev, dev);
+	uint32_t block_size = dev->dev.block_size;
+	uint32_t write_size = SDHCI_ADMA_MAX_TRANS_SZ;
+	uint32_t data_len = count * block_size;
+	uint64_t data_addr = (uint64_t)block * block_size;
+	uint8_t *sptr = (uint8_t *)buf;
+	uint32_t ret = 0;
+
+	arch_clean_invalidate_cache_range((addr_t)(buf), data_len);
+
+	while (data_len > write_size) {
+		ret = mmc_sdhci_write(dev->mmc, (void *)sptr, (data_addr / block_size), (write_size / block_size));
+		if (ret)
+			return ERR_IO;
+
+		sptr += write_size;
+		data_addr += write_size;
+		data_len -= write_size;
+	}
+
+	if (data_len) {
+		ret = mmc_sdhci_write(dev->mmc, (void *)sptr, (data_addr / block_size), (data_len / block_size));
+		if (ret)
+			return ERR_IO;
+	}
+
+	return count * block_size;
+}
+
 void lk2nd_mmc_sdhci_bio_register(void)
 {
 	struct mmc_bdev *bdev = malloc(sizeof(*bdev));
@@ -75,7 +106,8 @@ void lk2nd_mmc_sdhci_bio_register(void)
 
 	bdev->mmc = mmc;
 	bdev->dev.read_block = lk2nd_mmc_sdhci_bdev_read_block;
+	bdev->dev.write_block = lk2nd_mmc_sdhci_bdev_write_block;
 
 	bio_register_device(&bdev->dev);
-	partition_publish(name, 0);
+	lk2nd_partition_publish(name);
 }
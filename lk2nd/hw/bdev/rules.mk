@@ -7,6 +7,7 @@ MODULES += \
 
 OBJS += \
 	$(LOCAL_DIR)/bdev.o \
+	$(LOCAL_DIR)/linear.o \
 	$(LOCAL_DIR)/util.o \
 	$(LOCAL_DIR)/wrapper.o \
 
@@ -2,10 +2,12 @@
 /* Copyright (c) 2023 Nikita Travkin <nikita@trvn.ru> */
 
 #include <debug.h>
+#include <err.h>
 #include <lib/bio.h>
 #include <lib/partition.h>
 #include <partition_parser.h>
 #include <stdlib.h>
+#include <string.h>
 
 #include <lk2nd/init.h>
 
@@ -16,6 +18,14 @@ static ssize_t lk2nd_wrapper_bdev_read_block(struct bdev *bdev, void *buf, bnum_
 	return mmc_read((uint64_t)block * bdev->block_size, buf, count * bdev->block_size);
 }
 
+static ssize_t lk2nd_wrapper_bdev_write_block(struct bdev *bdev, const void *buf, bnum_t block, uint count)
+{
+	if (mmc_write((uint64_t)block * bdev->block_size, count * bdev->block_size, (void *)buf))
+		return ERR_IO;
+
+	return count * bdev->block_size;
+}
+
 static void lk2nd_wrapper_publish_subdevices(bdev_t *bdev)
 {
 	struct partition_entry* entries = partition_get_partition_entries();
@@ -23,12 +33,23 @@ static void lk2nd_wrapper_publish_subdevices(bdev_t *bdev)
 	bdev_t *subdev;
 	char name[32];
 
+	/* Disk images nested in partitions are picked up later by boot_scan(). */
+	if (lk2nd_partition_publish(bdev->name) >= 0)
+		return;
+
+	/* Fall back to whatever the Qualcomm partition parser found. */
 	for (i = 0; i < count; ++i) {
 		snprintf(name, sizeof(name), "%sp%d", bdev->name, i);
-		bio_publish_subdevice(bdev->name, name, entries[i].first_lba, entries[i].size);
+		if (bio_publish_subdevice(bdev->name, name, entries[i].first_lba, entries[i].size))
+			continue;
 
 		subdev = bio_open(name);
+		if (!subdev)
+			continue;
 		subdev->label = (char *)entries[i].name;
+		memcpy(subdev->type_guid, entries[i].type_guid, sizeof(subdev->type_guid));
+		memcpy(subdev->unique_guid, entries[i].unique_partition_guid, sizeof(subdev->unique_guid));
+		subdev->attributes = entries[i].attribute_flag;
 		bio_close(subdev);
 
 		/* There may be subpartitions... */
@@ -47,11 +68,8 @@ void lk2nd_wrapper_bio_register(void)
 	bio_initialize_bdev(bdev, name, block_size, card_capacity / block_size);
 
 	bdev->read_block = lk2nd_wrapper_bdev_read_block;
+	bdev->write_block = lk2nd_wrapper_bdev_write_block;
 
 	bio_register_device(bdev);
-
-	/*
-	 * FIXME: the lk's partition module is not GPT aware yet.
-	 */
 	lk2nd_wrapper_publish_subdevices(bdev);
 }
@@ -2,7 +2,12 @@
 #ifndef LK2ND_BOOT_H
 #define LK2ND_BOOT_H
 
-void lk2nd_boot(void);
+#include <stdbool.h>
+#include <stdint.h>
+
+bool lk2nd_boot(void);
+uint64_t lk2nd_boot_image_offset(const char *label);
+bool lk2nd_boot_have_atags(void);
 
 #endif /* LK2ND_BOOT_H */
 
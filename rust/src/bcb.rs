@@ -0,0 +1,206 @@
+//! The Android bootloader control block in the `misc` partition: what recovery or `reboot
+//! bootloader` asked the bootloader to do next, and the A/B slot metadata.
+//!
+//! Slot tries are only read here, the menu doesn't count them down.
+
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use crc::{Crc, CRC_32_ISO_HDLC};
+use snafu::Snafu;
+use crate::bio::{self, BioError};
+
+#[derive(Debug, Snafu)]
+pub enum BcbError {
+    #[snafu(display("no misc partition"))]
+    NotFound,
+    #[snafu(display("failed to open {name}"))]
+    Open { name: String },
+    #[snafu(display("I/O error: {source}"))]
+    Io { source: BioError },
+}
+
+impl From<BioError> for BcbError {
+    fn from(source: BioError) -> Self {
+        BcbError::Io { source }
+    }
+}
+
+const MISC_LABEL: &str = "misc";
+
+// struct bootloader_message, at the start of misc.
+const COMMAND_LEN: usize = 32;
+const RECOVERY_OFFSET: usize = 64;
+const RECOVERY_LEN: usize = 768;
+
+/// Boot into recovery, with the arguments in `recovery`. Recovery clears it when it's done.
+pub const COMMAND_BOOT_RECOVERY: &str = "boot-recovery";
+/// Stay in the bootloader, once.
+pub const COMMAND_BOOTONCE_BOOTLOADER: &str = "bootonce-bootloader";
+
+// struct bootloader_control, in the slot_suffix field of struct bootloader_message_ab.
+const CONTROL_OFFSET: u64 = 2048;
+const CONTROL_SIZE: usize = 32;
+const CONTROL_MAGIC: u32 = 0x42414342;
+const CONTROL_VERSION: u8 = 1;
+const CONTROL_MAX_SLOTS: usize = 4;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+#[derive(Clone, Copy, Debug)]
+pub struct Slot {
+    /// 0 means the slot can't be booted, higher is preferred.
+    pub priority: u8,
+    pub tries_remaining: u8,
+    pub successful: bool,
+}
+
+impl Slot {
+    pub fn is_bootable(&self) -> bool {
+        self.priority > 0 && (self.successful || self.tries_remaining > 0)
+    }
+}
+
+#[derive(Debug)]
+pub struct Misc {
+    /// Name of the misc block device.
+    device: String,
+    pub command: String,
+    pub recovery: String,
+    /// A/B slots, empty if the device doesn't have any.
+    pub slots: Vec<Slot>,
+}
+
+/// The suffix of the partitions of slot `idx`, e.g. `_a`.
+pub fn slot_suffix(idx: usize) -> String {
+    ['_', (b'a' + idx as u8) as char].iter().collect()
+}
+
+fn string(data: &[u8]) -> String {
+    let len = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..len]).to_string()
+}
+
+fn parse_slots(control: &[u8]) -> Vec<Slot> {
+    if LittleEndian::read_u32(&control[4..]) != CONTROL_MAGIC || control[8] != CONTROL_VERSION
+        || CRC32.checksum(&control[..28]) != LittleEndian::read_u32(&control[28..])
+    {
+        return Vec::new();
+    }
+    let count = (control[9] & 0x7) as usize;
+    control[12..12 + 2 * count.min(CONTROL_MAX_SLOTS)].chunks_exact(2).map(|v| Slot {
+        priority: v[0] & 0xf,
+        tries_remaining: (v[0] >> 4) & 0x7,
+        successful: v[0] & 0x80 != 0,
+    }).collect()
+}
+
+impl Misc {
+    /// The slot to boot: the bootable one with the highest priority.
+    pub fn active_slot(&self) -> Option<usize> {
+        self.slots.iter().enumerate()
+            .filter(|(_, v)| v.is_bootable())
+            .max_by_key(|(idx, v)| (v.priority, core::cmp::Reverse(*idx)))
+            .map(|(idx, _)| idx)
+    }
+
+    /// Clear the command, so that it's only acted on once.
+    pub fn clear_command(&mut self) -> Result<(), BcbError> {
+        let mut dev = bio::open_writable(&self.device).map_err(|_| BcbError::Open { name: self.device.clone() })?;
+        dev.write_at(0, &[0; COMMAND_LEN])?;
+        self.command.clear();
+        Ok(())
+    }
+}
+
+/// Read the bootloader control block from the partition labeled `misc`.
+pub fn read() -> Result<Misc, BcbError> {
+    let device = bio::get_bdevs().unwrap_or_default().into_iter()
+        .find(|v| v.label.as_deref() == Some(MISC_LABEL))
+        .ok_or(BcbError::NotFound)?
+        .name;
+    let mut dev = bio::open(&device).map_err(|_| BcbError::Open { name: device.clone() })?;
+
+    let mut message = vec![0; RECOVERY_OFFSET + RECOVERY_LEN];
+    dev.read_at(0, &mut message)?;
+    let mut control = [0; CONTROL_SIZE];
+    dev.read_at(CONTROL_OFFSET, &mut control)?;
+
+    Ok(Misc {
+        command: string(&message[..COMMAND_LEN]),
+        recovery: string(&message[RECOVERY_OFFSET..]),
+        slots: parse_slots(&control),
+        device,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bootloader_control with `slots` as (priority, tries_remaining, successful).
+    fn control(slots: &[(u8, u8, bool)]) -> [u8; CONTROL_SIZE] {
+        let mut control = [0; CONTROL_SIZE];
+        control[..2].copy_from_slice(b"_a");
+        LittleEndian::write_u32(&mut control[4..], CONTROL_MAGIC);
+        control[8] = CONTROL_VERSION;
+        control[9] = slots.len() as u8;
+        for (i, &(priority, tries, successful)) in slots.iter().enumerate() {
+            control[12 + i * 2] = priority | tries << 4 | (successful as u8) << 7;
+        }
+        let crc = CRC32.checksum(&control[..28]);
+        LittleEndian::write_u32(&mut control[28..], crc);
+        control
+    }
+
+    fn misc(control: &[u8]) -> Misc {
+        Misc {
+            device: "misc".to_string(),
+            command: String::new(),
+            recovery: String::new(),
+            slots: parse_slots(control),
+        }
+    }
+
+    #[test]
+    fn slots() {
+        let slots = parse_slots(&control(&[(15, 0, true), (14, 7, false)]));
+        assert_eq!(slots.len(), 2);
+        assert_eq!((slots[0].priority, slots[0].tries_remaining, slots[0].successful), (15, 0, true));
+        assert_eq!((slots[1].priority, slots[1].tries_remaining, slots[1].successful), (14, 7, false));
+    }
+
+    #[test]
+    fn invalid_control() {
+        let mut bad_crc = control(&[(15, 0, true)]);
+        bad_crc[12] = 14;
+        assert!(parse_slots(&bad_crc).is_empty());
+
+        let mut bad_magic = control(&[(15, 0, true)]);
+        bad_magic[4] = 0;
+        assert!(parse_slots(&bad_magic).is_empty());
+
+        assert!(parse_slots(&[0; CONTROL_SIZE]).is_empty());
+    }
+
+    #[test]
+    fn active_slot() {
+        // Highest priority wins.
+        assert_eq!(misc(&control(&[(14, 0, true), (15, 0, true)])).active_slot(), Some(1));
+        // Slots that haven't booted successfully need tries left.
+        assert_eq!(misc(&control(&[(14, 0, true), (15, 0, false)])).active_slot(), Some(0));
+        assert_eq!(misc(&control(&[(14, 0, true), (15, 3, false)])).active_slot(), Some(1));
+        // Priority 0 means unbootable.
+        assert_eq!(misc(&control(&[(0, 7, true), (0, 7, true)])).active_slot(), None);
+        // Ties go to the first slot.
+        assert_eq!(misc(&control(&[(15, 0, true), (15, 0, true)])).active_slot(), Some(0));
+        assert_eq!(misc(&[0; CONTROL_SIZE]).active_slot(), None);
+    }
+
+    #[test]
+    fn suffixes() {
+        assert_eq!(slot_suffix(0), "_a");
+        assert_eq!(slot_suffix(1), "_b");
+    }
+}
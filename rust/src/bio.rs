@@ -1,10 +1,15 @@
+use alloc::boxed::Box;
 use alloc::ffi::CString;
 use alloc::string::{String, ToString};
+use alloc::vec;
 use alloc::vec::Vec;
 use core::ffi::{c_char, c_int, c_long, c_longlong, c_uint, c_ulong, c_void, CStr};
 
 use crate::lk_list::{list_node, LkListIterator};
 use crate::lk_mutex::{acquire, Mutex, MutexGuard};
+use crate::guid::{self, Guid};
+use crate::kernel_boot::{BootError, Payload};
+use crate::partition::Partition;
 use crate::println;
 use fatfs::{IoBase, IoError, Read, Seek, SeekFrom, Write};
 use snafu::prelude::*;
@@ -17,18 +22,66 @@ pub struct BlockDev {
     pub block_count: u32,
     pub label: Option<String>,
     pub is_leaf: bool,
+    /// Partition type GUID, if this is a GPT partition.
+    pub type_guid: Option<Guid>,
+    /// GPT partition attributes. Only meaningful if there is a `type_guid`.
+    pub attributes: u64,
+}
+
+/// GPT attribute for partitions UEFI firmware doesn't make block devices for, e.g. because
+/// something else owns them.
+const GPT_ATTR_NO_BLOCK_IO: u64 = 1 << 1;
+
+impl BlockDev {
+    /// Whether the partition is hidden from boot loaders by its GPT attributes.
+    pub fn is_hidden(&self) -> bool {
+        self.type_guid.is_some() && self.attributes & GPT_ATTR_NO_BLOCK_IO != 0
+    }
 }
 
 pub struct OpenDevice {
-    dev: *mut sys::bdev_t,
+    dev: Device,
     read_pos: c_longlong,
     size: c_longlong,
+    /// Only set for devices on the write allowlist, see [open_writable].
+    writable: bool,
+}
+
+enum Device {
+    Bdev(*mut sys::bdev_t),
+    /// Not a block device at all, e.g. a filesystem image in a file.
+    Image(Box<dyn Image>),
+}
+
+/// Something that can be read like a block device without being one.
+pub trait Image {
+    /// Fill `buf` from `offset` bytes in. Reads never go past the size the device was opened with.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BioError>;
+}
+
+/// Disk images in memory, for tests.
+#[cfg(test)]
+impl Image for Vec<u8> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BioError> {
+        buf.copy_from_slice(&self[offset as usize..][..buf.len()]);
+        Ok(())
+    }
 }
 
+/// What images claim their block size is, for anything that cares.
+const IMAGE_BLOCK_SIZE: u64 = 512;
+
+/// Partition types and names that may be opened for writing. Nothing else can be written to, so
+/// that a bug can't scribble over the modem, TZ or other firmware partitions.
+const WRITABLE_TYPES: [Guid; 2] = [guid::ESP, guid::XBOOTLDR];
+const WRITABLE_LABELS: [&str; 2] = ["esp", "misc"];
+
 impl Drop for OpenDevice {
     fn drop(&mut self) {
-        unsafe {
-            sys::bio_close(self.dev);
+        if let Device::Bdev(dev) = self.dev {
+            unsafe {
+                sys::bio_close(dev);
+            }
         }
     }
 }
@@ -38,7 +91,11 @@ pub enum BioError {
     UnexpectedEOF,
     WriteZero,
     #[snafu(display("read error {code}"))]
-    ReadError{code: c_long}
+    ReadError{code: c_long},
+    #[snafu(display("write error {code}"))]
+    WriteError{code: c_long},
+    #[snafu(display("device is read-only"))]
+    ReadOnly,
 }
 
 impl IoError for BioError {
@@ -55,16 +112,124 @@ impl IoError for BioError {
     }
 }
 
+impl OpenDevice {
+    /// Open `image`, `size` bytes long, as a device.
+    pub fn from_image(image: Box<dyn Image>, size: u64) -> Self {
+        Self {
+            dev: Device::Image(image),
+            read_pos: 0,
+            size: size as c_longlong,
+            writable: false,
+        }
+    }
+
+    pub fn block_size(&self) -> u64 {
+        match self.dev {
+            Device::Bdev(dev) => unsafe { (*dev).block_size as u64 },
+            Device::Image(_) => IMAGE_BLOCK_SIZE,
+        }
+    }
+
+    pub fn block_count(&self) -> u32 {
+        match self.dev {
+            Device::Bdev(dev) => unsafe { (*dev).block_count },
+            Device::Image(_) => (self.size as u64 / IMAGE_BLOCK_SIZE) as u32,
+        }
+    }
+
+    /// Fill `buf` from `offset` bytes into the device, without moving the read position.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BioError> {
+        let dev = match &mut self.dev {
+            Device::Bdev(dev) => *dev,
+            Device::Image(image) => {
+                if offset.checked_add(buf.len() as u64).is_none_or(|end| end > self.size as u64) {
+                    return Err(BioError::UnexpectedEOF);
+                }
+                return image.read_at(offset, buf);
+            }
+        };
+        let read = unsafe {
+            sys::bio_read(dev, buf.as_mut_ptr() as _, offset as c_longlong, buf.len() as c_ulong)
+        };
+        if read < 0 {
+            return Err(BioError::ReadError{code: read});
+        }
+        if (read as usize) < buf.len() {
+            return Err(BioError::UnexpectedEOF);
+        }
+        Ok(())
+    }
+
+    /// Write `buf` to `offset` bytes into the device, without moving the read position. Partial
+    /// blocks at either end are read first so the rest of them stays as it was.
+    pub fn write_at(&mut self, mut offset: u64, mut buf: &[u8]) -> Result<(), BioError> {
+        let Device::Bdev(dev) = self.dev else {
+            return Err(BioError::ReadOnly);
+        };
+        if !self.writable {
+            return Err(BioError::ReadOnly);
+        }
+        if offset.checked_add(buf.len() as u64).is_none_or(|end| end > self.size as u64) {
+            return Err(BioError::UnexpectedEOF);
+        }
+
+        let block_size = self.block_size();
+        let mut block = vec![0; block_size as usize];
+        while !buf.is_empty() {
+            let lba = offset / block_size;
+            let skip = (offset % block_size) as usize;
+            let len = if skip == 0 && buf.len() as u64 >= block_size {
+                let len = (buf.len() as u64 / block_size * block_size) as usize;
+                write_blocks(dev, lba, &buf[..len])?;
+                len
+            } else {
+                let len = buf.len().min(block_size as usize - skip);
+                self.read_at(lba * block_size, &mut block)?;
+                block[skip..skip + len].copy_from_slice(&buf[..len]);
+                write_blocks(dev, lba, &block)?;
+                len
+            };
+            offset += len as u64;
+            buf = &buf[len..];
+        }
+        Ok(())
+    }
+}
+
+/// Write whole blocks from `buf` to `dev`, starting at block `lba`.
+fn write_blocks(dev: *mut sys::bdev_t, lba: u64, buf: &[u8]) -> Result<(), BioError> {
+    let block_size = unsafe { (*dev).block_size as usize };
+    let lba: c_uint = lba.try_into().map_err(|_| BioError::UnexpectedEOF)?;
+    let count: c_uint = (buf.len() / block_size).try_into().map_err(|_| BioError::UnexpectedEOF)?;
+    let written = unsafe { sys::bio_write_block(dev, buf.as_ptr() as _, lba, count) };
+    if written < 0 {
+        return Err(BioError::WriteError{code: written});
+    }
+    if (written as usize) < buf.len() {
+        return Err(BioError::WriteZero);
+    }
+    Ok(())
+}
+
 impl IoBase for OpenDevice {
     type Error = BioError;
 }
 
 impl Read for OpenDevice {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let dev = match &mut self.dev {
+            Device::Bdev(dev) => *dev,
+            Device::Image(image) => {
+                let len = buf.len().min((self.size - self.read_pos).max(0) as usize);
+                image.read_at(self.read_pos as u64, &mut buf[..len])?;
+                self.read_pos += len as c_longlong;
+                return Ok(len);
+            }
+        };
         // NOTE: explicitly not doing bounds checks here, as they're already done in bio_read()
         let read = unsafe {
             sys::bio_read(
-                self.dev,
+                dev,
                 buf.as_mut_ptr() as _,
                 self.read_pos,
                 buf.len() as c_ulong,
@@ -79,9 +244,11 @@ impl Read for OpenDevice {
 }
 
 impl Write for OpenDevice {
-    fn write(&mut self, _buf: &[u8]) -> Result<usize, Self::Error> {
-        // unimplemented - this should trigger a WriteZero error
-        Ok(0)
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let len = buf.len().min((self.size - self.read_pos).max(0) as usize);
+        self.write_at(self.read_pos as u64, &buf[..len])?;
+        self.read_pos += len as c_longlong;
+        Ok(len)
     }
 
     fn flush(&mut self) -> Result<(), Self::Error> {
@@ -90,6 +257,17 @@ impl Write for OpenDevice {
     }
 }
 
+/// The whole device, for loading a kernel straight from a partition.
+impl Payload for OpenDevice {
+    fn len(&self) -> Result<u64, BootError> {
+        Ok(self.size as u64)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BootError> {
+        OpenDevice::read_at(self, offset, buf).map_err(|_| BootError::Io)
+    }
+}
+
 impl Seek for OpenDevice {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
         match pos {
@@ -121,10 +299,95 @@ pub fn get_bdevs() -> Result<Vec<BlockDev>, ()> {
             is_leaf: dev.is_leaf,
             label,
             block_count: dev.block_count,
+            type_guid: Some(Guid(dev.type_guid)).filter(|v| !v.is_zero()),
+            attributes: dev.attributes,
         })
     }).collect())
 }
 
+/// Publish `partition` of the `parent` device as a new device called `name`.
+pub fn publish_subdevice(parent: &str, name: &str, partition: &Partition) -> Result<(), ()> {
+    let start: c_uint = partition.start_lba.try_into().map_err(|_| ())?;
+    let len: usize = partition.lba_count.try_into().map_err(|_| ())?;
+    let c_parent = CString::new(parent).map_err(|_| ())?;
+    let c_name = CString::new(name).map_err(|_| ())?;
+    if unsafe { sys::bio_publish_subdevice(c_parent.as_ptr(), c_name.as_ptr(), start, len) } < 0 {
+        return Err(());
+    }
+
+    let dev = unsafe { sys::bio_open(c_name.as_ptr()).as_mut() }.ok_or(())?;
+    if let Some(label) = partition.name.as_deref().and_then(leak_label) {
+        dev.label = label;
+    }
+    dev.type_guid = partition.type_guid.unwrap_or_default().0;
+    dev.unique_guid = partition.partuuid.unwrap_or_default().0;
+    dev.attributes = partition.attributes;
+    unsafe { sys::bio_close(dev) };
+    Ok(())
+}
+
+/// Remove the devices called `names` published with [publish_subdevice] again, which makes
+/// `parent` a leaf device again.
+pub fn unpublish_subdevices(parent: &str, names: &[String]) {
+    for name in names {
+        let Ok(c_name) = CString::new(name.as_str()) else {
+            continue;
+        };
+        if let Some(dev) = unsafe { sys::bio_open(c_name.as_ptr()).as_mut() } {
+            unsafe {
+                sys::bio_unregister_device(dev);
+                sys::bio_close(dev);
+            }
+        }
+    }
+    let Ok(c_parent) = CString::new(parent) else {
+        return;
+    };
+    if let Some(dev) = unsafe { sys::bio_open(c_parent.as_ptr()).as_mut() } {
+        dev.is_leaf = true;
+        unsafe { sys::bio_close(dev) };
+    }
+}
+
+/// A run of 512 byte sectors of a device published with [publish_linear].
+pub struct LinearExtent<'a> {
+    /// The device the sectors are on, or `None` for sectors that read as zeroes.
+    pub parent: Option<&'a str>,
+    pub start: u64,
+    pub count: u64,
+}
+
+/// Publish a device called `name` that is made up of `extents`, in order.
+pub fn publish_linear(name: &str, label: &str, extents: &[LinearExtent]) -> Result<(), ()> {
+    let parents = extents.iter()
+        .map(|v| v.parent.map(CString::new).transpose())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| ())?;
+    let c_extents = extents.iter().zip(&parents).map(|(extent, parent)| {
+        Ok(sys::lk2nd_bdev_extent {
+            parent: parent.as_ref().map_or(core::ptr::null(), |v| v.as_ptr()),
+            start: extent.start.try_into().map_err(|_| ())?,
+            count: extent.count.try_into().map_err(|_| ())?,
+        })
+    }).collect::<Result<Vec<_>, ()>>()?;
+    let c_name = CString::new(name).map_err(|_| ())?;
+    if unsafe { sys::lk2nd_bdev_publish_linear(c_name.as_ptr(), c_extents.as_ptr(), c_extents.len()) } < 0 {
+        return Err(());
+    }
+
+    let label = leak_label(label).ok_or(())?;
+    let dev = unsafe { sys::bio_open(c_name.as_ptr()).as_mut() }.ok_or(())?;
+    dev.label = label;
+    unsafe { sys::bio_close(dev) };
+    Ok(())
+}
+
+/// Turn `label` into a C string for a published device, or `None` if it has a NUL in it.
+fn leak_label(label: &str) -> Option<*mut c_char> {
+    // Published devices are never freed, so neither is their label.
+    CString::new(label).ok().map(CString::into_raw)
+}
+
 pub fn open(name: &str) -> Result<OpenDevice, ()> {
     let name = CString::new(name).map_err(|_| ())?;
     let dev = unsafe { sys::bio_open(name.as_ptr()) };
@@ -134,13 +397,48 @@ pub fn open(name: &str) -> Result<OpenDevice, ()> {
     } else {
         let dev_ref = unsafe { &mut *dev };
         Ok(OpenDevice {
-            dev,
+            dev: Device::Bdev(dev),
             read_pos: 0,
             size: dev_ref.size,
+            writable: false,
         })
     }
 }
 
+/// Like [open], but the device can be written to as well. Fails for anything that isn't on the
+/// write allowlist.
+pub fn open_writable(name: &str) -> Result<OpenDevice, ()> {
+    let mut dev = open(name)?;
+    let Device::Bdev(bdev) = dev.dev else {
+        return Err(());
+    };
+    let bdev = unsafe { &*bdev };
+    let label = (!bdev.label.is_null()).then(|| unsafe { CStr::from_ptr(bdev.label) }.to_str().ok()).flatten();
+    if !WRITABLE_TYPES.contains(&Guid(bdev.type_guid)) && !label.is_some_and(|v| WRITABLE_LABELS.contains(&v)) {
+        println!("{}: not allowed to write to {:?}", name, label);
+        return Err(());
+    }
+    dev.writable = true;
+    Ok(dev)
+}
+
+/// Host test builds don't link against LK. They only open images, which never get here.
+#[cfg(test)]
+mod host {
+    use core::ffi::{c_long, c_longlong, c_ulong, c_void};
+    use super::sys::bdev_t;
+
+    #[no_mangle]
+    extern "C" fn bio_close(_dev: *mut bdev_t) {
+        unreachable!()
+    }
+
+    #[no_mangle]
+    extern "C" fn bio_read(_dev: *mut bdev_t, _buf: *mut c_void, _offset: c_longlong, _len: c_ulong) -> c_long {
+        unreachable!()
+    }
+}
+
 mod sys {
     #![allow(non_camel_case_types)]
 
@@ -165,17 +463,45 @@ mod sys {
         pub block_count: c_uint,
         pub label: *mut c_char,
         pub is_leaf: bool,
+        pub type_guid: [u8; 16],
+        pub unique_guid: [u8; 16],
+        pub attributes: u64,
+    }
+
+    #[repr(C)]
+    pub struct lk2nd_bdev_extent {
+        pub parent: *const c_char,
+        pub start: c_uint,
+        pub count: c_uint,
     }
 
     extern "C" {
         pub fn bio_get_bdevs() -> *mut bdev_struct;
         pub fn bio_open(name: *const c_char) -> *mut bdev_t;
         pub fn bio_close(dev: *mut bdev_t);
+        pub fn bio_unregister_device(dev: *mut bdev_t);
+        pub fn bio_publish_subdevice(
+            parent_dev: *const c_char,
+            subdev: *const c_char,
+            startblock: c_uint,
+            len: usize,
+        ) -> c_int;
+        pub fn lk2nd_bdev_publish_linear(
+            name: *const c_char,
+            extents: *const lk2nd_bdev_extent,
+            count: usize,
+        ) -> c_int;
         pub fn bio_read(
             dev: *mut bdev_t,
             buf: *mut c_void,
             offset: c_longlong,
             len: c_ulong,
         ) -> c_long;
+        pub fn bio_write_block(
+            dev: *mut bdev_t,
+            buf: *const c_void,
+            block: c_uint,
+            count: c_uint,
+        ) -> c_long;
     }
 }
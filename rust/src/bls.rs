@@ -0,0 +1,373 @@
+//! Boot Loader Specification Type #1 entries, i.e. `/loader/entries/*.conf`.
+//!
+//! See https://uapi-group.org/specifications/specs/boot_loader_specification/
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use anyhow::{Context, Error};
+use crate::{BootOption, kernel_boot, lk2nd_device, println};
+use crate::fbcon::FbCon888;
+use crate::fs::{self, BootFs};
+use crate::kernel_boot::{Concat, Payload};
+
+const ENTRIES_DIR: &str = "/loader/entries";
+
+/// A single entry file. Only the keys we can make use of are kept.
+#[derive(Clone, Debug, Default)]
+pub struct Entry {
+    /// The file name without `.conf`.
+    pub id: String,
+    pub title: Option<String>,
+    pub version: Option<String>,
+    pub machine_id: Option<String>,
+    pub sort_key: Option<String>,
+    pub linux: Option<String>,
+    pub initrd: Vec<String>,
+    pub devicetree: Option<String>,
+    pub devicetree_overlay: Vec<String>,
+    pub options: Vec<String>,
+    pub architecture: Option<String>,
+}
+
+impl Entry {
+    /// The name to show in the boot menu.
+    pub fn title(&self) -> &str {
+        self.title.as_deref().unwrap_or(&self.id)
+    }
+}
+
+/// Parse an entry file. Unknown keys are ignored, as the specification asks.
+pub fn parse(id: &str, data: &str) -> Entry {
+    let mut entry = Entry {
+        id: id.to_string(),
+        ..Default::default()
+    };
+
+    for line in data.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.split_once(|c: char| c.is_ascii_whitespace()) {
+            Some((key, value)) => (key, value.trim()),
+            None => (line, ""),
+        };
+        let value = value.to_string();
+
+        match key {
+            "title" => entry.title = Some(value),
+            "version" => entry.version = Some(value),
+            "machine-id" => entry.machine_id = Some(value),
+            "sort-key" => entry.sort_key = Some(value),
+            "linux" => entry.linux = Some(value),
+            "initrd" => entry.initrd.push(value),
+            "devicetree" => entry.devicetree = Some(value),
+            "devicetree-overlay" => entry.devicetree_overlay.extend(value.split_ascii_whitespace().map(|v| v.to_string())),
+            "options" => entry.options.push(value),
+            "architecture" => entry.architecture = Some(value.to_ascii_lowercase()),
+            _ => {}
+        }
+    }
+
+    entry
+}
+
+/// The menu order from the specification: entries with a `sort-key` come first, ordered by it,
+/// then by `machine-id` and newest `version` first. Everything else goes by newest file name.
+pub fn compare(a: &Entry, b: &Entry) -> Ordering {
+    match (&a.sort_key, &b.sort_key) {
+        (Some(_), None) => return Ordering::Less,
+        (None, Some(_)) => return Ordering::Greater,
+        (Some(ka), Some(kb)) => {
+            let ordering = ka.cmp(kb)
+                .then_with(|| a.machine_id.cmp(&b.machine_id))
+                .then_with(|| version_cmp(
+                    b.version.as_deref().unwrap_or_default(),
+                    a.version.as_deref().unwrap_or_default(),
+                ));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        (None, None) => {}
+    }
+    version_cmp(&b.id, &a.id)
+}
+
+/// The version comparison from the UAPI Version Format Specification, a port of systemd's
+/// strverscmp_improved().
+pub fn version_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    if a.is_empty() || b.is_empty() {
+        return a.cmp(b);
+    }
+
+    fn is_valid(c: u8) -> bool {
+        c.is_ascii_alphanumeric() || matches!(c, b'~' | b'-' | b'^' | b'.')
+    }
+    fn first(s: &[u8]) -> u8 {
+        s.first().copied().unwrap_or(0)
+    }
+    fn skip(s: &[u8], n: usize) -> &[u8] {
+        &s[n.min(s.len())..]
+    }
+    fn prefix_len(s: &[u8], f: fn(&u8) -> bool) -> usize {
+        s.iter().take_while(|c| f(c)).count()
+    }
+
+    loop {
+        a = skip(a, a.iter().take_while(|&&c| !is_valid(c)).count());
+        b = skip(b, b.iter().take_while(|&&c| !is_valid(c)).count());
+
+        // '~' marks pre-releases, which are older.
+        if first(a) == b'~' || first(b) == b'~' {
+            let ordering = (first(a) != b'~').cmp(&(first(b) != b'~'));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            a = skip(a, 1);
+            b = skip(b, 1);
+        }
+
+        // Once either runs out, the longer one is newer.
+        if a.is_empty() || b.is_empty() {
+            return a.cmp(b);
+        }
+
+        // '-' separates version and release.
+        if first(a) == b'-' || first(b) == b'-' {
+            let ordering = (first(a) != b'-').cmp(&(first(b) != b'-'));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            a = skip(a, 1);
+            b = skip(b, 1);
+        }
+
+        // '^' marks patched versions. These are newer than the plain version, which was handled
+        // above, but older than any further point release.
+        if first(a) == b'^' || first(b) == b'^' {
+            let ordering = (first(a) != b'^').cmp(&(first(b) != b'^'));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            a = skip(a, 1);
+            b = skip(b, 1);
+        }
+
+        // '.' separates point releases.
+        if first(a) == b'.' || first(b) == b'.' {
+            let ordering = (first(a) != b'.').cmp(&(first(b) != b'.'));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            a = skip(a, 1);
+            b = skip(b, 1);
+        }
+
+        let (la, lb);
+        if first(a).is_ascii_digit() || first(b).is_ascii_digit() {
+            // Numbers are newer than letters, and compare by value.
+            a = skip(a, prefix_len(a, |&c| c == b'0'));
+            b = skip(b, prefix_len(b, |&c| c == b'0'));
+            la = prefix_len(a, u8::is_ascii_digit);
+            lb = prefix_len(b, u8::is_ascii_digit);
+            let ordering = (la != 0).cmp(&(lb != 0))
+                .then(la.cmp(&lb))
+                .then_with(|| a[..la].cmp(&b[..lb]));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        } else {
+            la = prefix_len(a, u8::is_ascii_alphabetic);
+            lb = prefix_len(b, u8::is_ascii_alphabetic);
+            let n = la.min(lb);
+            let ordering = a[..n].cmp(&b[..n]).then(la.cmp(&lb));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+
+        a = skip(a, la);
+        b = skip(b, lb);
+    }
+}
+
+struct BlsBootConfig {
+    id: String,
+    name: String,
+    fs: Rc<dyn BootFs>,
+    linux: String,
+    initrd: Vec<String>,
+    devicetree: Option<String>,
+    overlays: Vec<String>,
+    cmdline: String,
+}
+
+impl BlsBootConfig {
+    fn try_boot(&self) -> anyhow::Result<()> {
+        let open = |path: &str| self.fs.open(path).map_err(Error::msg).with_context(|| format!("open {} failed", path));
+        let mut kernel = open(&self.linux)?;
+        let mut dtb = self.devicetree.as_deref().map(open).transpose()?;
+        let mut overlays = Vec::new();
+        for overlay in &self.overlays {
+            overlays.push(open(overlay)?);
+        }
+        let mut initrd = Vec::new();
+        for path in &self.initrd {
+            initrd.push(open(path)?);
+        }
+        let mut initrd = (!initrd.is_empty()).then(|| Concat::new(initrd));
+
+        kernel_boot::boot(
+            kernel.as_mut(),
+            dtb.as_mut().map(|v| v.as_mut() as &mut dyn Payload),
+            &mut overlays,
+            initrd.as_mut().map(|v| v as &mut dyn Payload),
+            &self.cmdline,
+        ).map_err(Error::msg)
+    }
+}
+
+impl BootOption for BlsBootConfig {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn label(&self) -> &str {
+        &self.name
+    }
+
+    fn splash(&self, _display: &mut FbCon888) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn boot(&mut self) -> ! {
+        if let Err(err) = self.try_boot() {
+            println!("booting {} failed: {:?}", self.name, err);
+        }
+        panic!("noes");
+    }
+}
+
+/// Turn parsed entries into boot options, in menu order.
+fn boot_options(mut entries: Vec<Entry>, suffix: &str, fs: Rc<dyn BootFs>) -> Vec<Box<dyn BootOption>> {
+    entries.sort_by(compare);
+
+    let mut options: Vec<Box<dyn BootOption>> = Vec::new();
+    for entry in &entries {
+        if let Some(arch) = &entry.architecture {
+            if arch != "aa64" && arch != "arm" {
+                continue;
+            }
+        }
+        let Some(linux) = &entry.linux else {
+            println!("bls: skipping {}: no linux", entry.id);
+            continue;
+        };
+
+        // Tell apart entries that only differ in their version, like systemd-boot does.
+        let mut name = entry.title().to_string();
+        if entries.iter().filter(|v| v.title() == entry.title()).count() > 1 {
+            if let Some(version) = &entry.version {
+                name = format!("{} ({})", name, version);
+            }
+        }
+
+        let path = |v: &str| fs::join("/", v);
+        // 32-bit kernels can still make do with an appended DTB or ATAGs.
+        let devicetree = match &entry.devicetree {
+            Some(devicetree) => Some(path(devicetree)),
+            None => find_dtb(fs.as_ref(), entry, &path(linux)),
+        };
+        if devicetree.is_none() && entry.architecture.as_deref() != Some("arm") {
+            println!("bls: skipping {}: no devicetree for this device", entry.id);
+            continue;
+        }
+
+        options.push(Box::new(BlsBootConfig {
+            // systemd-boot matches the default entry against the file name.
+            id: format!("{}.conf", entry.id),
+            name: format!("{}{}", name, suffix),
+            fs: fs.clone(),
+            linux: path(linux),
+            initrd: entry.initrd.iter().map(|v| path(v)).collect(),
+            devicetree,
+            overlays: entry.devicetree_overlay.iter().map(|v| path(v)).collect(),
+            cmdline: entry.options.join(" "),
+        }));
+    }
+    options
+}
+
+/// Look for a DTB for this device when the entry doesn't name one: next to the kernel, or where
+/// distributions install them, like `/dtbs/<version>` (Debian) or `/dtb-<version>` (Fedora).
+fn find_dtb(fs: &dyn BootFs, entry: &Entry, linux: &str) -> Option<String> {
+    let mut dirs: Vec<String> = linux.rsplit_once('/').map(|(dir, _)| dir.to_string()).into_iter().collect();
+    if let Some(version) = &entry.version {
+        dirs.push(format!("/dtbs/{}", version));
+        dirs.push(format!("/dtb-{}", version));
+    }
+    dirs.extend(["/dtbs".to_string(), "/dtb".to_string()]);
+    dirs.iter()
+        .flat_map(|dir| lk2nd_device::dtb_paths(dir))
+        .find(|path| fs.exists(path))
+}
+
+fn entry_id(name: &str) -> Option<&str> {
+    name.strip_suffix(".conf").filter(|v| !v.is_empty() && !v.starts_with('.'))
+}
+
+/// Collect the entries in `/loader/entries`. `suffix` is appended to their names.
+pub fn scan(fs: Rc<dyn BootFs>, suffix: &str) -> anyhow::Result<Vec<Box<dyn BootOption>>> {
+    let mut entries = Vec::new();
+    for entry in fs.read_dir(ENTRIES_DIR).map_err(Error::msg)? {
+        let Some(id) = entry_id(&entry.name).filter(|_| !entry.is_dir) else {
+            continue;
+        };
+        let path = format!("{}/{}", ENTRIES_DIR, entry.name);
+        match fs.read(&path) {
+            Ok(data) => entries.push(parse(id, &String::from_utf8_lossy(&data))),
+            Err(err) => println!("bls: failed to read {}: {:?}", path, err),
+        }
+    }
+
+    Ok(boot_options(entries, suffix, fs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_order() {
+        // Oldest first, from systemd's test-string-util.c.
+        let versions = [
+            "", "~1", "ab", "abb", "abc", "0001", "002", "12", "122", "122.9", "123~rc1", "123", "123-a",
+            "123-a.1", "123-a1", "123-a1.1", "123-a3", "123-a3.1", "123^patch1", "123^1", "123.a-1",
+            "123.1-1", "123a-1", "124",
+        ];
+        for (i, a) in versions.iter().enumerate() {
+            for (j, b) in versions.iter().enumerate() {
+                assert_eq!(version_cmp(a, b), i.cmp(&j), "{:?} vs {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn version_equal() {
+        assert_eq!(version_cmp("1.01", "1.1"), Ordering::Equal);
+        // Characters outside the spec are skipped.
+        assert_eq!(version_cmp("1.0_1", "1.0+1"), Ordering::Equal);
+    }
+
+    #[test]
+    fn kernel_versions() {
+        assert_eq!(version_cmp("6.9.0-rc7", "6.10.0"), Ordering::Less);
+        assert_eq!(version_cmp("6.10.0-100.fc40.aarch64", "6.10.0-99.fc40.aarch64"), Ordering::Greater);
+    }
+}
@@ -0,0 +1,92 @@
+//! Boot counting like systemd-boot does it.
+//!
+//! A boot entry named `NAME+LEFT-DONE.efi` has `LEFT` tries left and has been tried `DONE` times
+//! without being marked good. Each boot of it counts one try by renaming the file, until
+//! userspace (systemd-bless-boot) decides the entry works and drops the counter from the name.
+//! Entries with no tries left are assumed to be broken, and go to the bottom of the menu.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Counter {
+    pub left: u32,
+    pub done: u32,
+}
+
+impl Counter {
+    /// Whether all tries are used up.
+    pub fn is_bad(&self) -> bool {
+        self.left == 0
+    }
+
+    /// The counter after one more try, or `None` if there are no tries left to count.
+    pub fn next(&self) -> Option<Counter> {
+        Some(Counter { left: self.left.checked_sub(1)?, done: self.done.saturating_add(1) })
+    }
+}
+
+fn number(s: &str) -> Option<u32> {
+    s.bytes().all(|c| c.is_ascii_digit()).then(|| s.parse().ok()).flatten()
+}
+
+/// Split the counter off the file name `name` ending in `ext`. Returns the name without the
+/// counter, which is what the entry is identified by, and the counter if there is one.
+pub fn parse(name: &str, ext: &str) -> (String, Option<Counter>) {
+    let Some((stem, counter)) = name.strip_suffix(ext).and_then(|v| v.rsplit_once('+')) else {
+        return (name.to_string(), None);
+    };
+    let counter = match counter.split_once('-') {
+        Some((left, done)) => number(left).zip(number(done)).map(|(left, done)| Counter { left, done }),
+        None => number(counter).map(|left| Counter { left, done: 0 }),
+    };
+    match counter {
+        Some(counter) => (format!("{}{}", stem, ext), Some(counter)),
+        None => (name.to_string(), None),
+    }
+}
+
+/// The file name for the entry `name` ending in `ext` with `counter`.
+pub fn file_name(name: &str, ext: &str, counter: Counter) -> String {
+    let stem = name.strip_suffix(ext).unwrap_or(name);
+    format!("{}+{}-{}{}", stem, counter.left, counter.done, ext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter(left: u32, done: u32) -> Option<Counter> {
+        Some(Counter { left, done })
+    }
+
+    #[test]
+    fn parse_counters() {
+        assert_eq!(parse("linux+3.efi", ".efi"), ("linux.efi".to_string(), counter(3, 0)));
+        assert_eq!(parse("linux+2-1.efi", ".efi"), ("linux.efi".to_string(), counter(2, 1)));
+        assert_eq!(parse("linux+0-3.efi", ".efi"), ("linux.efi".to_string(), counter(0, 3)));
+        assert_eq!(parse("fedora-6.9+1-2.conf", ".conf"), ("fedora-6.9.conf".to_string(), counter(1, 2)));
+        // Only the last '+' starts the counter.
+        assert_eq!(parse("a+b+5.efi", ".efi"), ("a+b.efi".to_string(), counter(5, 0)));
+    }
+
+    #[test]
+    fn parse_no_counter() {
+        for name in ["linux.efi", "linux+.efi", "linux+-1.efi", "linux+1-.efi", "linux+a.efi", "linux+1-2-3.efi", "linux+-1-2.efi"] {
+            assert_eq!(parse(name, ".efi"), (name.to_string(), None), "{}", name);
+        }
+        // The counter has to be right before the extension.
+        assert_eq!(parse("linux+3.efi.bak", ".efi"), ("linux+3.efi.bak".to_string(), None));
+    }
+
+    #[test]
+    fn count_down() {
+        let counter = Counter { left: 1, done: 2 };
+        assert!(!counter.is_bad());
+        let next = counter.next().unwrap();
+        assert_eq!(next, Counter { left: 0, done: 3 });
+        assert!(next.is_bad());
+        assert_eq!(next.next(), None);
+        assert_eq!(file_name("linux.efi", ".efi", next), "linux+0-3.efi");
+    }
+}
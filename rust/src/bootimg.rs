@@ -0,0 +1,452 @@
+//! Android boot images, as made by mkbootimg, in the `boot` and `recovery` partitions.
+//!
+//! Header versions 0 to 2 have everything in the boot image. From version 3 on the DTB, the
+//! vendor ramdisk and most of the command line live in `vendor_boot` instead. Bootconfig is not
+//! supported, and neither are the QCDT images older Qualcomm devices put where version 0-2 headers
+//! have their version.
+//! https://source.android.com/docs/core/architecture/bootloader/boot-image-header
+
+use alloc::boxed::Box;
+use alloc::ffi::CString;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use snafu::Snafu;
+use crate::{BootOption, kernel_boot, lk2nd_device, println};
+use crate::bcb;
+use crate::bio::{self, BioError, BlockDev, OpenDevice};
+use crate::fbcon::FbCon888;
+use crate::fdt::Fdt;
+use crate::kernel_boot::{BootError, Concat, Payload, Slice};
+
+#[derive(Debug, Snafu)]
+pub enum BootImgError {
+    #[snafu(display("failed to open {name}"))]
+    Open { name: String },
+    #[snafu(display("I/O error: {source}"))]
+    Io { source: BioError },
+    #[snafu(display("not a boot image"))]
+    InvalidMagic,
+    #[snafu(display("unsupported header version {version}"))]
+    UnsupportedVersion { version: u32 },
+    #[snafu(display("invalid page size {page_size}"))]
+    InvalidPageSize { page_size: u32 },
+    #[snafu(display("{name} is larger than the partition"))]
+    Truncated { name: &'static str },
+    #[snafu(display("no vendor_boot{suffix} for header version {version}"))]
+    VendorBootMissing { suffix: String, version: u32 },
+}
+
+impl From<BioError> for BootImgError {
+    fn from(source: BioError) -> Self {
+        BootImgError::Io { source }
+    }
+}
+
+/// Partitions that boot images are looked for in, with the A/B slot suffixes added.
+const BOOT_LABELS: [&str; 2] = ["boot", "recovery"];
+const RECOVERY_LABEL: &str = "recovery";
+const VENDOR_BOOT_LABEL: &str = "vendor_boot";
+
+const BOOT_MAGIC: &[u8] = b"ANDROID!";
+const VENDOR_BOOT_MAGIC: &[u8] = b"VNDRBOOT";
+/// Large enough for all versions of both headers.
+const HEADER_READ_SIZE: usize = 4096;
+/// Versions 3 and 4 have a fixed page size.
+const V3_PAGE_SIZE: u64 = 4096;
+
+const BOOT_NAME_SIZE: usize = 16;
+const BOOT_ARGS_SIZE: usize = 512;
+const BOOT_EXTRA_ARGS_SIZE: usize = 1024;
+const V3_BOOT_ARGS_SIZE: usize = 1536;
+const VENDOR_BOOT_ARGS_SIZE: usize = 2048;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
+/// A byte range of a block device.
+#[derive(Clone, Debug)]
+struct Section {
+    device: String,
+    start: u64,
+    size: u64,
+}
+
+/// What we need from a boot image header, with the offsets relative to the partition.
+#[derive(Debug)]
+struct BootImage {
+    version: u32,
+    name: String,
+    os_version: u32,
+    kernel: (u64, u64),
+    ramdisk: (u64, u64),
+    dtb: Option<(u64, u64)>,
+    cmdline: String,
+}
+
+struct VendorBoot {
+    ramdisk: (u64, u64),
+    dtb: (u64, u64),
+    cmdline: String,
+}
+
+pub struct AndroidBootConfig {
+    /// The partition the boot image is in.
+    id: String,
+    name: String,
+    slot: Option<String>,
+    kernel: Section,
+    /// Vendor ramdisk first, then the boot image's own one.
+    ramdisks: Vec<Section>,
+    dtb: Option<Section>,
+    cmdline: String,
+}
+
+/// `data` up to the NUL padding that mkbootimg fills its fixed size fields with.
+fn until_nul(data: &[u8]) -> &[u8] {
+    let len = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    &data[..len]
+}
+
+fn string(data: &[u8]) -> String {
+    String::from_utf8_lossy(until_nul(data)).trim().to_string()
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    value.div_ceil(align) * align
+}
+
+/// Lay out `sizes` one after the other from `start`, each padded to whole pages. Returns the
+/// (offset, size) of each.
+fn layout<const N: usize>(start: u64, page_size: u64, sizes: [u32; N]) -> [(u64, u64); N] {
+    let mut offset = start;
+    sizes.map(|size| {
+        let section = (offset, size as u64);
+        offset += align_up(size as u64, page_size);
+        section
+    })
+}
+
+fn check_page_size(page_size: u32) -> Result<u64, BootImgError> {
+    if page_size < 512 || !page_size.is_power_of_two() {
+        return Err(BootImgError::InvalidPageSize { page_size });
+    }
+    Ok(page_size as u64)
+}
+
+fn check_bounds(dev: &OpenDevice, name: &'static str, (offset, size): (u64, u64)) -> Result<(), BootImgError> {
+    if offset + size > dev.len().unwrap_or(0) {
+        return Err(BootImgError::Truncated { name });
+    }
+    Ok(())
+}
+
+/// Parse the boot image header at `offset` in `dev`.
+fn parse_boot(dev: &mut OpenDevice, offset: u64) -> Result<BootImage, BootImgError> {
+    let mut header = vec![0; HEADER_READ_SIZE];
+    dev.read_at(offset, &mut header)?;
+    if &header[..BOOT_MAGIC.len()] != BOOT_MAGIC {
+        return Err(BootImgError::InvalidMagic);
+    }
+    let u32_at = |pos: usize| LittleEndian::read_u32(&header[pos..]);
+
+    let image = match u32_at(40) {
+        version @ (3 | 4) => {
+            let [kernel, ramdisk] = layout(offset + V3_PAGE_SIZE, V3_PAGE_SIZE, [u32_at(8), u32_at(12)]);
+            BootImage {
+                version,
+                name: String::new(),
+                os_version: u32_at(16),
+                kernel,
+                ramdisk,
+                dtb: None,
+                cmdline: string(&header[44..44 + V3_BOOT_ARGS_SIZE]),
+            }
+        }
+        version => {
+            // Anything larger is the size of a QCDT image, which makes it version 0.
+            let version = if version > 4 { 0 } else { version };
+            let page_size = check_page_size(u32_at(36))?;
+            let recovery_dtbo_size = if version >= 1 { u32_at(1632) } else { 0 };
+            let dtb_size = if version >= 2 { u32_at(1648) } else { 0 };
+            let [kernel, ramdisk, _second, _recovery_dtbo, dtb] = layout(offset + page_size, page_size, [
+                u32_at(8), u32_at(16), u32_at(24), recovery_dtbo_size, dtb_size,
+            ]);
+            // The extra args carry on where the first 511 bytes of the command line stop, each
+            // field has its own NUL terminator.
+            let cmdline = 64..64 + BOOT_ARGS_SIZE;
+            let extra = 608..608 + BOOT_EXTRA_ARGS_SIZE;
+            BootImage {
+                version,
+                name: string(&header[48..48 + BOOT_NAME_SIZE]),
+                os_version: u32_at(44),
+                kernel,
+                ramdisk,
+                dtb: (dtb_size > 0).then_some(dtb),
+                cmdline: string(&[until_nul(&header[cmdline]), &header[extra]].concat()),
+            }
+        }
+    };
+
+    check_bounds(dev, "kernel", image.kernel)?;
+    check_bounds(dev, "ramdisk", image.ramdisk)?;
+    if let Some(dtb) = image.dtb {
+        check_bounds(dev, "DTB", dtb)?;
+    }
+    Ok(image)
+}
+
+/// Parse the vendor boot image header at the start of `dev`.
+fn parse_vendor_boot(dev: &mut OpenDevice) -> Result<VendorBoot, BootImgError> {
+    let mut header = vec![0; HEADER_READ_SIZE];
+    dev.read_at(0, &mut header)?;
+    if &header[..VENDOR_BOOT_MAGIC.len()] != VENDOR_BOOT_MAGIC {
+        return Err(BootImgError::InvalidMagic);
+    }
+    let u32_at = |pos: usize| LittleEndian::read_u32(&header[pos..]);
+
+    let version = u32_at(8);
+    if !(3..=4).contains(&version) {
+        return Err(BootImgError::UnsupportedVersion { version });
+    }
+    let page_size = check_page_size(u32_at(12))?;
+    // With version 4 there can be several vendor ramdisks, they are all loaded back to back.
+    let [ramdisk, dtb] = layout(align_up(u32_at(2096) as u64, page_size), page_size, [u32_at(24), u32_at(2100)]);
+
+    check_bounds(dev, "vendor ramdisk", ramdisk)?;
+    check_bounds(dev, "DTB", dtb)?;
+    Ok(VendorBoot {
+        ramdisk,
+        dtb,
+        cmdline: string(&header[28..28 + VENDOR_BOOT_ARGS_SIZE]),
+    })
+}
+
+/// Pick the DTB for this device out of the concatenated DTBs at `range` in `dev`, by the
+/// compatible of their root node. If nothing matches the first one is used.
+fn pick_dtb(dev: &mut OpenDevice, (offset, size): (u64, u64)) -> Result<Option<(u64, u64)>, BootImgError> {
+    let mut data = vec![0; size as usize];
+    dev.read_at(offset, &mut data)?;
+
+    let mut dtbs = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() && BigEndian::read_u32(&data[pos..]) == FDT_MAGIC {
+        let len = BigEndian::read_u32(&data[pos + 4..]) as usize;
+        if len < 8 || pos + len > data.len() {
+            break;
+        }
+        dtbs.push(pos..pos + len);
+        pos += len;
+    }
+
+    for compatible in lk2nd_device::compatibles() {
+        for dtb in &dtbs {
+            if Fdt::new(&data[dtb.clone()]).is_ok_and(|fdt| fdt.is_compatible(0, &compatible)) {
+                println!("picked DTB matching {}", compatible);
+                return Ok(Some((offset + dtb.start as u64, dtb.len() as u64)));
+            }
+        }
+    }
+    Ok(dtbs.first().map(|dtb| (offset + dtb.start as u64, dtb.len() as u64)))
+}
+
+/// The Android version in `os_version`, e.g. `11` or `8.1`.
+fn android_version(os_version: u32) -> Option<String> {
+    let version = os_version >> 11;
+    let (a, b, c) = (version >> 14 & 0x7f, version >> 7 & 0x7f, version & 0x7f);
+    match (a, b, c) {
+        (0, _, _) => None,
+        (a, 0, 0) => Some(format!("{}", a)),
+        (a, b, 0) => Some(format!("{}.{}", a, b)),
+        (a, b, c) => Some(format!("{}.{}.{}", a, b, c)),
+    }
+}
+
+impl AndroidBootConfig {
+    /// Read the boot image in the partition `dev`, labeled `label`, and the `vendor_boot` of the
+    /// same slot if it needs one.
+    pub fn new(dev: &BlockDev, label: &str, devs: &[BlockDev]) -> Result<Self, BootImgError> {
+        let mut bdev = bio::open(&dev.name).map_err(|_| BootImgError::Open { name: dev.name.clone() })?;
+        let offset = CString::new(label).map(|v| unsafe { sys::lk2nd_boot_image_offset(v.as_ptr()) }).unwrap_or(0);
+        let image = parse_boot(&mut bdev, offset)?;
+
+        let slot = ["_a", "_b"].into_iter().find(|v| label.ends_with(v));
+        let section = |device: &str, (start, size): (u64, u64)| Section { device: device.to_string(), start, size };
+        let mut ramdisks = Vec::new();
+        let mut dtb = image.dtb.map(|range| pick_dtb(&mut bdev, range)).transpose()?.flatten()
+            .map(|range| section(&dev.name, range));
+        let mut cmdline = image.cmdline.clone();
+
+        if image.version >= 3 {
+            let suffix = slot.unwrap_or_default();
+            let vendor_label = format!("{}{}", VENDOR_BOOT_LABEL, suffix);
+            let vendor_dev = devs.iter()
+                .find(|v| v.label.as_deref() == Some(vendor_label.as_str()))
+                .ok_or(BootImgError::VendorBootMissing { suffix: suffix.to_string(), version: image.version })?;
+            let mut vendor_bdev = bio::open(&vendor_dev.name)
+                .map_err(|_| BootImgError::Open { name: vendor_dev.name.clone() })?;
+            let vendor = parse_vendor_boot(&mut vendor_bdev)?;
+
+            ramdisks.push(section(&vendor_dev.name, vendor.ramdisk));
+            dtb = pick_dtb(&mut vendor_bdev, vendor.dtb)?.map(|range| section(&vendor_dev.name, range));
+            cmdline = format!("{} {}", vendor.cmdline, cmdline);
+        }
+        if image.ramdisk.1 > 0 {
+            ramdisks.push(section(&dev.name, image.ramdisk));
+        }
+        if let Some(slot) = slot {
+            cmdline = format!("{} androidboot.slot_suffix={}", cmdline, slot);
+        }
+
+        let title = if !image.name.is_empty() {
+            image.name.clone()
+        } else {
+            match android_version(image.os_version) {
+                Some(version) => format!("Android {}", version),
+                None => "Android boot image".to_string(),
+            }
+        };
+
+        Ok(Self {
+            id: label.to_string(),
+            name: format!("{} ({})", title, label),
+            slot: slot.map(|v| v.to_string()),
+            kernel: section(&dev.name, image.kernel),
+            ramdisks,
+            dtb,
+            cmdline: cmdline.trim().to_string(),
+        })
+    }
+
+    fn try_boot(&self) -> Result<(), BootError> {
+        let open = |section: &Section| -> Result<Slice<'static>, BootError> {
+            let dev = bio::open(&section.device).map_err(|_| BootError::Io)?;
+            Ok(Slice::new(Box::new(dev), (section.start, section.size)))
+        };
+        let mut kernel = open(&self.kernel)?;
+        let mut dtb = self.dtb.as_ref().map(open).transpose()?;
+        let ramdisks = self.ramdisks.iter()
+            .map(|v| open(v).map(|v| Box::new(v) as Box<dyn Payload>))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut initrd = (!ramdisks.is_empty()).then(|| Concat::new(ramdisks));
+
+        kernel_boot::boot(
+            &mut kernel,
+            dtb.as_mut().map(|v| v as &mut dyn Payload),
+            &mut [],
+            initrd.as_mut().map(|v| v as &mut dyn Payload),
+            &self.cmdline,
+        )
+    }
+}
+
+impl BootOption for AndroidBootConfig {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn label(&self) -> &str {
+        &self.name
+    }
+
+    fn splash(&self, _display: &mut FbCon888) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn is_recovery(&self) -> bool {
+        self.id.starts_with(RECOVERY_LABEL)
+    }
+
+    fn slot(&self) -> Option<&str> {
+        self.slot.as_deref()
+    }
+
+    fn boot(&mut self) -> ! {
+        if let Err(err) = self.try_boot() {
+            println!("booting {} failed: {:?}", self.name, err);
+        }
+        panic!("noes");
+    }
+}
+
+/// Find the boot images in the `boot` and `recovery` partitions of `devs`, and their A/B slot
+/// variants.
+pub fn scan(devs: &[BlockDev]) -> Vec<Box<dyn BootOption>> {
+    let mut options: Vec<Box<dyn BootOption>> = Vec::new();
+    for label in BOOT_LABELS {
+        for label in [label.to_string(), format!("{}{}", label, bcb::slot_suffix(0)), format!("{}{}", label, bcb::slot_suffix(1))] {
+            let Some(dev) = devs.iter().find(|v| v.is_leaf && v.label.as_deref() == Some(label.as_str())) else {
+                continue;
+            };
+            match AndroidBootConfig::new(dev, &label, devs) {
+                Ok(option) => {
+                    println!("found boot image in {}: {}", label, option.name);
+                    options.push(Box::new(option));
+                }
+                Err(BootImgError::InvalidMagic) => {}
+                Err(err) => println!("{}: {}", label, err),
+            }
+        }
+    }
+    options
+}
+
+mod sys {
+    use core::ffi::c_char;
+
+    extern "C" {
+        pub fn lk2nd_boot_image_offset(label: *const c_char) -> u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_SIZE: usize = 2048;
+
+    /// A version 2 boot image with a one page kernel and `cmdline` split like mkbootimg does.
+    fn boot_image(cmdline: &str) -> Vec<u8> {
+        let mut image = vec![0; PAGE_SIZE * 2];
+        image[..BOOT_MAGIC.len()].copy_from_slice(BOOT_MAGIC);
+        LittleEndian::write_u32(&mut image[8..], PAGE_SIZE as u32);
+        LittleEndian::write_u32(&mut image[36..], PAGE_SIZE as u32);
+        LittleEndian::write_u32(&mut image[40..], 2);
+        image[48..56].copy_from_slice(b"mainline");
+        let (first, rest) = cmdline.as_bytes().split_at(cmdline.len().min(BOOT_ARGS_SIZE - 1));
+        image[64..64 + first.len()].copy_from_slice(first);
+        image[608..608 + rest.len()].copy_from_slice(rest);
+        image
+    }
+
+    fn parse(image: Vec<u8>) -> BootImage {
+        let size = image.len() as u64;
+        parse_boot(&mut OpenDevice::from_image(Box::new(image), size), 0).unwrap()
+    }
+
+    #[test]
+    fn v2_header() {
+        let image = parse(boot_image("console=ttyMSM0"));
+        assert_eq!(image.version, 2);
+        assert_eq!(image.name, "mainline");
+        assert_eq!(image.kernel, (PAGE_SIZE as u64, PAGE_SIZE as u64));
+        assert_eq!(image.ramdisk.1, 0);
+        assert!(image.dtb.is_none());
+        assert_eq!(image.cmdline, "console=ttyMSM0");
+    }
+
+    #[test]
+    fn long_cmdline() {
+        let cmdline = (0..100).map(|i| format!("arg{}=value", i)).collect::<Vec<_>>().join(" ");
+        assert!(cmdline.len() > BOOT_ARGS_SIZE);
+        assert_eq!(parse(boot_image(&cmdline)).cmdline, cmdline);
+    }
+
+    #[test]
+    fn android_versions() {
+        assert_eq!(android_version(0), None);
+        assert_eq!(android_version((11 << 14) << 11), Some("11".to_string()));
+        assert_eq!(android_version((8 << 14 | 1 << 7) << 11), Some("8.1".to_string()));
+    }
+}
@@ -0,0 +1,974 @@
+//! Read-only btrfs driver, for distributions that keep `/boot` on a btrfs root filesystem.
+//!
+//! Only single device filesystems are supported. Paths are relative to the default subvolume and
+//! cross into other subvolumes like directories do, so `/@/boot` reaches into the `@` subvolume
+//! when the top level is the default.
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::cell::RefCell;
+use crc::{Crc, CRC_32_ISCSI};
+use snafu::{ResultExt, Snafu};
+use crate::bio::{BioError, OpenDevice};
+use crate::decompress::{self, Compression, DecompressError};
+use crate::fs::{BootFs, DirEntry};
+use crate::kernel_boot::{BootError, Payload};
+
+#[derive(Debug, Snafu)]
+pub enum BtrfsError {
+    #[snafu(display("I/O error: {source}"))]
+    Io { source: BioError },
+    #[snafu(display("invalid superblock"))]
+    InvalidSuperblock,
+    #[snafu(display("unsupported incompatible features {features:#x}"))]
+    UnsupportedFeatures { features: u64 },
+    #[snafu(display("filesystems on multiple devices are not supported"))]
+    MultipleDevices,
+    #[snafu(display("corrupt {what}"))]
+    Corrupt { what: &'static str },
+    #[snafu(display("logical address {logical:#x} is not mapped"))]
+    Unmapped { logical: u64 },
+    #[snafu(display("no such file or directory"))]
+    NotFound,
+    #[snafu(display("not a directory"))]
+    NotADirectory,
+    #[snafu(display("not a regular file"))]
+    NotAFile,
+    #[snafu(display("too many levels of symbolic links"))]
+    TooManySymlinks,
+    #[snafu(display("unsupported compression {kind}"))]
+    UnsupportedCompression { kind: u8 },
+    #[snafu(display("decompression failed: {source}"))]
+    Decompress { source: DecompressError },
+}
+
+impl From<BioError> for BtrfsError {
+    fn from(source: BioError) -> Self {
+        BtrfsError::Io { source }
+    }
+}
+
+/// Where distributions put `/boot`, relative to the default subvolume: in a btrfs filesystem of
+/// its own, on the default subvolume, or in a subvolume below the top level, like Fedora's `root`
+/// or Ubuntu's `@` and `@boot`.
+///
+/// There is no way to name another subvolume, as the kernel command line saying which one is the
+/// root is in the boot entries that are being looked for. Layouts other than these have to make
+/// the subvolume holding `/boot` the default one with `btrfs subvolume set-default`.
+pub const BOOT_DIRS: &[&str] = &["/", "/boot", "/root/boot", "/@/boot", "/@boot"];
+
+const SUPERBLOCK_OFFSET: u64 = 64 * 1024;
+const SUPERBLOCK_SIZE: usize = 4096;
+const MAGIC: &[u8; 8] = b"_BHRfS_M";
+const CSUM_SIZE: usize = 32;
+const CSUM_TYPE_CRC32C: u16 = 0;
+const SYS_CHUNK_ARRAY_OFFSET: usize = 0x32b;
+const SYS_CHUNK_ARRAY_MAX: usize = 2048;
+
+const INCOMPAT_MIXED_BACKREF: u64 = 0x1;
+const INCOMPAT_DEFAULT_SUBVOL: u64 = 0x2;
+const INCOMPAT_MIXED_GROUPS: u64 = 0x4;
+const INCOMPAT_COMPRESS_LZO: u64 = 0x8;
+const INCOMPAT_COMPRESS_ZSTD: u64 = 0x10;
+const INCOMPAT_BIG_METADATA: u64 = 0x20;
+const INCOMPAT_EXTENDED_IREF: u64 = 0x40;
+const INCOMPAT_SKINNY_METADATA: u64 = 0x100;
+const INCOMPAT_NO_HOLES: u64 = 0x200;
+const INCOMPAT_METADATA_UUID: u64 = 0x400;
+const INCOMPAT_RAID1C34: u64 = 0x800;
+const INCOMPAT_SIMPLE_QUOTA: u64 = 0x10000;
+/// Everything that doesn't change how files are found and read, or that is handled here.
+const INCOMPAT_SUPPORTED: u64 = INCOMPAT_MIXED_BACKREF | INCOMPAT_DEFAULT_SUBVOL | INCOMPAT_MIXED_GROUPS
+    | INCOMPAT_COMPRESS_LZO | INCOMPAT_COMPRESS_ZSTD | INCOMPAT_BIG_METADATA | INCOMPAT_EXTENDED_IREF
+    | INCOMPAT_SKINNY_METADATA | INCOMPAT_NO_HOLES | INCOMPAT_METADATA_UUID | INCOMPAT_RAID1C34
+    | INCOMPAT_SIMPLE_QUOTA;
+
+const HEADER_SIZE: usize = 0x65;
+const ITEM_SIZE: usize = 25;
+const KEY_PTR_SIZE: usize = 33;
+const MAX_LEVEL: u8 = 8;
+
+const FS_TREE_OBJECTID: u64 = 5;
+const ROOT_TREE_DIR_OBJECTID: u64 = 6;
+const FIRST_CHUNK_TREE_OBJECTID: u64 = 256;
+
+const INODE_ITEM_KEY: u8 = 1;
+const DIR_ITEM_KEY: u8 = 84;
+const DIR_INDEX_KEY: u8 = 96;
+const EXTENT_DATA_KEY: u8 = 108;
+const ROOT_ITEM_KEY: u8 = 132;
+const CHUNK_ITEM_KEY: u8 = 228;
+
+const FT_REG_FILE: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+const FILE_EXTENT_INLINE: u8 = 0;
+const FILE_EXTENT_PREALLOC: u8 = 2;
+
+const COMPRESS_NONE: u8 = 0;
+const COMPRESS_ZLIB: u8 = 1;
+const COMPRESS_LZO: u8 = 2;
+const COMPRESS_ZSTD: u8 = 3;
+/// Compressed extents never hold more than this, see `BTRFS_MAX_UNCOMPRESSED` in Linux.
+const MAX_UNCOMPRESSED: u64 = 128 * 1024;
+
+/// Profiles that spread a chunk over several stripes, which would need the other devices.
+const BLOCK_GROUP_STRIPED: u64 = 0x8 | 0x40 | 0x80 | 0x100;
+
+// Linux allows 40, but nothing we boot from nests symlinks anywhere near that deep.
+const MAX_SYMLINKS: usize = 8;
+
+const CRC32C: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    objectid: u64,
+    ty: u8,
+    offset: u64,
+}
+
+impl Key {
+    fn parse(data: &[u8]) -> Self {
+        Key {
+            objectid: LittleEndian::read_u64(data),
+            ty: data[8],
+            offset: LittleEndian::read_u64(&data[9..]),
+        }
+    }
+
+    /// All keys of one type for one object.
+    fn range(objectid: u64, ty: u8) -> (Key, Key) {
+        (Key { objectid, ty, offset: 0 }, Key { objectid, ty, offset: u64::MAX })
+    }
+}
+
+/// Where a range of logical addresses is on our device.
+#[derive(Clone, Copy, Debug)]
+struct Chunk {
+    logical: u64,
+    len: u64,
+    physical: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Subvol {
+    /// Logical address of the root of its tree.
+    tree: u64,
+    /// Inode number of its top directory.
+    dir: u64,
+}
+
+struct DirItem {
+    location: Key,
+    ty: u8,
+    name: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum ExtentData {
+    Inline(Vec<u8>),
+    Regular { disk_bytenr: u64, disk_len: u64, offset: u64, compression: u8, ram_bytes: u64 },
+    /// Preallocated extents and explicit holes.
+    Zero,
+}
+
+#[derive(Debug)]
+struct FileExtent {
+    /// Where in the file this extent starts.
+    start: u64,
+    len: u64,
+    data: ExtentData,
+}
+
+pub struct Btrfs {
+    dev: RefCell<OpenDevice>,
+    devid: u64,
+    /// What tree blocks are tagged with, to tell them from stale blocks of an older filesystem.
+    metadata_fsid: [u8; 16],
+    nodesize: u64,
+    sectorsize: u64,
+    verify_csum: bool,
+    /// Sorted by logical address.
+    chunks: Vec<Chunk>,
+    root_tree: u64,
+    default: Subvol,
+}
+
+impl Btrfs {
+    pub fn new(mut dev: OpenDevice) -> Result<Self, BtrfsError> {
+        let mut sb = vec![0; SUPERBLOCK_SIZE];
+        dev.read_at(SUPERBLOCK_OFFSET, &mut sb)?;
+        if &sb[0x40..0x48] != MAGIC {
+            return Err(BtrfsError::InvalidSuperblock);
+        }
+
+        // Other checksums are only found on filesystems made for them, not on /boot.
+        let verify_csum = LittleEndian::read_u16(&sb[0xc4..]) == CSUM_TYPE_CRC32C;
+        if verify_csum && CRC32C.checksum(&sb[CSUM_SIZE..]) != LittleEndian::read_u32(&sb) {
+            return Err(BtrfsError::Corrupt { what: "superblock checksum" });
+        }
+
+        let incompat = LittleEndian::read_u64(&sb[0xbc..]);
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(BtrfsError::UnsupportedFeatures { features: incompat & !INCOMPAT_SUPPORTED });
+        }
+        if LittleEndian::read_u64(&sb[0x88..]) != 1 {
+            return Err(BtrfsError::MultipleDevices);
+        }
+
+        let sectorsize = LittleEndian::read_u32(&sb[0x90..]) as u64;
+        let nodesize = LittleEndian::read_u32(&sb[0x94..]) as u64;
+        if !sectorsize.is_power_of_two() || !(4096..=65536).contains(&sectorsize)
+            || !nodesize.is_power_of_two() || nodesize < sectorsize || nodesize > 65536 {
+            return Err(BtrfsError::InvalidSuperblock);
+        }
+
+        let fsid = if incompat & INCOMPAT_METADATA_UUID != 0 { &sb[0x23b..0x24b] } else { &sb[0x20..0x30] };
+        let devid = LittleEndian::read_u64(&sb[0xc9..]);
+
+        // The chunks holding the chunk tree are in the superblock itself.
+        let sys_chunk_array_size = LittleEndian::read_u32(&sb[0xa0..]) as usize;
+        if sys_chunk_array_size > SYS_CHUNK_ARRAY_MAX {
+            return Err(BtrfsError::InvalidSuperblock);
+        }
+        let sys_chunk_array = &sb[SYS_CHUNK_ARRAY_OFFSET..][..sys_chunk_array_size];
+        let mut chunks = Vec::new();
+        let mut pos = 0;
+        while pos < sys_chunk_array.len() {
+            let key = Key::parse(sys_chunk_array.get(pos..pos + 17).ok_or(BtrfsError::InvalidSuperblock)?);
+            pos += 17;
+            let len = sys_chunk_array.get(pos + 44..pos + 46)
+                .map(|v| 48 + LittleEndian::read_u16(v) as usize * 32)
+                .ok_or(BtrfsError::InvalidSuperblock)?;
+            let item = sys_chunk_array.get(pos..pos + len).ok_or(BtrfsError::InvalidSuperblock)?;
+            if key.ty != CHUNK_ITEM_KEY {
+                return Err(BtrfsError::InvalidSuperblock);
+            }
+            chunks.extend(parse_chunk(key, item, devid));
+            pos += len;
+        }
+
+        let mut fs = Self {
+            dev: RefCell::new(dev),
+            devid,
+            metadata_fsid: fsid.try_into().unwrap(),
+            nodesize,
+            sectorsize,
+            verify_csum,
+            chunks,
+            root_tree: LittleEndian::read_u64(&sb[0x50..]),
+            default: Subvol { tree: 0, dir: 0 },
+        };
+
+        let (min, max) = Key::range(FIRST_CHUNK_TREE_OBJECTID, CHUNK_ITEM_KEY);
+        let mut chunks = fs.search(LittleEndian::read_u64(&sb[0x58..]), min, max)?
+            .into_iter()
+            .filter_map(|(key, item)| parse_chunk(key, &item, fs.devid))
+            .collect::<Vec<_>>();
+        chunks.sort_by_key(|v| v.logical);
+        fs.chunks = chunks;
+
+        // `btrfs subvolume set-default` records the default in a "default" entry of the root tree.
+        let (min, max) = Key::range(ROOT_TREE_DIR_OBJECTID, DIR_ITEM_KEY);
+        let default = fs.search(fs.root_tree, min, max)?
+            .iter()
+            .flat_map(|(_, item)| parse_dir_items(item))
+            .find(|v| v.name == b"default")
+            .map(|v| v.location.objectid)
+            .unwrap_or(FS_TREE_OBJECTID);
+        fs.default = fs.subvol(default)?;
+        Ok(fs)
+    }
+
+    fn read_logical(&self, logical: u64, buf: &mut [u8]) -> Result<(), BtrfsError> {
+        let idx = self.chunks.partition_point(|v| v.logical + v.len <= logical);
+        let chunk = self.chunks.get(idx)
+            .filter(|v| v.logical <= logical && logical + buf.len() as u64 <= v.logical + v.len)
+            .ok_or(BtrfsError::Unmapped { logical })?;
+        Ok(self.dev.borrow_mut().read_at(chunk.physical + logical - chunk.logical, buf)?)
+    }
+
+    fn node(&self, logical: u64) -> Result<Vec<u8>, BtrfsError> {
+        let mut node = vec![0; self.nodesize as usize];
+        self.read_logical(logical, &mut node)?;
+        if self.verify_csum && CRC32C.checksum(&node[CSUM_SIZE..]) != LittleEndian::read_u32(&node) {
+            return Err(BtrfsError::Corrupt { what: "tree block checksum" });
+        }
+        if node[0x20..0x30] != self.metadata_fsid || LittleEndian::read_u64(&node[0x30..]) != logical {
+            return Err(BtrfsError::Corrupt { what: "tree block header" });
+        }
+        Ok(node)
+    }
+
+    /// Collect the items from `min` to `max` in the tree at `root`.
+    fn search(&self, root: u64, min: Key, max: Key) -> Result<Vec<(Key, Vec<u8>)>, BtrfsError> {
+        let mut items = Vec::new();
+        self.search_node(root, min, max, MAX_LEVEL, &mut items)?;
+        Ok(items)
+    }
+
+    /// `parent_level` makes sure every step goes down, so a corrupt tree can't loop.
+    fn search_node(&self, logical: u64, min: Key, max: Key, parent_level: u8, items: &mut Vec<(Key, Vec<u8>)>) -> Result<(), BtrfsError> {
+        let corrupt = || BtrfsError::Corrupt { what: "tree block" };
+        let node = self.node(logical)?;
+        let count = LittleEndian::read_u32(&node[0x60..]) as usize;
+        let level = node[0x64];
+        if level >= parent_level {
+            return Err(corrupt());
+        }
+        let body = &node[HEADER_SIZE..];
+
+        if level == 0 {
+            for i in 0..count {
+                let item = body.get(i * ITEM_SIZE..(i + 1) * ITEM_SIZE).ok_or_else(corrupt)?;
+                let key = Key::parse(item);
+                if key < min || key > max {
+                    continue;
+                }
+                let offset = LittleEndian::read_u32(&item[17..]) as usize;
+                let size = LittleEndian::read_u32(&item[21..]) as usize;
+                items.push((key, body.get(offset..offset + size).ok_or_else(corrupt)?.to_vec()));
+            }
+            return Ok(());
+        }
+
+        let ptrs = body.get(..count * KEY_PTR_SIZE).ok_or_else(corrupt)?;
+        for (i, ptr) in ptrs.chunks_exact(KEY_PTR_SIZE).enumerate() {
+            // Child i holds the keys from its own key up to the key of child i + 1.
+            if Key::parse(ptr) > max {
+                break;
+            }
+            if ptrs.get((i + 1) * KEY_PTR_SIZE..).is_some_and(|v| !v.is_empty() && Key::parse(v) <= min) {
+                continue;
+            }
+            self.search_node(LittleEndian::read_u64(&ptr[17..]), min, max, level, items)?;
+        }
+        Ok(())
+    }
+
+    fn subvol(&self, id: u64) -> Result<Subvol, BtrfsError> {
+        let (min, max) = Key::range(id, ROOT_ITEM_KEY);
+        let (_, item) = self.search(self.root_tree, min, max)?
+            .into_iter()
+            .next()
+            .ok_or(BtrfsError::NotFound)?;
+        if item.len() < 184 {
+            return Err(BtrfsError::Corrupt { what: "root item" });
+        }
+        Ok(Subvol {
+            tree: LittleEndian::read_u64(&item[176..]),
+            dir: LittleEndian::read_u64(&item[168..]),
+        })
+    }
+
+    fn dir_entries(&self, subvol: Subvol, dir: u64) -> Result<Vec<DirItem>, BtrfsError> {
+        let (min, max) = Key::range(dir, DIR_INDEX_KEY);
+        Ok(self.search(subvol.tree, min, max)?
+            .iter()
+            .flat_map(|(_, item)| parse_dir_items(item))
+            .collect())
+    }
+
+    /// Find what `path` points to, following symlinks, as its subvolume, inode number and file
+    /// type.
+    fn lookup(&self, path: &str) -> Result<(Subvol, u64, u8), BtrfsError> {
+        fn components(path: &str) -> impl DoubleEndedIterator<Item = String> + '_ {
+            path.split('/').filter(|v| !v.is_empty() && *v != ".").map(|v| v.to_string())
+        }
+
+        // There are no `..` entries, so keep track of how we got here instead.
+        let mut stack = vec![(self.default, self.default.dir, FT_DIR)];
+        // Components still to look up, the next one last.
+        let mut remaining = components(path).rev().collect::<Vec<_>>();
+        let mut symlinks = 0;
+
+        while let Some(name) = remaining.pop() {
+            let (subvol, dir, ty) = *stack.last().unwrap();
+            if ty != FT_DIR {
+                return Err(BtrfsError::NotADirectory);
+            }
+            if name == ".." {
+                if stack.len() > 1 {
+                    stack.pop();
+                }
+                continue;
+            }
+
+            let entry = self.dir_entries(subvol, dir)?
+                .into_iter()
+                .find(|v| v.name == name.as_bytes())
+                .ok_or(BtrfsError::NotFound)?;
+            if entry.ty == FT_SYMLINK {
+                symlinks += 1;
+                if symlinks > MAX_SYMLINKS {
+                    return Err(BtrfsError::TooManySymlinks);
+                }
+                let mut file = self.file(subvol, entry.location.objectid)?;
+                let mut target = vec![0; file.size as usize];
+                file.read(0, &mut target)?;
+                let target = String::from_utf8_lossy(&target);
+                remaining.extend(components(&target).rev());
+                if target.starts_with('/') {
+                    stack.truncate(1);
+                }
+                continue;
+            }
+
+            // Subvolumes show up as directory entries pointing at their root item.
+            stack.push(match entry.location.ty {
+                ROOT_ITEM_KEY => {
+                    let subvol = self.subvol(entry.location.objectid)?;
+                    (subvol, subvol.dir, FT_DIR)
+                }
+                _ => (subvol, entry.location.objectid, entry.ty),
+            });
+        }
+        Ok(*stack.last().unwrap())
+    }
+
+    fn file(&self, subvol: Subvol, ino: u64) -> Result<BtrfsFile<'_>, BtrfsError> {
+        let key = Key { objectid: ino, ty: INODE_ITEM_KEY, offset: 0 };
+        let (_, inode) = self.search(subvol.tree, key, key)?
+            .into_iter()
+            .next()
+            .ok_or(BtrfsError::Corrupt { what: "missing inode" })?;
+        if inode.len() < 24 {
+            return Err(BtrfsError::Corrupt { what: "inode item" });
+        }
+
+        let (min, max) = Key::range(ino, EXTENT_DATA_KEY);
+        let extents = self.search(subvol.tree, min, max)?
+            .into_iter()
+            .map(|(key, item)| self.parse_extent(key.offset, &item))
+            .collect::<Result<_, _>>()?;
+
+        Ok(BtrfsFile {
+            fs: self,
+            size: LittleEndian::read_u64(&inode[16..]),
+            extents,
+            cache: None,
+        })
+    }
+
+    fn parse_extent(&self, start: u64, item: &[u8]) -> Result<FileExtent, BtrfsError> {
+        let corrupt = || BtrfsError::Corrupt { what: "file extent" };
+        let header = item.get(..21).ok_or_else(corrupt)?;
+        let ram_bytes = LittleEndian::read_u64(&header[8..]);
+        let compression = header[16];
+        // Encryption and other encodings are reserved, nothing writes them.
+        if header[17] != 0 || LittleEndian::read_u16(&header[18..]) != 0 {
+            return Err(corrupt());
+        }
+
+        if header[20] == FILE_EXTENT_INLINE {
+            let mut data = item[21..].to_vec();
+            if compression != COMPRESS_NONE {
+                data = self.decompress(compression, &mut data, ram_bytes)?;
+            }
+            return Ok(FileExtent { start, len: data.len() as u64, data: ExtentData::Inline(data) });
+        }
+
+        let body = item.get(21..53).ok_or_else(corrupt)?;
+        let disk_bytenr = LittleEndian::read_u64(body);
+        let len = LittleEndian::read_u64(&body[24..]);
+        let data = if header[20] == FILE_EXTENT_PREALLOC || disk_bytenr == 0 {
+            ExtentData::Zero
+        } else {
+            ExtentData::Regular {
+                disk_bytenr,
+                disk_len: LittleEndian::read_u64(&body[8..]),
+                offset: LittleEndian::read_u64(&body[16..]),
+                compression,
+                ram_bytes,
+            }
+        };
+        Ok(FileExtent { start, len, data })
+    }
+
+    fn decompress(&self, compression: u8, data: &mut [u8], ram_bytes: u64) -> Result<Vec<u8>, BtrfsError> {
+        if ram_bytes > MAX_UNCOMPRESSED {
+            return Err(BtrfsError::Corrupt { what: "compressed extent size" });
+        }
+        let mut out = vec![0; ram_bytes as usize];
+        match compression {
+            COMPRESS_ZLIB => decompress::inflate(data, &mut out),
+            COMPRESS_ZSTD => Compression::Zstd.decompress(data, &mut out),
+            COMPRESS_LZO => unlzo(data, &mut out, self.sectorsize as usize),
+            kind => return Err(BtrfsError::UnsupportedCompression { kind }),
+        }.context(DecompressSnafu)?;
+        Ok(out)
+    }
+}
+
+fn parse_chunk(key: Key, item: &[u8], devid: u64) -> Option<Chunk> {
+    if item.len() < 48 {
+        return None;
+    }
+    let len = LittleEndian::read_u64(item);
+    let profile = LittleEndian::read_u64(&item[24..]);
+    let stripes = LittleEndian::read_u16(&item[44..]) as usize;
+    if item.len() < 48 + stripes * 32 {
+        return None;
+    }
+    if profile & BLOCK_GROUP_STRIPED != 0 && stripes > 1 {
+        return None;
+    }
+    // Any copy will do for DUP and RAID1 chunks.
+    item[48..].chunks_exact(32)
+        .take(stripes)
+        .find(|v| LittleEndian::read_u64(v) == devid)
+        .map(|v| Chunk { logical: key.offset, len, physical: LittleEndian::read_u64(&v[8..]) })
+}
+
+/// Parse the entries in a DIR_ITEM or DIR_INDEX item. DIR_ITEMs can hold several entries whose
+/// names hash the same.
+fn parse_dir_items(mut item: &[u8]) -> Vec<DirItem> {
+    let mut items = Vec::new();
+    while item.len() >= 30 {
+        let data_len = LittleEndian::read_u16(&item[25..]) as usize;
+        let name_len = LittleEndian::read_u16(&item[27..]) as usize;
+        let Some(name) = item.get(30..30 + name_len) else {
+            break;
+        };
+        items.push(DirItem {
+            location: Key::parse(item),
+            ty: item[29],
+            name: name.to_vec(),
+        });
+        item = item.get(30 + name_len + data_len..).unwrap_or_default();
+    }
+    items
+}
+
+/// btrfs splits LZO compressed extents into separately compressed segments, each preceded by its
+/// length. Segment lengths never straddle a sector, the rest of the sector is padding instead.
+/// See fs/btrfs/lzo.c in Linux.
+fn unlzo(data: &[u8], out: &mut [u8], sectorsize: usize) -> Result<usize, DecompressError> {
+    const LEN_SIZE: usize = 4;
+    let len_at = |pos: usize| data.get(pos..pos + LEN_SIZE).map(LittleEndian::read_u32).ok_or(DecompressError::Lzo);
+
+    let total = (len_at(0)? as usize).min(data.len());
+    let mut pos = LEN_SIZE;
+    let mut written = 0;
+    while pos < total && written < out.len() {
+        if sectorsize - pos % sectorsize < LEN_SIZE {
+            pos = pos.next_multiple_of(sectorsize);
+        }
+        let len = len_at(pos)? as usize;
+        pos += LEN_SIZE;
+        let segment = data.get(pos..pos + len).ok_or(DecompressError::Lzo)?;
+        written += decompress::unlzo(segment, &mut out[written..])?;
+        pos += len;
+    }
+    Ok(written)
+}
+
+pub struct BtrfsFile<'a> {
+    fs: &'a Btrfs,
+    size: u64,
+    /// Sorted by where they start. Anything they don't cover is a hole.
+    extents: Vec<FileExtent>,
+    /// The last compressed extent that was read, decompressed.
+    cache: Option<(u64, Vec<u8>)>,
+}
+
+impl BtrfsFile<'_> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BtrfsError> {
+        let end = offset.checked_add(buf.len() as u64).filter(|&v| v <= self.size)
+            .ok_or(BtrfsError::Corrupt { what: "file size" })?;
+        buf.fill(0);
+
+        for extent in &self.extents {
+            let from = offset.max(extent.start);
+            let to = end.min(extent.start + extent.len);
+            if from >= to {
+                continue;
+            }
+            let chunk = &mut buf[(from - offset) as usize..(to - offset) as usize];
+            let within = from - extent.start;
+
+            match &extent.data {
+                ExtentData::Inline(data) => {
+                    let src = data.get(within as usize..within as usize + chunk.len())
+                        .ok_or(BtrfsError::Corrupt { what: "inline extent" })?;
+                    chunk.copy_from_slice(src);
+                }
+                ExtentData::Regular { disk_bytenr, offset, compression: COMPRESS_NONE, .. } => {
+                    self.fs.read_logical(disk_bytenr + offset + within, chunk)?;
+                }
+                &ExtentData::Regular { disk_bytenr, disk_len, offset, compression, ram_bytes } => {
+                    if self.cache.as_ref().is_none_or(|(v, _)| *v != disk_bytenr) {
+                        let mut data = vec![0; disk_len as usize];
+                        self.fs.read_logical(disk_bytenr, &mut data)?;
+                        self.cache = Some((disk_bytenr, self.fs.decompress(compression, &mut data, ram_bytes)?));
+                    }
+                    let (_, data) = self.cache.as_ref().unwrap();
+                    let start = (offset + within) as usize;
+                    let src = data.get(start..start + chunk.len())
+                        .ok_or(BtrfsError::Corrupt { what: "compressed extent" })?;
+                    chunk.copy_from_slice(src);
+                }
+                ExtentData::Zero => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Payload for BtrfsFile<'_> {
+    fn len(&self) -> Result<u64, BootError> {
+        Ok(self.size)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BootError> {
+        self.read(offset, buf).map_err(|_| BootError::Io)
+    }
+}
+
+impl BootFs for Btrfs {
+    fn open(&self, path: &str) -> Result<Box<dyn Payload + '_>, BootError> {
+        let file = self.lookup(path).and_then(|(subvol, ino, ty)| match ty {
+            FT_REG_FILE => self.file(subvol, ino),
+            _ => Err(BtrfsError::NotAFile),
+        });
+        Ok(Box::new(file.map_err(|_| BootError::Io)?))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, BootError> {
+        let entries = self.lookup(path)
+            .and_then(|(subvol, ino, ty)| match ty {
+                FT_DIR => self.dir_entries(subvol, ino),
+                _ => Err(BtrfsError::NotADirectory),
+            })
+            .map_err(|_| BootError::Io)?;
+        Ok(entries.into_iter()
+            .map(|v| DirEntry {
+                name: String::from_utf8_lossy(&v.name).into_owned(),
+                is_dir: v.ty == FT_DIR || v.location.ty == ROOT_ITEM_KEY,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    // `zstd -19 --no-check` and Python's `zlib.compress()` of `compressible()`.
+    const ZSTD_EXTENT: &[u8] = include_bytes!("../testdata/btrfs-extent.zst");
+    const ZLIB_EXTENT: &[u8] = include_bytes!("../testdata/btrfs-extent.zlib");
+    // "hello btrfs\n" as literals, then again as a match.
+    const LZO_SEGMENT: &[u8] = b"\x1dhello btrfs\n\x2a\x2c\x00\x11\x00\x00";
+
+    const FSID: [u8; 16] = [0x42; 16];
+    const NODESIZE: usize = 4096;
+    const SECTORSIZE: usize = 4096;
+    // The system chunk is only found in the superblock, the other one only in the chunk tree.
+    const SYSTEM: Chunk = Chunk { logical: 0x100000, len: 0x10000, physical: 0x20000 };
+    const DATA: Chunk = Chunk { logical: 0x200000, len: 0x40000, physical: 0x40000 };
+    const IMAGE_SIZE: usize = 0x80000;
+
+    const ROOT_TREE: u64 = DATA.logical;
+    const FS_TREE: u64 = DATA.logical + 0x1000;
+    const FS_LEAVES: [u64; 2] = [DATA.logical + 0x2000, DATA.logical + 0x3000];
+    const SUBVOL_TREE: u64 = DATA.logical + 0x4000;
+    const SUBVOL_ID: u64 = 256;
+    const DIR_ID: u64 = 256;
+
+    fn key(objectid: u64, ty: u8, offset: u64) -> Key {
+        Key { objectid, ty, offset }
+    }
+
+    fn key_bytes(key: Key) -> Vec<u8> {
+        let mut data = vec![0; 17];
+        LittleEndian::write_u64(&mut data, key.objectid);
+        data[8] = key.ty;
+        LittleEndian::write_u64(&mut data[9..], key.offset);
+        data
+    }
+
+    fn node(logical: u64, level: u8, count: usize) -> Vec<u8> {
+        let mut node = vec![0; NODESIZE];
+        node[0x20..0x30].copy_from_slice(&FSID);
+        LittleEndian::write_u64(&mut node[0x30..], logical);
+        LittleEndian::write_u32(&mut node[0x60..], count as u32);
+        node[0x64] = level;
+        node
+    }
+
+    fn checksum(node: &mut [u8]) {
+        let csum = CRC32C.checksum(&node[CSUM_SIZE..]);
+        LittleEndian::write_u32(node, csum);
+    }
+
+    fn leaf(logical: u64, items: &[(Key, Vec<u8>)]) -> Vec<u8> {
+        let mut node = node(logical, 0, items.len());
+        let mut end = NODESIZE - HEADER_SIZE;
+        for (i, (key, data)) in items.iter().enumerate() {
+            end -= data.len();
+            let item = &mut node[HEADER_SIZE + i * ITEM_SIZE..][..ITEM_SIZE];
+            item[..17].copy_from_slice(&key_bytes(*key));
+            LittleEndian::write_u32(&mut item[17..], end as u32);
+            LittleEndian::write_u32(&mut item[21..], data.len() as u32);
+            node[HEADER_SIZE + end..][..data.len()].copy_from_slice(data);
+        }
+        checksum(&mut node);
+        node
+    }
+
+    fn internal(logical: u64, children: &[(Key, u64)]) -> Vec<u8> {
+        let mut node = node(logical, 1, children.len());
+        for (i, &(key, child)) in children.iter().enumerate() {
+            let ptr = &mut node[HEADER_SIZE + i * KEY_PTR_SIZE..][..KEY_PTR_SIZE];
+            ptr[..17].copy_from_slice(&key_bytes(key));
+            LittleEndian::write_u64(&mut ptr[17..], child);
+        }
+        checksum(&mut node);
+        node
+    }
+
+    fn chunk_item(chunk: Chunk, ty: u64) -> Vec<u8> {
+        let mut item = vec![0; 48 + 32];
+        LittleEndian::write_u64(&mut item, chunk.len);
+        LittleEndian::write_u64(&mut item[24..], ty);
+        LittleEndian::write_u16(&mut item[44..], 1);
+        LittleEndian::write_u64(&mut item[48..], 1);
+        LittleEndian::write_u64(&mut item[56..], chunk.physical);
+        item
+    }
+
+    fn root_item(tree: u64) -> Vec<u8> {
+        let mut item = vec![0; 439];
+        LittleEndian::write_u64(&mut item[168..], DIR_ID);
+        LittleEndian::write_u64(&mut item[176..], tree);
+        item
+    }
+
+    fn inode_item(size: u64) -> Vec<u8> {
+        let mut item = vec![0; 160];
+        LittleEndian::write_u64(&mut item[16..], size);
+        item
+    }
+
+    fn dir_item(location: Key, ty: u8, name: &str) -> Vec<u8> {
+        let mut item = key_bytes(location);
+        item.resize(30, 0);
+        LittleEndian::write_u16(&mut item[27..], name.len() as u16);
+        item[29] = ty;
+        item.extend_from_slice(name.as_bytes());
+        item
+    }
+
+    fn inline_extent(data: &[u8]) -> Vec<u8> {
+        let mut item = vec![0; 21];
+        LittleEndian::write_u64(&mut item[8..], data.len() as u64);
+        item[20] = FILE_EXTENT_INLINE;
+        item.extend_from_slice(data);
+        item
+    }
+
+    /// A regular extent using `len` bytes from `offset` into the extent at `disk_bytenr`.
+    fn extent(ty: u8, disk_bytenr: u64, disk_len: u64, offset: u64, len: u64, compression: u8, ram_bytes: u64) -> Vec<u8> {
+        let mut item = vec![0; 53];
+        LittleEndian::write_u64(&mut item[8..], ram_bytes);
+        item[16] = compression;
+        item[20] = ty;
+        LittleEndian::write_u64(&mut item[21..], disk_bytenr);
+        LittleEndian::write_u64(&mut item[29..], disk_len);
+        LittleEndian::write_u64(&mut item[37..], offset);
+        LittleEndian::write_u64(&mut item[45..], len);
+        item
+    }
+
+    fn regular(disk_bytenr: u64, offset: u64, len: u64) -> Vec<u8> {
+        extent(1, disk_bytenr, 0x2000, offset, len, COMPRESS_NONE, 0x2000)
+    }
+
+    fn compressed(disk_bytenr: u64, disk_len: usize, compression: u8) -> Vec<u8> {
+        extent(1, disk_bytenr, disk_len as u64, 0, 0x2000, compression, 0x2000)
+    }
+
+    fn data(len: usize, seed: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * seed + i / SECTORSIZE) as u8).collect()
+    }
+
+    fn compressible() -> Vec<u8> {
+        (0..512).map(|i| format!("{i:04} compressed\n")).collect::<String>().into_bytes()
+    }
+
+    /// The two segments of an LZO compressed extent.
+    fn lzo_extent() -> Vec<u8> {
+        let mut extent = vec![0; 4];
+        for _ in 0..2 {
+            extent.extend_from_slice(&(LZO_SEGMENT.len() as u32).to_le_bytes());
+            extent.extend_from_slice(LZO_SEGMENT);
+        }
+        let len = extent.len() as u32;
+        LittleEndian::write_u32(&mut extent, len);
+        extent
+    }
+
+    fn write_logical(image: &mut [u8], logical: u64, data: &[u8]) {
+        let chunk = [SYSTEM, DATA].into_iter().find(|v| v.logical <= logical && logical < v.logical + v.len).unwrap();
+        image[(chunk.physical + logical - chunk.logical) as usize..][..data.len()].copy_from_slice(data);
+    }
+
+    /// A filesystem with an `@` subvolume holding /boot/vmlinuz, and at the top level:
+    /// - /inline
+    /// - /regular, two extents with a hole in between
+    /// - /zstd, /zlib and /lzo, compressed
+    /// - /prealloc
+    /// - /boot, a symlink to @/boot
+    ///
+    /// The top level's tree has two levels. `default` is the subvolume set as the default, if any.
+    fn image(default: Option<u64>) -> Vec<u8> {
+        let mut image = vec![0; IMAGE_SIZE];
+
+        let chunk_key = |chunk: Chunk| key(FIRST_CHUNK_TREE_OBJECTID, CHUNK_ITEM_KEY, chunk.logical);
+        let sys_chunk = [key_bytes(chunk_key(SYSTEM)), chunk_item(SYSTEM, 0x2)].concat();
+        let sb = &mut image[SUPERBLOCK_OFFSET as usize..][..SUPERBLOCK_SIZE];
+        sb[0x20..0x30].copy_from_slice(&FSID);
+        sb[0x40..0x48].copy_from_slice(MAGIC);
+        LittleEndian::write_u64(&mut sb[0x50..], ROOT_TREE);
+        LittleEndian::write_u64(&mut sb[0x58..], SYSTEM.logical);
+        LittleEndian::write_u64(&mut sb[0x88..], 1);
+        LittleEndian::write_u32(&mut sb[0x90..], SECTORSIZE as u32);
+        LittleEndian::write_u32(&mut sb[0x94..], NODESIZE as u32);
+        LittleEndian::write_u32(&mut sb[0xa0..], sys_chunk.len() as u32);
+        let incompat = INCOMPAT_DEFAULT_SUBVOL | INCOMPAT_COMPRESS_LZO | INCOMPAT_COMPRESS_ZSTD | INCOMPAT_NO_HOLES;
+        LittleEndian::write_u64(&mut sb[0xbc..], incompat);
+        LittleEndian::write_u64(&mut sb[0xc9..], 1);
+        sb[SYS_CHUNK_ARRAY_OFFSET..][..sys_chunk.len()].copy_from_slice(&sys_chunk);
+        let csum = CRC32C.checksum(&sb[CSUM_SIZE..]);
+        LittleEndian::write_u32(sb, csum);
+
+        let chunk_tree = leaf(SYSTEM.logical, &[
+            (chunk_key(SYSTEM), chunk_item(SYSTEM, 0x2)),
+            (chunk_key(DATA), chunk_item(DATA, 0x1 | 0x4)),
+        ]);
+        write_logical(&mut image, SYSTEM.logical, &chunk_tree);
+
+        let mut root_items = vec![(key(FS_TREE_OBJECTID, ROOT_ITEM_KEY, 0), root_item(FS_TREE))];
+        if let Some(id) = default {
+            let default = dir_item(key(id, ROOT_ITEM_KEY, 0), FT_DIR, "default");
+            root_items.push((key(ROOT_TREE_DIR_OBJECTID, DIR_ITEM_KEY, 0), default));
+        }
+        root_items.push((key(SUBVOL_ID, ROOT_ITEM_KEY, 0), root_item(SUBVOL_TREE)));
+        write_logical(&mut image, ROOT_TREE, &leaf(ROOT_TREE, &root_items));
+
+        let files = [
+            ("inline", FT_REG_FILE, vec![(0, inline_extent(b"inline data\n"))], 12),
+            ("regular", FT_REG_FILE, vec![
+                (0, regular(DATA.logical + 0x10000, 0, 0x2000)),
+                // The second half of another extent, after a hole.
+                (0x3000, regular(DATA.logical + 0x12000, 0x1000, 0x1000)),
+            ], 0x3f00),
+            ("zstd", FT_REG_FILE, vec![(0, compressed(DATA.logical + 0x14000, ZSTD_EXTENT.len(), COMPRESS_ZSTD))], 0x2000),
+            ("zlib", FT_REG_FILE, vec![
+                // Only part of the extent, like after the rest was overwritten.
+                (0, extent(1, DATA.logical + 0x15000, ZLIB_EXTENT.len() as u64, 100, 0x1000, COMPRESS_ZLIB, 0x2000)),
+            ], 0x1000),
+            ("lzo", FT_REG_FILE, vec![(0, compressed(DATA.logical + 0x16000, lzo_extent().len(), COMPRESS_LZO))], 48),
+            ("prealloc", FT_REG_FILE, vec![(0, extent(FILE_EXTENT_PREALLOC, DATA.logical + 0x17000, 0x1000, 0, 0x1000, 0, 0x1000))], 0x1000),
+            ("boot", FT_SYMLINK, vec![(0, inline_extent(b"@/boot"))], 6),
+        ];
+        write_logical(&mut image, DATA.logical + 0x10000, &data(0x2000, 3));
+        write_logical(&mut image, DATA.logical + 0x12000, &data(0x2000, 5));
+        write_logical(&mut image, DATA.logical + 0x14000, ZSTD_EXTENT);
+        write_logical(&mut image, DATA.logical + 0x15000, ZLIB_EXTENT);
+        write_logical(&mut image, DATA.logical + 0x16000, &lzo_extent());
+        write_logical(&mut image, DATA.logical + 0x17000, &[0xff; 0x1000]);
+
+        let mut items = vec![(key(DIR_ID, INODE_ITEM_KEY, 0), inode_item(0))];
+        items.push((key(DIR_ID, DIR_INDEX_KEY, 2), dir_item(key(SUBVOL_ID, ROOT_ITEM_KEY, u64::MAX), FT_DIR, "@")));
+        for (i, (name, ty, _, _)) in files.iter().enumerate() {
+            let ino = DIR_ID + 1 + i as u64;
+            items.push((key(DIR_ID, DIR_INDEX_KEY, 3 + i as u64), dir_item(key(ino, INODE_ITEM_KEY, 0), *ty, name)));
+        }
+        for (i, (_, _, extents, size)) in files.into_iter().enumerate() {
+            let ino = DIR_ID + 1 + i as u64;
+            items.push((key(ino, INODE_ITEM_KEY, 0), inode_item(size)));
+            items.extend(extents.into_iter().map(|(offset, item)| (key(ino, EXTENT_DATA_KEY, offset), item)));
+        }
+        let (first, second) = items.split_at(items.len() / 2);
+        write_logical(&mut image, FS_LEAVES[0], &leaf(FS_LEAVES[0], first));
+        write_logical(&mut image, FS_LEAVES[1], &leaf(FS_LEAVES[1], second));
+        write_logical(&mut image, FS_TREE, &internal(FS_TREE, &[(first[0].0, FS_LEAVES[0]), (second[0].0, FS_LEAVES[1])]));
+
+        write_logical(&mut image, DATA.logical + 0x18000, &data(0x1800, 7));
+        let subvol = leaf(SUBVOL_TREE, &[
+            (key(DIR_ID, INODE_ITEM_KEY, 0), inode_item(0)),
+            (key(DIR_ID, DIR_INDEX_KEY, 2), dir_item(key(DIR_ID + 1, INODE_ITEM_KEY, 0), FT_DIR, "boot")),
+            (key(DIR_ID + 1, INODE_ITEM_KEY, 0), inode_item(0)),
+            (key(DIR_ID + 1, DIR_INDEX_KEY, 2), dir_item(key(DIR_ID + 2, INODE_ITEM_KEY, 0), FT_REG_FILE, "vmlinuz")),
+            (key(DIR_ID + 2, INODE_ITEM_KEY, 0), inode_item(0x1800)),
+            (key(DIR_ID + 2, EXTENT_DATA_KEY, 0), regular(DATA.logical + 0x18000, 0, 0x2000)),
+        ]);
+        write_logical(&mut image, SUBVOL_TREE, &subvol);
+        image
+    }
+
+    fn open(image: Vec<u8>) -> Result<Btrfs, BtrfsError> {
+        Btrfs::new(OpenDevice::from_image(Box::new(image), IMAGE_SIZE as u64))
+    }
+
+    fn read(fs: &Btrfs, path: &str) -> Vec<u8> {
+        BootFs::read(fs, path).unwrap()
+    }
+
+    #[test]
+    fn chunk_tree() {
+        let fs = open(image(None)).unwrap();
+        assert_eq!(fs.chunks.len(), 2);
+        assert_eq!(fs.chunks[1].physical, DATA.physical);
+
+        // Tree blocks are checked to be where they say they are.
+        let mut image = image(None);
+        image[DATA.physical as usize + 0x30] ^= 0x1;
+        assert!(matches!(open(image), Err(BtrfsError::Corrupt { .. })));
+    }
+
+    #[test]
+    fn inline_extents() {
+        let fs = open(image(None)).unwrap();
+        assert_eq!(read(&fs, "/inline"), b"inline data\n");
+    }
+
+    #[test]
+    fn regular_extents() {
+        let fs = open(image(None)).unwrap();
+        let mut expected = data(0x2000, 3);
+        expected.resize(0x3000, 0);
+        expected.extend_from_slice(&data(0x2000, 5)[0x1000..0x1f00]);
+        assert!(read(&fs, "/regular") == expected);
+        assert_eq!(read(&fs, "/prealloc"), [0; 0x1000]);
+    }
+
+    #[test]
+    fn compressed_extents() {
+        let fs = open(image(None)).unwrap();
+        assert!(read(&fs, "/zstd") == compressible());
+        assert!(read(&fs, "/zlib") == compressible()[100..][..0x1000]);
+        assert_eq!(read(&fs, "/lzo"), b"hello btrfs\n".repeat(4));
+    }
+
+    #[test]
+    fn subvolumes() {
+        let fs = open(image(None)).unwrap();
+        assert!(read(&fs, "/@/boot/vmlinuz") == data(0x1800, 7));
+        assert!(read(&fs, "/boot/vmlinuz") == data(0x1800, 7));
+        let mut root = fs.read_dir("/").unwrap().into_iter().map(|v| (v.name, v.is_dir)).collect::<Vec<_>>();
+        root.sort();
+        assert_eq!(root[0], ("@".into(), true));
+        assert_eq!(root[1], ("boot".into(), false));
+
+        let fs = open(image(Some(SUBVOL_ID))).unwrap();
+        assert!(read(&fs, "/boot/vmlinuz") == data(0x1800, 7));
+        assert!(fs.open("/inline").is_err());
+    }
+}
@@ -0,0 +1,615 @@
+//! Kernel image decompression.
+//!
+//! Handles the formats kbuild produces for `Image.gz`, `Image.zst` and `Image.lz4`, plus EFI zboot
+//! images (`vmlinuz.efi`), which wrap one of those in a small PE decompressor stub. Raw zlib and
+//! LZO1X streams are handled too, for btrfs compressed extents, and MicroLZMA for EROFS.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::ffi::{c_int, c_uint, c_ulong, CStr};
+use lzma_rust2::{LzmaReader, Read as LzmaRead};
+use ruzstd::blocks::block::BlockType;
+use ruzstd::blocks::literals_section::{LiteralsSection, LiteralsSectionType};
+use ruzstd::blocks::sequence_section::{Sequence, SequencesHeader};
+use ruzstd::decoding::block_decoder;
+use ruzstd::decoding::literals_section_decoder::decode_literals;
+use ruzstd::decoding::scratch::{FSEScratch, HuffmanScratch};
+use ruzstd::decoding::sequence_section_decoder::decode_sequences;
+use ruzstd::frame;
+use snafu::Snafu;
+
+#[derive(Debug, Snafu)]
+pub enum DecompressError {
+    #[snafu(display("gzip error {code}"))]
+    Gzip { code: c_int },
+    #[snafu(display("zstd error"))]
+    Zstd,
+    #[snafu(display("lz4 error"))]
+    Lz4,
+    #[snafu(display("zlib error {code}"))]
+    Zlib { code: c_int },
+    #[snafu(display("lzo error"))]
+    Lzo,
+    #[snafu(display("lzma error"))]
+    Lzma,
+    #[snafu(display("EFI zboot image is malformed"))]
+    InvalidZboot,
+    #[snafu(display("unsupported EFI zboot compression {method}"))]
+    UnsupportedZboot { method: String },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Lz4,
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: u32 = 0xfd2fb528;
+const LZ4_LEGACY_MAGIC: u32 = 0x184c2102;
+const ZBOOT_MAGIC: &[u8; 4] = b"zimg";
+// Legacy lz4 blocks decompress to at most 8MB, so can't be bigger than LZ4_compressBound() of that.
+const LZ4_LEGACY_BLOCK_BOUND: usize = 8 * 1024 * 1024 + 8 * 1024 * 1024 / 255 + 16;
+
+impl Compression {
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.len() < 4 {
+            return None;
+        }
+        if data[..2] == GZIP_MAGIC {
+            Some(Compression::Gzip)
+        } else if LittleEndian::read_u32(data) == ZSTD_MAGIC {
+            Some(Compression::Zstd)
+        } else if LittleEndian::read_u32(data) == LZ4_LEGACY_MAGIC {
+            Some(Compression::Lz4)
+        } else {
+            None
+        }
+    }
+
+    /// Decompress `data` into `out`, returning the decompressed size.
+    pub fn decompress(self, data: &mut [u8], out: &mut [u8]) -> Result<usize, DecompressError> {
+        self.decompress_stream(data, out).map(|(written, _)| written)
+    }
+
+    /// Like [`Compression::decompress`], but also return how much of `data` the compressed stream
+    /// took up, to find what was appended after it.
+    pub fn decompress_stream(self, data: &mut [u8], out: &mut [u8]) -> Result<(usize, usize), DecompressError> {
+        match self {
+            Compression::Gzip => gunzip(data, out),
+            Compression::Zstd => unzstd(data, out),
+            Compression::Lz4 => unlz4(data, out),
+        }
+    }
+}
+
+/// If `image` is an EFI zboot image, find its compressed payload and how it was compressed.
+///
+/// See `struct linux_efi_zboot_header` in the kernel's `include/linux/pe.h`.
+pub fn zboot_payload(image: &mut [u8]) -> Result<Option<(Compression, &mut [u8])>, DecompressError> {
+    if image.len() < 64 || &image[..2] != b"MZ" || &image[4..8] != ZBOOT_MAGIC {
+        return Ok(None);
+    }
+
+    let offset = LittleEndian::read_u32(&image[8..]) as usize;
+    let size = LittleEndian::read_u32(&image[12..]) as usize;
+    let method = CStr::from_bytes_until_nul(&image[24..56])
+        .ok()
+        .and_then(|v| v.to_str().ok())
+        .ok_or(DecompressError::InvalidZboot)?;
+    let compression = match method {
+        "gzip" => Compression::Gzip,
+        "zstd" | "zstd22" => Compression::Zstd,
+        "lz4" => Compression::Lz4,
+        method => return Err(DecompressError::UnsupportedZboot { method: method.to_string() }),
+    };
+
+    let payload = image.get_mut(offset..offset + size).ok_or(DecompressError::InvalidZboot)?;
+    Ok(Some((compression, payload)))
+}
+
+fn gunzip(data: &mut [u8], out: &mut [u8]) -> Result<(usize, usize), DecompressError> {
+    let mut pos = 0;
+    let mut out_size = 0;
+    let ret = unsafe {
+        sys::decompress(
+            data.as_mut_ptr(),
+            data.len() as c_uint,
+            out.as_mut_ptr(),
+            out.len() as c_uint,
+            &mut pos,
+            &mut out_size,
+        )
+    };
+    if ret != 0 {
+        return Err(DecompressError::Gzip { code: ret });
+    }
+    Ok((out_size as usize, pos as usize))
+}
+
+/// Decompress zstd frames straight into `out`.
+///
+/// ruzstd's `FrameDecoder` keeps its own copy of the decoding window on the heap, which is far too
+/// small for the 8-128MB windows of `zstd -19` and `zstd -22 --ultra` kernels. Only its block
+/// parsing is used here, and sequences are executed on `out`, which then doubles as the window.
+fn unzstd(data: &mut [u8], out: &mut [u8]) -> Result<(usize, usize), DecompressError> {
+    let mut input: &[u8] = data;
+    let mut written = 0;
+
+    // kbuild appends the decompressed size after the last frame, so stop at anything that isn't
+    // another frame rather than insisting on consuming all input.
+    while input.len() >= 4 && LittleEndian::read_u32(input) == ZSTD_MAGIC {
+        let (frame, _) = frame::read_frame_header(&mut input).map_err(|_| DecompressError::Zstd)?;
+        if frame.header.dictionary_id().is_some() {
+            return Err(DecompressError::Zstd);
+        }
+        written += unzstd_frame(&mut input, out, written)?;
+        if frame.header.descriptor.content_checksum_flag() {
+            input = input.get(4..).ok_or(DecompressError::Zstd)?;
+        }
+    }
+
+    if written == 0 {
+        return Err(DecompressError::Zstd);
+    }
+    Ok((written, data.len() - input.len()))
+}
+
+/// Decompress the blocks of one frame from `input` to `out`, starting at `start`. Matches may
+/// reach back into earlier frames' output, which is harmless as a valid frame never does that.
+/// Returns the decompressed size of the frame.
+fn unzstd_frame(input: &mut &[u8], out: &mut [u8], start: usize) -> Result<usize, DecompressError> {
+    let mut blocks = block_decoder::new();
+    let mut huf = HuffmanScratch::new();
+    let mut fse = FSEScratch::new();
+    let mut literals = Vec::new();
+    let mut sequences = Vec::new();
+    let mut offset_hist = [1, 4, 8];
+    let mut pos = start;
+
+    loop {
+        let (header, _) = blocks.read_block_header(&mut *input).map_err(|_| DecompressError::Zstd)?;
+        let content = input.get(..header.content_size as usize).ok_or(DecompressError::Zstd)?;
+        *input = &input[content.len()..];
+
+        match header.block_type {
+            BlockType::Raw => {
+                out.get_mut(pos..pos + content.len()).ok_or(DecompressError::Zstd)?.copy_from_slice(content);
+                pos += content.len();
+            }
+            BlockType::RLE => {
+                let len = header.decompressed_size as usize;
+                out.get_mut(pos..pos + len).ok_or(DecompressError::Zstd)?.fill(content[0]);
+                pos += len;
+            }
+            BlockType::Compressed => {
+                let mut section = LiteralsSection::new();
+                let header_len = section.parse_from_header(content).map_err(|_| DecompressError::Zstd)? as usize;
+                let raw = &content[header_len..];
+                let literals_len = match (section.compressed_size, &section.ls_type) {
+                    (Some(size), _) => size as usize,
+                    (None, LiteralsSectionType::RLE) => 1,
+                    (None, _) => section.regenerated_size as usize,
+                };
+                let raw_literals = raw.get(..literals_len).ok_or(DecompressError::Zstd)?;
+                literals.clear();
+                decode_literals(&section, &mut huf, raw_literals, &mut literals).map_err(|_| DecompressError::Zstd)?;
+
+                let raw = &raw[literals_len..];
+                let mut seq_section = SequencesHeader::new();
+                let header_len = seq_section.parse_from_header(raw).map_err(|_| DecompressError::Zstd)? as usize;
+                sequences.clear();
+                if seq_section.num_sequences != 0 {
+                    decode_sequences(&seq_section, &raw[header_len..], &mut fse, &mut sequences)
+                        .map_err(|_| DecompressError::Zstd)?;
+                }
+                pos = execute_sequences(&sequences, &literals, &mut offset_hist, out, pos)?;
+            }
+            BlockType::Reserved => return Err(DecompressError::Zstd),
+        }
+
+        if header.last_block {
+            return Ok(pos - start);
+        }
+    }
+}
+
+/// Apply `sequences` to `out` at `pos`, returning the new position.
+/// https://github.com/facebook/zstd/blob/dev/doc/zstd_compression_format.md#sequence-execution
+fn execute_sequences(
+    sequences: &[Sequence],
+    literals: &[u8],
+    offset_hist: &mut [u32; 3],
+    out: &mut [u8],
+    mut pos: usize,
+) -> Result<usize, DecompressError> {
+    let mut literals = literals;
+    for seq in sequences {
+        let (lit, rest) = literals.split_at_checked(seq.ll as usize).ok_or(DecompressError::Zstd)?;
+        out.get_mut(pos..pos + lit.len()).ok_or(DecompressError::Zstd)?.copy_from_slice(lit);
+        pos += lit.len();
+        literals = rest;
+
+        // Offset values 1-3 pick a recent offset, shifted by one when there are no literals.
+        let repeat = match seq.of {
+            0 => return Err(DecompressError::Zstd),
+            1..=3 if seq.ll == 0 => Some(seq.of),
+            1..=3 => Some(seq.of - 1),
+            _ => None,
+        };
+        let offset = match repeat {
+            None => seq.of - 3,
+            Some(3) => offset_hist[0].wrapping_sub(1),
+            Some(idx) => offset_hist[idx as usize],
+        };
+        match repeat {
+            Some(0) => {}
+            Some(1) => *offset_hist = [offset, offset_hist[0], offset_hist[2]],
+            _ => *offset_hist = [offset, offset_hist[0], offset_hist[1]],
+        }
+
+        let (offset, len) = (offset as usize, seq.ml as usize);
+        if offset == 0 || offset > pos || pos + len > out.len() {
+            return Err(DecompressError::Zstd);
+        }
+        // Matches may overlap what they produce, so this has to go byte by byte.
+        for i in pos..pos + len {
+            out[i] = out[i - offset];
+        }
+        pos += len;
+    }
+    out.get_mut(pos..pos + literals.len()).ok_or(DecompressError::Zstd)?.copy_from_slice(literals);
+    Ok(pos + literals.len())
+}
+
+/// The legacy lz4 format used by the kernel: a magic followed by length-prefixed blocks.
+fn unlz4(data: &mut [u8], out: &mut [u8]) -> Result<(usize, usize), DecompressError> {
+    let mut pos = 4;
+    let mut written = 0;
+
+    while pos + 4 <= data.len() {
+        let block_size = LittleEndian::read_u32(&data[pos..]) as usize;
+        // Concatenated streams repeat the magic.
+        if block_size as u32 == LZ4_LEGACY_MAGIC {
+            pos += 4;
+            continue;
+        }
+        // Anything that doesn't look like a block is the size kbuild appends.
+        if block_size > LZ4_LEGACY_BLOCK_BOUND || pos + 4 + block_size > data.len() {
+            break;
+        }
+        pos += 4;
+        written += lz4_flex::block::decompress_into(&data[pos..pos + block_size], &mut out[written..])
+            .map_err(|_| DecompressError::Lz4)?;
+        pos += block_size;
+    }
+
+    if written == 0 {
+        return Err(DecompressError::Lz4);
+    }
+    Ok((written, pos))
+}
+
+/// Decompress a zlib stream, as opposed to the gzip files handled by [`Compression::Gzip`].
+pub fn inflate(data: &[u8], out: &mut [u8]) -> Result<usize, DecompressError> {
+    let mut in_len = data.len() as c_ulong;
+    let mut out_len = out.len() as c_ulong;
+    let ret = unsafe { sys::uncompress2(out.as_mut_ptr(), &mut out_len, data.as_ptr(), &mut in_len) };
+    if ret != 0 {
+        return Err(DecompressError::Zlib { code: ret });
+    }
+    Ok(out_len as usize)
+}
+
+/// Decompress a raw LZO1X stream, returning the decompressed size.
+///
+/// This follows lib/lzo/lzo1x_decompress_safe.c in Linux, minus the lzo-rle extension that only
+/// zram uses.
+pub fn unlzo(data: &[u8], out: &mut [u8]) -> Result<usize, DecompressError> {
+    const M2_MAX_OFFSET: usize = 0x800;
+    const M4_BASE_OFFSET: usize = 0x4000;
+
+    let byte = |ip: &mut usize| -> Result<usize, DecompressError> {
+        let v = *data.get(*ip).ok_or(DecompressError::Lzo)?;
+        *ip += 1;
+        Ok(v as usize)
+    };
+    // Lengths that don't fit in the instruction are continued by zero bytes worth 255 each.
+    let length = |ip: &mut usize, base: usize| -> Result<usize, DecompressError> {
+        let mut len = base;
+        loop {
+            match byte(ip)? {
+                0 => len += 255,
+                v => return Ok(len + v),
+            }
+        }
+    };
+    let literals = |ip: &mut usize, op: &mut usize, out: &mut [u8], len: usize| -> Result<(), DecompressError> {
+        let src = data.get(*ip..*ip + len).ok_or(DecompressError::Lzo)?;
+        out.get_mut(*op..*op + len).ok_or(DecompressError::Lzo)?.copy_from_slice(src);
+        *ip += len;
+        *op += len;
+        Ok(())
+    };
+
+    let (mut ip, mut op) = (0, 0);
+    // How many literals followed the last instruction, 4 standing for a whole literal run.
+    let mut state = 0;
+    if data.first().is_some_and(|&v| v > 17) {
+        let len = byte(&mut ip)? - 17;
+        literals(&mut ip, &mut op, out, len)?;
+        state = if len < 4 { len } else { 4 };
+    }
+
+    loop {
+        let t = byte(&mut ip)?;
+        let (distance, len, next);
+        if t < 16 {
+            match state {
+                0 => {
+                    let len = if t == 0 { length(&mut ip, 15)? } else { t } + 3;
+                    literals(&mut ip, &mut op, out, len)?;
+                    state = 4;
+                    continue;
+                }
+                4 => {
+                    distance = 1 + M2_MAX_OFFSET + (t >> 2) + (byte(&mut ip)? << 2);
+                    len = 3;
+                }
+                _ => {
+                    distance = 1 + (t >> 2) + (byte(&mut ip)? << 2);
+                    len = 2;
+                }
+            }
+            next = t & 3;
+        } else if t >= 64 {
+            distance = 1 + ((t >> 2) & 7) + (byte(&mut ip)? << 3);
+            len = (t >> 5) + 1;
+            next = t & 3;
+        } else if t >= 32 {
+            len = if t & 31 == 0 { length(&mut ip, 31)? } else { t & 31 } + 2;
+            let v = byte(&mut ip)? | byte(&mut ip)? << 8;
+            distance = 1 + (v >> 2);
+            next = v & 3;
+        } else {
+            len = if t & 7 == 0 { length(&mut ip, 7)? } else { t & 7 } + 2;
+            let v = byte(&mut ip)? | byte(&mut ip)? << 8;
+            let offset = ((t & 8) << 11) + (v >> 2);
+            // A zero offset marks the end of the stream.
+            if offset == 0 {
+                return Ok(op);
+            }
+            distance = offset + M4_BASE_OFFSET;
+            next = v & 3;
+        }
+
+        if distance > op || op + len > out.len() {
+            return Err(DecompressError::Lzo);
+        }
+        // Matches may overlap with what they produce, so this has to go byte by byte.
+        for i in op..op + len {
+            out[i] = out[i - distance];
+        }
+        op += len;
+
+        literals(&mut ip, &mut op, out, next)?;
+        state = next;
+    }
+}
+
+/// Decompress a MicroLZMA stream into all of `out`, which has to be exactly the decompressed size.
+///
+/// MicroLZMA is raw LZMA without an end marker, the range coder's first byte, which is always
+/// zero, replaced by the complement of the properties byte. See include/linux/xz.h.
+pub fn unmicrolzma(data: &[u8], out: &mut [u8]) -> Result<(), DecompressError> {
+    let (&props, rest) = data.split_first().ok_or(DecompressError::Lzma)?;
+    let mut input = Vec::with_capacity(data.len());
+    input.push(0);
+    input.extend_from_slice(rest);
+    // Nothing can refer back further than the start of the output.
+    let mut reader = LzmaReader::new_with_props(input.as_slice(), out.len() as u64, !props, out.len() as u32, None)
+        .map_err(|_| DecompressError::Lzma)?;
+    reader.read_exact(out).map_err(|_| DecompressError::Lzma)
+}
+
+mod sys {
+    use core::ffi::{c_int, c_uint, c_ulong};
+
+    extern "C" {
+        pub fn decompress(
+            in_buf: *mut u8,
+            in_len: c_uint,
+            out_buf: *mut u8,
+            out_len: c_uint,
+            pos: *mut c_uint,
+            out_size: *mut c_uint,
+        ) -> c_int;
+        pub fn uncompress2(dest: *mut u8, dest_len: *mut c_ulong, source: *const u8, source_len: *mut c_ulong) -> c_int;
+    }
+}
+
+/// Host test builds don't link against LK, so its zlib is stood in for by miniz_oxide.
+#[cfg(test)]
+mod host {
+    use core::ffi::{c_int, c_uint, c_ulong};
+    use core::slice;
+    use miniz_oxide::inflate::core::inflate_flags::{
+        TINFL_FLAG_PARSE_ZLIB_HEADER, TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF,
+    };
+    use miniz_oxide::inflate::core::{decompress as inflate, DecompressorOxide};
+    use miniz_oxide::inflate::TINFLStatus;
+
+    const Z_DATA_ERROR: c_int = -3;
+
+    /// Returns how much of `data` was consumed and the decompressed size.
+    fn inflate_all(data: &[u8], out: &mut [u8], flags: u32) -> Option<(usize, usize)> {
+        let flags = flags | TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+        match inflate(&mut DecompressorOxide::new(), data, out, 0, flags) {
+            (TINFLStatus::Done, consumed, written) => Some((consumed, written)),
+            _ => None,
+        }
+    }
+
+    /// See lib/zlib_inflate/decompress.c.
+    #[no_mangle]
+    extern "C" fn decompress(
+        in_buf: *mut u8,
+        in_len: c_uint,
+        out_buf: *mut u8,
+        out_len: c_uint,
+        pos: *mut c_uint,
+        out_size: *mut c_uint,
+    ) -> c_int {
+        let data = unsafe { slice::from_raw_parts(in_buf, in_len as usize) };
+        let out = unsafe { slice::from_raw_parts_mut(out_buf, out_len as usize) };
+        // The file name is the only optional header field LK skips.
+        let mut start = 10;
+        if data[3] & 0x8 != 0 {
+            start += data[start..].iter().position(|&v| v == 0).unwrap() + 1;
+        }
+        let Some((consumed, written)) = inflate_all(&data[start..], out, 0) else {
+            return Z_DATA_ERROR;
+        };
+        unsafe {
+            // The CRC32 and size trailer is counted but not checked.
+            *pos = (start + consumed + 8) as c_uint;
+            *out_size = written as c_uint;
+        }
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn uncompress2(dest: *mut u8, dest_len: *mut c_ulong, source: *const u8, source_len: *mut c_ulong) -> c_int {
+        let data = unsafe { slice::from_raw_parts(source, *source_len as usize) };
+        let out = unsafe { slice::from_raw_parts_mut(dest, *dest_len as usize) };
+        let Some((consumed, written)) = inflate_all(data, out, TINFL_FLAG_PARSE_ZLIB_HEADER) else {
+            return Z_DATA_ERROR;
+        };
+        unsafe {
+            *source_len = consumed as c_ulong;
+            *dest_len = written as c_ulong;
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    // Made with `zstd -19`, `lz4 -l` and `gzip -9n` from `plain()`. The zstd and lz4 files are two
+    // streams, one for each half, followed by the decompressed size like kbuild appends.
+    const ZSTD: &[u8] = include_bytes!("../testdata/kernel.zst");
+    const LZ4: &[u8] = include_bytes!("../testdata/kernel.lz4");
+    const GZIP: &[u8] = include_bytes!("../testdata/kernel.gz");
+
+    fn plain() -> Vec<u8> {
+        (0..2000)
+            .map(|i| alloc::format!("{i:05} the quick brown fox {} jumps over the lazy dog\n", i * 7919 % 1000))
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    /// Detect and decompress `data`, checking that the trailing size was left alone.
+    fn roundtrip(data: &[u8], trailer: usize) {
+        let plain = plain();
+        let compression = Compression::detect(data).unwrap();
+        let mut out = vec![0; plain.len()];
+        let (written, consumed) = compression.decompress_stream(&mut data.to_vec(), &mut out).unwrap();
+        assert_eq!(consumed, data.len() - trailer);
+        assert_eq!(written, plain.len());
+        assert!(out == plain);
+    }
+
+    #[test]
+    fn zstd() {
+        roundtrip(ZSTD, 4);
+    }
+
+    #[test]
+    fn zstd_corrupt() {
+        let mut plain = plain();
+        let mut data = ZSTD.to_vec();
+        // Cut the first frame off part way through its block.
+        let mut truncated = data[..ZSTD.len() / 4].to_vec();
+        assert!(Compression::Zstd.decompress(&mut truncated, &mut plain).is_err());
+        // Too small an output buffer has to fail rather than write past it.
+        assert!(Compression::Zstd.decompress(&mut data, &mut plain[..1000]).is_err());
+    }
+
+    #[test]
+    fn lz4() {
+        roundtrip(LZ4, 4);
+    }
+
+    #[test]
+    fn gzip() {
+        roundtrip(GZIP, 0);
+    }
+
+    #[test]
+    fn zboot() {
+        let mut image = vec![0; 64];
+        image[..2].copy_from_slice(b"MZ");
+        image[4..8].copy_from_slice(ZBOOT_MAGIC);
+        LittleEndian::write_u32(&mut image[8..], 64);
+        LittleEndian::write_u32(&mut image[12..], GZIP.len() as u32);
+        image[24..28].copy_from_slice(b"gzip");
+        image.extend_from_slice(GZIP);
+        // Where the PE stub's data would follow the payload.
+        image.extend_from_slice(&[0xff; 64]);
+
+        let (compression, payload) = zboot_payload(&mut image).unwrap().unwrap();
+        assert!(matches!(compression, Compression::Gzip));
+        assert_eq!(payload, GZIP);
+        let mut out = vec![0; plain().len()];
+        compression.decompress(payload, &mut out).unwrap();
+        assert!(out == plain());
+
+        image[24..32].copy_from_slice(b"xzkern\0\0");
+        assert!(matches!(zboot_payload(&mut image), Err(DecompressError::UnsupportedZboot { .. })));
+        image[24..32].copy_from_slice(b"gzip\0\0\0\0");
+        let len = image.len() as u32;
+        LittleEndian::write_u32(&mut image[12..], len);
+        assert!(matches!(zboot_payload(&mut image), Err(DecompressError::InvalidZboot)));
+        assert!(zboot_payload(&mut GZIP.to_vec()).unwrap().is_none());
+    }
+
+    #[test]
+    fn lzo() {
+        #[rustfmt::skip]
+        let data = [
+            // The stream starts with 4 literals.
+            17 + 4, b'a', b'b', b'c', b'd',
+            // 8 and then 4 bytes from 4 back, followed by 1 literal.
+            0xec, 0x00,
+            0x6d, 0x00, b'!',
+            // 40 bytes from 17 back, the length continued in the next byte.
+            0x20, 7, 0x40, 0x00,
+            // 5 literals.
+            0x02, b'h', b'e', b'l', b'l', b'o',
+            // 3 bytes from 5 back and 2 literals, then 2 bytes from 2 back.
+            0x52, 0x00, b'X', b'Y',
+            0x04, 0x00,
+            // End of stream.
+            0x11, 0x00, 0x00,
+        ];
+        let expected = [
+            "abcdabcdabcdabcd!",
+            "abcdabcdabcdabcd!abcdabcdabcdabcd!abcdab",
+            "hello",
+            "helXYXY",
+        ]
+        .concat();
+
+        let mut out = vec![0; 256];
+        let len = unlzo(&data, &mut out).unwrap();
+        assert_eq!(core::str::from_utf8(&out[..len]).unwrap(), expected);
+
+        assert!(unlzo(&data[..data.len() - 3], &mut out).is_err());
+        assert!(unlzo(&data, &mut out[..expected.len() - 1]).is_err());
+    }
+}
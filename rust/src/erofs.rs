@@ -0,0 +1,1140 @@
+//! Read-only EROFS driver, for system images that carry their own kernel, either as a partition or
+//! as an image file on another filesystem.
+//!
+//! Uncompressed files can be plain, have their tail inline after the inode, or be split into
+//! chunks. Compressed files can be LZ4 or MicroLZMA, with either the full or the compact index
+//! mkfs.erofs writes. Deduplicated and fragment-packed files, and images spanning several devices,
+//! can't be read.
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::cell::RefCell;
+use crc::{Crc, CRC_32_ISCSI};
+use snafu::{ResultExt, Snafu};
+use crate::bio::{BioError, OpenDevice};
+use crate::decompress::{self, DecompressError};
+use crate::fs::{BootFs, DirEntry};
+use crate::kernel_boot::{BootError, Payload};
+
+#[derive(Debug, Snafu)]
+pub enum ErofsError {
+    #[snafu(display("I/O error: {source}"))]
+    Io { source: BioError },
+    #[snafu(display("invalid superblock"))]
+    InvalidSuperblock,
+    #[snafu(display("unsupported block size 2^{bits}"))]
+    UnsupportedBlockSize { bits: u8 },
+    #[snafu(display("unsupported incompatible features {features:#x}"))]
+    UnsupportedFeatures { features: u32 },
+    #[snafu(display("filesystems on multiple devices are not supported"))]
+    MultipleDevices,
+    #[snafu(display("corrupt {what}"))]
+    Corrupt { what: &'static str },
+    #[snafu(display("no such file or directory"))]
+    NotFound,
+    #[snafu(display("not a directory"))]
+    NotADirectory,
+    #[snafu(display("not a regular file"))]
+    NotAFile,
+    #[snafu(display("too many levels of symbolic links"))]
+    TooManySymlinks,
+    #[snafu(display("unsupported data layout {layout}"))]
+    UnsupportedLayout { layout: u16 },
+    #[snafu(display("unsupported compression {algorithm}"))]
+    UnsupportedCompression { algorithm: u8 },
+    #[snafu(display("{what} are not supported"))]
+    Unsupported { what: &'static str },
+    #[snafu(display("decompression failed: {source}"))]
+    Decompress { source: DecompressError },
+}
+
+impl From<BioError> for ErofsError {
+    fn from(source: BioError) -> Self {
+        ErofsError::Io { source }
+    }
+}
+
+const MAGIC: u32 = 0xe0f5e1e2;
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 128;
+const BLOCK_SIZE_BITS: u8 = 12;
+const BLOCK_SIZE: u64 = 1 << BLOCK_SIZE_BITS;
+/// Inodes are addressed in slots of this size from the start of the metadata.
+const INODE_SLOT_BITS: u32 = 5;
+const COMPACT_INODE_SIZE: usize = 32;
+const EXTENDED_INODE_SIZE: usize = 64;
+/// `struct erofs_xattr_ibody_header`, which comes before the inline xattrs.
+const XATTR_IBODY_HEADER_SIZE: u64 = 12;
+// Linux allows 40, but nothing we boot from nests symlinks anywhere near that deep.
+const MAX_SYMLINKS: usize = 8;
+/// An address of nothing, e.g. a hole in a chunked file.
+const NULL_ADDR: u32 = 0xffffffff;
+
+const FEATURE_COMPAT_SB_CHKSUM: u32 = 0x1;
+const FEATURE_INCOMPAT_ZERO_PADDING: u32 = 0x1;
+const FEATURE_INCOMPAT_DEVICE_TABLE: u32 = 0x8;
+/// Everything up to long xattr name prefixes. Big pclusters, chunks, inline tails, fragments and
+/// dedupe only matter to files that use them, and those are checked for when they're read.
+const FEATURES_SUPPORTED: u32 = 0x7f;
+
+const LAYOUT_FLAT_PLAIN: u16 = 0;
+const LAYOUT_COMPRESSED_FULL: u16 = 1;
+const LAYOUT_FLAT_INLINE: u16 = 2;
+const LAYOUT_COMPRESSED_COMPACT: u16 = 3;
+const LAYOUT_CHUNK_BASED: u16 = 4;
+
+const CHUNK_FORMAT_BLKBITS_MASK: u16 = 0x1f;
+const CHUNK_FORMAT_INDEXES: u16 = 0x20;
+const CHUNK_INDEX_SIZE: u64 = 8;
+const CHUNK_BLOCK_MAP_SIZE: u64 = 4;
+
+/// `struct z_erofs_map_header`, which compression indexes start with.
+const MAP_HEADER_SIZE: u64 = 8;
+const FULL_INDEX_SIZE: u64 = 8;
+const ADVISE_COMPACTED_2B: u16 = 0x1;
+const ADVISE_BIG_PCLUSTER_1: u16 = 0x2;
+const ADVISE_BIG_PCLUSTER_2: u16 = 0x4;
+const ADVISE_INLINE_PCLUSTER: u16 = 0x8;
+const ADVISE_INTERLACED_PCLUSTER: u16 = 0x10;
+const ADVISE_FRAGMENT_PCLUSTER: u16 = 0x20;
+const FRAGMENT_INODE_BIT: u8 = 0x80;
+
+const LCLUSTER_TYPE_PLAIN: u8 = 0;
+const LCLUSTER_TYPE_HEAD1: u8 = 1;
+const LCLUSTER_TYPE_NONHEAD: u8 = 2;
+const LCLUSTER_TYPE_HEAD2: u8 = 3;
+const LI_PARTIAL_REF: u16 = 1 << 15;
+/// Set in the first delta of the lcluster after a big pcluster's head, which then holds how many
+/// blocks the pcluster is instead.
+const LI_D0_CBLKCNT: u16 = 1 << 11;
+
+const COMPRESSION_LZ4: u8 = 0;
+const COMPRESSION_LZMA: u8 = 1;
+
+const S_IFMT: u16 = 0xf000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFREG: u16 = 0x8000;
+const S_IFLNK: u16 = 0xa000;
+
+const FT_DIR: u8 = 2;
+const DIRENT_SIZE: usize = 12;
+
+const CRC32C: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+pub struct Erofs {
+    dev: RefCell<OpenDevice>,
+    features: u32,
+    root_nid: u64,
+    meta_blkaddr: u64,
+}
+
+#[derive(Clone)]
+struct Inode {
+    format: u16,
+    mode: u16,
+    size: u64,
+    /// Where the data is, or how it's chunked, depending on the layout.
+    info: u32,
+    /// Where whatever comes after the inode and its xattrs starts: the tail of the data or indexes.
+    tail: u64,
+}
+
+impl Inode {
+    fn layout(&self) -> u16 {
+        (self.format >> 1) & 0x7
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+}
+
+impl Erofs {
+    pub fn new(mut dev: OpenDevice) -> Result<Self, ErofsError> {
+        let mut sb = vec![0; BLOCK_SIZE as usize - SUPERBLOCK_OFFSET as usize];
+        dev.read_at(SUPERBLOCK_OFFSET, &mut sb)?;
+        if LittleEndian::read_u32(&sb) != MAGIC {
+            return Err(ErofsError::InvalidSuperblock);
+        }
+        if sb[12] != BLOCK_SIZE_BITS {
+            return Err(ErofsError::UnsupportedBlockSize { bits: sb[12] });
+        }
+        // The checksum covers the rest of the first block, with the checksum itself zeroed.
+        if LittleEndian::read_u32(&sb[8..]) & FEATURE_COMPAT_SB_CHKSUM != 0 {
+            let checksum = LittleEndian::read_u32(&sb[4..]);
+            sb[4..8].fill(0);
+            if !CRC32C.checksum(&sb) != checksum {
+                return Err(ErofsError::InvalidSuperblock);
+            }
+        }
+        let sb = &sb[..SUPERBLOCK_SIZE];
+
+        let features = LittleEndian::read_u32(&sb[80..]);
+        if features & !FEATURES_SUPPORTED != 0 {
+            return Err(ErofsError::UnsupportedFeatures { features: features & !FEATURES_SUPPORTED });
+        }
+        if features & FEATURE_INCOMPAT_DEVICE_TABLE != 0 && LittleEndian::read_u16(&sb[86..]) != 0 {
+            return Err(ErofsError::MultipleDevices);
+        }
+        // Directories made of bigger blocks than data.
+        if sb[90] != 0 {
+            return Err(ErofsError::Unsupported { what: "directory block sizes" });
+        }
+
+        Ok(Self {
+            dev: RefCell::new(dev),
+            features,
+            root_nid: LittleEndian::read_u16(&sb[14..]) as u64,
+            meta_blkaddr: LittleEndian::read_u32(&sb[40..]) as u64,
+        })
+    }
+
+    fn read(&self, pos: u64, len: usize) -> Result<Vec<u8>, ErofsError> {
+        let mut data = vec![0; len];
+        self.dev.borrow_mut().read_at(pos, &mut data)?;
+        Ok(data)
+    }
+
+    fn inode(&self, nid: u64) -> Result<Inode, ErofsError> {
+        let pos = self.meta_blkaddr * BLOCK_SIZE + (nid << INODE_SLOT_BITS);
+        let mut raw = self.read(pos, COMPACT_INODE_SIZE)?;
+        let format = LittleEndian::read_u16(&raw);
+        let (inode_size, size) = if format & 1 != 0 {
+            raw = self.read(pos, EXTENDED_INODE_SIZE)?;
+            (EXTENDED_INODE_SIZE, LittleEndian::read_u64(&raw[8..]))
+        } else {
+            (COMPACT_INODE_SIZE, LittleEndian::read_u32(&raw[8..]) as u64)
+        };
+        let xattr_count = LittleEndian::read_u16(&raw[2..]) as u64;
+        let xattr_size = match xattr_count {
+            0 => 0,
+            count => XATTR_IBODY_HEADER_SIZE + (count - 1) * 4,
+        };
+        Ok(Inode {
+            format,
+            mode: LittleEndian::read_u16(&raw[4..]),
+            size,
+            info: LittleEndian::read_u32(&raw[16..]),
+            tail: pos + inode_size as u64 + xattr_size,
+        })
+    }
+
+    fn file(&self, inode: Inode) -> Result<ErofsFile<'_>, ErofsError> {
+        let data = match inode.layout() {
+            _ if inode.size == 0 => Data::Flat { start: 0, inline: None },
+            LAYOUT_FLAT_PLAIN => Data::Flat { start: inode.info as u64 * BLOCK_SIZE, inline: None },
+            LAYOUT_FLAT_INLINE => {
+                // Only the last block is inline, even if it's a whole one.
+                let blocks = inode.size.div_ceil(BLOCK_SIZE) - 1;
+                if (inode.tail % BLOCK_SIZE) + (inode.size - blocks * BLOCK_SIZE) > BLOCK_SIZE {
+                    return Err(ErofsError::Corrupt { what: "inline data" });
+                }
+                Data::Flat { start: inode.info as u64 * BLOCK_SIZE, inline: Some((blocks * BLOCK_SIZE, inode.tail)) }
+            }
+            LAYOUT_CHUNK_BASED => self.chunks(&inode)?,
+            LAYOUT_COMPRESSED_FULL | LAYOUT_COMPRESSED_COMPACT => self.pclusters(&inode)?,
+            layout => return Err(ErofsError::UnsupportedLayout { layout }),
+        };
+        Ok(ErofsFile {
+            fs: self,
+            size: inode.size,
+            data,
+            cache: None,
+        })
+    }
+
+    /// Read where each chunk of a chunked file is.
+    fn chunks(&self, inode: &Inode) -> Result<Data, ErofsError> {
+        let format = inode.info as u16;
+        let chunk_bits = BLOCK_SIZE_BITS as u32 + (format & CHUNK_FORMAT_BLKBITS_MASK) as u32;
+        if chunk_bits >= 48 {
+            return Err(ErofsError::Corrupt { what: "chunk size" });
+        }
+        let count = inode.size.div_ceil(1 << chunk_bits) as usize;
+        // Either full chunk indexes, of which only the block address matters with a single device,
+        // or just block addresses.
+        let (entry_size, addr_offset) = match format & CHUNK_FORMAT_INDEXES != 0 {
+            true => (CHUNK_INDEX_SIZE, 4),
+            false => (CHUNK_BLOCK_MAP_SIZE, 0),
+        };
+        let raw = self.read(inode.tail.next_multiple_of(entry_size), count * entry_size as usize)?;
+        let chunks = raw.chunks_exact(entry_size as usize)
+            .map(|v| match LittleEndian::read_u32(&v[addr_offset..]) {
+                NULL_ADDR => None,
+                addr => Some(addr as u64 * BLOCK_SIZE),
+            })
+            .collect();
+        Ok(Data::Chunked { chunk_bits, chunks })
+    }
+
+    /// Read the compression indexes of a compressed file, and turn them into where each pcluster
+    /// is and what part of the file it decompresses to.
+    ///
+    /// Files are split into logical clusters (lclusters), which start a new extent of the file
+    /// (head) or continue the one before (nonhead). An extent decompresses from one physical
+    /// cluster (pcluster) of one or more blocks, or is stored as is in a plain one.
+    fn pclusters(&self, inode: &Inode) -> Result<Data, ErofsError> {
+        let header_pos = inode.tail.next_multiple_of(8);
+        let header = self.read(header_pos, MAP_HEADER_SIZE as usize)?;
+        let inline_size = LittleEndian::read_u16(&header[2..]) as u64;
+        let advise = LittleEndian::read_u16(&header[4..]);
+        let algorithms = [header[6] & 0xf, header[6] >> 4];
+        if header[7] & FRAGMENT_INODE_BIT != 0 || advise & ADVISE_FRAGMENT_PCLUSTER != 0 {
+            return Err(ErofsError::Unsupported { what: "fragments" });
+        }
+        let lcluster_bits = BLOCK_SIZE_BITS as u32 + (header[7] & 0x7) as u32;
+        let count = inode.size.div_ceil(1 << lcluster_bits) as usize;
+
+        let (lclusters, index_end) = match inode.layout() {
+            LAYOUT_COMPRESSED_FULL => {
+                // There are 8 reserved bytes after the header.
+                let pos = header_pos + MAP_HEADER_SIZE + 8;
+                let raw = self.read(pos, count * FULL_INDEX_SIZE as usize)?;
+                let lclusters = raw.chunks_exact(FULL_INDEX_SIZE as usize)
+                    .map(|v| full_lcluster(v, lcluster_bits))
+                    .collect::<Result<Vec<_>, _>>()?;
+                (lclusters, pos + count as u64 * FULL_INDEX_SIZE)
+            }
+            _ => self.compact_lclusters(header_pos + MAP_HEADER_SIZE, count, advise, lcluster_bits)?,
+        };
+
+        let mut pclusters: Vec<Pcluster> = Vec::new();
+        for (lcn, lcluster) in lclusters.iter().enumerate() {
+            let &Lcluster::Head { ty, offset, partial, blkaddr } = lcluster else {
+                if lcn == 0 {
+                    return Err(ErofsError::Corrupt { what: "compression index" });
+                }
+                continue;
+            };
+            if partial {
+                return Err(ErofsError::Unsupported { what: "partially referenced pclusters" });
+            }
+
+            // Big pclusters say how many blocks they take in the lcluster after the head, as long
+            // as that's part of the same extent.
+            let big = match ty {
+                LCLUSTER_TYPE_HEAD1 => advise & ADVISE_BIG_PCLUSTER_1 != 0,
+                LCLUSTER_TYPE_HEAD2 => advise & ADVISE_BIG_PCLUSTER_2 != 0,
+                _ => false,
+            };
+            let blocks = match lclusters.get(lcn + 1) {
+                Some(&Lcluster::Nonhead { blocks: Some(blocks), .. }) if big => blocks as u64,
+                Some(Lcluster::Nonhead { .. }) if big => {
+                    return Err(ErofsError::Corrupt { what: "compression index" });
+                }
+                _ => 1 << (lcluster_bits - BLOCK_SIZE_BITS as u32),
+            };
+            let kind = match ty {
+                LCLUSTER_TYPE_PLAIN => Kind::Plain { interlaced: advise & ADVISE_INTERLACED_PCLUSTER != 0 },
+                _ => match algorithms[(ty == LCLUSTER_TYPE_HEAD2) as usize] {
+                    COMPRESSION_LZ4 if self.features & FEATURE_INCOMPAT_ZERO_PADDING == 0 => {
+                        return Err(ErofsError::Unsupported { what: "LZ4 pclusters without zero padding" });
+                    }
+                    COMPRESSION_LZ4 => Kind::Lz4,
+                    COMPRESSION_LZMA => Kind::Lzma,
+                    algorithm => return Err(ErofsError::UnsupportedCompression { algorithm }),
+                },
+            };
+            let start = ((lcn as u64) << lcluster_bits) + offset as u64;
+            if start >= inode.size || pclusters.last().is_some_and(|v| v.start >= start) {
+                return Err(ErofsError::Corrupt { what: "compression index" });
+            }
+            pclusters.push(Pcluster {
+                start,
+                pos: blkaddr as u64 * BLOCK_SIZE,
+                len: blocks * BLOCK_SIZE,
+                kind,
+            });
+        }
+
+        // With tail packing, the last pcluster follows the indexes.
+        if advise & ADVISE_INLINE_PCLUSTER != 0 {
+            let last = pclusters.last_mut().ok_or(ErofsError::Corrupt { what: "compression index" })?;
+            last.pos = index_end;
+            last.len = inline_size;
+        }
+        Ok(Data::Compressed { pclusters })
+    }
+
+    /// Decode a compact compression index, returning the lclusters and where the index ends.
+    ///
+    /// Entries are packed into packs of 2 (4 bytes each) or 16 (2 bytes each), each pack ending with
+    /// the address of its first pcluster. Each entry is an lcluster type and either where in the
+    /// lcluster its extent starts or how far back the head is. The first few entries are 4 bytes, up
+    /// to 32 byte alignment, then as many as fit in 2 byte packs if the file has them, then 4 byte
+    /// ones again. See z_erofs_load_compact_lcluster() in Linux.
+    fn compact_lclusters(&self, start: u64, count: usize, advise: u16, lcluster_bits: u32) -> Result<(Vec<Lcluster>, u64), ErofsError> {
+        let corrupt = || ErofsError::Corrupt { what: "compression index" };
+        if lcluster_bits != BLOCK_SIZE_BITS as u32 {
+            return Err(corrupt());
+        }
+        let big = advise & ADVISE_BIG_PCLUSTER_1 != 0;
+        // The deltas need at least enough bits for LI_D0_CBLKCNT.
+        let lo_bits = lcluster_bits.max(12);
+
+        let initial_4b = ((32 - start % 32) / 4 % 8) as usize;
+        let count_2b = match advise & ADVISE_COMPACTED_2B != 0 && initial_4b < count {
+            true => (count - initial_4b) / 16 * 16,
+            false => 0,
+        };
+        // Where each run of packs starts, and how big its entries are.
+        let runs = [
+            (0, start, 4),
+            (initial_4b, start + initial_4b as u64 * 4, 2),
+            (initial_4b + count_2b, start + initial_4b as u64 * 4 + count_2b as u64 * 2, 4),
+        ];
+        let pack_of = |lcn: usize| {
+            let &(first, pos, entry_size) = runs.iter().rev().find(|&&(first, _, _)| lcn >= first).unwrap();
+            let pack_len = if entry_size == 4 { 2 } else { 16 };
+            let pack = (lcn - first) / pack_len;
+            (pos + (pack * pack_len * entry_size) as u64, pack_len, (lcn - first) % pack_len)
+        };
+
+        let (last_pack, last_len, _) = pack_of(count - 1);
+        let index_end = last_pack + (last_len * if last_len == 2 { 4 } else { 2 }) as u64;
+        let raw = self.read(start, (index_end - start) as usize)?;
+
+        let mut lclusters = Vec::with_capacity(count);
+        for lcn in 0..count {
+            let (pos, vcnt, i) = pack_of(lcn);
+            let pack = &raw[(pos - start) as usize..][..vcnt * if vcnt == 2 { 4 } else { 2 }];
+            let encode_bits = (pack.len() - 4) * 8 / vcnt;
+            let decode = |i: usize| {
+                let bit = encode_bits * i;
+                let v = LittleEndian::read_u32(&pack[bit / 8..]) >> (bit % 8);
+                ((v >> lo_bits) as u8 & 0x3, (v & ((1 << lo_bits) - 1)) as u16)
+            };
+
+            let (ty, lo) = decode(i);
+            if ty == LCLUSTER_TYPE_NONHEAD {
+                // The last entry of a pack holds the distance to the next head instead, which only
+                // matters here if it's also the first after a big pcluster's head.
+                let blocks = (lo & LI_D0_CBLKCNT != 0).then_some(lo & !LI_D0_CBLKCNT);
+                if blocks.is_some() && !big {
+                    return Err(corrupt());
+                }
+                lclusters.push(Lcluster::Nonhead { blocks });
+                continue;
+            }
+
+            // The others follow the address at the end of the pack in order. Without big pclusters,
+            // that's the address of the block before the first.
+            let mut blocks = if big { 0 } else { 1 };
+            let mut j = i as isize;
+            while j > 0 {
+                j -= 1;
+                let (ty, lo) = decode(j as usize);
+                if ty != LCLUSTER_TYPE_NONHEAD {
+                    blocks += 1;
+                } else if !big {
+                    // Jump back to the head, which counts if it's in this pack.
+                    j -= lo as isize;
+                    if j >= 0 {
+                        blocks += 1;
+                    }
+                } else if lo & LI_D0_CBLKCNT != 0 {
+                    // Skip the head, which is right before.
+                    j -= 1;
+                    blocks += (lo & !LI_D0_CBLKCNT) as u32;
+                } else if lo <= 1 {
+                    return Err(corrupt());
+                } else {
+                    // Jump back to the entry after the head, which has the block count.
+                    j -= lo as isize - 2;
+                }
+            }
+            let base = LittleEndian::read_u32(&pack[pack.len() - 4..]);
+            lclusters.push(Lcluster::Head {
+                ty,
+                offset: lo,
+                partial: false,
+                blkaddr: base.wrapping_add(blocks),
+            });
+        }
+        Ok((lclusters, index_end))
+    }
+
+    fn dir_entries(&self, inode: &Inode) -> Result<Vec<(String, u64, u8)>, ErofsError> {
+        if !inode.is_dir() {
+            return Err(ErofsError::NotADirectory);
+        }
+        let mut file = self.file(inode.clone())?;
+        let mut data = vec![0; file.size as usize];
+        file.read(0, &mut data)?;
+
+        // Each block starts with the dirents, then has the names, one after the other.
+        let corrupt = || ErofsError::Corrupt { what: "directory" };
+        let mut entries = Vec::new();
+        for block in data.chunks(BLOCK_SIZE as usize) {
+            let dirents = block.get(8..10).ok_or_else(corrupt)?;
+            let count = LittleEndian::read_u16(dirents) as usize / DIRENT_SIZE;
+            let nameoff = |i: usize| {
+                block.get(i * DIRENT_SIZE + 8..i * DIRENT_SIZE + 10).map(LittleEndian::read_u16).ok_or_else(corrupt)
+            };
+            for i in 0..count {
+                let dirent = block.get(i * DIRENT_SIZE..(i + 1) * DIRENT_SIZE).ok_or_else(corrupt)?;
+                let start = nameoff(i)? as usize;
+                // The last name goes up to the end of the block, or a NUL before that.
+                let end = match i + 1 < count {
+                    true => nameoff(i + 1)? as usize,
+                    false => block.len(),
+                };
+                // Names follow the dirents in order, and can't be empty.
+                if start < count * DIRENT_SIZE || start >= end || end > block.len() {
+                    return Err(corrupt());
+                }
+                let name = &block[start..end];
+                let name = name.split(|&v| v == 0).next().unwrap_or_default();
+                entries.push((String::from_utf8_lossy(name).into_owned(), LittleEndian::read_u64(dirent), dirent[10]));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn symlink_target(&self, inode: Inode) -> Result<String, ErofsError> {
+        let mut file = self.file(inode)?;
+        let mut data = vec![0; file.size as usize];
+        file.read(0, &mut data)?;
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    }
+
+    /// Find the inode at `path`, following symlinks.
+    fn lookup(&self, path: &str) -> Result<Inode, ErofsError> {
+        fn components(path: &str) -> impl DoubleEndedIterator<Item = String> + '_ {
+            path.split('/').filter(|v| !v.is_empty() && *v != ".").map(|v| v.to_string())
+        }
+
+        // Components still to look up, the next one last.
+        let mut remaining = components(path).rev().collect::<Vec<_>>();
+        let mut current = self.inode(self.root_nid)?;
+        let mut symlinks = 0;
+
+        while let Some(name) = remaining.pop() {
+            let (_, nid, _) = self.dir_entries(&current)?
+                .into_iter()
+                .find(|(v, _, _)| *v == name)
+                .ok_or(ErofsError::NotFound)?;
+            let inode = self.inode(nid)?;
+            if !inode.is_symlink() {
+                current = inode;
+                continue;
+            }
+
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(ErofsError::TooManySymlinks);
+            }
+            let target = self.symlink_target(inode)?;
+            remaining.extend(components(&target).rev());
+            if target.starts_with('/') {
+                current = self.inode(self.root_nid)?;
+            }
+        }
+        Ok(current)
+    }
+}
+
+enum Lcluster {
+    Head {
+        ty: u8,
+        /// Where in the lcluster the extent starts, the part before belonging to the one before.
+        offset: u16,
+        /// Whether the extent is only part of what the pcluster decompresses to.
+        partial: bool,
+        blkaddr: u32,
+    },
+    Nonhead {
+        /// How many blocks the pcluster is, if this is the first lcluster after a big one's head.
+        blocks: Option<u16>,
+    },
+}
+
+/// Decode an entry of a full compression index, `struct z_erofs_lcluster_index`.
+fn full_lcluster(raw: &[u8], lcluster_bits: u32) -> Result<Lcluster, ErofsError> {
+    let advise = LittleEndian::read_u16(raw);
+    let ty = (advise & 0x3) as u8;
+    if ty == LCLUSTER_TYPE_NONHEAD {
+        let delta = LittleEndian::read_u16(&raw[4..]);
+        return Ok(Lcluster::Nonhead {
+            blocks: (delta & LI_D0_CBLKCNT != 0).then_some(delta & !LI_D0_CBLKCNT),
+        });
+    }
+    let offset = LittleEndian::read_u16(&raw[2..]);
+    if offset as u32 >= 1 << lcluster_bits {
+        return Err(ErofsError::Corrupt { what: "compression index" });
+    }
+    Ok(Lcluster::Head {
+        ty,
+        offset,
+        partial: advise & LI_PARTIAL_REF != 0,
+        blkaddr: LittleEndian::read_u32(&raw[4..]),
+    })
+}
+
+enum Kind {
+    /// Stored as is, rotated to keep blocks aligned if interlaced.
+    Plain { interlaced: bool },
+    Lz4,
+    Lzma,
+}
+
+struct Pcluster {
+    /// Where in the file the extent it decompresses to starts. It ends where the next one starts.
+    start: u64,
+    /// Where the pcluster is on the device.
+    pos: u64,
+    len: u64,
+    kind: Kind,
+}
+
+enum Data {
+    /// One run of blocks, except for the part from `inline.0` on that's at `inline.1`.
+    Flat { start: u64, inline: Option<(u64, u64)> },
+    /// Where each chunk is, if anywhere.
+    Chunked { chunk_bits: u32, chunks: Vec<Option<u64>> },
+    Compressed { pclusters: Vec<Pcluster> },
+}
+
+pub struct ErofsFile<'a> {
+    fs: &'a Erofs,
+    size: u64,
+    data: Data,
+    /// The last pcluster that was read, decompressed.
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl ErofsFile<'_> {
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), ErofsError> {
+        let end = offset.checked_add(buf.len() as u64).filter(|&v| v <= self.size)
+            .ok_or(ErofsError::Corrupt { what: "file size" })?;
+        let mut dev = self.fs.dev.borrow_mut();
+
+        match &self.data {
+            &Data::Flat { start, inline } => {
+                let inline_start = inline.map_or(end, |(v, _)| v.clamp(offset, end));
+                let (blocks, tail) = buf.split_at_mut((inline_start - offset) as usize);
+                dev.read_at(start + offset, blocks)?;
+                if let Some((from, pos)) = inline {
+                    dev.read_at(pos + (offset.max(from) - from), tail)?;
+                }
+            }
+            &Data::Chunked { chunk_bits, ref chunks } => {
+                let mut offset = offset;
+                let mut buf = buf;
+                while !buf.is_empty() {
+                    let within = offset & ((1 << chunk_bits) - 1);
+                    let len = ((1 << chunk_bits) - within).min(buf.len() as u64) as usize;
+                    let (chunk, rest) = buf.split_at_mut(len);
+                    match chunks.get((offset >> chunk_bits) as usize).copied().flatten() {
+                        Some(pos) => dev.read_at(pos + within, chunk)?,
+                        None => chunk.fill(0),
+                    }
+                    offset += len as u64;
+                    buf = rest;
+                }
+            }
+            Data::Compressed { pclusters } => {
+                // The first pcluster whose extent has any of the range.
+                let first = pclusters.partition_point(|v| v.start <= offset).saturating_sub(1);
+                for (index, pcluster) in pclusters.iter().enumerate().skip(first) {
+                    if pcluster.start >= end {
+                        break;
+                    }
+                    let extent_end = pclusters.get(index + 1).map_or(self.size, |v| v.start);
+                    let from = offset.max(pcluster.start);
+                    let to = end.min(extent_end);
+                    if self.cache.as_ref().is_none_or(|(v, _)| *v != index) {
+                        let mut data = vec![0; pcluster.len as usize];
+                        dev.read_at(pcluster.pos, &mut data)?;
+                        let out = decompress(pcluster, &data, (extent_end - pcluster.start) as usize)?;
+                        self.cache = Some((index, out));
+                    }
+                    let (_, data) = self.cache.as_ref().unwrap();
+                    buf[(from - offset) as usize..(to - offset) as usize]
+                        .copy_from_slice(&data[(from - pcluster.start) as usize..(to - pcluster.start) as usize]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decompress `data`, read from `pcluster`, to the `len` bytes of its extent.
+fn decompress(pcluster: &Pcluster, data: &[u8], len: usize) -> Result<Vec<u8>, ErofsError> {
+    let corrupt = || ErofsError::Corrupt { what: "pcluster" };
+    // Compressed data is padded with zeros at the start to end with the pcluster, and neither LZ4
+    // nor MicroLZMA can start with a zero.
+    let compressed = || &data[data.iter().position(|&v| v != 0).unwrap_or(data.len())..];
+    let mut out = vec![0; len];
+    match pcluster.kind {
+        Kind::Plain { interlaced: false } => out.copy_from_slice(data.get(..len).ok_or_else(corrupt)?),
+        Kind::Plain { interlaced: true } => {
+            // The end of the pcluster is what's before the next block boundary in the file.
+            let head = ((BLOCK_SIZE - pcluster.start % BLOCK_SIZE) as usize).min(len);
+            let from = data.len().checked_sub(head).ok_or_else(corrupt)? % BLOCK_SIZE as usize;
+            out[..head].copy_from_slice(&data[from..from + head]);
+            out[head..].copy_from_slice(data.get(..len - head).ok_or_else(corrupt)?);
+        }
+        Kind::Lz4 => {
+            let n = lz4_flex::block::decompress_into(compressed(), &mut out)
+                .map_err(|_| DecompressError::Lz4)
+                .context(DecompressSnafu)?;
+            if n != len {
+                return Err(corrupt());
+            }
+        }
+        Kind::Lzma => decompress::unmicrolzma(compressed(), &mut out).context(DecompressSnafu)?,
+    }
+    Ok(out)
+}
+
+impl Payload for ErofsFile<'_> {
+    fn len(&self) -> Result<u64, BootError> {
+        Ok(self.size)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BootError> {
+        self.read(offset, buf).map_err(|_| BootError::Io)
+    }
+}
+
+impl BootFs for Erofs {
+    fn open(&self, path: &str) -> Result<Box<dyn Payload + '_>, BootError> {
+        let file = self.lookup(path).and_then(|inode| match inode.is_file() {
+            true => self.file(inode),
+            false => Err(ErofsError::NotAFile),
+        });
+        Ok(Box::new(file.map_err(|_| BootError::Io)?))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, BootError> {
+        let entries = self.lookup(path).and_then(|inode| self.dir_entries(&inode)).map_err(|_| BootError::Io)?;
+        Ok(entries.into_iter()
+            .filter(|(name, _, _)| name != "." && name != "..")
+            .map(|(name, _, ty)| DirEntry { name, is_dir: ty == FT_DIR })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    // Python's raw LZMA1 `lzma.compress()` of `text("erofs", LZMA_SIZE)`, with lc=3, lp=0, pb=2, and
+    // the first byte replaced by the inverted properties to make it MicroLZMA.
+    const LZMA_EXTENT: &[u8] = include_bytes!("../testdata/erofs-extent.microlzma");
+
+    const FT_REG_FILE: u8 = 1;
+    const FT_SYMLINK: u8 = 7;
+    const FEATURE_INCOMPAT_BIG_PCLUSTER: u32 = 0x2;
+    const FEATURE_INCOMPAT_CHUNKED_FILE: u32 = 0x4;
+
+    const META_BLKADDR: u64 = 1;
+    const IMAGE_BLOCKS: usize = 72;
+
+    // Each inode gets a block of its own, the index into the metadata blocks.
+    const ROOT: u64 = 0;
+    const PLAIN: u64 = 1;
+    const INLINE: u64 = 2;
+    const CHUNKED: u64 = 3;
+    const LINK: u64 = 4;
+    const FULL: u64 = 5;
+    const COMPACT: u64 = 6;
+    const BIG: u64 = 7;
+    const LZMA: u64 = 8;
+
+    const PLAIN_SIZE: usize = 2 * BLOCK_SIZE as usize + 100;
+    const INLINE_SIZE: usize = BLOCK_SIZE as usize + 300;
+    const CHUNKED_SIZE: usize = 3 * BLOCK_SIZE as usize - 10;
+    const FULL_SIZE: usize = 2 * BLOCK_SIZE as usize + 500 + 3000;
+    const COMPACT_SIZE: usize = 24 * BLOCK_SIZE as usize - 500;
+    const BIG_SIZE: usize = 6 * BLOCK_SIZE as usize - 100;
+    const LZMA_SIZE: usize = 8000;
+
+    const PLAIN_ADDR: u32 = 20;
+    const INLINE_ADDR: u32 = 23;
+    const CHUNK_ADDRS: [u32; 3] = [24, NULL_ADDR, 25];
+    const FULL_ADDR: u32 = 30;
+    const LZMA_ADDR: u32 = 35;
+    const COMPACT_ADDR: u32 = 40;
+    const BIG_ADDR: u32 = 60;
+
+    /// Where the extents of /lz4-compact start, as the lcluster and the offset in it.
+    const COMPACT_HEADS: [(usize, u16); 17] = [
+        (0, 0), (1, 0), (3, 0), (4, 100), (5, 0), (8, 0), (9, 0), (11, 0), (12, 2000), (13, 0),
+        (14, 0), (16, 0), (17, 0), (19, 0), (20, 0), (22, 300), (23, 0),
+    ];
+
+    fn nid(index: u64) -> u64 {
+        index << (BLOCK_SIZE_BITS as u32 - INODE_SLOT_BITS)
+    }
+
+    /// Where a compact index starts, after the inode and the map header.
+    fn index_start(index: u64) -> u64 {
+        META_BLKADDR * BLOCK_SIZE + (nid(index) << INODE_SLOT_BITS) + COMPACT_INODE_SIZE as u64 + MAP_HEADER_SIZE
+    }
+
+    fn block(image: &mut [u8], addr: u64) -> &mut [u8] {
+        &mut image[(addr * BLOCK_SIZE) as usize..][..BLOCK_SIZE as usize]
+    }
+
+    /// Lines of `word`s, each starting with its number.
+    fn text(word: &str, len: usize) -> Vec<u8> {
+        let line = format!("{word} ").repeat(9);
+        (0..).flat_map(|i| format!("{i:06} {line}\n").into_bytes()).take(len).collect()
+    }
+
+    /// Write a compact inode, returning its block to put the tail in after the first 32 bytes.
+    fn inode(image: &mut [u8], index: u64, layout: u16, mode: u16, size: usize, info: u32) -> &mut [u8] {
+        let block = block(image, META_BLKADDR + index);
+        LittleEndian::write_u16(block, layout << 1);
+        LittleEndian::write_u16(&mut block[4..], mode);
+        LittleEndian::write_u32(&mut block[8..], size as u32);
+        LittleEndian::write_u32(&mut block[16..], info);
+        block
+    }
+
+    fn dir(entries: &[(&str, u64, u8)]) -> Vec<u8> {
+        let mut data = vec![0; entries.len() * DIRENT_SIZE];
+        for (i, &(name, index, ty)) in entries.iter().enumerate() {
+            let nameoff = data.len() as u16;
+            let dirent = &mut data[i * DIRENT_SIZE..];
+            LittleEndian::write_u64(dirent, nid(index));
+            LittleEndian::write_u16(&mut dirent[8..], nameoff);
+            dirent[10] = ty;
+            data.extend_from_slice(name.as_bytes());
+        }
+        data
+    }
+
+    /// Write `z_erofs_map_header`, with both heads using `algorithm`.
+    fn map_header(tail: &mut [u8], advise: u16, algorithm: u8) {
+        LittleEndian::write_u16(&mut tail[4..], advise);
+        tail[6] = algorithm | algorithm << 4;
+    }
+
+    /// A full index entry, with the block address of a head or the first delta of a nonhead.
+    fn full_entry(ty: u8, offset: u16, value: u32) -> [u8; 8] {
+        let mut entry = [0; 8];
+        LittleEndian::write_u16(&mut entry, ty as u16);
+        LittleEndian::write_u16(&mut entry[2..], offset);
+        LittleEndian::write_u32(&mut entry[4..], value);
+        entry
+    }
+
+    /// The compact index entries of a file without big pclusters, as the type and what goes in the
+    /// rest of the entry, both if it's not and if it is the last of a pack.
+    fn compact_entries(count: usize, heads: &[(usize, u16)]) -> Vec<(u8, u16, u16)> {
+        (0..count).map(|lcn| {
+            // The head of this extent, and the one after.
+            let next = heads.iter().position(|&(v, _)| v > lcn).unwrap_or(heads.len());
+            let next_lcn = heads.get(next).map_or(count, |&(v, _)| v);
+            match heads[next - 1] {
+                (v, offset) if v == lcn => (LCLUSTER_TYPE_HEAD1, offset, offset),
+                (v, _) => (LCLUSTER_TYPE_NONHEAD, (lcn - v) as u16, (next_lcn - lcn) as u16),
+            }
+        }).collect()
+    }
+
+    /// Lay out a compact index starting at `start` the way mkfs.erofs does, with the address at the
+    /// end of each pack from `bases`.
+    fn compact_index(start: u64, entries: &[(u8, u16, u16)], compacted_2b: bool, bases: &[u32]) -> Vec<u8> {
+        let initial_4b = ((32 - start % 32) / 4 % 8) as usize;
+        let count_2b = match compacted_2b && initial_4b < entries.len() {
+            true => (entries.len() - initial_4b) / 16 * 16,
+            false => 0,
+        };
+        let mut index = Vec::new();
+        let mut first = 0;
+        for &base in bases {
+            let (vcnt, entry_size) = match first >= initial_4b && first < initial_4b + count_2b {
+                true => (16, 2),
+                false => (2, 4),
+            };
+            // Room to write the last entry's bits as a u32.
+            let mut pack = vec![0; vcnt * entry_size + 3];
+            let encode_bits = (vcnt * entry_size * 8 - 32) / vcnt;
+            for i in 0..vcnt.min(entries.len().saturating_sub(first)) {
+                let (ty, lo, last_lo) = entries[first + i];
+                let lo = if i == vcnt - 1 { last_lo } else { lo };
+                let bit = encode_bits * i;
+                let v = LittleEndian::read_u32(&pack[bit / 8..]) | ((ty as u32) << 12 | lo as u32) << (bit % 8);
+                LittleEndian::write_u32(&mut pack[bit / 8..], v);
+            }
+            pack.truncate(vcnt * entry_size);
+            let len = pack.len();
+            LittleEndian::write_u32(&mut pack[len - 4..], base);
+            index.extend_from_slice(&pack);
+            first += vcnt;
+        }
+        assert!(first >= entries.len());
+        index
+    }
+
+    /// Compress `data` into the pcluster of `blocks` blocks at `addr`, zero padded at the start.
+    fn write_lz4(image: &mut [u8], addr: u32, blocks: usize, data: &[u8]) {
+        let compressed = lz4_flex::block::compress(data);
+        let end = (addr as usize + blocks) * BLOCK_SIZE as usize;
+        assert!(compressed.len() <= blocks * BLOCK_SIZE as usize);
+        image[end - compressed.len()..end].copy_from_slice(&compressed);
+    }
+
+    fn write_checksum(image: &mut [u8]) {
+        let sb = &mut image[SUPERBLOCK_OFFSET as usize..BLOCK_SIZE as usize];
+        sb[4..8].fill(0);
+        let checksum = !CRC32C.checksum(sb);
+        LittleEndian::write_u32(&mut sb[4..], checksum);
+    }
+
+    fn chunked() -> Vec<u8> {
+        let mut data = text("chunk", CHUNKED_SIZE);
+        data[BLOCK_SIZE as usize..2 * BLOCK_SIZE as usize].fill(0);
+        data
+    }
+
+    /// A filesystem with a checksummed superblock and, in its inline root directory:
+    /// - /plain, with an extended inode
+    /// - /inline, with its last 300 bytes inline after some xattrs
+    /// - /chunked, in 3 one block chunks with the second a hole
+    /// - /link, a symlink to plain
+    /// - /lz4-full, with a full index: an LZ4 extent in a big pcluster, then a plain one
+    /// - /lz4-compact, with a compact index of 4 and 2 byte packs
+    /// - /lz4-big, with a compact index of big pclusters
+    /// - /lzma, with a full index and one MicroLZMA extent
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; IMAGE_BLOCKS * BLOCK_SIZE as usize];
+
+        let sb = &mut image[SUPERBLOCK_OFFSET as usize..];
+        LittleEndian::write_u32(sb, MAGIC);
+        LittleEndian::write_u32(&mut sb[8..], FEATURE_COMPAT_SB_CHKSUM);
+        sb[12] = BLOCK_SIZE_BITS;
+        LittleEndian::write_u16(&mut sb[14..], nid(ROOT) as u16);
+        LittleEndian::write_u32(&mut sb[40..], META_BLKADDR as u32);
+        LittleEndian::write_u32(&mut sb[80..], FEATURE_INCOMPAT_ZERO_PADDING | FEATURE_INCOMPAT_BIG_PCLUSTER | FEATURE_INCOMPAT_CHUNKED_FILE);
+        write_checksum(&mut image);
+
+        let root = dir(&[
+            (".", ROOT, FT_DIR),
+            ("..", ROOT, FT_DIR),
+            ("chunked", CHUNKED, FT_REG_FILE),
+            ("inline", INLINE, FT_REG_FILE),
+            ("link", LINK, FT_SYMLINK),
+            ("lz4-big", BIG, FT_REG_FILE),
+            ("lz4-compact", COMPACT, FT_REG_FILE),
+            ("lz4-full", FULL, FT_REG_FILE),
+            ("lzma", LZMA, FT_REG_FILE),
+            ("plain", PLAIN, FT_REG_FILE),
+        ]);
+        inode(&mut image, ROOT, LAYOUT_FLAT_INLINE, S_IFDIR | 0o755, root.len(), 0)[32..][..root.len()].copy_from_slice(&root);
+
+        // An extended inode has a 64 bit size at the same place.
+        let plain = inode(&mut image, PLAIN, LAYOUT_FLAT_PLAIN, S_IFREG | 0o644, 0, PLAIN_ADDR);
+        plain[0] |= 1;
+        LittleEndian::write_u64(&mut plain[8..], PLAIN_SIZE as u64);
+        let offset = PLAIN_ADDR as usize * BLOCK_SIZE as usize;
+        image[offset..][..PLAIN_SIZE].copy_from_slice(&text("plain", PLAIN_SIZE));
+
+        // Two xattr entries take the header and a word.
+        let data = text("inline", INLINE_SIZE);
+        let inline = inode(&mut image, INLINE, LAYOUT_FLAT_INLINE, S_IFREG | 0o644, INLINE_SIZE, INLINE_ADDR);
+        LittleEndian::write_u16(&mut inline[2..], 2);
+        inline[48..][..300].copy_from_slice(&data[BLOCK_SIZE as usize..]);
+        block(&mut image, INLINE_ADDR as u64).copy_from_slice(&data[..BLOCK_SIZE as usize]);
+
+        let data = chunked();
+        let chunks = inode(&mut image, CHUNKED, LAYOUT_CHUNK_BASED, S_IFREG | 0o644, CHUNKED_SIZE, CHUNK_FORMAT_INDEXES as u32);
+        for (i, addr) in CHUNK_ADDRS.into_iter().enumerate() {
+            LittleEndian::write_u32(&mut chunks[32 + i * CHUNK_INDEX_SIZE as usize + 4..], addr);
+        }
+        for (chunk, addr) in data.chunks(BLOCK_SIZE as usize).zip(CHUNK_ADDRS) {
+            if addr != NULL_ADDR {
+                block(&mut image, addr as u64)[..chunk.len()].copy_from_slice(chunk);
+            }
+        }
+
+        inode(&mut image, LINK, LAYOUT_FLAT_INLINE, S_IFLNK | 0o777, 5, 0)[32..37].copy_from_slice(b"plain");
+
+        // The big pcluster's extent ends 500 bytes into the third lcluster.
+        let data = text("full", FULL_SIZE);
+        let full = inode(&mut image, FULL, LAYOUT_COMPRESSED_FULL, S_IFREG | 0o644, FULL_SIZE, 3);
+        map_header(&mut full[32..], ADVISE_BIG_PCLUSTER_1, COMPRESSION_LZ4);
+        full[48..56].copy_from_slice(&full_entry(LCLUSTER_TYPE_HEAD1, 0, FULL_ADDR));
+        full[56..64].copy_from_slice(&full_entry(LCLUSTER_TYPE_NONHEAD, 0, (LI_D0_CBLKCNT | 2) as u32));
+        full[64..72].copy_from_slice(&full_entry(LCLUSTER_TYPE_PLAIN, 500, FULL_ADDR + 2));
+        let split = 2 * BLOCK_SIZE as usize + 500;
+        write_lz4(&mut image, FULL_ADDR, 2, &data[..split]);
+        block(&mut image, FULL_ADDR as u64 + 2)[..FULL_SIZE - split].copy_from_slice(&data[split..]);
+
+        // Each extent has a pcluster of one block, in order. Each pack's address is the block before
+        // the first one that starts in it.
+        let data = text("compact", COMPACT_SIZE);
+        let entries = compact_entries(24, &COMPACT_HEADS);
+        let bases = [0, 2, 4, 6, 22].map(|first| {
+            COMPACT_ADDR + COMPACT_HEADS.iter().position(|&(v, _)| v >= first).unwrap() as u32 - 1
+        });
+        let compact = inode(&mut image, COMPACT, LAYOUT_COMPRESSED_COMPACT, S_IFREG | 0o644, COMPACT_SIZE, 17);
+        map_header(&mut compact[32..], ADVISE_COMPACTED_2B, COMPRESSION_LZ4);
+        let index = compact_index(index_start(COMPACT), &entries, true, &bases);
+        compact[40..][..index.len()].copy_from_slice(&index);
+        for (i, &(lcn, offset)) in COMPACT_HEADS.iter().enumerate() {
+            let start = lcn * BLOCK_SIZE as usize + offset as usize;
+            let end = COMPACT_HEADS.get(i + 1).map_or(COMPACT_SIZE, |&(lcn, offset)| lcn * BLOCK_SIZE as usize + offset as usize);
+            write_lz4(&mut image, COMPACT_ADDR + i as u32, 1, &data[start..end]);
+        }
+
+        // Extents of 2, 1 and 3 blocks, starting at lclusters 0, 2 and 3. Each pack's address is
+        // that of the first pcluster it has a block count or head of.
+        let data = text("big", BIG_SIZE);
+        let entries = [
+            (LCLUSTER_TYPE_HEAD1, 0, 0),
+            (LCLUSTER_TYPE_NONHEAD, LI_D0_CBLKCNT | 2, LI_D0_CBLKCNT | 2),
+            (LCLUSTER_TYPE_HEAD1, 0, 0),
+            (LCLUSTER_TYPE_HEAD1, 0, 0),
+            (LCLUSTER_TYPE_NONHEAD, LI_D0_CBLKCNT | 3, LI_D0_CBLKCNT | 3),
+            (LCLUSTER_TYPE_NONHEAD, 2, 1),
+        ];
+        let big = inode(&mut image, BIG, LAYOUT_COMPRESSED_COMPACT, S_IFREG | 0o644, BIG_SIZE, 6);
+        map_header(&mut big[32..], ADVISE_BIG_PCLUSTER_1, COMPRESSION_LZ4);
+        let index = compact_index(index_start(BIG), &entries, false, &[BIG_ADDR, BIG_ADDR + 2, BIG_ADDR + 3]);
+        big[40..][..index.len()].copy_from_slice(&index);
+        let lcluster = BLOCK_SIZE as usize;
+        write_lz4(&mut image, BIG_ADDR, 2, &data[..2 * lcluster]);
+        write_lz4(&mut image, BIG_ADDR + 2, 1, &data[2 * lcluster..3 * lcluster]);
+        write_lz4(&mut image, BIG_ADDR + 3, 3, &data[3 * lcluster..]);
+
+        let lzma = inode(&mut image, LZMA, LAYOUT_COMPRESSED_FULL, S_IFREG | 0o644, LZMA_SIZE, 1);
+        map_header(&mut lzma[32..], 0, COMPRESSION_LZMA);
+        lzma[48..56].copy_from_slice(&full_entry(LCLUSTER_TYPE_HEAD1, 0, LZMA_ADDR));
+        lzma[56..64].copy_from_slice(&full_entry(LCLUSTER_TYPE_NONHEAD, 0, 1));
+        let end = (LZMA_ADDR as usize + 1) * BLOCK_SIZE as usize;
+        image[end - LZMA_EXTENT.len()..end].copy_from_slice(LZMA_EXTENT);
+        image
+    }
+
+    fn open(image: Vec<u8>) -> Result<Erofs, ErofsError> {
+        let size = image.len() as u64;
+        Erofs::new(OpenDevice::from_image(Box::new(image), size))
+    }
+
+    fn read(fs: &Erofs, path: &str) -> Vec<u8> {
+        BootFs::read(fs, path).unwrap()
+    }
+
+    fn read_at(fs: &Erofs, path: &str, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        fs.open(path).unwrap().read_at(offset, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn superblock() {
+        assert!(open(image()).is_ok());
+
+        let mut corrupt = image();
+        corrupt[SUPERBLOCK_OFFSET as usize + 200] = 1;
+        assert!(matches!(open(corrupt), Err(ErofsError::InvalidSuperblock)));
+
+        let mut image = image();
+        image[SUPERBLOCK_OFFSET as usize + 80] |= 0x80;
+        write_checksum(&mut image);
+        assert!(matches!(open(image), Err(ErofsError::UnsupportedFeatures { features: 0x80 })));
+    }
+
+    #[test]
+    fn flat_files() {
+        let fs = open(image()).unwrap();
+        let root = fs.read_dir("/").unwrap().into_iter().map(|v| v.name).collect::<Vec<_>>();
+        assert_eq!(root, ["chunked", "inline", "link", "lz4-big", "lz4-compact", "lz4-full", "lzma", "plain"]);
+
+        assert!(read(&fs, "/plain") == text("plain", PLAIN_SIZE));
+        assert!(read(&fs, "/link") == text("plain", PLAIN_SIZE));
+        assert!(read(&fs, "/inline") == text("inline", INLINE_SIZE));
+        // Across the end of the last block and the inline tail.
+        assert!(read_at(&fs, "/inline", BLOCK_SIZE - 10, 20) == text("inline", INLINE_SIZE)[BLOCK_SIZE as usize - 10..][..20]);
+        assert!(read(&fs, "/chunked") == chunked());
+        assert!(read_at(&fs, "/chunked", BLOCK_SIZE - 10, 20) == chunked()[BLOCK_SIZE as usize - 10..][..20]);
+    }
+
+    #[test]
+    fn full_index() {
+        let fs = open(image()).unwrap();
+        let data = text("full", FULL_SIZE);
+        assert!(read(&fs, "/lz4-full") == data);
+        // Across the end of the big pcluster's extent and the plain one.
+        let split = 2 * BLOCK_SIZE as usize + 500;
+        assert!(read_at(&fs, "/lz4-full", split as u64 - 10, 20) == data[split - 10..][..20]);
+
+        // LZ4 can only be found in a pcluster if it's padded at the start.
+        let mut image = image();
+        image[SUPERBLOCK_OFFSET as usize + 80] &= !FEATURE_INCOMPAT_ZERO_PADDING as u8;
+        write_checksum(&mut image);
+        let fs = open(image).unwrap();
+        assert!(fs.open("/lz4-full").is_err());
+        assert!(read(&fs, "/plain") == text("plain", PLAIN_SIZE));
+    }
+
+    #[test]
+    fn compact_index_packs() {
+        let fs = open(image()).unwrap();
+        let start = index_start(COMPACT);
+        let (lclusters, end) = fs.compact_lclusters(start, 24, ADVISE_COMPACTED_2B, 12).unwrap();
+        // 3 packs of 4 byte entries up to 32 byte alignment, one of 2 byte entries, then another 4
+        // byte one.
+        assert_eq!(end, start + 3 * 8 + 32 + 8);
+        let heads = lclusters.iter().enumerate()
+            .filter_map(|(lcn, v)| match *v {
+                Lcluster::Head { ty, offset, blkaddr, .. } => Some((lcn, ty, offset, blkaddr)),
+                Lcluster::Nonhead { blocks } => {
+                    assert_eq!(blocks, None);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        let expected = COMPACT_HEADS.iter().enumerate()
+            .map(|(i, &(lcn, offset))| (lcn, LCLUSTER_TYPE_HEAD1, offset, COMPACT_ADDR + i as u32))
+            .collect::<Vec<_>>();
+        assert_eq!(heads, expected);
+
+        let data = text("compact", COMPACT_SIZE);
+        assert!(read(&fs, "/lz4-compact") == data);
+        // Across three extents.
+        let offset = 11 * BLOCK_SIZE as usize + 100;
+        assert!(read_at(&fs, "/lz4-compact", offset as u64, 2 * BLOCK_SIZE as usize) == data[offset..][..2 * BLOCK_SIZE as usize]);
+    }
+
+    #[test]
+    fn big_pclusters() {
+        let fs = open(image()).unwrap();
+        let start = index_start(BIG);
+        let (lclusters, _) = fs.compact_lclusters(start, 6, ADVISE_BIG_PCLUSTER_1, 12).unwrap();
+        let blkaddrs = lclusters.iter()
+            .filter_map(|v| match *v {
+                Lcluster::Head { blkaddr, .. } => Some(blkaddr),
+                Lcluster::Nonhead { .. } => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(blkaddrs, [BIG_ADDR, BIG_ADDR + 2, BIG_ADDR + 3]);
+        assert!(read(&fs, "/lz4-big") == text("big", BIG_SIZE));
+
+        // A block count is only valid after the head of a big pcluster.
+        assert!(matches!(fs.compact_lclusters(start, 6, 0, 12), Err(ErofsError::Corrupt { .. })));
+    }
+
+    #[test]
+    fn lzma() {
+        let fs = open(image()).unwrap();
+        assert!(read(&fs, "/lzma") == text("erofs", LZMA_SIZE));
+    }
+}
@@ -0,0 +1,568 @@
+//! Read-only exFAT driver, for SD cards that are too large to come formatted as FAT32.
+//!
+//! Names are compared case-insensitively using Unicode simple uppercase mapping instead of the
+//! volume's up-case table. The two only differ for names nobody puts on a boot partition.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::cell::RefCell;
+use snafu::Snafu;
+use crate::bio::{BioError, OpenDevice};
+use crate::fs::{BootFs, DirEntry};
+use crate::kernel_boot::{BootError, Payload};
+
+#[derive(Debug, Snafu)]
+pub enum ExFatError {
+    #[snafu(display("I/O error: {source}"))]
+    Io { source: BioError },
+    #[snafu(display("invalid boot sector"))]
+    InvalidBootSector,
+    #[snafu(display("corrupt {what}"))]
+    Corrupt { what: &'static str },
+    #[snafu(display("no such file or directory"))]
+    NotFound,
+    #[snafu(display("not a directory"))]
+    NotADirectory,
+    #[snafu(display("not a regular file"))]
+    NotAFile,
+}
+
+impl From<BioError> for ExFatError {
+    fn from(source: BioError) -> Self {
+        ExFatError::Io { source }
+    }
+}
+
+const FS_NAME: &[u8; 8] = b"EXFAT   ";
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+// The main boot region is 11 sectors, followed by a sector full of their checksum.
+const BOOT_REGION_SECTORS: usize = 11;
+const REVISION_MAJOR: u8 = 1;
+const VOLUME_FLAG_ACTIVE_FAT: u16 = 0x1;
+// Clusters can be at most 32MB.
+const CLUSTER_SHIFT_MAX: u8 = 25;
+
+const FIRST_CLUSTER: u32 = 2;
+const FAT_END_OF_CHAIN: u32 = 0xffffffff;
+// How much of the FAT is read at a time when following a cluster chain.
+const FAT_CHUNK_SIZE: u64 = 4096;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_END_OF_DIRECTORY: u8 = 0x00;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM_EXTENSION: u8 = 0xc0;
+const ENTRY_FILE_NAME: u8 = 0xc1;
+const NAME_CHARS_PER_ENTRY: usize = 15;
+const ATTR_DIRECTORY: u16 = 0x10;
+const STREAM_FLAG_NO_FAT_CHAIN: u8 = 0x2;
+
+pub struct ExFat {
+    dev: RefCell<OpenDevice>,
+    /// Byte offset of the active FAT.
+    fat_offset: u64,
+    fat_size: u64,
+    /// Byte offset of the first cluster.
+    heap_offset: u64,
+    cluster_shift: u32,
+    cluster_count: u32,
+    root_cluster: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Node {
+    is_dir: bool,
+    first_cluster: u32,
+    /// `None` for the root directory, which is as long as its cluster chain.
+    size: Option<u64>,
+    /// Anything between this and `size` reads as zeroes.
+    valid_size: u64,
+    /// The clusters are allocated in one go and not recorded in the FAT.
+    contiguous: bool,
+}
+
+impl ExFat {
+    pub fn new(mut dev: OpenDevice) -> Result<Self, ExFatError> {
+        let mut sector = vec![0; 512];
+        dev.read_at(0, &mut sector)?;
+        if &sector[3..11] != FS_NAME || sector[510..512] != BOOT_SIGNATURE || sector[11..64].iter().any(|&v| v != 0) {
+            return Err(ExFatError::InvalidBootSector);
+        }
+
+        let sector_shift = sector[108];
+        let cluster_shift = sector[109];
+        let fats = sector[110];
+        if !(9..=12).contains(&sector_shift) || sector_shift + cluster_shift > CLUSTER_SHIFT_MAX
+            || !(1..=2).contains(&fats) || sector[105] != REVISION_MAJOR {
+            return Err(ExFatError::InvalidBootSector);
+        }
+
+        let sector_size = 1 << sector_shift;
+        let mut boot_region = vec![0; (BOOT_REGION_SECTORS + 1) * sector_size];
+        dev.read_at(0, &mut boot_region)?;
+        let (boot_region, checksums) = boot_region.split_at(BOOT_REGION_SECTORS * sector_size);
+        if LittleEndian::read_u32(checksums) != boot_checksum(boot_region) {
+            return Err(ExFatError::Corrupt { what: "boot region checksum" });
+        }
+
+        let fat_size = (LittleEndian::read_u32(&sector[84..]) as u64) << sector_shift;
+        let mut fat_offset = (LittleEndian::read_u32(&sector[80..]) as u64) << sector_shift;
+        // With two FATs, the volume flags say which one is in use.
+        if fats == 2 && LittleEndian::read_u16(&sector[106..]) & VOLUME_FLAG_ACTIVE_FAT != 0 {
+            fat_offset += fat_size;
+        }
+
+        let cluster_count = LittleEndian::read_u32(&sector[92..]);
+        let root_cluster = LittleEndian::read_u32(&sector[96..]);
+        if fat_size < (cluster_count as u64 + FIRST_CLUSTER as u64) * 4
+            || root_cluster < FIRST_CLUSTER || root_cluster - FIRST_CLUSTER >= cluster_count {
+            return Err(ExFatError::InvalidBootSector);
+        }
+
+        Ok(Self {
+            dev: RefCell::new(dev),
+            fat_offset,
+            fat_size,
+            heap_offset: (LittleEndian::read_u32(&sector[88..]) as u64) << sector_shift,
+            cluster_shift: (sector_shift + cluster_shift) as u32,
+            cluster_count,
+            root_cluster,
+        })
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), ExFatError> {
+        Ok(self.dev.borrow_mut().read_at(offset, buf)?)
+    }
+
+    fn root(&self) -> Node {
+        Node {
+            is_dir: true,
+            first_cluster: self.root_cluster,
+            size: None,
+            valid_size: 0,
+            contiguous: false,
+        }
+    }
+
+    fn fat_entry(&self, cluster: u32, cache: &mut Option<(u64, Vec<u8>)>) -> Result<u32, ExFatError> {
+        let offset = cluster as u64 * 4;
+        let chunk = offset / FAT_CHUNK_SIZE * FAT_CHUNK_SIZE;
+        if cache.as_ref().map(|(v, _)| *v) != Some(chunk) {
+            let mut data = vec![0; FAT_CHUNK_SIZE.min(self.fat_size - chunk) as usize];
+            self.read(self.fat_offset + chunk, &mut data)?;
+            *cache = Some((chunk, data));
+        }
+        let (_, data) = cache.as_ref().unwrap();
+        Ok(LittleEndian::read_u32(&data[(offset - chunk) as usize..]))
+    }
+
+    /// The data of `node` as (byte offset, length) runs on the device.
+    fn runs(&self, node: &Node) -> Result<Vec<(u64, u64)>, ExFatError> {
+        let corrupt = || ExFatError::Corrupt { what: "cluster chain" };
+        let cluster_size = 1 << self.cluster_shift;
+        let needed = node.size.map(|v| v.div_ceil(cluster_size));
+        let in_heap = |cluster: u32, count: u64| cluster >= FIRST_CLUSTER
+            && (cluster - FIRST_CLUSTER) as u64 + count <= self.cluster_count as u64;
+
+        // Runs of (first cluster, cluster count).
+        let mut clusters: Vec<(u32, u64)> = Vec::new();
+        if needed == Some(0) {
+            // Empty files may or may not have a cluster allocated.
+        } else if node.first_cluster == 0 {
+            return Err(corrupt());
+        } else if node.contiguous {
+            let count = needed.ok_or_else(corrupt)?;
+            if !in_heap(node.first_cluster, count) {
+                return Err(corrupt());
+            }
+            clusters.push((node.first_cluster, count));
+        } else {
+            let mut cache = None;
+            let mut cluster = node.first_cluster;
+            let mut total = 0;
+            loop {
+                if !in_heap(cluster, 1) || total >= self.cluster_count as u64 {
+                    return Err(corrupt());
+                }
+                match clusters.last_mut() {
+                    Some((first, count)) if *first as u64 + *count == cluster as u64 => *count += 1,
+                    _ => clusters.push((cluster, 1)),
+                }
+                total += 1;
+                if Some(total) == needed {
+                    break;
+                }
+                cluster = self.fat_entry(cluster, &mut cache)?;
+                if cluster == FAT_END_OF_CHAIN {
+                    if needed.is_some() {
+                        return Err(corrupt());
+                    }
+                    break;
+                }
+            }
+        }
+
+        Ok(clusters.into_iter()
+            .map(|(first, count)| (
+                self.heap_offset + (((first - FIRST_CLUSTER) as u64) << self.cluster_shift),
+                count << self.cluster_shift,
+            ))
+            .collect())
+    }
+
+    fn file(&self, node: &Node) -> Result<ExFatFile<'_>, ExFatError> {
+        Ok(ExFatFile {
+            fs: self,
+            runs: self.runs(node)?,
+            size: node.size.unwrap_or(0),
+            valid_size: node.valid_size,
+        })
+    }
+
+    fn dir_entries(&self, dir: &Node) -> Result<Vec<(String, Node)>, ExFatError> {
+        if !dir.is_dir {
+            return Err(ExFatError::NotADirectory);
+        }
+        let mut data = Vec::new();
+        for (offset, len) in self.runs(dir)? {
+            let start = data.len();
+            data.resize(start + len as usize, 0);
+            self.read(offset, &mut data[start..])?;
+        }
+        if let Some(size) = dir.size {
+            data.truncate(size as usize);
+        }
+
+        let mut entries = Vec::new();
+        let mut pos = 0;
+        while pos + ENTRY_SIZE <= data.len() {
+            match data[pos] {
+                ENTRY_END_OF_DIRECTORY => break,
+                ENTRY_FILE => {
+                    let len = (1 + data[pos + 1] as usize) * ENTRY_SIZE;
+                    let set = data.get(pos..pos + len).ok_or(ExFatError::Corrupt { what: "directory entry" })?;
+                    // A set with a bad checksum is skipped rather than failing the whole directory.
+                    if let Some(entry) = parse_file(set) {
+                        entries.push(entry);
+                    }
+                    pos += len;
+                }
+                // Deleted entries, the allocation bitmap, up-case table, volume label and so on.
+                _ => pos += ENTRY_SIZE,
+            }
+        }
+        Ok(entries)
+    }
+
+    fn lookup(&self, path: &str) -> Result<Node, ExFatError> {
+        let mut node = self.root();
+        for name in path.split('/').filter(|v| !v.is_empty() && *v != ".") {
+            node = self.dir_entries(&node)?
+                .into_iter()
+                .find(|(v, _)| v.chars().flat_map(char::to_uppercase).eq(name.chars().flat_map(char::to_uppercase)))
+                .map(|(_, v)| v)
+                .ok_or(ExFatError::NotFound)?;
+        }
+        Ok(node)
+    }
+}
+
+fn boot_checksum(region: &[u8]) -> u32 {
+    region.iter()
+        .enumerate()
+        // VolumeFlags and PercentInUse change without the checksum being updated.
+        .filter(|(i, _)| !matches!(i, 106 | 107 | 112))
+        .fold(0u32, |sum, (_, &v)| sum.rotate_right(1).wrapping_add(v as u32))
+}
+
+/// Parse a file directory entry set: the file entry, its stream extension and its name entries.
+fn parse_file(set: &[u8]) -> Option<(String, Node)> {
+    let checksum = set.iter()
+        .enumerate()
+        .filter(|(i, _)| !matches!(i, 2 | 3))
+        .fold(0u16, |sum, (_, &v)| sum.rotate_right(1).wrapping_add(v as u16));
+    if checksum != LittleEndian::read_u16(&set[2..]) {
+        return None;
+    }
+
+    let stream = set.get(ENTRY_SIZE..2 * ENTRY_SIZE)?;
+    if stream[0] != ENTRY_STREAM_EXTENSION {
+        return None;
+    }
+    let name_len = stream[3] as usize;
+    let name = set[2 * ENTRY_SIZE..]
+        .chunks_exact(ENTRY_SIZE)
+        .take_while(|v| v[0] == ENTRY_FILE_NAME)
+        .flat_map(|v| v[2..2 + 2 * NAME_CHARS_PER_ENTRY].chunks_exact(2))
+        .map(LittleEndian::read_u16)
+        .take(name_len)
+        .collect::<Vec<_>>();
+    if name.len() != name_len {
+        return None;
+    }
+
+    let size = LittleEndian::read_u64(&stream[24..]);
+    let valid_size = LittleEndian::read_u64(&stream[8..]);
+    if valid_size > size {
+        return None;
+    }
+    let node = Node {
+        is_dir: LittleEndian::read_u16(&set[4..]) & ATTR_DIRECTORY != 0,
+        first_cluster: LittleEndian::read_u32(&stream[20..]),
+        size: Some(size),
+        valid_size,
+        contiguous: stream[1] & STREAM_FLAG_NO_FAT_CHAIN != 0,
+    };
+    Some((char::decode_utf16(name).map(|v| v.unwrap_or(char::REPLACEMENT_CHARACTER)).collect(), node))
+}
+
+pub struct ExFatFile<'a> {
+    fs: &'a ExFat,
+    runs: Vec<(u64, u64)>,
+    size: u64,
+    valid_size: u64,
+}
+
+impl Payload for ExFatFile<'_> {
+    fn len(&self) -> Result<u64, BootError> {
+        Ok(self.size)
+    }
+
+    fn read_at(&mut self, mut offset: u64, buf: &mut [u8]) -> Result<(), BootError> {
+        if offset.checked_add(buf.len() as u64).is_none_or(|end| end > self.size) {
+            return Err(BootError::Io);
+        }
+        let valid = self.valid_size.saturating_sub(offset).min(buf.len() as u64) as usize;
+        let (mut buf, zeroes) = buf.split_at_mut(valid);
+        zeroes.fill(0);
+
+        let mut start = 0;
+        for &(pos, len) in &self.runs {
+            if buf.is_empty() {
+                break;
+            }
+            if offset < start + len {
+                let within = offset - start;
+                let (chunk, rest) = buf.split_at_mut(buf.len().min((len - within) as usize));
+                self.fs.read(pos + within, chunk).map_err(|_| BootError::Io)?;
+                offset += chunk.len() as u64;
+                buf = rest;
+            }
+            start += len;
+        }
+        if !buf.is_empty() {
+            return Err(BootError::Io);
+        }
+        Ok(())
+    }
+}
+
+impl BootFs for ExFat {
+    fn open(&self, path: &str) -> Result<Box<dyn Payload + '_>, BootError> {
+        let file = self.lookup(path).and_then(|node| match node.is_dir {
+            false => self.file(&node),
+            true => Err(ExFatError::NotAFile),
+        });
+        Ok(Box::new(file.map_err(|_| BootError::Io)?))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, BootError> {
+        let entries = self.lookup(path).and_then(|node| self.dir_entries(&node)).map_err(|_| BootError::Io)?;
+        Ok(entries.into_iter()
+            .map(|(name, node)| DirEntry { name, is_dir: node.is_dir })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECTOR_SIZE: usize = 512;
+    // One sector clusters, so that small files still need a few.
+    const CLUSTER_SIZE: usize = SECTOR_SIZE;
+    const FAT_SECTOR: usize = 24;
+    const FAT_SECTORS: usize = 8;
+    const HEAP_SECTOR: usize = FAT_SECTOR + FAT_SECTORS;
+    const CLUSTERS: usize = 64;
+
+    /// A directory entry set for a file or directory.
+    fn entry_set(name: &str, attr: u16, first_cluster: u32, size: u64, valid_size: u64, flags: u8) -> Vec<u8> {
+        let name = name.encode_utf16().collect::<Vec<_>>();
+        let name_entries = name.len().div_ceil(NAME_CHARS_PER_ENTRY);
+        let mut set = vec![0; (2 + name_entries) * ENTRY_SIZE];
+        set[0] = ENTRY_FILE;
+        set[1] = 1 + name_entries as u8;
+        LittleEndian::write_u16(&mut set[4..], attr);
+
+        let stream = &mut set[ENTRY_SIZE..];
+        stream[0] = ENTRY_STREAM_EXTENSION;
+        // AllocationPossible, plus NoFatChain if set.
+        stream[1] = 0x1 | flags;
+        stream[3] = name.len() as u8;
+        LittleEndian::write_u64(&mut stream[8..], valid_size);
+        LittleEndian::write_u32(&mut stream[20..], first_cluster);
+        LittleEndian::write_u64(&mut stream[24..], size);
+
+        for (entry, chars) in set[2 * ENTRY_SIZE..].chunks_exact_mut(ENTRY_SIZE).zip(name.chunks(NAME_CHARS_PER_ENTRY)) {
+            entry[0] = ENTRY_FILE_NAME;
+            for (i, &c) in chars.iter().enumerate() {
+                LittleEndian::write_u16(&mut entry[2 + 2 * i..], c);
+            }
+        }
+
+        let checksum = set.iter()
+            .enumerate()
+            .filter(|(i, _)| !matches!(i, 2 | 3))
+            .fold(0u16, |sum, (_, &v)| sum.rotate_right(1).wrapping_add(v as u16));
+        LittleEndian::write_u16(&mut set[2..], checksum);
+        set
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 + i / CLUSTER_SIZE) as u8).collect()
+    }
+
+    fn cluster(image: &mut [u8], cluster: u32) -> &mut [u8] {
+        &mut image[(HEAP_SECTOR * SECTOR_SIZE) + (cluster - FIRST_CLUSTER) as usize * CLUSTER_SIZE..][..CLUSTER_SIZE]
+    }
+
+    /// Write `data` to `clusters`, linking them in the FAT unless they're `contiguous`.
+    fn write(image: &mut [u8], clusters: &[u32], data: &[u8], contiguous: bool) {
+        for (i, (&nr, chunk)) in clusters.iter().zip(data.chunks(CLUSTER_SIZE)).enumerate() {
+            cluster(image, nr)[..chunk.len()].copy_from_slice(chunk);
+            if !contiguous {
+                let next = clusters.get(i + 1).copied().unwrap_or(FAT_END_OF_CHAIN);
+                LittleEndian::write_u32(&mut image[FAT_SECTOR * SECTOR_SIZE + nr as usize * 4..], next);
+            }
+        }
+    }
+
+    /// A volume with:
+    /// - /EFI/BOOT/BOOTAA64.EFI
+    /// - /vmlinuz, in clusters that are out of order in the FAT
+    /// - /Image, without a FAT chain and only partly valid
+    /// - /bad, with a broken checksum
+    /// - /Ümlaut.conf
+    ///
+    /// The root directory takes up two clusters.
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; (HEAP_SECTOR + CLUSTERS) * SECTOR_SIZE];
+
+        let boot = &mut image[..SECTOR_SIZE];
+        boot[3..11].copy_from_slice(FS_NAME);
+        LittleEndian::write_u32(&mut boot[80..], FAT_SECTOR as u32);
+        LittleEndian::write_u32(&mut boot[84..], FAT_SECTORS as u32);
+        LittleEndian::write_u32(&mut boot[88..], HEAP_SECTOR as u32);
+        LittleEndian::write_u32(&mut boot[92..], CLUSTERS as u32);
+        LittleEndian::write_u32(&mut boot[96..], 2);
+        boot[105] = REVISION_MAJOR;
+        boot[108] = 9;
+        boot[109] = 0;
+        boot[110] = 1;
+        boot[510..512].copy_from_slice(&BOOT_SIGNATURE);
+        let checksum = boot_checksum(&image[..BOOT_REGION_SECTORS * SECTOR_SIZE]);
+        for v in image[BOOT_REGION_SECTORS * SECTOR_SIZE..][..SECTOR_SIZE].chunks_exact_mut(4) {
+            LittleEndian::write_u32(v, checksum);
+        }
+
+        let efi = data(100);
+        write(&mut image, &[9], &efi, false);
+        let boot_dir = entry_set("BOOTAA64.EFI", 0, 9, efi.len() as u64, efi.len() as u64, 0);
+        write(&mut image, &[8], &boot_dir, false);
+        let efi_dir = entry_set("BOOT", ATTR_DIRECTORY, 8, CLUSTER_SIZE as u64, CLUSTER_SIZE as u64, 0);
+        write(&mut image, &[4], &efi_dir, false);
+
+        let vmlinuz = data(3 * CLUSTER_SIZE - 10);
+        write(&mut image, &[7, 5, 6], &vmlinuz, false);
+        let kernel = data(4 * CLUSTER_SIZE - 100);
+        write(&mut image, &[10, 11, 12, 13], &kernel, true);
+        write(&mut image, &[14], b"conf", false);
+
+        let mut bad = entry_set("bad", 0, 14, 4, 4, 0);
+        bad[4] ^= 0x1;
+        let mut deleted = entry_set("deleted", 0, 14, 4, 4, 0);
+        deleted[0] &= !0x80;
+        // The allocation bitmap's entry, which isn't used here.
+        let mut bitmap = vec![0; ENTRY_SIZE];
+        bitmap[0] = 0x81;
+        let root = [
+            bitmap,
+            entry_set("EFI", ATTR_DIRECTORY, 4, CLUSTER_SIZE as u64, CLUSTER_SIZE as u64, 0),
+            deleted,
+            entry_set("vmlinuz", 0, 7, vmlinuz.len() as u64, vmlinuz.len() as u64, 0),
+            bad,
+            entry_set("Image", 0, 10, kernel.len() as u64, 2 * CLUSTER_SIZE as u64 + 10, STREAM_FLAG_NO_FAT_CHAIN),
+            entry_set("Ümlaut.conf", 0, 14, 4, 4, 0),
+        ].concat();
+        assert!(root.len() > CLUSTER_SIZE);
+        write(&mut image, &[2, 3], &root, false);
+        image
+    }
+
+    fn read(fs: &ExFat, path: &str) -> Vec<u8> {
+        BootFs::read(fs, path).unwrap()
+    }
+
+    fn open(image: Vec<u8>) -> Result<ExFat, ExFatError> {
+        let size = image.len() as u64;
+        ExFat::new(OpenDevice::from_image(Box::new(image), size))
+    }
+
+    #[test]
+    fn read_dir() {
+        let fs = open(image()).unwrap();
+        let root = fs.read_dir("/").unwrap().into_iter().map(|v| (v.name, v.is_dir)).collect::<Vec<_>>();
+        assert_eq!(root, [
+            ("EFI".into(), true),
+            ("vmlinuz".into(), false),
+            ("Image".into(), false),
+            ("Ümlaut.conf".into(), false),
+        ]);
+    }
+
+    #[test]
+    fn fat_chain() {
+        let fs = open(image()).unwrap();
+        assert!(read(&fs, "/vmlinuz") == data(3 * CLUSTER_SIZE - 10));
+        assert!(read(&fs, "/EFI/BOOT/BOOTAA64.EFI") == data(100));
+
+        // A chain that ends too early.
+        let mut image = image();
+        LittleEndian::write_u32(&mut image[FAT_SECTOR * SECTOR_SIZE + 5 * 4..], FAT_END_OF_CHAIN);
+        assert!(open(image).unwrap().open("/vmlinuz").is_err());
+    }
+
+    #[test]
+    fn no_fat_chain() {
+        let fs = open(image()).unwrap();
+        let mut expected = data(4 * CLUSTER_SIZE - 100);
+        // Past the valid size reads as zeroes.
+        expected[2 * CLUSTER_SIZE + 10..].fill(0);
+        assert!(read(&fs, "/Image") == expected);
+    }
+
+    #[test]
+    fn checksums() {
+        let fs = open(image()).unwrap();
+        assert!(fs.open("/bad").is_err());
+
+        let mut image = image();
+        // VolumeFlags aren't covered by the checksum, unlike the rest of the boot region.
+        image[106] = 0x2;
+        let mut corrupt = image.clone();
+        corrupt[SECTOR_SIZE + 1] ^= 0x1;
+        assert!(open(image).is_ok());
+        assert!(matches!(open(corrupt), Err(ExFatError::Corrupt { .. })));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let fs = open(image()).unwrap();
+        assert!(read(&fs, "/efi/boot/bootaa64.efi") == data(100));
+        assert_eq!(read(&fs, "/ümlaut.CONF"), b"conf");
+        assert!(fs.open("/EFI/BOOT/BOOTAA64.EF").is_err());
+    }
+}
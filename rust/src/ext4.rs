@@ -0,0 +1,722 @@
+//! Read-only ext2/3/4 driver.
+//!
+//! Unlike lib/fs/ext2 this understands extents, 64-bit block numbers, flex_bg, metadata_csum and
+//! inline data, which is what mkfs.ext4 enables by default nowadays. HTree directories need no
+//! special handling: their index blocks look like empty directory entries to a linear scan.
+//!
+//! The journal is never replayed, so a filesystem that wasn't cleanly unmounted may show stale
+//! data.
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::cell::RefCell;
+use crc::{Crc, CRC_32_ISCSI};
+use snafu::Snafu;
+use crate::bio::{BioError, OpenDevice};
+use crate::fs::{BootFs, DirEntry};
+use crate::kernel_boot::{BootError, Payload};
+use crate::println;
+
+#[derive(Debug, Snafu)]
+pub enum Ext4Error {
+    #[snafu(display("I/O error: {source}"))]
+    Io { source: BioError },
+    #[snafu(display("invalid superblock"))]
+    InvalidSuperblock,
+    #[snafu(display("unsupported incompatible features {features:#x}"))]
+    UnsupportedFeatures { features: u32 },
+    #[snafu(display("corrupt {what}"))]
+    Corrupt { what: &'static str },
+    #[snafu(display("no such file or directory"))]
+    NotFound,
+    #[snafu(display("not a directory"))]
+    NotADirectory,
+    #[snafu(display("not a regular file"))]
+    NotAFile,
+    #[snafu(display("too many levels of symbolic links"))]
+    TooManySymlinks,
+}
+
+impl From<BioError> for Ext4Error {
+    fn from(source: BioError) -> Self {
+        Ext4Error::Io { source }
+    }
+}
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+const ROOT_INODE: u32 = 2;
+// Linux allows 40, but nothing we boot from nests symlinks anywhere near that deep.
+const MAX_SYMLINKS: usize = 8;
+
+const COMPAT_SPARSE_SUPER2: u32 = 0x200;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_MMP: u32 = 0x100;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const INCOMPAT_EA_INODE: u32 = 0x400;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+const INCOMPAT_INLINE_DATA: u32 = 0x8000;
+const INCOMPAT_CASEFOLD: u32 = 0x20000;
+/// Everything that doesn't change how files are found and read, or that is handled here.
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE | INCOMPAT_RECOVER | INCOMPAT_META_BG
+    | INCOMPAT_EXTENTS | INCOMPAT_64BIT | INCOMPAT_MMP | INCOMPAT_FLEX_BG | INCOMPAT_EA_INODE
+    | INCOMPAT_CSUM_SEED | INCOMPAT_LARGEDIR | INCOMPAT_INLINE_DATA | INCOMPAT_CASEFOLD;
+
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+
+const DESC_SIZE_MIN: u64 = 32;
+const DESC_SIZE_64BIT: u64 = 64;
+
+const INODE_SIZE_GOOD_OLD: u64 = 128;
+const INODE_FLAG_EXTENTS: u32 = 0x80000;
+const INODE_FLAG_INLINE_DATA: u32 = 0x10000000;
+const INODE_BLOCK_SIZE: usize = 60;
+
+const S_IFMT: u16 = 0xf000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFREG: u16 = 0x8000;
+const S_IFLNK: u16 = 0xa000;
+
+const EXTENT_MAGIC: u16 = 0xf30a;
+const EXTENT_MAX_DEPTH: u16 = 5;
+// Extents longer than this are uninitialized, i.e. read as zeroes.
+const EXTENT_INIT_MAX_LEN: u16 = 32768;
+
+const XATTR_MAGIC: u32 = 0xea020000;
+const XATTR_INDEX_SYSTEM: u8 = 7;
+const XATTR_INLINE_DATA: &[u8] = b"data";
+
+const CRC32C: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+pub struct Ext4 {
+    dev: RefCell<OpenDevice>,
+    block_size: u64,
+    blocks_count: u64,
+    blocks_per_group: u64,
+    inodes_count: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    desc_size: u64,
+    first_data_block: u64,
+    first_meta_bg: u64,
+    backup_bgs: [u32; 2],
+    compat: u32,
+    incompat: u32,
+    ro_compat: u32,
+}
+
+/// A run of file blocks. `physical` is `None` for uninitialized extents.
+#[derive(Clone, Copy, Debug)]
+struct Extent {
+    logical: u64,
+    len: u64,
+    physical: Option<u64>,
+}
+
+enum FileData {
+    /// Sorted by logical block, anything not covered is a hole.
+    Extents(Vec<Extent>),
+    Inline(Vec<u8>),
+}
+
+struct Inode {
+    raw: Vec<u8>,
+}
+
+impl Inode {
+    fn mode(&self) -> u16 {
+        LittleEndian::read_u16(&self.raw[0x0..])
+    }
+
+    fn size(&self) -> u64 {
+        LittleEndian::read_u32(&self.raw[0x4..]) as u64 | (LittleEndian::read_u32(&self.raw[0x6c..]) as u64) << 32
+    }
+
+    fn flags(&self) -> u32 {
+        LittleEndian::read_u32(&self.raw[0x20..])
+    }
+
+    /// `i_block`, the block map, extent tree root or inline data.
+    fn block(&self) -> &[u8] {
+        &self.raw[0x28..][..INODE_BLOCK_SIZE]
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode() & S_IFMT == S_IFDIR
+    }
+
+    fn is_file(&self) -> bool {
+        self.mode() & S_IFMT == S_IFREG
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.mode() & S_IFMT == S_IFLNK
+    }
+
+    /// Find an extended attribute stored in the inode itself, after `i_extra_isize`.
+    fn ibody_xattr(&self, index: u8, name: &[u8]) -> Option<&[u8]> {
+        let raw = &self.raw;
+        let extra_isize = LittleEndian::read_u16(raw.get(0x80..0x82)?) as usize;
+        let header = 128 + extra_isize;
+        if LittleEndian::read_u32(raw.get(header..header + 4)?) != XATTR_MAGIC {
+            return None;
+        }
+
+        // Value offsets are relative to the first entry.
+        let base = header + 4;
+        let mut pos = base;
+        while let Some(entry) = raw.get(pos..pos + 16) {
+            if LittleEndian::read_u32(entry) == 0 {
+                break;
+            }
+            let name_len = entry[0] as usize;
+            if entry[1] == index && raw.get(pos + 16..pos + 16 + name_len)? == name {
+                // Values in a separate EA inode are never used for anything we read.
+                if LittleEndian::read_u32(&entry[4..]) != 0 {
+                    return None;
+                }
+                let offset = base + LittleEndian::read_u16(&entry[2..]) as usize;
+                let size = LittleEndian::read_u32(&entry[8..]) as usize;
+                return raw.get(offset..offset + size);
+            }
+            pos += (16 + name_len + 3) & !3;
+        }
+        None
+    }
+}
+
+impl Ext4 {
+    pub fn new(mut dev: OpenDevice) -> Result<Self, Ext4Error> {
+        let mut sb = vec![0; SUPERBLOCK_SIZE];
+        dev.read_at(SUPERBLOCK_OFFSET, &mut sb)?;
+        if LittleEndian::read_u16(&sb[0x38..]) != MAGIC {
+            return Err(Ext4Error::InvalidSuperblock);
+        }
+
+        let incompat = LittleEndian::read_u32(&sb[0x60..]);
+        let ro_compat = LittleEndian::read_u32(&sb[0x64..]);
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(Ext4Error::UnsupportedFeatures { features: incompat & !INCOMPAT_SUPPORTED });
+        }
+        // The checksum is crc32c without the final inversion, like all ext4 checksums.
+        if ro_compat & RO_COMPAT_METADATA_CSUM != 0 && !CRC32C.checksum(&sb[..0x3fc]) != LittleEndian::read_u32(&sb[0x3fc..]) {
+            return Err(Ext4Error::Corrupt { what: "superblock checksum" });
+        }
+
+        let log_block_size = LittleEndian::read_u32(&sb[0x18..]);
+        if log_block_size > 6 {
+            return Err(Ext4Error::InvalidSuperblock);
+        }
+        let block_size = 1024 << log_block_size;
+
+        let inode_size = match LittleEndian::read_u32(&sb[0x4c..]) {
+            0 => INODE_SIZE_GOOD_OLD,
+            _ => LittleEndian::read_u16(&sb[0x58..]) as u64,
+        };
+        if inode_size < INODE_SIZE_GOOD_OLD || !inode_size.is_power_of_two() || inode_size > block_size {
+            return Err(Ext4Error::InvalidSuperblock);
+        }
+
+        let (desc_size, blocks_count_hi) = if incompat & INCOMPAT_64BIT != 0 {
+            (LittleEndian::read_u16(&sb[0xfe..]) as u64, LittleEndian::read_u32(&sb[0x150..]) as u64)
+        } else {
+            (DESC_SIZE_MIN, 0)
+        };
+        if desc_size < DESC_SIZE_MIN || !desc_size.is_power_of_two() || desc_size > block_size {
+            return Err(Ext4Error::InvalidSuperblock);
+        }
+
+        let inodes_per_group = LittleEndian::read_u32(&sb[0x28..]);
+        let blocks_per_group = LittleEndian::read_u32(&sb[0x20..]) as u64;
+        if inodes_per_group == 0 || blocks_per_group == 0 {
+            return Err(Ext4Error::InvalidSuperblock);
+        }
+
+        if incompat & INCOMPAT_RECOVER != 0 {
+            println!("ext4: journal needs recovery, files may be out of date");
+        }
+
+        Ok(Self {
+            dev: RefCell::new(dev),
+            block_size,
+            blocks_count: LittleEndian::read_u32(&sb[0x4..]) as u64 | blocks_count_hi << 32,
+            blocks_per_group,
+            inodes_count: LittleEndian::read_u32(&sb[0x0..]),
+            inodes_per_group,
+            inode_size,
+            desc_size,
+            first_data_block: LittleEndian::read_u32(&sb[0x14..]) as u64,
+            first_meta_bg: LittleEndian::read_u32(&sb[0x104..]) as u64,
+            backup_bgs: [LittleEndian::read_u32(&sb[0x24c..]), LittleEndian::read_u32(&sb[0x250..])],
+            compat: LittleEndian::read_u32(&sb[0x5c..]),
+            incompat,
+            ro_compat,
+        })
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), Ext4Error> {
+        Ok(self.dev.borrow_mut().read_at(offset, buf)?)
+    }
+
+    fn read_block(&self, block: u64) -> Result<Vec<u8>, Ext4Error> {
+        if block >= self.blocks_count {
+            return Err(Ext4Error::Corrupt { what: "block number" });
+        }
+        let mut data = vec![0; self.block_size as usize];
+        self.read(block * self.block_size, &mut data)?;
+        Ok(data)
+    }
+
+    /// Whether `group` has a copy of the superblock, and so of the META_BG descriptors.
+    fn has_super(&self, group: u64) -> bool {
+        if group == 0 {
+            return true;
+        }
+        if self.compat & COMPAT_SPARSE_SUPER2 != 0 {
+            return self.backup_bgs.contains(&(group as u32));
+        }
+        if group == 1 || self.ro_compat & RO_COMPAT_SPARSE_SUPER == 0 {
+            return true;
+        }
+        // Otherwise only powers of 3, 5 and 7 have one.
+        [3, 5, 7].iter().any(|&base| {
+            let mut n = base;
+            while n < group {
+                n *= base;
+            }
+            n == group
+        })
+    }
+
+    /// The block holding the `nr`th block of group descriptors.
+    fn descriptor_block(&self, nr: u64) -> u64 {
+        if self.incompat & INCOMPAT_META_BG == 0 || nr < self.first_meta_bg {
+            return self.first_data_block + 1 + nr;
+        }
+        // With META_BG each block of descriptors lives in the first group it describes.
+        let group = nr * (self.block_size / self.desc_size);
+        self.first_data_block + group * self.blocks_per_group + self.has_super(group) as u64
+    }
+
+    fn inode(&self, ino: u32) -> Result<Inode, Ext4Error> {
+        if ino == 0 || ino > self.inodes_count {
+            return Err(Ext4Error::Corrupt { what: "inode number" });
+        }
+        let group = ((ino - 1) / self.inodes_per_group) as u64;
+        let index = ((ino - 1) % self.inodes_per_group) as u64;
+
+        let descs_per_block = self.block_size / self.desc_size;
+        let mut desc = vec![0; self.desc_size as usize];
+        self.read(
+            self.descriptor_block(group / descs_per_block) * self.block_size + group % descs_per_block * self.desc_size,
+            &mut desc,
+        )?;
+        let mut inode_table = LittleEndian::read_u32(&desc[0x8..]) as u64;
+        if self.desc_size >= DESC_SIZE_64BIT {
+            inode_table |= (LittleEndian::read_u32(&desc[0x28..]) as u64) << 32;
+        }
+        if inode_table == 0 || inode_table >= self.blocks_count {
+            return Err(Ext4Error::Corrupt { what: "group descriptor" });
+        }
+
+        let mut raw = vec![0; self.inode_size as usize];
+        self.read(inode_table * self.block_size + index * self.inode_size, &mut raw)?;
+        Ok(Inode { raw })
+    }
+
+    fn data(&self, inode: &Inode) -> Result<FileData, Ext4Error> {
+        let size = inode.size();
+        if inode.flags() & INODE_FLAG_INLINE_DATA != 0 {
+            let mut data = inode.block().to_vec();
+            if let Some(extra) = inode.ibody_xattr(XATTR_INDEX_SYSTEM, XATTR_INLINE_DATA) {
+                data.extend_from_slice(extra);
+            }
+            if (data.len() as u64) < size {
+                return Err(Ext4Error::Corrupt { what: "inline data" });
+            }
+            data.truncate(size as usize);
+            return Ok(FileData::Inline(data));
+        }
+
+        let mut extents = Vec::new();
+        if inode.flags() & INODE_FLAG_EXTENTS != 0 {
+            self.extent_node(inode.block(), None, &mut extents)?;
+            extents.sort_by_key(|v| v.logical);
+        } else {
+            let end = size.div_ceil(self.block_size);
+            let mut logical = 0;
+            for (i, ptr) in inode.block().chunks_exact(4).enumerate() {
+                // 12 direct blocks, then single, double and triple indirect ones.
+                let level = i.saturating_sub(11) as u32;
+                self.block_map(LittleEndian::read_u32(ptr), level, &mut logical, end, &mut extents)?;
+            }
+        }
+        Ok(FileData::Extents(extents))
+    }
+
+    /// Collect the extents below an extent tree node. `depth` is what the node's depth should be,
+    /// as found in its parent.
+    fn extent_node(&self, node: &[u8], depth: Option<u16>, extents: &mut Vec<Extent>) -> Result<(), Ext4Error> {
+        let corrupt = Ext4Error::Corrupt { what: "extent tree" };
+        if node.len() < 12 || LittleEndian::read_u16(node) != EXTENT_MAGIC {
+            return Err(corrupt);
+        }
+        let entries = LittleEndian::read_u16(&node[2..]) as usize;
+        let node_depth = LittleEndian::read_u16(&node[6..]);
+        if 12 + entries * 12 > node.len() || node_depth > EXTENT_MAX_DEPTH || depth.is_some_and(|v| v != node_depth) {
+            return Err(corrupt);
+        }
+
+        for entry in node[12..].chunks_exact(12).take(entries) {
+            let logical = LittleEndian::read_u32(entry) as u64;
+            if node_depth == 0 {
+                let mut len = LittleEndian::read_u16(&entry[4..]);
+                let initialized = len <= EXTENT_INIT_MAX_LEN;
+                if !initialized {
+                    len -= EXTENT_INIT_MAX_LEN;
+                }
+                let start = (LittleEndian::read_u16(&entry[6..]) as u64) << 32 | LittleEndian::read_u32(&entry[8..]) as u64;
+                if start + len as u64 > self.blocks_count {
+                    return Err(corrupt);
+                }
+                extents.push(Extent { logical, len: len as u64, physical: initialized.then_some(start) });
+            } else {
+                let leaf = (LittleEndian::read_u16(&entry[8..]) as u64) << 32 | LittleEndian::read_u32(&entry[4..]) as u64;
+                self.extent_node(&self.read_block(leaf)?, Some(node_depth - 1), extents)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Collect the blocks below a block map pointer, `level` being how many indirect blocks are
+    /// in between. Blocks before `logical` have already been collected, and anything from `end`
+    /// is past the end of the file.
+    fn block_map(&self, ptr: u32, level: u32, logical: &mut u64, end: u64, extents: &mut Vec<Extent>) -> Result<(), Ext4Error> {
+        if *logical >= end {
+            return Ok(());
+        }
+        if ptr == 0 {
+            *logical += (self.block_size / 4).pow(level);
+            return Ok(());
+        }
+        if level == 0 {
+            let physical = ptr as u64;
+            match extents.last_mut() {
+                Some(last) if last.logical + last.len == *logical && last.physical.map(|v| v + last.len) == Some(physical) => last.len += 1,
+                _ => extents.push(Extent { logical: *logical, len: 1, physical: Some(physical) }),
+            }
+            *logical += 1;
+            return Ok(());
+        }
+        for ptr in self.read_block(ptr as u64)?.chunks_exact(4) {
+            self.block_map(LittleEndian::read_u32(ptr), level - 1, logical, end, extents)?;
+        }
+        Ok(())
+    }
+
+    fn file(&self, inode: &Inode) -> Result<Ext4File<'_>, Ext4Error> {
+        Ok(Ext4File {
+            fs: self,
+            size: inode.size(),
+            data: self.data(inode)?,
+        })
+    }
+
+    fn read_extents(&self, extents: &[Extent], mut offset: u64, mut buf: &mut [u8]) -> Result<(), Ext4Error> {
+        while !buf.is_empty() {
+            let block = offset / self.block_size;
+            let within = offset % self.block_size;
+            // The first extent that doesn't end before `block`.
+            let idx = extents.partition_point(|v| v.logical + v.len <= block);
+            let (len, physical) = match extents.get(idx) {
+                Some(extent) if extent.logical <= block => (
+                    (extent.logical + extent.len - block) * self.block_size - within,
+                    extent.physical.map(|v| (v + block - extent.logical) * self.block_size + within),
+                ),
+                // A hole until the next extent, or until the end of the file.
+                Some(extent) => ((extent.logical - block) * self.block_size - within, None),
+                None => (u64::MAX, None),
+            };
+            let (chunk, rest) = buf.split_at_mut(len.min(buf.len() as u64) as usize);
+            match physical {
+                Some(physical) => self.read(physical, chunk)?,
+                None => chunk.fill(0),
+            }
+            offset += chunk.len() as u64;
+            buf = rest;
+        }
+        Ok(())
+    }
+
+    fn dir_entries(&self, inode: &Inode) -> Result<Vec<(String, u32)>, Ext4Error> {
+        if !inode.is_dir() {
+            return Err(Ext4Error::NotADirectory);
+        }
+        let file = self.file(inode)?;
+        let mut data = vec![0; file.size as usize];
+        file.read(0, &mut data)?;
+
+        let mut entries = Vec::new();
+        if let FileData::Inline(_) = file.data {
+            // Inline directories start with the parent inode instead of `.` and `..`.
+            let corrupt = || Ext4Error::Corrupt { what: "inline directory" };
+            let parent = LittleEndian::read_u32(data.get(..4).ok_or_else(corrupt)?);
+            entries.push(("..".to_string(), parent));
+            let (first, rest) = data.split_at(INODE_BLOCK_SIZE.min(data.len()));
+            parse_dirents(&first[4..], self.block_size, &mut entries)?;
+            parse_dirents(rest, self.block_size, &mut entries)?;
+        } else {
+            for block in data.chunks(self.block_size as usize) {
+                parse_dirents(block, self.block_size, &mut entries)?;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn symlink_target(&self, inode: &Inode) -> Result<String, Ext4Error> {
+        let size = inode.size();
+        // Fast symlinks keep the target in the block map.
+        let data = if inode.flags() & (INODE_FLAG_EXTENTS | INODE_FLAG_INLINE_DATA) == 0 && size < INODE_BLOCK_SIZE as u64 {
+            inode.block()[..size as usize].to_vec()
+        } else {
+            let mut data = vec![0; size as usize];
+            self.file(inode)?.read(0, &mut data)?;
+            data
+        };
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    }
+
+    /// Find the inode at `path`, following symlinks.
+    fn lookup(&self, path: &str) -> Result<Inode, Ext4Error> {
+        fn components(path: &str) -> impl DoubleEndedIterator<Item = String> + '_ {
+            path.split('/').filter(|v| !v.is_empty() && *v != ".").map(|v| v.to_string())
+        }
+
+        // Components still to look up, the next one last.
+        let mut remaining = components(path).rev().collect::<Vec<_>>();
+        let mut current = self.inode(ROOT_INODE)?;
+        let mut symlinks = 0;
+
+        while let Some(name) = remaining.pop() {
+            let (_, ino) = self.dir_entries(&current)?
+                .into_iter()
+                .find(|(v, _)| *v == name)
+                .ok_or(Ext4Error::NotFound)?;
+            let inode = self.inode(ino)?;
+            if !inode.is_symlink() {
+                current = inode;
+                continue;
+            }
+
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(Ext4Error::TooManySymlinks);
+            }
+            let target = self.symlink_target(&inode)?;
+            remaining.extend(components(&target).rev());
+            if target.starts_with('/') {
+                current = self.inode(ROOT_INODE)?;
+            }
+        }
+        Ok(current)
+    }
+}
+
+/// Parse the directory entries in one directory block.
+fn parse_dirents(block: &[u8], block_size: u64, entries: &mut Vec<(String, u32)>) -> Result<(), Ext4Error> {
+    let mut pos = 0;
+    while pos + 8 <= block.len() {
+        let ino = LittleEndian::read_u32(&block[pos..]);
+        let rec_len = match LittleEndian::read_u16(&block[pos + 4..]) {
+            // 64KB blocks can't have their length in 16 bits.
+            0 | 65535 if block_size == 65536 => 65536,
+            len => len as usize,
+        };
+        let name_len = block[pos + 6] as usize;
+        if rec_len < 8 || pos + rec_len > block.len() || 8 + name_len > rec_len {
+            return Err(Ext4Error::Corrupt { what: "directory entry" });
+        }
+        // Unused entries, checksum tails and HTree index blocks have no inode.
+        if ino != 0 {
+            entries.push((String::from_utf8_lossy(&block[pos + 8..][..name_len]).into_owned(), ino));
+        }
+        pos += rec_len;
+    }
+    Ok(())
+}
+
+pub struct Ext4File<'a> {
+    fs: &'a Ext4,
+    size: u64,
+    data: FileData,
+}
+
+impl Ext4File<'_> {
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<(), Ext4Error> {
+        if offset.checked_add(buf.len() as u64).is_none_or(|end| end > self.size) {
+            return Err(Ext4Error::Corrupt { what: "file size" });
+        }
+        match &self.data {
+            FileData::Inline(data) => buf.copy_from_slice(&data[offset as usize..][..buf.len()]),
+            FileData::Extents(extents) => self.fs.read_extents(extents, offset, buf)?,
+        }
+        Ok(())
+    }
+}
+
+impl Payload for Ext4File<'_> {
+    fn len(&self) -> Result<u64, BootError> {
+        Ok(self.size)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BootError> {
+        self.read(offset, buf).map_err(|_| BootError::Io)
+    }
+}
+
+impl BootFs for Ext4 {
+    fn open(&self, path: &str) -> Result<Box<dyn Payload + '_>, BootError> {
+        let file = self.lookup(path).and_then(|inode| match inode.is_file() {
+            true => self.file(&inode),
+            false => Err(Ext4Error::NotAFile),
+        });
+        Ok(Box::new(file.map_err(|_| BootError::Io)?))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, BootError> {
+        let entries = self.lookup(path).and_then(|inode| self.dir_entries(&inode)).map_err(|_| BootError::Io)?;
+        entries.into_iter()
+            .filter(|(name, _)| name != "." && name != "..")
+            .map(|(name, ino)| Ok(DirEntry { name, is_dir: self.inode(ino)?.is_dir() }))
+            .collect::<Result<_, Ext4Error>>()
+            .map_err(|_| BootError::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decompress::Compression;
+
+    // Both made with `-b 1024 -N 256 -d` from the same tree, 2MB and compressed with `zstd -19`.
+    // The ext4 one has `-O inline_data,^has_journal` and was run through `e2fsck -D` to index
+    // /big, the ext2 one has block maps instead of extents.
+    const EXT4: &[u8] = include_bytes!("../testdata/ext4.img.zst");
+    const EXT2: &[u8] = include_bytes!("../testdata/ext2.img.zst");
+    const IMAGE_SIZE: usize = 2 << 20;
+
+    fn open(image: &[u8]) -> Ext4 {
+        let mut data = vec![0; IMAGE_SIZE];
+        Compression::Zstd.decompress(&mut image.to_vec(), &mut data).unwrap();
+        Ext4::new(OpenDevice::from_image(Box::new(data), IMAGE_SIZE as u64)).unwrap()
+    }
+
+    fn read(fs: &Ext4, path: &str) -> Vec<u8> {
+        BootFs::read(fs, path).unwrap()
+    }
+
+    /// /boot/vmlinuz, 300 blocks long: two extents, or reaching the double indirect block.
+    fn vmlinuz() -> Vec<u8> {
+        (0..300 * 1024).map(|i: usize| (i * 7 + i / 1024) as u8).collect()
+    }
+
+    /// /sparse, with every 10th block written: more extents than fit in the inode.
+    fn sparse() -> Vec<u8> {
+        let mut data = vec![0; 51 * 1024];
+        for i in 0..6 {
+            data[i * 10 * 1024..][..1024].fill(i as u8 + 1);
+        }
+        data
+    }
+
+    fn check_files(fs: &Ext4) {
+        assert!(read(fs, "/boot/vmlinuz") == vmlinuz());
+        // Across the end of the first extent or the direct blocks.
+        let mut buf = vec![0; 4096];
+        fs.open("/boot/vmlinuz").unwrap().read_at(9 * 1024 - 100, &mut buf).unwrap();
+        assert!(buf == vmlinuz()[9 * 1024 - 100..][..4096]);
+        assert!(read(fs, "/sparse") == sparse());
+        assert!(fs.open("/boot/vmlinuz").unwrap().read_at(300 * 1024 - 1, &mut buf).is_err());
+    }
+
+    fn check_symlinks(fs: &Ext4) {
+        let entry = b"title Linux\nlinux /vmlinuz\n";
+        // Relative, to a directory.
+        assert_eq!(read(fs, "/loader/entries/linux.conf"), entry);
+        // Absolute, in the inode.
+        assert!(read(fs, "/vmlinuz") == vmlinuz());
+        // Longer than fits in the inode.
+        assert!(read(fs, "/slow") == vmlinuz());
+        assert!(matches!(fs.lookup("/loop"), Err(Ext4Error::TooManySymlinks)));
+    }
+
+    fn check_big_dir(fs: &Ext4) {
+        let mut names = fs.read_dir("/big").unwrap().into_iter().map(|v| v.name).collect::<Vec<_>>();
+        names.sort();
+        let expected = (0..200).map(|i| alloc::format!("entry-with-a-longish-name-{i:03}")).collect::<Vec<_>>();
+        assert_eq!(names, expected);
+        assert_eq!(read(fs, "/big/entry-with-a-longish-name-150"), b"150");
+    }
+
+    #[test]
+    fn extents() {
+        let fs = open(EXT4);
+        assert_ne!(fs.incompat & INCOMPAT_EXTENTS, 0);
+        check_files(&fs);
+    }
+
+    #[test]
+    fn block_map() {
+        let fs = open(EXT2);
+        assert_eq!(fs.incompat & INCOMPAT_EXTENTS, 0);
+        check_files(&fs);
+        check_symlinks(&fs);
+        check_big_dir(&fs);
+    }
+
+    #[test]
+    fn inline_data() {
+        let fs = open(EXT4);
+        assert_eq!(read(&fs, "/tiny"), b"inline\n");
+        // Continued in the system.data extended attribute.
+        let small = (0..70).map(|i| b'A' + i % 26).collect::<Vec<_>>();
+        assert_eq!(read(&fs, "/small"), small);
+
+        let entries = fs.read_dir("/boot/loader/entries").unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "linux.conf");
+        assert!(!entries[0].is_dir);
+        let mut boot = fs.read_dir("/boot").unwrap().into_iter().map(|v| (v.name, v.is_dir)).collect::<Vec<_>>();
+        boot.sort();
+        assert_eq!(boot, [("loader".into(), true), ("vmlinuz".into(), false)]);
+    }
+
+    #[test]
+    fn htree() {
+        check_big_dir(&open(EXT4));
+    }
+
+    #[test]
+    fn symlinks() {
+        check_symlinks(&open(EXT4));
+    }
+
+    #[test]
+    fn not_found() {
+        let fs = open(EXT4);
+        assert!(matches!(fs.lookup("/boot/missing"), Err(Ext4Error::NotFound)));
+        assert!(matches!(fs.lookup("/tiny/file"), Err(Ext4Error::NotADirectory)));
+        assert!(fs.open("/boot").is_err());
+    }
+}
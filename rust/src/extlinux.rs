@@ -1,105 +1,325 @@
-use alloc::{format, vec};
+use alloc::format;
 use alloc::boxed::Box;
-use alloc::ffi::CString;
+use alloc::rc::Rc;
 use alloc::string::{String, ToString};
-use core::ffi::CStr;
-use anyhow::{ensure, Error, Context};
-use embedded_graphics::draw_target::DrawTarget;
-use embedded_graphics::image::{Image, ImageDrawable};
-use embedded_graphics::pixelcolor::Rgb888;
-use crate::{BootOption, lk_fs};
+use alloc::vec::Vec;
+use anyhow::{bail, ensure, Error, Context};
+use crate::{BootOption, kernel_boot, lk2nd_device, println};
+use crate::kernel_boot::Payload;
 use crate::fbcon::FbCon888;
-use crate::lk_fs::LkFile;
+use crate::fs::{self, BootFs};
+use crate::loader::{LoaderConfig, Timeout};
+use core::time::Duration;
+
+const CONFIG_PATH: &str = "/extlinux/extlinux.conf";
+
+/// A single `label` block from extlinux.conf.
+#[derive(Clone, Debug, Default)]
+pub struct Label {
+    pub name: String,
+    pub menu_label: Option<String>,
+    pub kernel: Option<String>,
+    pub initrd: Option<String>,
+    pub fdt: Option<String>,
+    pub fdtdir: Option<String>,
+    pub fdtoverlays: Vec<String>,
+    pub append: Option<String>,
+}
+
+impl Label {
+    /// The name to show in the boot menu.
+    pub fn title(&self) -> &str {
+        self.menu_label.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// Everything we understand from an extlinux.conf.
+#[derive(Debug, Default)]
+pub struct Config {
+    pub default: Option<String>,
+    /// Timeout in tenths of a second, as specified by syslinux. 0 means no timeout.
+    pub timeout: Option<u32>,
+    pub menu_title: Option<String>,
+    pub labels: Vec<Label>,
+}
+
+impl Config {
+    /// The menu settings, as if they came from a loader.conf.
+    pub fn loader_config(&self) -> LoaderConfig {
+        LoaderConfig {
+            default: self.default.clone(),
+            timeout: self.timeout.map(|v| match v {
+                0 => Timeout::Never,
+                v => Timeout::After(Duration::from_millis(v as u64 * 100)),
+            }),
+            console_mode: None,
+        }
+    }
+}
+
+/// Parse an extlinux.conf. Unknown keywords are ignored, as are any label-specific keywords
+/// that appear before the first `label`.
+pub fn parse(data: &str) -> Config {
+    let mut config = Config::default();
+
+    for line in data.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (keyword, value) = split_keyword(line);
+        let keyword = keyword.to_ascii_lowercase();
+
+        if keyword == "menu" {
+            let (keyword, value) = split_keyword(value);
+            match keyword.to_ascii_lowercase().as_str() {
+                "title" => config.menu_title = Some(value.to_string()),
+                "label" => if let Some(label) = config.labels.last_mut() {
+                    label.menu_label = Some(value.to_string());
+                },
+                "default" => if let Some(label) = config.labels.last() {
+                    config.default = Some(label.name.clone());
+                },
+                _ => {}
+            }
+            continue;
+        }
+
+        match keyword.as_str() {
+            "default" => config.default = Some(value.to_string()),
+            "timeout" => config.timeout = value.parse().ok(),
+            "label" => config.labels.push(Label {
+                name: value.to_string(),
+                ..Default::default()
+            }),
+            _ => {
+                let Some(label) = config.labels.last_mut() else {
+                    continue;
+                };
+                let value = Some(value.to_string());
+                match keyword.as_str() {
+                    "kernel" | "linux" => label.kernel = value,
+                    "initrd" => label.initrd = value,
+                    "append" => label.append = value,
+                    "fdt" | "devicetree" => label.fdt = value,
+                    "fdtdir" | "devicetreedir" => label.fdtdir = value,
+                    "fdtoverlays" | "devicetree-overlay" => {
+                        label.fdtoverlays = value.iter()
+                            .flat_map(|v| v.split_ascii_whitespace())
+                            .map(|v| v.to_string())
+                            .collect();
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    config
+}
+
+fn split_keyword(line: &str) -> (&str, &str) {
+    match line.split_once(|c: char| c.is_ascii_whitespace()) {
+        Some((keyword, value)) => (keyword, value.trim()),
+        None => (line, ""),
+    }
+}
 
 struct ExtLinuxBootConfig {
-    label: sys::extlinux_label,
+    id: String,
     name: String,
+    fs: Rc<dyn BootFs>,
+    kernel: String,
+    initrd: Option<String>,
+    dtb: String,
+    overlays: Vec<String>,
+    cmdline: String,
+}
+
+impl ExtLinuxBootConfig {
+    /// Resolve all paths in the label and make sure the files they point to exist.
+    fn new(fs: Rc<dyn BootFs>, label: &Label, name: String) -> anyhow::Result<Self> {
+        let kernel = fs::join("/", label.kernel.as_ref().context("kernel is not specified")?);
+        ensure!(fs.exists(&kernel), "kernel {} does not exist", kernel);
+
+        // lk2nd needs to patch the dtb to boot.
+        let dtb = match (&label.fdt, &label.fdtdir) {
+            (Some(fdt), _) => fs::join("/", fdt),
+            (None, Some(fdtdir)) => {
+                ensure!(!lk2nd_device::dtb_hints().is_empty(), "the dtb-files for this device is not set");
+                lk2nd_device::dtb_paths(&fs::join("/", fdtdir))
+                    .into_iter()
+                    .find(|path| fs.exists(path))
+                    .context("no matching dtb found in fdtdir")?
+            }
+            (None, None) => bail!("neither fdt nor fdtdir is specified"),
+        };
+        ensure!(fs.exists(&dtb), "FDT {} does not exist", dtb);
+
+        let overlays = label.fdtoverlays.iter().map(|v| fs::join("/", v)).collect::<Vec<_>>();
+        for overlay in &overlays {
+            ensure!(fs.exists(overlay), "FDT overlay {} does not exist", overlay);
+        }
+
+        let initrd = label.initrd.as_ref().map(|v| fs::join("/", v));
+        if let Some(initrd) = &initrd {
+            ensure!(fs.exists(initrd), "initramfs {} does not exist", initrd);
+        }
+
+        Ok(Self {
+            id: label.name.clone(),
+            name,
+            fs,
+            kernel,
+            initrd,
+            dtb,
+            overlays,
+            cmdline: label.append.clone().unwrap_or_default(),
+        })
+    }
+
+    fn try_boot(&self) -> anyhow::Result<()> {
+        let open = |path: &str| self.fs.open(path).map_err(Error::msg).with_context(|| format!("open {} failed", path));
+        let mut kernel = open(&self.kernel)?;
+        let mut dtb = open(&self.dtb)?;
+        let mut overlays = Vec::new();
+        for overlay in &self.overlays {
+            overlays.push(open(overlay)?);
+        }
+        let mut initrd = self.initrd.as_deref().map(open).transpose()?;
+
+        kernel_boot::boot(
+            kernel.as_mut(),
+            Some(dtb.as_mut()),
+            &mut overlays,
+            initrd.as_mut().map(|v| v.as_mut() as &mut dyn Payload),
+            &self.cmdline,
+        ).map_err(Error::msg)
+    }
 }
 
 impl BootOption for ExtLinuxBootConfig {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
     fn label(&self) -> &str {
         &self.name
     }
 
-    fn splash(&self, display: &mut FbCon888) -> Result<(), ()> {
+    fn splash(&self, _display: &mut FbCon888) -> Result<(), ()> {
         Ok(())
     }
 
     fn boot(&mut self) -> ! {
-        unsafe { sys::extlinux_boot_label(&mut self.label); }
+        if let Err(err) = self.try_boot() {
+            println!("booting {} failed: {:?}", self.name, err);
+        }
+        panic!("noes");
     }
+}
 
+/// Collect the labels in `/extlinux/extlinux.conf`. `suffix` is appended to their names.
+pub fn scan(fs: Rc<dyn BootFs>, suffix: &str) -> anyhow::Result<(Vec<Box<dyn BootOption>>, LoaderConfig)> {
+    let data = fs.read(CONFIG_PATH).map_err(Error::msg).context("read extlinux.conf failed")?;
 
-}
+    let config = parse(&String::from_utf8_lossy(&data));
+    if let Some(title) = &config.menu_title {
+        println!("extlinux: found \"{}\"", title);
+    }
 
-pub fn scan<'a>(partition: &str) -> anyhow::Result<Box<dyn BootOption + 'a>> {
-    let mountpoint = format!("/{}", partition);
-    lk_fs::mount(&mountpoint, "ext2", partition).context("ext2 mount failed")?;
-    let file = LkFile::open(&format!("{}/extlinux/extlinux.conf", mountpoint)).map_err(Error::msg).context("open extlinux.conf failed")?;
-    let (_, size) = file.stat().map_err(Error::msg).context("stat extlinux.conf failed")?;
-    let mut data = vec![0; size + 1];
-    file.read(&mut data[..size], 0).context("read extlinux.conf failed")?;
-
-    let mut label: sys::extlinux_label = Default::default();
-    let ret = unsafe { sys::extlinux_parse_conf(data.as_mut_ptr() as _, data.len() as _, &mut label) };
-    ensure!(ret >= 0, "parsing extlinux.conf failed");
-
-    let root = CString::new(mountpoint.clone()).map_err(Error::msg).context("mountpoint is invalid UTF-8?!")?;
-    let ret = unsafe { sys::extlinux_expand_conf(&mut label, root.as_ptr()) };
-    ensure!(ret, "expanding extlinux.conf failed");
-
-
-    let mut name = if label.label.is_null() { "".to_string() } else {
-        let str = unsafe { CStr::from_ptr(label.label) };
-        CString::from(str).to_string_lossy().to_string()
-    };
-
-    // TODO: properly detect where devices are coming from, somehow...
-    if partition.starts_with("wrp0") {
-        name += " (internal)"
-    } else {
-        name += " (SD card)"
-    }
-    Ok(Box::new(ExtLinuxBootConfig {
-        label,
-        name,
-    }))
+    let mut options: Vec<Box<dyn BootOption>> = Vec::new();
+    for label in &config.labels {
+        match ExtLinuxBootConfig::new(fs.clone(), label, format!("{}{}", label.title(), suffix)) {
+            Ok(option) => options.push(Box::new(option)),
+            Err(err) => println!("extlinux: skipping label {}: {:?}", label.name, err),
+        }
+    }
+
+    Ok((options, config.loader_config()))
 }
 
-mod sys {
-    #![allow(non_camel_case_types)]
-
-    use core::ffi::{c_char, c_int, c_uint};
-
-    #[repr(C)]
-    #[derive(Debug)]
-    pub struct extlinux_label {
-        pub label: *const c_char,
-        pub kernel: *const c_char,
-        pub initramfs: *const c_char,
-        pub dtb: *const c_char,
-        pub dtbdir: *const c_char,
-        pub dtboverlays: *mut *const c_char,
-        pub cmdline: *const c_char,
-    }
-
-    impl Default for extlinux_label {
-        fn default() -> Self {
-            Self {
-                label: 0 as _,
-                kernel: 0 as _,
-                initramfs: 0 as _,
-                dtb: 0 as _,
-                dtbdir: 0 as _,
-                dtboverlays: 0 as _,
-                cmdline: 0 as _,
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "\
+# Generated by u-boot-update
+menu title Boot menu
+timeout 35
+default edge
+
+label stable
+    menu label Linux LTS
+    linux /vmlinuz-lts
+    initrd /initramfs-lts
+    fdtdir /dtbs-lts
+    append root=/dev/sda2 quiet
+
+label edge
+    LINUX /vmlinuz-edge
+    FDT /dtbs-edge/qcom/msm8916-samsung-a5u-eur.dtb
+    fdtoverlays /overlays/a.dtbo   /overlays/b.dtbo
+";
+
+    #[test]
+    fn labels() {
+        let config = parse(CONFIG);
+        assert_eq!(config.menu_title.as_deref(), Some("Boot menu"));
+        assert_eq!(config.labels.len(), 2);
+
+        let stable = &config.labels[0];
+        assert_eq!(stable.name, "stable");
+        assert_eq!(stable.title(), "Linux LTS");
+        assert_eq!(stable.kernel.as_deref(), Some("/vmlinuz-lts"));
+        assert_eq!(stable.initrd.as_deref(), Some("/initramfs-lts"));
+        assert_eq!(stable.fdtdir.as_deref(), Some("/dtbs-lts"));
+        assert_eq!(stable.append.as_deref(), Some("root=/dev/sda2 quiet"));
+
+        // Keywords are case insensitive.
+        let edge = &config.labels[1];
+        assert_eq!(edge.title(), "edge");
+        assert_eq!(edge.kernel.as_deref(), Some("/vmlinuz-edge"));
+        assert_eq!(edge.fdt.as_deref(), Some("/dtbs-edge/qcom/msm8916-samsung-a5u-eur.dtb"));
+        assert_eq!(edge.fdtoverlays, ["/overlays/a.dtbo", "/overlays/b.dtbo"]);
+        assert_eq!(edge.initrd, None);
+    }
+
+    #[test]
+    fn default() {
+        assert_eq!(parse(CONFIG).default.as_deref(), Some("edge"));
+
+        // `menu default` picks the label it's in.
+        let config = parse("default edge\nlabel stable\nmenu default\nlabel edge\n");
+        assert_eq!(config.default.as_deref(), Some("stable"));
+        // Until a later `default` overrides it.
+        let config = parse("label stable\nmenu default\nlabel edge\ndefault edge\n");
+        assert_eq!(config.default.as_deref(), Some("edge"));
+        // Outside a label it doesn't pick anything.
+        assert_eq!(parse("menu default\nlabel stable\n").default, None);
     }
 
-    extern "C" {
-        pub fn extlinux_parse_conf(data: *mut c_char, size: c_uint, label: *mut extlinux_label) -> c_int;
-        pub fn extlinux_expand_conf(label: *mut extlinux_label, root: *const c_char) -> bool;
-        pub fn extlinux_boot_label(label: *mut extlinux_label) -> !;
+    #[test]
+    fn timeout() {
+        let config = parse(CONFIG);
+        assert_eq!(config.timeout, Some(35));
+        assert_eq!(config.loader_config().timeout, Some(Timeout::After(Duration::from_millis(3500))));
+        assert_eq!(config.loader_config().default.as_deref(), Some("edge"));
+
+        assert_eq!(parse("timeout 0").loader_config().timeout, Some(Timeout::Never));
+        assert_eq!(parse("timeout soon").loader_config().timeout, None);
+        assert_eq!(parse("").loader_config().timeout, None);
+    }
+
+    #[test]
+    fn keywords_before_label() {
+        let config = parse("linux /vmlinuz\nmenu label Linux\nappend quiet\nlabel linux\ninitrd /initrd\n");
+        assert_eq!(config.labels.len(), 1);
+        let label = &config.labels[0];
+        assert_eq!(label.title(), "linux");
+        assert_eq!(label.kernel, None);
+        assert_eq!(label.append, None);
+        assert_eq!(label.initrd.as_deref(), Some("/initrd"));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,897 @@
+//! Read-only f2fs driver, for Android userdata partitions that boot files were put on.
+//!
+//! Files are found through the newest valid checkpoint: node addresses come from the NAT, or from
+//! the NAT journal in the checkpoint for nodes that changed since the NAT was last written. The SIT
+//! only tracks which blocks are in use, which doesn't matter for reading.
+//!
+//! Roll-forward recovery is never done, so files fsync()ed after the last checkpoint may show stale
+//! data. Encrypted directories and compressed files can't be read, which leaves out most of an
+//! Android userdata partition, but not the directories boot files are put in.
+
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::cell::RefCell;
+use crc::{Algorithm, Crc};
+use snafu::Snafu;
+use crate::bio::{BioError, OpenDevice};
+use crate::fs::{BootFs, DirEntry};
+use crate::kernel_boot::{BootError, Payload};
+
+#[derive(Debug, Snafu)]
+pub enum F2fsError {
+    #[snafu(display("I/O error: {source}"))]
+    Io { source: BioError },
+    #[snafu(display("invalid superblock"))]
+    InvalidSuperblock,
+    #[snafu(display("unsupported features {features:#x}"))]
+    UnsupportedFeatures { features: u32 },
+    #[snafu(display("filesystems on multiple devices are not supported"))]
+    MultipleDevices,
+    #[snafu(display("no valid checkpoint"))]
+    NoCheckpoint,
+    #[snafu(display("corrupt {what}"))]
+    Corrupt { what: &'static str },
+    #[snafu(display("no such file or directory"))]
+    NotFound,
+    #[snafu(display("not a directory"))]
+    NotADirectory,
+    #[snafu(display("not a regular file"))]
+    NotAFile,
+    #[snafu(display("too many levels of symbolic links"))]
+    TooManySymlinks,
+    #[snafu(display("encrypted"))]
+    Encrypted,
+    #[snafu(display("compressed files are not supported"))]
+    Compressed,
+}
+
+impl From<BioError> for F2fsError {
+    fn from(source: BioError) -> Self {
+        F2fsError::Io { source }
+    }
+}
+
+const MAGIC: u32 = 0xf2f52010;
+const BLOCK_SIZE: u64 = 4096;
+const LOG_BLOCK_SIZE: u32 = 12;
+const LOG_BLOCKS_PER_SEG: u32 = 9;
+/// The superblock is at this offset in each of the first two blocks, the second one a backup.
+const SUPERBLOCK_OFFSET: u64 = 1024;
+// Linux allows 40, but nothing we boot from nests symlinks anywhere near that deep.
+const MAX_SYMLINKS: usize = 8;
+
+const FEATURE_EXTRA_ATTR: u32 = 0x8;
+const FEATURE_FLEXIBLE_INLINE_XATTR: u32 = 0x40;
+const FEATURE_SB_CHKSUM: u32 = 0x800;
+/// Everything up to read-only images. None of these change how files are found, the ones that
+/// change how they're read are per inode.
+const FEATURES_SUPPORTED: u32 = 0x7fff;
+
+const CP_COMPACT_SUM_FLAG: u32 = 0x4;
+const CP_UMOUNT_FLAG: u32 = 0x1;
+const CP_LARGE_NAT_BITMAP_FLAG: u32 = 0x400;
+/// Where the checksum of a checkpoint block is, unless it says otherwise.
+const CP_CHKSUM_OFFSET: usize = 4092;
+/// Where the NAT and SIT bitmaps start, i.e. the end of the fixed size part of a checkpoint block.
+const CP_BITMAP_OFFSET: usize = 192;
+
+const NAT_ENTRY_SIZE: u64 = 9;
+const NAT_ENTRY_PER_BLOCK: u64 = BLOCK_SIZE / NAT_ENTRY_SIZE;
+/// Offset of the journal in a data summary block, after 512 summary entries.
+const SUM_JOURNAL_OFFSET: usize = 512 * 7;
+const NAT_JOURNAL_ENTRY_SIZE: usize = 13;
+const NAT_JOURNAL_ENTRIES: usize = 38;
+/// Current data and node segments, each with its own summary block.
+const NR_CURSEG_DATA_TYPE: u32 = 3;
+const NR_CURSEG_TYPE: u32 = 6;
+
+const NULL_ADDR: u32 = 0;
+const NEW_ADDR: u32 = 0xffffffff;
+
+const INODE_I_ADDR: usize = 360;
+const INODE_I_NID: usize = 4052;
+const NODE_FOOTER: usize = 4072;
+const DEF_ADDRS_PER_INODE: usize = 923;
+const DEFAULT_INLINE_XATTR_ADDRS: usize = 50;
+const ADDRS_PER_BLOCK: u64 = 1018;
+const NIDS_PER_BLOCK: u64 = 1018;
+
+const INLINE_XATTR: u8 = 0x1;
+const INLINE_DATA: u8 = 0x2;
+const INLINE_DENTRY: u8 = 0x4;
+const EXTRA_ATTR: u8 = 0x20;
+const FADVISE_ENCRYPT: u8 = 0x4;
+const COMPR_FL: u32 = 0x4;
+
+const S_IFMT: u16 = 0xf000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFREG: u16 = 0x8000;
+const S_IFLNK: u16 = 0xa000;
+
+const FT_DIR: u8 = 2;
+
+const DENTRY_SIZE: usize = 11;
+const DENTRY_SLOT_LEN: usize = 8;
+const NR_DENTRY_IN_BLOCK: usize = 214;
+/// Where the dentries in a dentry block start, after the bitmap and some reserved bytes.
+const DENTRY_OFFSET: usize = 30;
+
+/// f2fs_crc32(): crc32_le() seeded with the magic and without a final xor. The crc crate reflects
+/// `init` itself.
+const CRC32: Crc<u32> = Crc::<u32>::new(&Algorithm {
+    width: 32,
+    poly: 0x04c11db7,
+    init: MAGIC.reverse_bits(),
+    refin: true,
+    refout: true,
+    xorout: 0,
+    check: 0,
+    residue: 0,
+});
+
+pub struct F2fs {
+    dev: RefCell<OpenDevice>,
+    features: u32,
+    root_ino: u32,
+    nat_blkaddr: u64,
+    max_nid: u64,
+    /// Which of the two copies of each NAT block is current.
+    nat_bitmap: Vec<u8>,
+    /// NAT entries that changed since the NAT was last written.
+    nat_journal: BTreeMap<u32, u32>,
+    /// The last NAT block read, most lookups hit the same one.
+    nat_cache: RefCell<Option<(u64, Vec<u8>)>>,
+}
+
+#[derive(Clone)]
+struct Inode {
+    raw: Vec<u8>,
+    /// Words of `i_addr` taken by extra attributes at the start.
+    extra: usize,
+    /// Words of `i_addr` that point to data blocks, or are inline data.
+    addrs: usize,
+}
+
+impl Inode {
+    fn mode(&self) -> u16 {
+        LittleEndian::read_u16(&self.raw)
+    }
+
+    fn size(&self) -> u64 {
+        LittleEndian::read_u64(&self.raw[16..])
+    }
+
+    fn is_dir(&self) -> bool {
+        self.mode() & S_IFMT == S_IFDIR
+    }
+
+    fn is_file(&self) -> bool {
+        self.mode() & S_IFMT == S_IFREG
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.mode() & S_IFMT == S_IFLNK
+    }
+
+    fn is_inline(&self) -> bool {
+        self.raw[3] & (INLINE_DATA | INLINE_DENTRY) != 0
+    }
+
+    /// Inline data and dentries skip the first data address.
+    fn inline_data(&self) -> &[u8] {
+        &self.raw[INODE_I_ADDR + (self.extra + 1) * 4..][..(self.addrs - 1) * 4]
+    }
+
+    fn addr(&self, index: usize) -> u32 {
+        LittleEndian::read_u32(&self.raw[INODE_I_ADDR + (self.extra + index) * 4..])
+    }
+
+    fn nid(&self, index: usize) -> u32 {
+        LittleEndian::read_u32(&self.raw[INODE_I_NID + index * 4..])
+    }
+}
+
+impl F2fs {
+    pub fn new(mut dev: OpenDevice) -> Result<Self, F2fsError> {
+        let mut sb = vec![0; BLOCK_SIZE as usize - SUPERBLOCK_OFFSET as usize];
+        let mut valid = false;
+        for block in 0..2 {
+            dev.read_at(block * BLOCK_SIZE + SUPERBLOCK_OFFSET, &mut sb)?;
+            if superblock_valid(&sb) {
+                valid = true;
+                break;
+            }
+        }
+        if !valid {
+            return Err(F2fsError::InvalidSuperblock);
+        }
+
+        let features = LittleEndian::read_u32(&sb[2180..]);
+        if features & !FEATURES_SUPPORTED != 0 {
+            return Err(F2fsError::UnsupportedFeatures { features: features & !FEATURES_SUPPORTED });
+        }
+        // The path of the first extra device, if any.
+        if sb[2201] != 0 {
+            return Err(F2fsError::MultipleDevices);
+        }
+
+        // There are two checkpoint packs in consecutive segments, the newer valid one is current.
+        let cp_blkaddr = LittleEndian::read_u32(&sb[76..]) as u64;
+        let mut checkpoint = None;
+        for pack in 0..2 {
+            let addr = cp_blkaddr + (pack << LOG_BLOCKS_PER_SEG);
+            if let Some((version, cp)) = read_checkpoint(&mut dev, addr)? {
+                if checkpoint.as_ref().is_none_or(|&(v, _, _)| version > v) {
+                    checkpoint = Some((version, addr, cp));
+                }
+            }
+        }
+        let (_, cp_addr, cp) = checkpoint.ok_or(F2fsError::NoCheckpoint)?;
+        let cp_flags = LittleEndian::read_u32(&cp[132..]);
+
+        let sit_bitmap_size = LittleEndian::read_u32(&cp[156..]) as usize;
+        let nat_bitmap_size = LittleEndian::read_u32(&cp[160..]) as usize;
+        // See __bitmap_ptr() in Linux: where the NAT bitmap is depends on what else had to fit.
+        let nat_bitmap_offset = if cp_flags & CP_LARGE_NAT_BITMAP_FLAG != 0 {
+            CP_BITMAP_OFFSET + 4
+        } else if LittleEndian::read_u32(&sb[1664..]) > 0 {
+            CP_BITMAP_OFFSET
+        } else {
+            CP_BITMAP_OFFSET + sit_bitmap_size
+        };
+        let nat_bitmap = cp.get(nat_bitmap_offset..nat_bitmap_offset + nat_bitmap_size)
+            .ok_or(F2fsError::Corrupt { what: "checkpoint" })?
+            .to_vec();
+
+        // The NAT journal is in the summary of the current hot data segment.
+        let cp_blocks = LittleEndian::read_u32(&cp[136..]) as u64;
+        let (summary_addr, journal_offset) = if cp_flags & CP_COMPACT_SUM_FLAG != 0 {
+            (cp_addr + LittleEndian::read_u32(&cp[140..]) as u64, 0)
+        } else {
+            let summaries = if cp_flags & CP_UMOUNT_FLAG != 0 { NR_CURSEG_TYPE } else { NR_CURSEG_DATA_TYPE };
+            (cp_addr + cp_blocks - (summaries as u64 + 1), SUM_JOURNAL_OFFSET)
+        };
+        let mut summary = vec![0; BLOCK_SIZE as usize];
+        dev.read_at(summary_addr * BLOCK_SIZE, &mut summary)?;
+        let journal = &summary[journal_offset..];
+        let nat_journal = journal[2..]
+            .chunks_exact(NAT_JOURNAL_ENTRY_SIZE)
+            .take((LittleEndian::read_u16(journal) as usize).min(NAT_JOURNAL_ENTRIES))
+            .map(|v| (LittleEndian::read_u32(v), LittleEndian::read_u32(&v[9..])))
+            .collect();
+
+        // Each NAT block is there twice, so half of the NAT segments hold distinct entries.
+        let nat_segments = LittleEndian::read_u32(&sb[60..]) as u64 / 2;
+        Ok(Self {
+            dev: RefCell::new(dev),
+            features,
+            root_ino: LittleEndian::read_u32(&sb[96..]),
+            nat_blkaddr: LittleEndian::read_u32(&sb[84..]) as u64,
+            max_nid: (nat_segments << LOG_BLOCKS_PER_SEG) * NAT_ENTRY_PER_BLOCK,
+            nat_bitmap,
+            nat_journal,
+            nat_cache: RefCell::new(None),
+        })
+    }
+
+    fn read_block(&self, addr: u64) -> Result<Vec<u8>, F2fsError> {
+        let mut block = vec![0; BLOCK_SIZE as usize];
+        self.dev.borrow_mut().read_at(addr * BLOCK_SIZE, &mut block)?;
+        Ok(block)
+    }
+
+    /// Find where node `nid` is.
+    fn node_addr(&self, nid: u32) -> Result<u32, F2fsError> {
+        if let Some(&addr) = self.nat_journal.get(&nid) {
+            return Ok(addr);
+        }
+        if nid as u64 >= self.max_nid {
+            return Err(F2fsError::Corrupt { what: "node id" });
+        }
+
+        let block = nid as u64 / NAT_ENTRY_PER_BLOCK;
+        let blocks_per_seg = 1 << LOG_BLOCKS_PER_SEG;
+        let mut addr = self.nat_blkaddr + ((block >> LOG_BLOCKS_PER_SEG) << LOG_BLOCKS_PER_SEG << 1)
+            + (block & (blocks_per_seg - 1));
+        // Unlike dentry bitmaps, this one counts from the most significant bit.
+        if self.nat_bitmap.get(block as usize / 8).is_some_and(|v| v & (0x80 >> (block % 8)) != 0) {
+            addr += blocks_per_seg;
+        }
+
+        let mut cache = self.nat_cache.borrow_mut();
+        if cache.as_ref().is_none_or(|(v, _)| *v != addr) {
+            *cache = Some((addr, self.read_block(addr)?));
+        }
+        let (_, nat) = cache.as_ref().unwrap();
+        let entry = &nat[((nid as u64 % NAT_ENTRY_PER_BLOCK) * NAT_ENTRY_SIZE) as usize..];
+        Ok(LittleEndian::read_u32(&entry[5..]))
+    }
+
+    fn node(&self, nid: u32) -> Result<Vec<u8>, F2fsError> {
+        let addr = self.node_addr(nid)?;
+        if addr == NULL_ADDR || addr == NEW_ADDR {
+            return Err(F2fsError::Corrupt { what: "node address" });
+        }
+        let node = self.read_block(addr as u64)?;
+        if LittleEndian::read_u32(&node[NODE_FOOTER..]) != nid {
+            return Err(F2fsError::Corrupt { what: "node footer" });
+        }
+        Ok(node)
+    }
+
+    fn inode(&self, ino: u32) -> Result<Inode, F2fsError> {
+        let raw = self.node(ino)?;
+        let inline = raw[3];
+        let has_extra = inline & EXTRA_ATTR != 0 && self.features & FEATURE_EXTRA_ATTR != 0;
+        let extra = if has_extra { LittleEndian::read_u16(&raw[INODE_I_ADDR..]) as usize / 4 } else { 0 };
+        let xattr = match inline & INLINE_XATTR != 0 {
+            false => 0,
+            true if has_extra && self.features & FEATURE_FLEXIBLE_INLINE_XATTR != 0 => {
+                LittleEndian::read_u16(&raw[INODE_I_ADDR + 2..]) as usize
+            }
+            true => DEFAULT_INLINE_XATTR_ADDRS,
+        };
+        let addrs = DEF_ADDRS_PER_INODE.checked_sub(extra + xattr)
+            .filter(|&v| v > 1)
+            .ok_or(F2fsError::Corrupt { what: "inode" })?;
+        Ok(Inode { raw, extra, addrs })
+    }
+
+    fn file(&self, inode: Inode) -> Result<F2fsFile<'_>, F2fsError> {
+        if LittleEndian::read_u32(&inode.raw[80..]) & COMPR_FL != 0 {
+            return Err(F2fsError::Compressed);
+        }
+        if inode.is_inline() && inode.size() > inode.inline_data().len() as u64 {
+            return Err(F2fsError::Corrupt { what: "inline data" });
+        }
+        Ok(F2fsFile {
+            fs: self,
+            size: inode.size(),
+            inode,
+            nodes: Default::default(),
+        })
+    }
+
+    fn dir_entries(&self, inode: &Inode) -> Result<Vec<(String, u32, u8)>, F2fsError> {
+        if !inode.is_dir() {
+            return Err(F2fsError::NotADirectory);
+        }
+        if inode.raw[2] & FADVISE_ENCRYPT != 0 {
+            return Err(F2fsError::Encrypted);
+        }
+
+        let mut entries = Vec::new();
+        if inode.raw[3] & INLINE_DENTRY != 0 {
+            // Everything is sized to fit in the inline data with the bitmap first and the names last,
+            // see make_dentry_ptr_inline().
+            let data = inode.inline_data();
+            let count = data.len() * 8 / ((DENTRY_SIZE + DENTRY_SLOT_LEN) * 8 + 1);
+            let dentries = data.len() - count * (DENTRY_SIZE + DENTRY_SLOT_LEN);
+            parse_dentries(data, count, dentries, dentries + count * DENTRY_SIZE, &mut entries)?;
+        } else {
+            let mut file = self.file(inode.clone())?;
+            let mut data = vec![0; file.size as usize];
+            file.read(0, &mut data)?;
+            for block in data.chunks_exact(BLOCK_SIZE as usize) {
+                let names = DENTRY_OFFSET + NR_DENTRY_IN_BLOCK * DENTRY_SIZE;
+                parse_dentries(block, NR_DENTRY_IN_BLOCK, DENTRY_OFFSET, names, &mut entries)?;
+            }
+        }
+        Ok(entries)
+    }
+
+    fn symlink_target(&self, inode: Inode) -> Result<String, F2fsError> {
+        if inode.raw[2] & FADVISE_ENCRYPT != 0 {
+            return Err(F2fsError::Encrypted);
+        }
+        let mut file = self.file(inode)?;
+        let mut data = vec![0; file.size as usize];
+        file.read(0, &mut data)?;
+        Ok(String::from_utf8_lossy(&data).into_owned())
+    }
+
+    /// Find the inode at `path`, following symlinks.
+    fn lookup(&self, path: &str) -> Result<Inode, F2fsError> {
+        fn components(path: &str) -> impl DoubleEndedIterator<Item = String> + '_ {
+            path.split('/').filter(|v| !v.is_empty() && *v != ".").map(|v| v.to_string())
+        }
+
+        // Components still to look up, the next one last.
+        let mut remaining = components(path).rev().collect::<Vec<_>>();
+        let mut current = self.inode(self.root_ino)?;
+        let mut symlinks = 0;
+
+        while let Some(name) = remaining.pop() {
+            let (_, ino, _) = self.dir_entries(&current)?
+                .into_iter()
+                .find(|(v, _, _)| *v == name)
+                .ok_or(F2fsError::NotFound)?;
+            let inode = self.inode(ino)?;
+            if !inode.is_symlink() {
+                current = inode;
+                continue;
+            }
+
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(F2fsError::TooManySymlinks);
+            }
+            let target = self.symlink_target(inode)?;
+            remaining.extend(components(&target).rev());
+            if target.starts_with('/') {
+                current = self.inode(self.root_ino)?;
+            }
+        }
+        Ok(current)
+    }
+}
+
+/// Check the magic, geometry and, if there is one, checksum of a superblock.
+fn superblock_valid(sb: &[u8]) -> bool {
+    if LittleEndian::read_u32(sb) != MAGIC
+        || LittleEndian::read_u32(&sb[16..]) != LOG_BLOCK_SIZE
+        || LittleEndian::read_u32(&sb[20..]) != LOG_BLOCKS_PER_SEG {
+        return false;
+    }
+    if LittleEndian::read_u32(&sb[2180..]) & FEATURE_SB_CHKSUM != 0 {
+        let offset = LittleEndian::read_u32(&sb[32..]) as usize;
+        if offset + 4 > sb.len() || CRC32.checksum(&sb[..offset]) != LittleEndian::read_u32(&sb[offset..]) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Read the checkpoint pack at `addr`, returning its version and first block if it's valid. A pack
+/// starts and ends with a copy of the checkpoint block, and is only valid if both copies are the
+/// same version.
+fn read_checkpoint(dev: &mut OpenDevice, addr: u64) -> Result<Option<(u64, Vec<u8>)>, F2fsError> {
+    fn checkpoint_version(cp: &[u8]) -> Option<u64> {
+        let offset = LittleEndian::read_u32(&cp[164..]) as usize;
+        if !(CP_BITMAP_OFFSET..=CP_CHKSUM_OFFSET).contains(&offset) {
+            return None;
+        }
+        // Checkpoints with large NAT bitmaps move the checksum, but still cover the whole block.
+        let mut digest = CRC32.digest();
+        digest.update(&cp[..offset]);
+        digest.update(&cp[offset + 4..]);
+        (digest.finalize() == LittleEndian::read_u32(&cp[offset..])).then(|| LittleEndian::read_u64(cp))
+    }
+
+    let mut first = vec![0; BLOCK_SIZE as usize];
+    dev.read_at(addr * BLOCK_SIZE, &mut first)?;
+    let Some(version) = checkpoint_version(&first) else {
+        return Ok(None);
+    };
+    let blocks = LittleEndian::read_u32(&first[136..]) as u64;
+    if blocks < 2 || blocks > 1 << LOG_BLOCKS_PER_SEG {
+        return Ok(None);
+    }
+
+    let mut last = vec![0; BLOCK_SIZE as usize];
+    dev.read_at((addr + blocks - 1) * BLOCK_SIZE, &mut last)?;
+    Ok((checkpoint_version(&last) == Some(version)).then_some((version, first)))
+}
+
+/// Parse the dentries in a dentry block or inline dentry area: a bitmap of used slots, the
+/// dentries, then the names, 8 bytes per slot. Long names take up several slots.
+fn parse_dentries(data: &[u8], count: usize, dentries: usize, names: usize, entries: &mut Vec<(String, u32, u8)>) -> Result<(), F2fsError> {
+    let corrupt = || F2fsError::Corrupt { what: "dentry" };
+    let mut slot = 0;
+    while slot < count {
+        if data[slot / 8] & (1 << (slot % 8)) == 0 {
+            slot += 1;
+            continue;
+        }
+        let dentry = &data[dentries + slot * DENTRY_SIZE..][..DENTRY_SIZE];
+        let name_len = LittleEndian::read_u16(&dentry[8..]) as usize;
+        let name = data.get(names + slot * DENTRY_SLOT_LEN..)
+            .and_then(|v| v.get(..name_len))
+            .filter(|_| name_len > 0 && slot * DENTRY_SLOT_LEN + name_len <= count * DENTRY_SLOT_LEN)
+            .ok_or_else(corrupt)?;
+        entries.push((String::from_utf8_lossy(name).into_owned(), LittleEndian::read_u32(&dentry[4..]), dentry[10]));
+        slot += name_len.div_ceil(DENTRY_SLOT_LEN);
+    }
+    Ok(())
+}
+
+pub struct F2fsFile<'a> {
+    fs: &'a F2fs,
+    size: u64,
+    inode: Inode,
+    /// The last direct, indirect and double indirect node read, by node id.
+    nodes: [Option<(u32, Vec<u8>)>; 3],
+}
+
+impl F2fsFile<'_> {
+    /// A node along the way to a data block, `depth` being how many indirect levels are below.
+    fn node(&mut self, depth: usize, nid: u32) -> Result<&[u8], F2fsError> {
+        if self.nodes[depth].as_ref().is_none_or(|(v, _)| *v != nid) {
+            self.nodes[depth] = Some((nid, self.fs.node(nid)?));
+        }
+        Ok(&self.nodes[depth].as_ref().unwrap().1)
+    }
+
+    /// Find where block `index` of the file is, if anywhere.
+    fn block_addr(&mut self, index: u64) -> Result<Option<u32>, F2fsError> {
+        let mut index = index;
+        let addr = if index < self.inode.addrs as u64 {
+            self.inode.addr(index as usize)
+        } else {
+            index -= self.inode.addrs as u64;
+            // Two direct nodes, two indirect nodes and a double indirect node.
+            let mut found = None;
+            for (i, levels) in [0, 0, 1, 1, 2].into_iter().enumerate() {
+                let span = ADDRS_PER_BLOCK * NIDS_PER_BLOCK.pow(levels);
+                if index >= span {
+                    index -= span;
+                    continue;
+                }
+                let mut nid = self.inode.nid(i);
+                for depth in (0..levels).rev() {
+                    if nid == 0 {
+                        break;
+                    }
+                    let per_entry = ADDRS_PER_BLOCK * NIDS_PER_BLOCK.pow(depth);
+                    nid = LittleEndian::read_u32(&self.node(depth as usize + 1, nid)?[(index / per_entry) as usize * 4..]);
+                    index %= per_entry;
+                }
+                found = Some(match nid {
+                    0 => NULL_ADDR,
+                    nid => LittleEndian::read_u32(&self.node(0, nid)?[index as usize * 4..]),
+                });
+                break;
+            }
+            found.ok_or(F2fsError::Corrupt { what: "file size" })?
+        };
+        Ok((addr != NULL_ADDR && addr != NEW_ADDR).then_some(addr))
+    }
+
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), F2fsError> {
+        if offset.checked_add(buf.len() as u64).is_none_or(|end| end > self.size) {
+            return Err(F2fsError::Corrupt { what: "file size" });
+        }
+        if self.inode.is_inline() {
+            buf.copy_from_slice(&self.inode.inline_data()[offset as usize..][..buf.len()]);
+            return Ok(());
+        }
+
+        let mut offset = offset;
+        let mut buf = buf;
+        while !buf.is_empty() {
+            let index = offset / BLOCK_SIZE;
+            let within = offset % BLOCK_SIZE;
+            let start = self.block_addr(index)?;
+            // Read runs of consecutive blocks in one go.
+            let mut blocks = 1;
+            while within + blocks * BLOCK_SIZE < buf.len() as u64 {
+                let next = self.block_addr(index + blocks)?;
+                if start.zip(next).is_none_or(|(a, b)| b as u64 != a as u64 + blocks) {
+                    break;
+                }
+                blocks += 1;
+            }
+
+            let len = (blocks * BLOCK_SIZE - within).min(buf.len() as u64) as usize;
+            let (chunk, rest) = buf.split_at_mut(len);
+            match start {
+                Some(addr) => self.fs.dev.borrow_mut().read_at(addr as u64 * BLOCK_SIZE + within, chunk)?,
+                None => chunk.fill(0),
+            }
+            offset += len as u64;
+            buf = rest;
+        }
+        Ok(())
+    }
+}
+
+impl Payload for F2fsFile<'_> {
+    fn len(&self) -> Result<u64, BootError> {
+        Ok(self.size)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BootError> {
+        self.read(offset, buf).map_err(|_| BootError::Io)
+    }
+}
+
+impl BootFs for F2fs {
+    fn open(&self, path: &str) -> Result<Box<dyn Payload + '_>, BootError> {
+        let file = self.lookup(path).and_then(|inode| match inode.is_file() {
+            true => self.file(inode),
+            false => Err(F2fsError::NotAFile),
+        });
+        Ok(Box::new(file.map_err(|_| BootError::Io)?))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, BootError> {
+        let entries = self.lookup(path).and_then(|inode| self.dir_entries(&inode)).map_err(|_| BootError::Io)?;
+        Ok(entries.into_iter()
+            .filter(|(name, _, _)| name != "." && name != "..")
+            .map(|(name, _, ty)| DirEntry { name, is_dir: ty == FT_DIR })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FT_REG_FILE: u8 = 1;
+    const FT_SYMLINK: u8 = 7;
+
+    /// The two checkpoint packs are a segment apart.
+    const CP_ADDR: u64 = 2;
+    const NAT_ADDR: u64 = 520;
+    const NODE_ADDR: u32 = 600;
+    const DATA_ADDR: u32 = 700;
+    const IMAGE_BLOCKS: usize = 1040;
+    const BITMAP_SIZE: usize = 64;
+
+    const ROOT_INO: u32 = 3;
+    const BOOT_INO: u32 = 4;
+    const INLINE_INO: u32 = 5;
+    const LOADER_INO: u32 = 6;
+    /// Only in the NAT journal, at a different address in each checkpoint.
+    const JOURNAL_INO: u32 = 7;
+    const VMLINUZ_INO: u32 = 8;
+    const CONF_INO: u32 = 9;
+    const VMLINUZ_NODE: u32 = 10;
+
+    const CONF_NAME: &str = "a-rather-long-file-name.conf";
+
+    fn block(image: &mut [u8], addr: u64) -> &mut [u8] {
+        &mut image[(addr * BLOCK_SIZE) as usize..][..BLOCK_SIZE as usize]
+    }
+
+    fn inode(mode: u16, inline: u8, size: u64) -> Vec<u8> {
+        let mut node = vec![0; BLOCK_SIZE as usize];
+        LittleEndian::write_u16(&mut node, mode);
+        node[3] = inline;
+        LittleEndian::write_u64(&mut node[16..], size);
+        node
+    }
+
+    /// Where inline data and dentries go in an inode without extra attributes or inline xattrs.
+    fn inline_area(node: &mut [u8]) -> &mut [u8] {
+        &mut node[INODE_I_ADDR + 4..][..(DEF_ADDRS_PER_INODE - 1) * 4]
+    }
+
+    fn inline_file(mode: u16, data: &[u8]) -> Vec<u8> {
+        let mut node = inode(mode, INLINE_DATA, data.len() as u64);
+        inline_area(&mut node)[..data.len()].copy_from_slice(data);
+        node
+    }
+
+    fn write_dentries(data: &mut [u8], dentries: usize, names: usize, entries: &[(&str, u32, u8)]) {
+        let mut slot = 0;
+        for &(name, ino, ty) in entries {
+            let slots = name.len().div_ceil(DENTRY_SLOT_LEN);
+            for i in slot..slot + slots {
+                data[i / 8] |= 1 << (i % 8);
+            }
+            let dentry = &mut data[dentries + slot * DENTRY_SIZE..][..DENTRY_SIZE];
+            LittleEndian::write_u32(&mut dentry[4..], ino);
+            LittleEndian::write_u16(&mut dentry[8..], name.len() as u16);
+            dentry[10] = ty;
+            data[names + slot * DENTRY_SLOT_LEN..][..name.len()].copy_from_slice(name.as_bytes());
+            slot += slots;
+        }
+    }
+
+    /// Put node `nid` at its own address and point the NAT at it. Only the second copy of the NAT
+    /// block is current, the first one is left pointing nowhere.
+    fn write_node(image: &mut [u8], nid: u32, node: &[u8]) {
+        let addr = NODE_ADDR + nid;
+        write_node_at(image, nid, addr, node);
+        let entry = &mut block(image, NAT_ADDR + (1 << LOG_BLOCKS_PER_SEG))[nid as usize * NAT_ENTRY_SIZE as usize..];
+        LittleEndian::write_u32(&mut entry[1..], nid);
+        LittleEndian::write_u32(&mut entry[5..], addr);
+    }
+
+    fn write_node_at(image: &mut [u8], nid: u32, addr: u32, node: &[u8]) {
+        let block = block(image, addr as u64);
+        block.copy_from_slice(node);
+        LittleEndian::write_u32(&mut block[NODE_FOOTER..], nid);
+    }
+
+    /// Write checkpoint pack `pack`, with /journal's node at `journal_addr`. Compact packs keep the
+    /// NAT journal in a compacted summary, others in the summary of the hot data segment.
+    fn write_checkpoint(image: &mut [u8], pack: u64, version: u64, compact: bool, nat_bitmap: u8, journal_addr: u32) {
+        let addr = CP_ADDR + (pack << LOG_BLOCKS_PER_SEG);
+        let (blocks, summary, journal_offset) = match compact {
+            true => (3, 1, 0),
+            false => (2 + NR_CURSEG_DATA_TYPE, 1, SUM_JOURNAL_OFFSET),
+        };
+
+        let mut cp = vec![0; BLOCK_SIZE as usize];
+        LittleEndian::write_u64(&mut cp, version);
+        LittleEndian::write_u32(&mut cp[132..], if compact { CP_COMPACT_SUM_FLAG } else { 0 });
+        LittleEndian::write_u32(&mut cp[136..], blocks);
+        LittleEndian::write_u32(&mut cp[140..], summary);
+        LittleEndian::write_u32(&mut cp[156..], BITMAP_SIZE as u32);
+        LittleEndian::write_u32(&mut cp[160..], BITMAP_SIZE as u32);
+        LittleEndian::write_u32(&mut cp[164..], CP_CHKSUM_OFFSET as u32);
+        cp[CP_BITMAP_OFFSET + BITMAP_SIZE] = nat_bitmap;
+        let checksum = CRC32.checksum(&cp[..CP_CHKSUM_OFFSET]);
+        LittleEndian::write_u32(&mut cp[CP_CHKSUM_OFFSET..], checksum);
+        block(image, addr).copy_from_slice(&cp);
+        block(image, addr + blocks as u64 - 1).copy_from_slice(&cp);
+
+        let journal = &mut block(image, addr + summary as u64)[journal_offset..];
+        LittleEndian::write_u16(journal, 1);
+        LittleEndian::write_u32(&mut journal[2..], JOURNAL_INO);
+        LittleEndian::write_u32(&mut journal[2 + 5..], JOURNAL_INO);
+        LittleEndian::write_u32(&mut journal[2 + 9..], journal_addr);
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 13 + i / BLOCK_SIZE as usize) as u8).collect()
+    }
+
+    /// /boot/vmlinuz: 7 blocks with the third one a hole. The inode only has room for 4 block
+    /// addresses, so the rest are in a direct node.
+    fn vmlinuz() -> Vec<u8> {
+        let mut data = data(7 * BLOCK_SIZE as usize - 100);
+        data[2 * BLOCK_SIZE as usize..3 * BLOCK_SIZE as usize].fill(0);
+        data
+    }
+
+    /// A filesystem with two valid checkpoints, the second one newer, and:
+    /// - /boot/vmlinuz
+    /// - /boot/a-rather-long-file-name.conf
+    /// - /inline
+    /// - /loader, a symlink to boot
+    /// - /journal, reading "old" or "new" depending on the checkpoint
+    ///
+    /// The root directory's dentries are inline, /boot's are in a dentry block.
+    fn image() -> Vec<u8> {
+        let mut image = vec![0; IMAGE_BLOCKS * BLOCK_SIZE as usize];
+
+        let sb = &mut image[SUPERBLOCK_OFFSET as usize..];
+        LittleEndian::write_u32(sb, MAGIC);
+        LittleEndian::write_u32(&mut sb[16..], LOG_BLOCK_SIZE);
+        LittleEndian::write_u32(&mut sb[20..], LOG_BLOCKS_PER_SEG);
+        LittleEndian::write_u32(&mut sb[60..], 2);
+        LittleEndian::write_u32(&mut sb[76..], CP_ADDR as u32);
+        LittleEndian::write_u32(&mut sb[84..], NAT_ADDR as u32);
+        LittleEndian::write_u32(&mut sb[96..], ROOT_INO);
+        LittleEndian::write_u32(&mut sb[2180..], FEATURE_EXTRA_ATTR | FEATURE_FLEXIBLE_INLINE_XATTR);
+
+        write_checkpoint(&mut image, 0, 5, false, 0x80, NODE_ADDR + 50);
+        write_checkpoint(&mut image, 1, 6, true, 0x80, NODE_ADDR + 51);
+        write_node_at(&mut image, JOURNAL_INO, NODE_ADDR + 50, &inline_file(S_IFREG | 0o644, b"old"));
+        write_node_at(&mut image, JOURNAL_INO, NODE_ADDR + 51, &inline_file(S_IFREG | 0o644, b"new"));
+
+        let mut root = inode(S_IFDIR | 0o755, INLINE_DENTRY, BLOCK_SIZE);
+        let area = inline_area(&mut root);
+        let count = area.len() * 8 / ((DENTRY_SIZE + DENTRY_SLOT_LEN) * 8 + 1);
+        let dentries = area.len() - count * (DENTRY_SIZE + DENTRY_SLOT_LEN);
+        write_dentries(area, dentries, dentries + count * DENTRY_SIZE, &[
+            (".", ROOT_INO, FT_DIR),
+            ("..", ROOT_INO, FT_DIR),
+            ("boot", BOOT_INO, FT_DIR),
+            ("inline", INLINE_INO, FT_REG_FILE),
+            ("loader", LOADER_INO, FT_SYMLINK),
+            ("journal", JOURNAL_INO, FT_REG_FILE),
+        ]);
+        write_node(&mut image, ROOT_INO, &root);
+
+        let mut boot = inode(S_IFDIR | 0o755, 0, BLOCK_SIZE);
+        LittleEndian::write_u32(&mut boot[INODE_I_ADDR..], DATA_ADDR);
+        write_node(&mut image, BOOT_INO, &boot);
+        let dentry_block = block(&mut image, DATA_ADDR as u64);
+        let names = DENTRY_OFFSET + NR_DENTRY_IN_BLOCK * DENTRY_SIZE;
+        write_dentries(dentry_block, DENTRY_OFFSET, names, &[
+            (".", BOOT_INO, FT_DIR),
+            ("..", ROOT_INO, FT_DIR),
+            ("vmlinuz", VMLINUZ_INO, FT_REG_FILE),
+            (CONF_NAME, CONF_INO, FT_REG_FILE),
+        ]);
+
+        write_node(&mut image, INLINE_INO, &inline_file(S_IFREG | 0o644, b"inline data\n"));
+        write_node(&mut image, LOADER_INO, &inline_file(S_IFLNK | 0o777, b"boot"));
+        write_node(&mut image, CONF_INO, &inline_file(S_IFREG | 0o644, b"title Linux\n"));
+
+        // Extra attributes and a large inline xattr area leave 4 block addresses.
+        let vmlinuz = vmlinuz();
+        let mut node = inode(S_IFREG | 0o644, EXTRA_ATTR | INLINE_XATTR, vmlinuz.len() as u64);
+        LittleEndian::write_u16(&mut node[INODE_I_ADDR..], 8);
+        LittleEndian::write_u16(&mut node[INODE_I_ADDR + 2..], (DEF_ADDRS_PER_INODE - 2 - 4) as u16);
+        LittleEndian::write_u32(&mut node[INODE_I_NID..], VMLINUZ_NODE);
+        let mut direct = vec![0; BLOCK_SIZE as usize];
+        for (i, chunk) in vmlinuz.chunks(BLOCK_SIZE as usize).enumerate() {
+            if i == 2 {
+                continue;
+            }
+            let addr = DATA_ADDR + 1 + i as u32;
+            block(&mut image, addr as u64)[..chunk.len()].copy_from_slice(chunk);
+            match i {
+                0..4 => LittleEndian::write_u32(&mut node[INODE_I_ADDR + (2 + i) * 4..], addr),
+                _ => LittleEndian::write_u32(&mut direct[(i - 4) * 4..], addr),
+            }
+        }
+        write_node(&mut image, VMLINUZ_INO, &node);
+        write_node(&mut image, VMLINUZ_NODE, &direct);
+        image
+    }
+
+    fn open(image: Vec<u8>) -> Result<F2fs, F2fsError> {
+        let size = image.len() as u64;
+        F2fs::new(OpenDevice::from_image(Box::new(image), size))
+    }
+
+    fn read(fs: &F2fs, path: &str) -> Vec<u8> {
+        BootFs::read(fs, path).unwrap()
+    }
+
+    #[test]
+    fn checkpoint_selection() {
+        assert_eq!(read(&open(image()).unwrap(), "/journal"), b"new");
+
+        // The newer pack is only valid if its last block matches the first.
+        let mut torn = image();
+        block(&mut torn, CP_ADDR + (1 << LOG_BLOCKS_PER_SEG) + 2)[0] = 7;
+        assert_eq!(read(&open(torn).unwrap(), "/journal"), b"old");
+
+        let mut corrupt = image();
+        block(&mut corrupt, CP_ADDR + (1 << LOG_BLOCKS_PER_SEG))[100] ^= 0x1;
+        assert_eq!(read(&open(corrupt.clone()).unwrap(), "/journal"), b"old");
+
+        block(&mut corrupt, CP_ADDR)[100] ^= 0x1;
+        assert!(matches!(open(corrupt), Err(F2fsError::NoCheckpoint)));
+    }
+
+    #[test]
+    fn nat() {
+        let fs = open(image()).unwrap();
+        assert_eq!(fs.nat_journal.get(&JOURNAL_INO), Some(&(NODE_ADDR + 51)));
+        assert_eq!(fs.node_addr(ROOT_INO).unwrap(), NODE_ADDR + ROOT_INO);
+
+        // Without the bitmap pointing at the second copy of the NAT block, nothing is found.
+        let mut image = image();
+        write_checkpoint(&mut image, 1, 6, true, 0, NODE_ADDR + 51);
+        let fs = open(image).unwrap();
+        assert!(fs.node_addr(ROOT_INO).is_ok_and(|v| v == NULL_ADDR));
+        assert!(fs.read_dir("/").is_err());
+        // The journal doesn't depend on it.
+        assert_eq!(fs.node_addr(JOURNAL_INO).unwrap(), NODE_ADDR + 51);
+    }
+
+    #[test]
+    fn inline_dentries() {
+        let fs = open(image()).unwrap();
+        let root = fs.read_dir("/").unwrap().into_iter().map(|v| (v.name, v.is_dir)).collect::<Vec<_>>();
+        assert_eq!(root, [
+            ("boot".into(), true),
+            ("inline".into(), false),
+            ("loader".into(), false),
+            ("journal".into(), false),
+        ]);
+        let boot = fs.read_dir("/boot").unwrap().into_iter().map(|v| v.name).collect::<Vec<_>>();
+        assert_eq!(boot, ["vmlinuz", CONF_NAME]);
+    }
+
+    #[test]
+    fn inline_data() {
+        let fs = open(image()).unwrap();
+        assert_eq!(read(&fs, "/inline"), b"inline data\n");
+        assert_eq!(read(&fs, &alloc::format!("/loader/{CONF_NAME}")), b"title Linux\n");
+        let mut buf = [0; 4];
+        assert!(fs.open("/inline").unwrap().read_at(10, &mut buf).is_err());
+    }
+
+    #[test]
+    fn direct_node() {
+        let fs = open(image()).unwrap();
+        assert!(read(&fs, "/boot/vmlinuz") == vmlinuz());
+        // Starting in the inode and ending in the direct node.
+        let mut buf = vec![0; 2 * BLOCK_SIZE as usize];
+        fs.open("/boot/vmlinuz").unwrap().read_at(3 * BLOCK_SIZE + 10, &mut buf).unwrap();
+        assert!(buf == vmlinuz()[3 * BLOCK_SIZE as usize + 10..][..buf.len()]);
+    }
+}
@@ -0,0 +1,331 @@
+//! Safe wrappers around libfdt, and a way to hook into the devicetree fixups done by `boot_linux`.
+
+use alloc::ffi::CString;
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder};
+use core::ffi::{c_char, c_int, c_uint, c_void, CStr};
+use snafu::Snafu;
+
+#[derive(Debug, Snafu)]
+pub enum FdtError {
+    #[snafu(display("libfdt error {code}"))]
+    Libfdt { code: c_int },
+    InvalidString,
+}
+
+// From libfdt.h, used when a lookup fails without libfdt telling us why.
+const FDT_ERR_NOTFOUND: c_int = 1;
+const FDT_ERR_BADSTRUCTURE: c_int = 11;
+
+fn check(ret: c_int) -> Result<c_int, FdtError> {
+    if ret < 0 {
+        Err(FdtError::Libfdt { code: ret })
+    } else {
+        Ok(ret)
+    }
+}
+
+fn cstring(s: &str) -> Result<CString, FdtError> {
+    CString::new(s).map_err(|_| FdtError::InvalidString)
+}
+
+/// A read-only view of a flattened devicetree blob.
+pub struct Fdt<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, FdtError> {
+        check(unsafe { sys::fdt_check_full(data.as_ptr() as _, data.len()) })?;
+        Ok(Self { data })
+    }
+
+    fn ptr(&self) -> *const c_void {
+        self.data.as_ptr() as _
+    }
+
+    pub fn path_offset(&self, path: &str) -> Result<c_int, FdtError> {
+        let path = cstring(path)?;
+        check(unsafe { sys::fdt_path_offset(self.ptr(), path.as_ptr()) })
+    }
+
+    pub fn subnode_offset(&self, parent: c_int, name: &str) -> Result<c_int, FdtError> {
+        let name = cstring(name)?;
+        check(unsafe { sys::fdt_subnode_offset(self.ptr(), parent, name.as_ptr()) })
+    }
+
+    pub fn parent_offset(&self, node: c_int) -> Result<c_int, FdtError> {
+        check(unsafe { sys::fdt_parent_offset(self.ptr(), node) })
+    }
+
+    /// Offsets of all direct children of `node`.
+    pub fn subnodes(&self, node: c_int) -> Vec<c_int> {
+        let mut nodes = Vec::new();
+        let mut offset = unsafe { sys::fdt_first_subnode(self.ptr(), node) };
+        while offset >= 0 {
+            nodes.push(offset);
+            offset = unsafe { sys::fdt_next_subnode(self.ptr(), offset) };
+        }
+        nodes
+    }
+
+    pub fn name(&self, node: c_int) -> Result<&'a str, FdtError> {
+        let mut len = 0;
+        let ptr = unsafe { sys::fdt_get_name(self.ptr(), node, &mut len) };
+        if ptr.is_null() {
+            return Err(FdtError::Libfdt { code: len });
+        }
+        let name = unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) };
+        core::str::from_utf8(name).map_err(|_| FdtError::InvalidString)
+    }
+
+    pub fn property(&self, node: c_int, name: &str) -> Result<&'a [u8], FdtError> {
+        let name = cstring(name)?;
+        let mut len = 0;
+        let ptr = unsafe { sys::fdt_getprop(self.ptr(), node, name.as_ptr(), &mut len) };
+        if ptr.is_null() {
+            return Err(FdtError::Libfdt { code: len });
+        }
+        Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+    }
+
+    pub fn property_u32(&self, node: c_int, name: &str) -> Result<u32, FdtError> {
+        let value = self.property(node, name)?;
+        if value.len() != 4 {
+            return Err(FdtError::Libfdt { code: -FDT_ERR_BADSTRUCTURE });
+        }
+        Ok(BigEndian::read_u32(value))
+    }
+
+    pub fn property_str(&self, node: c_int, name: &str) -> Result<&'a str, FdtError> {
+        self.strings(node, name)?.first().copied().ok_or(FdtError::InvalidString)
+    }
+
+    /// All strings in a stringlist property such as `compatible`.
+    pub fn strings(&self, node: c_int, name: &str) -> Result<Vec<&'a str>, FdtError> {
+        Ok(self.property(node, name)?
+            .split(|&c| c == 0)
+            .filter(|v| !v.is_empty())
+            .filter_map(|v| core::str::from_utf8(v).ok())
+            .collect())
+    }
+
+    pub fn is_compatible(&self, node: c_int, compatible: &str) -> bool {
+        let Ok(compatible) = CString::new(compatible) else {
+            return false;
+        };
+        unsafe { sys::fdt_node_check_compatible(self.ptr(), node, compatible.as_ptr()) == 0 }
+    }
+
+    /// See lkfdt_node_is_available().
+    pub fn is_available(&self, node: c_int) -> bool {
+        unsafe { sys::lkfdt_node_is_available(self.ptr(), node) }
+    }
+
+    /// See lkfdt_get_reg(). Returns the address and size of the first entry in `reg`.
+    pub fn reg(&self, parent: c_int, node: c_int) -> Result<(u32, u32), FdtError> {
+        let (mut addr, mut size) = (0, 0);
+        check(unsafe { sys::lkfdt_get_reg(self.ptr(), parent, node, &mut addr, &mut size) })?;
+        Ok((addr, size))
+    }
+
+    /// See lkfdt_lookup_phandle().
+    pub fn lookup_phandle(&self, node: c_int, name: &str) -> Result<c_int, FdtError> {
+        let name = cstring(name)?;
+        check(unsafe { sys::lkfdt_lookup_phandle(self.ptr(), node, name.as_ptr()) })
+    }
+}
+
+/// A writable devicetree blob. Properties and nodes can only be added as long as there is
+/// free space left between the end of the blob and the end of the buffer.
+pub struct FdtMut<'a> {
+    data: &'a mut [u8],
+}
+
+impl<'a> FdtMut<'a> {
+    pub fn new(data: &'a mut [u8]) -> Result<Self, FdtError> {
+        check(unsafe { sys::fdt_check_full(data.as_ptr() as _, data.len()) })?;
+        Ok(Self { data })
+    }
+
+    /// Wrap a devicetree handed to us by C code, trusting the size in its header.
+    ///
+    /// # Safety
+    /// `fdt` must point to a valid devicetree that is not accessed by anything else for `'a`.
+    pub unsafe fn from_raw(fdt: *mut c_void) -> Result<Self, FdtError> {
+        check(sys::fdt_check_header(fdt))?;
+        let size = BigEndian::read_u32(core::slice::from_raw_parts((fdt as *const u8).add(4), 4));
+        Ok(Self { data: core::slice::from_raw_parts_mut(fdt as *mut u8, size as usize) })
+    }
+
+    /// Reads are done through a read-only view, which can't outlive the next modification.
+    pub fn as_fdt(&self) -> Fdt<'_> {
+        Fdt { data: self.data }
+    }
+
+    fn ptr(&mut self) -> *mut c_void {
+        self.data.as_mut_ptr() as _
+    }
+
+    pub fn path_offset(&self, path: &str) -> Result<c_int, FdtError> {
+        self.as_fdt().path_offset(path)
+    }
+
+    pub fn set_property(&mut self, node: c_int, name: &str, value: &[u8]) -> Result<(), FdtError> {
+        let name = cstring(name)?;
+        check(unsafe { sys::fdt_setprop(self.ptr(), node, name.as_ptr(), value.as_ptr() as _, value.len() as c_int) })?;
+        Ok(())
+    }
+
+    pub fn set_property_u32(&mut self, node: c_int, name: &str, value: u32) -> Result<(), FdtError> {
+        self.set_property(node, name, &value.to_be_bytes())
+    }
+
+    pub fn set_property_u64(&mut self, node: c_int, name: &str, value: u64) -> Result<(), FdtError> {
+        self.set_property(node, name, &value.to_be_bytes())
+    }
+
+    pub fn set_property_str(&mut self, node: c_int, name: &str, value: &str) -> Result<(), FdtError> {
+        let value = cstring(value)?;
+        self.set_property(node, name, value.as_bytes_with_nul())
+    }
+
+    pub fn delete_property(&mut self, node: c_int, name: &str) -> Result<(), FdtError> {
+        let name = cstring(name)?;
+        check(unsafe { sys::fdt_delprop(self.ptr(), node, name.as_ptr()) })?;
+        Ok(())
+    }
+
+    pub fn add_subnode(&mut self, parent: c_int, name: &str) -> Result<c_int, FdtError> {
+        let name = cstring(name)?;
+        check(unsafe { sys::fdt_add_subnode(self.ptr(), parent, name.as_ptr()) })
+    }
+
+    /// Find the `name` child of `parent`, creating it if it doesn't exist yet.
+    pub fn subnode_or_add(&mut self, parent: c_int, name: &str) -> Result<c_int, FdtError> {
+        match self.as_fdt().subnode_offset(parent, name) {
+            Err(FdtError::Libfdt { code }) if code == -FDT_ERR_NOTFOUND => self.add_subnode(parent, name),
+            ret => ret,
+        }
+    }
+
+    pub fn delete_node(&mut self, node: c_int) -> Result<(), FdtError> {
+        check(unsafe { sys::fdt_del_node(self.ptr(), node) })?;
+        Ok(())
+    }
+}
+
+/// `enum boot_type` from app/aboot/boot.h.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootType(pub c_uint);
+
+impl BootType {
+    pub const ARM64: Self = Self(1 << 0);
+    pub const ANDROID: Self = Self(1 << 1);
+    pub const DOWNSTREAM: Self = Self(1 << 2);
+    pub const LK2ND: Self = Self(1 << 3);
+    pub const ATAGS_COPY: Self = Self(1 << 4);
+
+    /// Whether any of the bits in `other` are set.
+    pub fn intersects(self, other: Self) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl core::ops::BitOr for BootType {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// `struct dt_update_handler` from app/aboot/boot.h. Use [`dt_update_handler!`] rather than
+/// building these by hand.
+#[repr(C)]
+pub struct DtUpdateHandler {
+    pub name: *const c_char,
+    pub update_dt: unsafe extern "C" fn(fdt: *mut c_void, cmdline: *const c_char, boot_type: c_uint) -> c_int,
+}
+
+unsafe impl Sync for DtUpdateHandler {}
+
+/// Glue between the C calling convention of dt_update_handler and a Rust handler.
+#[doc(hidden)]
+pub unsafe fn call_dt_update_handler(
+    handler: fn(&mut FdtMut, &str, BootType) -> Result<(), FdtError>,
+    fdt: *mut c_void,
+    cmdline: *const c_char,
+    boot_type: c_uint,
+) -> c_int {
+    let mut fdt = match FdtMut::from_raw(fdt) {
+        Ok(fdt) => fdt,
+        Err(FdtError::Libfdt { code }) => return code,
+        Err(_) => return -FDT_ERR_BADSTRUCTURE,
+    };
+    let cmdline = if cmdline.is_null() {
+        ""
+    } else {
+        CStr::from_ptr(cmdline).to_str().unwrap_or_default()
+    };
+
+    match handler(&mut fdt, cmdline, BootType(boot_type)) {
+        Ok(()) => 0,
+        Err(FdtError::Libfdt { code }) => code,
+        Err(FdtError::InvalidString) => -FDT_ERR_BADSTRUCTURE,
+    }
+}
+
+/// Register a `fn(&mut FdtMut, &str, BootType) -> Result<(), FdtError>` to be run by
+/// `boot_linux` along with the C handlers registered with `DEV_TREE_UPDATE()`.
+/// Like those, returning an error aborts the boot.
+///
+/// The handler ends up in a static named by the first argument. Nothing refers to it but the
+/// section it's in, so code that's linked in anyway must refer to it, e.g. with
+/// `core::hint::black_box(&NAME)`, for the linker to take it out of the archive.
+#[macro_export]
+macro_rules! dt_update_handler {
+    ($name:ident, $handler:ident) => {
+        // Keeping it would pull in libfdt, which host test builds don't link against.
+        #[cfg_attr(not(test), used, link_section = ".dt_update")]
+        static $name: $crate::fdt::DtUpdateHandler = {
+            unsafe extern "C" fn update_dt(
+                fdt: *mut core::ffi::c_void,
+                cmdline: *const core::ffi::c_char,
+                boot_type: core::ffi::c_uint,
+            ) -> core::ffi::c_int {
+                $crate::fdt::call_dt_update_handler($handler, fdt, cmdline, boot_type)
+            }
+
+            $crate::fdt::DtUpdateHandler {
+                name: concat!(stringify!($handler), "\0").as_ptr() as _,
+                update_dt,
+            }
+        };
+    };
+}
+
+mod sys {
+    use core::ffi::{c_char, c_int, c_void};
+
+    extern "C" {
+        pub fn fdt_check_header(fdt: *const c_void) -> c_int;
+        pub fn fdt_check_full(fdt: *const c_void, bufsize: usize) -> c_int;
+        pub fn fdt_path_offset(fdt: *const c_void, path: *const c_char) -> c_int;
+        pub fn fdt_subnode_offset(fdt: *const c_void, parent: c_int, name: *const c_char) -> c_int;
+        pub fn fdt_parent_offset(fdt: *const c_void, node: c_int) -> c_int;
+        pub fn fdt_first_subnode(fdt: *const c_void, node: c_int) -> c_int;
+        pub fn fdt_next_subnode(fdt: *const c_void, node: c_int) -> c_int;
+        pub fn fdt_get_name(fdt: *const c_void, node: c_int, lenp: *mut c_int) -> *const c_char;
+        pub fn fdt_getprop(fdt: *const c_void, node: c_int, name: *const c_char, lenp: *mut c_int) -> *const c_void;
+        pub fn fdt_node_check_compatible(fdt: *const c_void, node: c_int, compatible: *const c_char) -> c_int;
+        pub fn fdt_setprop(fdt: *mut c_void, node: c_int, name: *const c_char, val: *const c_void, len: c_int) -> c_int;
+        pub fn fdt_delprop(fdt: *mut c_void, node: c_int, name: *const c_char) -> c_int;
+        pub fn fdt_add_subnode(fdt: *mut c_void, parent: c_int, name: *const c_char) -> c_int;
+        pub fn fdt_del_node(fdt: *mut c_void, node: c_int) -> c_int;
+
+        pub fn lkfdt_node_is_available(fdt: *const c_void, node: c_int) -> bool;
+        pub fn lkfdt_get_reg(fdt: *const c_void, parent: c_int, node: c_int, addr: *mut u32, size: *mut u32) -> c_int;
+        pub fn lkfdt_lookup_phandle(fdt: *const c_void, node: c_int, prop: *const c_char) -> c_int;
+    }
+}
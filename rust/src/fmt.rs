@@ -18,3 +18,22 @@ macro_rules! println {
         }
     }};
 }
+
+/// Host test builds don't link against LK, print to stdout instead.
+#[cfg(test)]
+mod host {
+    extern crate std;
+
+    use core::ffi::{c_char, CStr};
+
+    #[no_mangle]
+    extern "C" fn _dputs(str: *const c_char) -> i32 {
+        std::print!("{}", unsafe { CStr::from_ptr(str) }.to_string_lossy());
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn _dputc(c: c_char) {
+        std::print!("{}", c as u8 as char);
+    }
+}
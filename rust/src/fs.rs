@@ -0,0 +1,138 @@
+//! Filesystems that boot files can be loaded from.
+
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use fatfs::Write;
+use crate::FatFS;
+use crate::bio::{BioError, Image, OpenDevice};
+use crate::kernel_boot::{BootError, FatRange, Payload};
+
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+/// A view of a filesystem, as far as the boot entry scanners care. Paths are absolute, from the
+/// root of the filesystem. Only FAT can be written to, for boot counting and entry selection.
+pub trait BootFs {
+    fn open(&self, path: &str) -> Result<Box<dyn Payload + '_>, BootError>;
+
+    /// The entries in the directory at `path`, without `.` and `..`.
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, BootError>;
+
+    fn exists(&self, path: &str) -> bool {
+        self.open(path).is_ok()
+    }
+
+    /// Rename the file at `from` to `to`. Only works on filesystems that can be written to.
+    fn rename(&self, _from: &str, _to: &str) -> Result<(), BootError> {
+        Err(BootError::Io)
+    }
+
+    /// Replace the contents of the file at `path` with `data`, creating it if needed. Only works
+    /// on filesystems that can be written to.
+    fn write(&self, _path: &str, _data: &[u8]) -> Result<(), BootError> {
+        Err(BootError::Io)
+    }
+
+    /// Read a whole (small) file, like a config file.
+    fn read(&self, path: &str) -> Result<Vec<u8>, BootError> {
+        let mut file = self.open(path)?;
+        let mut data = vec![0; file.len()? as usize];
+        file.read_at(0, &mut data)?;
+        Ok(data)
+    }
+}
+
+/// Join `path` onto `dir`, treating absolute paths as relative to `dir` like extlinux and BLS do.
+pub fn join(dir: &str, path: &str) -> String {
+    format!("{}/{}", dir.trim_end_matches('/'), path.trim_start_matches('/'))
+}
+
+/// LK's error code for I/O errors.
+const ERR_IO: core::ffi::c_long = -20;
+
+/// Open the file at `path` as a device, to read a filesystem image in it like a partition.
+pub fn open_image(fs: Rc<dyn BootFs>, path: &str) -> Result<OpenDevice, BootError> {
+    let file = fs.open(path)?;
+    let size = file.len()?;
+    // SAFETY: the file borrows from the filesystem, which the image keeps alive, and drops after it.
+    let file = unsafe { core::mem::transmute::<Box<dyn Payload + '_>, Box<dyn Payload + 'static>>(file) };
+    Ok(OpenDevice::from_image(Box::new(ImageFile { file, _fs: fs }), size))
+}
+
+struct ImageFile {
+    // Declared first so it's dropped first.
+    file: Box<dyn Payload>,
+    _fs: Rc<dyn BootFs>,
+}
+
+impl Image for ImageFile {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BioError> {
+        self.file.read_at(offset, buf).map_err(|_| BioError::ReadError { code: ERR_IO })
+    }
+}
+
+impl BootFs for FatFS {
+    fn open(&self, path: &str) -> Result<Box<dyn Payload + '_>, BootError> {
+        Ok(Box::new(FatRange::open(self, path)?))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, BootError> {
+        let path = path.trim_matches('/');
+        let root = self.root_dir();
+        let dir = if path.is_empty() { root } else { root.open_dir(path).map_err(|_| BootError::Io)? };
+        Ok(dir.iter()
+            .flatten()
+            .map(|v| DirEntry { name: v.file_name(), is_dir: v.is_dir() })
+            .filter(|v| v.name != "." && v.name != "..")
+            .collect())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<(), BootError> {
+        let root = self.root_dir();
+        root.rename(from.trim_start_matches('/'), &root, to.trim_start_matches('/')).map_err(|_| BootError::Io)
+    }
+
+    fn write(&self, path: &str, data: &[u8]) -> Result<(), BootError> {
+        let path = path.trim_start_matches('/');
+        // create_dir() opens directories that already exist.
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            self.root_dir().create_dir(parent).map_err(|_| BootError::Io)?;
+        }
+        let mut file = self.root_dir().create_file(path).map_err(|_| BootError::Io)?;
+        file.truncate().map_err(|_| BootError::Io)?;
+        file.write_all(data).map_err(|_| BootError::Io)?;
+        file.flush().map_err(|_| BootError::Io)
+    }
+}
+
+/// A directory of another filesystem, used as if it was the root, e.g. `/boot` on a root
+/// filesystem.
+///
+/// Files that don't exist in the directory are also tried from the real root. Boot entries for a
+/// `/boot` that isn't a partition of its own are written either way, depending on distribution.
+pub struct Subdir {
+    fs: Rc<dyn BootFs>,
+    root: String,
+}
+
+impl Subdir {
+    pub fn new(fs: Rc<dyn BootFs>, root: &str) -> Self {
+        Self { fs, root: root.into() }
+    }
+}
+
+impl BootFs for Subdir {
+    fn open(&self, path: &str) -> Result<Box<dyn Payload + '_>, BootError> {
+        self.fs.open(&join(&self.root, path)).or_else(|_| self.fs.open(path))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, BootError> {
+        self.fs.read_dir(&join(&self.root, path))
+    }
+}
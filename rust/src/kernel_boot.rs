@@ -1,34 +1,71 @@
 use alloc::boxed::Box;
 use alloc::ffi::CString;
+use alloc::format;
+use alloc::rc::Rc;
 use alloc::string::{String, ToString};
-use alloc::sync::Arc;
 use alloc::vec;
-use core::ffi::{c_char, c_uint, c_void};
-use core::ptr::slice_from_raw_parts_mut;
-use byteorder::{ByteOrder, LittleEndian};
+use alloc::vec::Vec;
+use core::ffi::{c_char, c_int, c_uint, c_void, CStr};
+use core::ptr::{null_mut, slice_from_raw_parts_mut};
+use core::sync::atomic::{AtomicPtr, Ordering};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use embedded_graphics::image::Image;
 use embedded_graphics::pixelcolor::Rgb888;
-use fatfs::{DefaultTimeProvider, LossyOemCpConverter, Read, Seek, SeekFrom};
-use object::{File, Object, ObjectSection, ReadCache, ReadCacheOps};
-use snafu::Snafu;
+use fatfs::{Read, Seek, SeekFrom};
+use object::{File, Object, ObjectSection, ReadCache, ReadCacheOps, ReadRef, Section};
+use snafu::{ResultExt, Snafu};
 use tinybmp::Bmp;
-use crate::bio::OpenDevice;
-use crate::{BootOption, FatFS, kernel_boot, println};
+use crate::{BootOption, FatFile, FatFS, lk2nd_device, println};
+use crate::boot_counting::{self, Counter};
+use crate::fs::BootFs;
+use crate::decompress::{zboot_payload, Compression, DecompressError};
+use crate::{dt_update_handler, fdt::{BootType, Fdt, FdtError, FdtMut}};
 use crate::fbcon::FbCon888;
 use embedded_graphics::prelude::*;
 
 pub struct UkiBootConfig {
-    fs: Arc<FatFS>,
+    fs: Rc<dyn BootFs>,
     path: String,
+    id: String,
     name: String,
     kernel: (u64, u64),
     initrd: (u64, u64),
     commandline: Option<CString>,
-    dtb: (u64, u64),
+    dtb: Option<(u64, u64)>,
+    /// `.dtbo` sections embedded in the UKI.
+    overlays: Vec<(u64, u64)>,
+    /// Overlays dropped next to the UKI, applied after the embedded ones.
+    extra_overlays: Vec<String>,
     pub splash: Option<(u64, u64)>,
+    /// Boot counter from the file name, if boot counting is enabled for this UKI.
+    counter: Option<Counter>,
+}
+
+impl UkiBootConfig {
+    /// Count a boot attempt by renaming the UKI to one try less, if it has tries left.
+    fn count_try(&mut self) {
+        let Some(next) = self.counter.and_then(|v| v.next()) else {
+            return;
+        };
+        let (dir, file_name) = self.path.rsplit_once('/').unwrap_or(("", &self.path));
+        let (name, _) = boot_counting::parse(file_name, ".efi");
+        let path = format!("{}/{}", dir, boot_counting::file_name(&name, ".efi", next));
+        match self.fs.rename(&self.path, &path) {
+            Ok(()) => {
+                println!("{}: {} tries left", name, next.left);
+                self.path = path;
+                self.counter = Some(next);
+            }
+            Err(err) => println!("failed to count boot of {}: {:?}", self.path, err),
+        }
+    }
 }
 
 impl BootOption for UkiBootConfig {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
     fn label(&self) -> &str {
         &self.name
     }
@@ -36,9 +73,8 @@ impl BootOption for UkiBootConfig {
     fn splash(&self, display: &mut FbCon888) -> Result<(), ()> {
         let (offset, size) = self.splash.clone().ok_or(())?;
         let mut buf = vec![0; size as usize];
-        let mut file = self.fs.root_dir().open_file(&self.path).map_err(|_| ())?;
-        file.seek(SeekFrom::Start(offset)).map_err(|_| ())?;
-        file.read_exact(&mut buf).map_err(|_| ())?;
+        let mut file = self.fs.open(&self.path).map_err(|_| ())?;
+        file.read_at(offset, &mut buf).map_err(|_| ())?;
 
         let splash = Bmp::<Rgb888>::from_slice(&buf).map_err(|_| ())?.with_alpha_bg(Rgb888::CSS_BLACK);
 
@@ -50,9 +86,32 @@ impl BootOption for UkiBootConfig {
         Ok(())
     }
 
+    fn is_bad(&self) -> bool {
+        self.counter.is_some_and(|v| v.is_bad())
+    }
+
     fn boot(&mut self) -> ! {
-        let file = self.fs.root_dir().open_file(&self.path).unwrap();
-        if let Err(err) = boot(file, &self) {
+        self.count_try();
+
+        let section = |range| Slice::new(self.fs.open(&self.path).unwrap(), range);
+        let mut kernel = section(self.kernel);
+        let mut dtb = self.dtb.map(section);
+        let mut initrd = section(self.initrd);
+
+        let mut overlays: Vec<Box<dyn Payload + '_>> = Vec::new();
+        for range in &self.overlays {
+            overlays.push(Box::new(section(*range)));
+        }
+        for path in &self.extra_overlays {
+            match self.fs.open(path) {
+                Ok(file) => overlays.push(file),
+                Err(err) => println!("failed to open overlay {}: {:?}", path, err),
+            }
+        }
+
+        let cmdline = self.commandline.clone().map(|v| v.to_string_lossy().to_string()).unwrap_or_default();
+
+        if let Err(err) = boot(&mut kernel, dtb.as_mut().map(|v| v as _), &mut overlays, Some(&mut initrd), &cmdline) {
             println!("oof: {:?}", err)
         }
         panic!("noes");
@@ -69,65 +128,233 @@ pub enum UkiParseError {
     KernelNotFound,
     #[snafu(display("initramfs not found"))]
     InitrdNotFound,
-    #[snafu(display("DTB not found"))]
-    DtbNotFound,
 }
 
-pub fn parse_uki(fs: Arc<FatFS>, path: &str) -> Result<UkiBootConfig, UkiParseError> {
-    let dir = fs.root_dir();
-    let file = dir.open_file(path).map_err(|_| UkiParseError::FileNotFound)?;
-    let reader = ReadCache::new(FatFileReadCacheOps { file: file.clone() });
+/// Parse a UKI into one boot option per profile.
+///
+/// Sections before the first `.profile` section are shared by all profiles, sections following a
+/// `.profile` section belong to that profile and replace shared sections of the same name. A UKI
+/// without any `.profile` section has a single, implicit profile.
+pub fn parse_uki(fs: Rc<dyn BootFs>, path: &str) -> Result<Vec<UkiBootConfig>, UkiParseError> {
+    let file = fs.open(path).map_err(|_| UkiParseError::FileNotFound)?;
+    let reader = ReadCache::new(PayloadReadCacheOps { payload: file, pos: 0 });
     let obj = File::parse(&reader).map_err(|_| UkiParseError::InvalidObject)?;
 
-    let name = obj.section_by_name(".osrel")
+    let mut base = Vec::new();
+    let mut profiles: Vec<(Section<_>, Vec<Section<_>>)> = Vec::new();
+    for section in obj.sections() {
+        if section.name() == Ok(".profile") {
+            profiles.push((section, Vec::new()));
+        } else if let Some((_, sections)) = profiles.last_mut() {
+            sections.push(section);
+        } else {
+            base.push(section);
+        }
+    }
+
+    let extra_overlays = extra_overlays(fs.as_ref(), path);
+
+    if profiles.is_empty() {
+        let sections: Vec<_> = base.iter().collect();
+        return Ok(vec![uki_profile(fs.clone(), path, &sections, None, &extra_overlays)?]);
+    }
+
+    // A broken profile is skipped, the others may still boot.
+    let mut ukis = Vec::new();
+    let mut error = None;
+    for (idx, (profile, sections)) in profiles.iter().enumerate() {
+        let mut merged: Vec<_> = base.iter()
+            .filter(|v| !sections.iter().any(|p| p.name() == v.name()))
+            .chain(sections.iter())
+            .collect();
+        merged.sort_by_key(|v| v.index().0);
+        match uki_profile(fs.clone(), path, &merged, profile.data().ok(), &extra_overlays) {
+            Ok(mut uki) => {
+                // Same ids as systemd-boot gives profiles, so loader.conf can pick one.
+                if idx > 0 {
+                    uki.id = format!("{}@{}", uki.id, idx);
+                }
+                ukis.push(uki);
+            }
+            Err(err) => {
+                println!("{}: skipping profile {}: {}", path, idx, err);
+                error.get_or_insert(err);
+            }
+        }
+    }
+    match error {
+        Some(err) if ukis.is_empty() => Err(err),
+        _ => Ok(ukis),
+    }
+}
+
+/// Find the `*.dtbo` files in the drop-in directory of a UKI.
+///
+/// systemd-stub looks for its addons in `<name>.efi.extra.d`, so that's checked as well as the
+/// shorter `<name>.extra.d`. Overlays are applied in the order of their file names.
+fn extra_overlays(fs: &dyn BootFs, path: &str) -> Vec<String> {
+    let stem = path.strip_suffix(".efi").unwrap_or(path);
+    let mut overlays = Vec::new();
+    for dir_path in [format!("{}.extra.d", path), format!("{}.extra.d", stem)] {
+        let Ok(entries) = fs.read_dir(&dir_path) else {
+            continue;
+        };
+        for entry in entries {
+            if !entry.is_dir && entry.name.ends_with(".dtbo") {
+                overlays.push(format!("{}/{}", dir_path, entry.name));
+            }
+        }
+    }
+    overlays.sort();
+    overlays
+}
+
+fn uki_profile<'data, R: ReadRef<'data>>(
+    fs: Rc<dyn BootFs>,
+    path: &str,
+    sections: &[&Section<'data, '_, R>],
+    profile: Option<&[u8]>,
+    extra_overlays: &[String],
+) -> Result<UkiBootConfig, UkiParseError> {
+    let section = |name: &str| sections.iter().find(|v| v.name() == Ok(name));
+
+    let pretty_name = section(".osrel")
         .and_then(|v| v.data().ok())
-        .and_then(|v| CString::new(v).ok())
-        .and_then(|v| v.into_string().ok())
-        .and_then(|v| v.split('\n')
-            .find(|v| v.starts_with("PRETTY_NAME="))
-            .and_then(|v| v.split("=").skip(1).next())
-            .filter(|v| v.len() > 2)
-            .and_then(|v| Some(String::from(&v[1..v.len()-1]))));
-    if name.is_none() {
-        return Err(UkiParseError::OSRelMissing);
-    }
-
-    let kernel = obj
-        .section_by_name(".linux")
+        .and_then(|v| os_release_field(v, "PRETTY_NAME"))
+        .ok_or(UkiParseError::OSRelMissing)?;
+
+    // Profiles are shown by their title, or failing that by their ID, like systemd-boot does.
+    let name = match profile {
+        None => pretty_name,
+        Some(profile) => match os_release_field(profile, "TITLE") {
+            Some(title) => title,
+            None => match os_release_field(profile, "ID") {
+                Some(id) => format!("{} ({})", pretty_name, id),
+                None => pretty_name,
+            },
+        },
+    };
+
+    let kernel = section(".linux")
         .and_then(|v| v.file_range())
         .ok_or(UkiParseError::KernelNotFound)?;
 
-    let initrd = obj
-        .section_by_name(".initrd")
+    let initrd = section(".initrd")
         .and_then(|v| v.file_range())
         .ok_or(UkiParseError::InitrdNotFound)?;
 
-    // TODO: multiple dtbs
-    // TODO: check picked DTB size
-    let dtb = obj
-        .section_by_name(".dtb")
-        .and_then(|v| v.file_range())
-        .ok_or(UkiParseError::DtbNotFound)?;
+    // 32-bit kernels can do without.
+    let dtb = pick_dtb(sections);
 
-    let commandline = obj
-        .section_by_name(".cmdline")
+    let commandline = section(".cmdline")
         .and_then(|v| v.data().ok())
         .and_then(|v| CString::new(v).ok());
 
-    let splash = obj.section_by_name(".splash").and_then(|v| v.file_range());
+    let overlays = sections.iter()
+        .filter(|v| v.name() == Ok(".dtbo"))
+        .filter_map(|v| v.file_range())
+        .collect();
+
+    let splash = section(".splash").and_then(|v| v.file_range());
+
+    // Like systemd-boot, the boot counter isn't part of the id.
+    let (id, counter) = boot_counting::parse(path.rsplit('/').next().unwrap_or(path), ".efi");
 
     Ok(UkiBootConfig {
-        fs: fs.clone(),
+        fs,
         path: String::from(path),
-        name: name.unwrap(),
+        id,
+        name,
         kernel,
         initrd,
         dtb,
+        overlays,
+        extra_overlays: extra_overlays.to_vec(),
         commandline,
         splash,
+        counter,
     })
 }
 
+/// Look up a field in os-release style `KEY=VALUE` data, as used by `.osrel` and `.profile`.
+fn os_release_field(data: &[u8], key: &str) -> Option<String> {
+    let data = core::str::from_utf8(data).ok()?;
+    data.lines()
+        .filter_map(|v| v.split_once('='))
+        .find(|(k, _)| k.trim() == key)
+        .map(|(_, v)| v.trim().trim_matches(|c| c == '"' || c == '\'').to_string())
+        .filter(|v| !v.is_empty())
+}
+
+/// Pick the DTB for this device out of a UKI.
+///
+/// `.dtbauto` sections, and multiple `.dtb` sections, are matched on the compatible of their root
+/// node. systemd-stub finds the compatible to look for through the SMBIOS-derived CHIDs in
+/// `.hwids`. We have no SMBIOS, so we go by what lk2nd detected, and only use `.hwids` to map
+/// lk2nd's model name to a compatible. If nothing matches, the first plain `.dtb` is used.
+fn pick_dtb<'data, R: ReadRef<'data>>(sections: &[&Section<'data, '_, R>]) -> Option<(u64, u64)> {
+    let mut compatibles = lk2nd_device::compatibles();
+    if let Some(model) = lk2nd_device::model() {
+        let hwids = sections.iter()
+            .find(|v| v.name() == Ok(".hwids"))
+            .and_then(|v| v.data().ok())
+            .unwrap_or(&[]);
+        compatibles.extend(parse_hwids(hwids).into_iter()
+            .filter(|(name, _)| *name == model)
+            .map(|(_, compatible)| compatible.to_string()));
+    }
+
+    let candidates: Vec<_> = sections.iter()
+        .filter(|v| matches!(v.name(), Ok(".dtb") | Ok(".dtbauto")))
+        .collect();
+
+    for compatible in &compatibles {
+        for section in &candidates {
+            let matched = section.data().ok()
+                .and_then(|data| Fdt::new(data).ok())
+                .map(|fdt| fdt.is_compatible(0, compatible))
+                .unwrap_or(false);
+            if matched {
+                println!("picked DTB matching {}", compatible);
+                return section.file_range();
+            }
+        }
+    }
+
+    candidates.iter()
+        .find(|v| v.name() == Ok(".dtb"))
+        .and_then(|v| v.file_range())
+}
+
+/// Parse the (name, compatible) pairs out of the devicetree entries of a `.hwids` section.
+fn parse_hwids(data: &[u8]) -> Vec<(&str, &str)> {
+    // struct Device { u32 descriptor; EFI_GUID chid; u32 name_offset; u32 compatible_offset; }
+    const DEVICE_SIZE: usize = 28;
+    const DEVICE_TYPE_DEVICETREE: u32 = 1;
+
+    let string_at = |offset: u32| data.get(offset as usize..)
+        .and_then(|v| CStr::from_bytes_until_nul(v).ok())
+        .and_then(|v| v.to_str().ok());
+
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos + DEVICE_SIZE <= data.len() {
+        let descriptor = LittleEndian::read_u32(&data[pos..]);
+        let size = (descriptor & 0x0fff_ffff) as usize;
+        if size < DEVICE_SIZE {
+            break;
+        }
+        if descriptor >> 28 == DEVICE_TYPE_DEVICETREE {
+            let name = string_at(LittleEndian::read_u32(&data[pos + 20..]));
+            let compatible = string_at(LittleEndian::read_u32(&data[pos + 24..]));
+            if let (Some(name), Some(compatible)) = (name, compatible) {
+                entries.push((name, compatible));
+            }
+        }
+        pos += size;
+    }
+    entries
+}
 
 #[derive(Debug, Snafu)]
 pub enum BootError {
@@ -135,84 +362,457 @@ pub enum BootError {
     Io,
     #[snafu(display("loaded kernel had invalid magic"))]
     InvalidKernel,
+    #[snafu(display("kernel does not fit in memory"))]
+    KernelTooBig,
+    #[snafu(display("no DTB to boot with"))]
+    DtbNotFound,
     #[snafu(display("DTB exceeds maximum 2MB"))]
     DtbTooBig,
+    #[snafu(display("initramfs does not fit in memory"))]
+    InitrdTooBig,
+    #[snafu(display("kernel decompression failed"))]
+    Decompress { source: DecompressError },
+    #[snafu(display("applying DTB overlay failed with error {code}"))]
+    Overlay { code: c_int },
     Failed,
 }
 
-// Boot a kernel from provided BootConfig
-// https://docs.kernel.org/arch/arm64/booting.html
-// TODO: currently 64-bit only
-pub fn boot(
-    mut file: fatfs::File<OpenDevice, DefaultTimeProvider, LossyOemCpConverter>,
-    config: &UkiBootConfig,
-) -> Result<(), BootError> {
+/// Something a kernel, DTB or initrd can be loaded from.
+pub trait Payload {
+    fn len(&self) -> Result<u64, BootError>;
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BootError>;
+}
+
+/// A byte range inside a file on a FAT filesystem.
+pub struct FatRange<'a> {
+    file: FatFile<'a>,
+    start: u64,
+    size: u64,
+}
+
+impl<'a> FatRange<'a> {
+    pub fn new(file: FatFile<'a>, (start, size): (u64, u64)) -> Self {
+        Self { file, start, size }
+    }
+
+    /// The whole of the file at `path`.
+    pub fn open(fs: &'a FatFS, path: &str) -> Result<Self, BootError> {
+        let mut file = fs.root_dir().open_file(path).map_err(|_| BootError::Io)?;
+        let size = file.seek(SeekFrom::End(0)).map_err(|_| BootError::Io)?;
+        Ok(Self::new(file, (0, size)))
+    }
+}
+
+impl<'a> Payload for FatRange<'a> {
+    fn len(&self) -> Result<u64, BootError> {
+        Ok(self.size)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BootError> {
+        if offset + buf.len() as u64 > self.size {
+            return Err(BootError::Io);
+        }
+        self.file.seek(SeekFrom::Start(self.start + offset)).map_err(|_| BootError::Io)?;
+        self.file.read_exact(buf).map_err(|_| BootError::Io)
+    }
+}
+
+/// A byte range inside another payload, such as a UKI section.
+pub struct Slice<'a> {
+    payload: Box<dyn Payload + 'a>,
+    start: u64,
+    size: u64,
+}
+
+impl<'a> Slice<'a> {
+    pub fn new(payload: Box<dyn Payload + 'a>, (start, size): (u64, u64)) -> Self {
+        Self { payload, start, size }
+    }
+}
+
+impl<'a> Payload for Slice<'a> {
+    fn len(&self) -> Result<u64, BootError> {
+        Ok(self.size)
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), BootError> {
+        if offset + buf.len() as u64 > self.size {
+            return Err(BootError::Io);
+        }
+        self.payload.read_at(self.start + offset, buf)
+    }
+}
+
+/// Several payloads loaded back to back, e.g. multiple initrds.
+pub struct Concat<'a> {
+    parts: Vec<Box<dyn Payload + 'a>>,
+}
+
+impl<'a> Concat<'a> {
+    pub fn new(parts: Vec<Box<dyn Payload + 'a>>) -> Self {
+        Self { parts }
+    }
+}
+
+impl<'a> Payload for Concat<'a> {
+    fn len(&self) -> Result<u64, BootError> {
+        self.parts.iter().map(|v| v.len()).sum()
+    }
+
+    fn read_at(&mut self, mut offset: u64, mut buf: &mut [u8]) -> Result<(), BootError> {
+        for part in self.parts.iter_mut() {
+            if buf.is_empty() {
+                break;
+            }
+            let len = part.len()?;
+            if offset >= len {
+                offset -= len;
+                continue;
+            }
+            let n = buf.len().min((len - offset) as usize);
+            let (head, tail) = buf.split_at_mut(n);
+            part.read_at(offset, head)?;
+            buf = tail;
+            offset = 0;
+        }
+        if !buf.is_empty() {
+            return Err(BootError::Io);
+        }
+        Ok(())
+    }
+}
+
+const SZ_2M: u64 = 2 * 1024 * 1024;
+const ARM64_MAGIC: u32 = 0x644d5241;
+const ARM64_DEFAULT_TEXT_OFFSET: u64 = 0x80000;
+const ZIMAGE_MAGIC: u32 = 0x016f2818;
+const ZIMAGE_SIZE_TAG: u32 = 0x5a534c4b;
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const ARM32_TEXT_OFFSET: u64 = 0x8000;
+// Raw 32-bit Images and zImages without a size tag, keep clear of the space they might need.
+const ARM32_KERNEL_FOOTPRINT: u64 = 32 * 1024 * 1024;
+// https://docs.kernel.org/arch/arm/booting.html recommends putting the DTB and initrd above
+// 128MB, out of the way of the zImage decompressor, and they must stay within lowmem.
+const ARM32_DTB_OFFSET: u64 = 128 * 1024 * 1024;
+const ARM32_LOWMEM_SIZE: u64 = 760 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+enum KernelKind {
+    Arm64,
+    /// A self-decompressing 32-bit zImage, `end` is the size of the zImage proper. Anything
+    /// after it is an appended DTB.
+    ZImage { end: u64 },
+    /// Anything else is assumed to be an uncompressed 32-bit Image.
+    Arm32,
+}
+
+/// The bits of the kernel header we care about.
+/// https://docs.kernel.org/arch/arm64/booting.html
+/// https://docs.kernel.org/arch/arm/booting.html
+struct KernelHeader {
+    kind: KernelKind,
+    text_offset: u64,
+    /// Memory needed at the load address, if the header tells us.
+    image_size: u64,
+}
+
+impl KernelHeader {
+    fn parse(image: &[u8]) -> Result<Self, BootError> {
+        if image.len() < 64 {
+            return Err(BootError::InvalidKernel);
+        }
+
+        if LittleEndian::read_u32(&image[0x24..]) == ZIMAGE_MAGIC {
+            let start = LittleEndian::read_u32(&image[0x28..]);
+            let end = LittleEndian::read_u32(&image[0x2c..]).wrapping_sub(start) as u64;
+            if end == 0 || end > image.len() as u64 {
+                return Err(BootError::InvalidKernel);
+            }
+            // The decompressed kernel goes at the text offset and the zImage moves itself past it
+            // before decompressing, so it needs room for both.
+            let image_size = zimage_decompressed_size(image)
+                .map(|v| v + end)
+                .unwrap_or(0);
+            return Ok(KernelHeader {
+                kind: KernelKind::ZImage { end },
+                text_offset: ARM32_TEXT_OFFSET,
+                image_size,
+            });
+        }
+
+        if LittleEndian::read_u32(&image[56..]) != ARM64_MAGIC {
+            return Ok(KernelHeader {
+                kind: KernelKind::Arm32,
+                text_offset: ARM32_TEXT_OFFSET,
+                image_size: 0,
+            });
+        }
+
+        // text_offset is not reliable until Linux 3.17, which is also when image_size was
+        // introduced. See Linux commit a2c1d73b94ed49f5fac12e95052d7b140783f800.
+        let image_size = LittleEndian::read_u64(&image[16..]);
+        let text_offset = if image_size != 0 {
+            LittleEndian::read_u64(&image[8..])
+        } else {
+            ARM64_DEFAULT_TEXT_OFFSET
+        };
+        Ok(KernelHeader {
+            kind: KernelKind::Arm64,
+            text_offset,
+            image_size,
+        })
+    }
+
+    /// How much memory the kernel needs at its load address.
+    fn footprint(&self, size: u64) -> u64 {
+        if self.image_size != 0 {
+            self.image_size.max(size)
+        } else {
+            ARM32_KERNEL_FOOTPRINT.max(size)
+        }
+    }
+}
+
+/// Decompressed size (including bss) of a zImage, from the size tag in its header table.
+/// See `arch/arm/boot/compressed/vmlinux.lds.S`.
+fn zimage_decompressed_size(image: &[u8]) -> Option<u64> {
+    if LittleEndian::read_u32(&image[0x34..]) != 0x45454545 {
+        return None;
+    }
+    let table = LittleEndian::read_u32(&image[0x38..]).checked_sub(LittleEndian::read_u32(&image[0x28..]))? as usize;
+    let word = |off: usize| image.get(off..off + 4).map(LittleEndian::read_u32);
+
+    // The table is a list of (size in words, tag, data...) entries, terminated by a zero size.
+    let mut pos = table;
+    loop {
+        let size = word(pos)? as usize;
+        if size == 0 {
+            return None;
+        }
+        if word(pos + 4)? == ZIMAGE_SIZE_TAG {
+            let size_offset = word(pos + 8)? as usize;
+            let bss_size = word(pos + 12)?;
+            return Some(word(size_offset)? as u64 + bss_size as u64);
+        }
+        pos += size * 4;
+    }
+}
+
+/// Physical addresses picked for a boot.
+#[derive(Debug)]
+struct Placement {
+    kernel: u64,
+    dtb: u64,
+    initrd: u64,
+}
+
+/// Lay out the kernel, DTB and initrd in DRAM.
+///
+/// The kernel goes at its text offset from the start of DRAM, the DTB gets its own 2MB region
+/// after the kernel and the initrd follows the DTB. Anything that would land on top of lk itself
+/// is moved past it, and everything has to fit into DRAM (and lowmem, for 32-bit kernels).
+fn place(header: &KernelHeader, kernel_size: u64, initrd_size: u64) -> Result<Placement, BootError> {
+    let ram_start = unsafe { sys::get_ddr_start() } as u64;
+    let mut ram_end = ram_start + unsafe { sys::smem_get_ddr_size() };
+    let lk = unsafe { (&sys::_start as *const u8 as u64, &sys::_end_of_ram as *const u8 as u64) };
+
+    let overlaps = |start: u64, size: u64| start < lk.1 && lk.0 < start + size;
+    let avoid_lk = |start: u64, size: u64| if overlaps(start, size) { align_up(lk.1, SZ_2M) } else { start };
+
+    let kernel = ram_start + header.text_offset;
+    let kernel_end = kernel + header.footprint(kernel_size);
+    if overlaps(kernel, kernel_end - kernel) || kernel_end > ram_end {
+        return Err(BootError::KernelTooBig);
+    }
+
+    let mut dtb = align_up(kernel_end, SZ_2M);
+    if header.kind != KernelKind::Arm64 {
+        dtb = dtb.max(ram_start + ARM32_DTB_OFFSET);
+        ram_end = ram_end.min(ram_start + ARM32_LOWMEM_SIZE);
+    }
+    let dtb = avoid_lk(dtb, SZ_2M);
+    let initrd = avoid_lk(dtb + SZ_2M, initrd_size);
+    if initrd + initrd_size > ram_end {
+        return Err(BootError::InitrdTooBig);
+    }
+
+    Ok(Placement { kernel, dtb, initrd })
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+unsafe fn phys_mem<'a>(addr: u64, size: u64) -> &'a mut [u8] {
+    &mut *slice_from_raw_parts_mut(addr as *mut u8, size as usize)
+}
+
+/// A kernel loaded into scratch memory.
+struct StagedKernel {
+    image: &'static mut [u8],
+    /// If the kernel was compressed (outside of a zboot image), the compressed stream with
+    /// whatever came after it and the offset it ended at. That's where `Image.gz-dtb` style
+    /// kernels keep their DTBs.
+    compressed: Option<(&'static mut [u8], usize)>,
+}
+
+/// Load the kernel into scratch memory, unwrapping and decompressing it if necessary.
+fn stage_kernel(kernel: &mut dyn Payload) -> Result<StagedKernel, BootError> {
+    let scratch = unsafe { sys::target_get_scratch_address() } as u64;
+    let scratch_size = unsafe { sys::target_get_max_flash_size() } as u64;
+
+    let size = kernel.len()?;
+    if size > scratch_size {
+        return Err(BootError::KernelTooBig);
+    }
+    let image = unsafe { phys_mem(scratch, size) };
+    kernel.read_at(0, image)?;
+
+    let decompress = |compression: Compression, payload: &mut [u8]| {
+        println!("decompressing {:?} kernel...", compression);
+        let out = unsafe { phys_mem(scratch + size, scratch_size - size) };
+        let (out_size, end) = compression.decompress_stream(payload, out).context(DecompressSnafu)?;
+        Ok::<_, BootError>((&mut out[..out_size], end))
+    };
+
+    if let Some((compression, payload)) = zboot_payload(image).context(DecompressSnafu)? {
+        let (image, _) = decompress(compression, payload)?;
+        return Ok(StagedKernel { image, compressed: None });
+    }
+    let Some(compression) = Compression::detect(image) else {
+        return Ok(StagedKernel { image, compressed: None });
+    };
+    let (out, end) = decompress(compression, image)?;
+    Ok(StagedKernel { image: out, compressed: Some((image, end)) })
+}
+
+/// Find the DTBs appended to a compressed kernel and copy the best match to `addr`.
+fn find_appended_dtb(data: &mut [u8], end: usize, addr: u64) -> bool {
+    // kbuild appends the decompressed size to lz4 and zstd kernels, the DTBs come after that.
+    let is_fdt = |pos: usize| data.get(pos..pos + 4).is_some_and(|v| BigEndian::read_u32(v) == FDT_MAGIC);
+    let offset = if !is_fdt(end) && is_fdt(end + 4) {
+        end + 4
+    } else {
+        end
+    };
+    let dtb = unsafe {
+        sys::dev_tree_appended(data.as_mut_ptr() as _, data.len() as c_uint, offset as c_uint, addr as *mut _)
+    };
+    !dtb.is_null()
+}
+
+/// Load the DTB and apply any overlays on top of it.
+fn load_dtb(dtb: &mut dyn Payload, overlays: &mut [Box<dyn Payload + '_>], addr: u64) -> Result<(), BootError> {
     // DTB may not exceed 2mb.
-    if config.dtb.1 > 2*1024*1024 {
+    let size = dtb.len()?;
+    if size > SZ_2M {
         return Err(BootError::DtbTooBig);
     }
+    dtb.read_at(0, unsafe { phys_mem(addr, size) })?;
 
-    let base = unsafe { sys::get_ddr_start() } as u64;
-
-    let (start, size) = config.kernel;
-    // Read text_offset + image_size + magic from kernel header
-    let mut buf = [0; 8];
-    file.seek(SeekFrom::Start(start + 8)).map_err(|_| BootError::Io)?;
-    file.read_exact(&mut buf).map_err(|_| BootError::Io)?;
-    let text_offset = LittleEndian::read_u64(&buf);
-    file.read_exact(&mut buf).map_err(|_| BootError::Io)?;
-    let image_size = LittleEndian::read_u64(&buf);
-    file.seek(SeekFrom::Current(32)).map_err(|_| BootError::Io)?;
-    file.read_exact(&mut buf[0..4]).map_err(|_| BootError::Io)?;
-    let magic = LittleEndian::read_u32(&buf);
-    if magic != 0x644d5241 {
-        return Err(BootError::InvalidKernel);
-    }
-
-    // Load kernel into base of DRAM.
-    let kernel_addr = base + text_offset;
-    let kernel = unsafe { &mut *slice_from_raw_parts_mut(kernel_addr as *mut u8, size as usize) };
-    file.seek(SeekFrom::Start(start)).map_err(|_| BootError::Io)?;
-    file.read_exact(kernel).map_err(|_| BootError::Io)?;
-
-    // Load DTB after kernel image.
-    let mut dtb_addr = kernel_addr + image_size;
-    // DTB must be in its own 2MB region.
-    dtb_addr += 2*1024*1024;
-    // DTB address must be 8-byte aligned.
-    dtb_addr += 8;
-    dtb_addr &= !0b111;
-    let (start, dtb_size) = config.dtb;
-    let dtb = unsafe { &mut *slice_from_raw_parts_mut(dtb_addr as *mut u8, dtb_size as usize) };
-    file.seek(SeekFrom::Start(start))
-        .map_err(|_| BootError::Io)?;
-    file.read_exact(dtb).map_err(|_| BootError::Io)?;
-
-    // Load initrd.
-    // Place initrd exactly 2mb after the start of DTB. This way we know that a) the initramfs is
-    // not overlapping the special DTB 2mb region and b) is already aligned.
-    let initrd_addr = dtb_addr + 2*1024*1024;
-    let (start, initrd_size) = config.initrd;
-    let initrd = unsafe { &mut *slice_from_raw_parts_mut(initrd_addr as *mut u8, initrd_size as usize) };
-    file.seek(SeekFrom::Start(start))
-        .map_err(|_| BootError::Io)?;
-    file.read_exact(initrd).map_err(|_| BootError::Io)?;
-
-    // Making sure it really is this code that booted the kernel ;)
-    let mut wow = config.commandline.clone().unwrap().to_string_lossy().to_string();
-    wow += " haha cool";
-    let wow = CString::new(wow).unwrap();
+    if overlays.is_empty() {
+        return Ok(());
+    }
+
+    let fdt = addr as *mut c_void;
+    let ret = unsafe { sys::fdt_open_into(fdt, fdt, SZ_2M as c_int) };
+    if ret < 0 {
+        return Err(BootError::Overlay { code: ret });
+    }
+    for overlay in overlays.iter_mut() {
+        let mut buf = vec![0u8; overlay.len()? as usize];
+        overlay.read_at(0, &mut buf)?;
+        let ret = unsafe { sys::fdt_overlay_apply(fdt, buf.as_mut_ptr() as _) };
+        if ret < 0 {
+            return Err(BootError::Overlay { code: ret });
+        }
+    }
+    let ret = unsafe { sys::fdt_pack(fdt) };
+    if ret < 0 {
+        return Err(BootError::Overlay { code: ret });
+    }
+    Ok(())
+}
+
+/// Boot a kernel. Only returns if something went wrong.
+///
+/// Kernels may come without a DTB, in which case one appended to the compressed kernel or to the
+/// zImage is used, as with aboot. Failing that, zImages get the ATAGs lk2nd was booted with.
+pub fn boot(
+    kernel: &mut dyn Payload,
+    dtb: Option<&mut dyn Payload>,
+    overlays: &mut [Box<dyn Payload + '_>],
+    initrd: Option<&mut dyn Payload>,
+    cmdline: &str,
+) -> Result<(), BootError> {
+    let StagedKernel { image, compressed } = stage_kernel(kernel)?;
+    let header = KernelHeader::parse(image)?;
+    let initrd_size = match &initrd {
+        Some(initrd) => initrd.len()?,
+        None => 0,
+    };
+    let placement = place(&header, image.len() as u64, initrd_size)?;
+    println!("loading {:?} kernel @ {:#x}, DTB @ {:#x}, initrd @ {:#x}",
+        header.kind, placement.kernel, placement.dtb, placement.initrd);
+
+    // Before the kernel is moved, that may overwrite the compressed kernel.
+    let appended_dtb = match (&dtb, compressed) {
+        (None, Some((data, end))) => find_appended_dtb(data, end, placement.dtb),
+        _ => false,
+    };
+
+    // Kernel first, the scratch area it was staged in may be reused for the initrd.
+    unsafe {
+        core::ptr::copy(image.as_ptr(), placement.kernel as *mut u8, image.len());
+        sys::arch_clean_invalidate_cache_range(placement.kernel as _, image.len());
+    }
+
+    if dtb.is_none() && !overlays.is_empty() {
+        println!("no DTB to apply {} overlays to, ignoring them", overlays.len());
+    }
+
+    let mut boot_type = BootType(0);
+    match (dtb, &header.kind) {
+        (Some(dtb), _) => load_dtb(dtb, overlays, placement.dtb)?,
+        (None, _) if appended_dtb => {}
+        (None, KernelKind::ZImage { end }) => {
+            let appended = unsafe {
+                sys::dev_tree_appended(
+                    placement.kernel as *mut _,
+                    image.len() as c_uint,
+                    *end as c_uint,
+                    placement.dtb as *mut _,
+                )
+            };
+            if appended.is_null() {
+                if !unsafe { sys::lk2nd_boot_have_atags() } {
+                    return Err(BootError::DtbNotFound);
+                }
+                println!("no DTB, passing on ATAGs");
+                // Only valid with WITH_LK2ND_DEVICE_2ND, which lk2nd_boot_have_atags() implies.
+                boot_type = boot_type | BootType::ATAGS_COPY;
+            }
+        }
+        (None, _) => return Err(BootError::DtbNotFound),
+    }
+
+    if let Some(initrd) = initrd {
+        initrd.read_at(0, unsafe { phys_mem(placement.initrd, initrd_size) })?;
+        unsafe { sys::arch_clean_invalidate_cache_range(placement.initrd as _, initrd_size as usize) };
+    }
+
+    let cmdline = CString::new(cmdline).map_err(|_| BootError::Failed)?;
 
     // Do the boot!
     unsafe {
         sys::boot_linux(
-            base as *mut _,
-            dtb_addr as *mut _,
-            wow.as_c_str().as_ptr(),
+            placement.kernel as *mut _,
+            placement.dtb as *mut _,
+            cmdline.as_ptr(),
             sys::board_machtype(),
-            initrd_addr as *mut _,
+            placement.initrd as *mut _,
             initrd_size as c_uint,
-            0,
+            boot_type.0,
         )
     }
 
@@ -220,30 +820,82 @@ pub fn boot(
     Err(BootError::Failed)
 }
 
-/// Trait glue to allow the object crate to read from a fatfs file.
-struct FatFileReadCacheOps<'a> {
-    file: fatfs::File<'a, OpenDevice, DefaultTimeProvider, LossyOemCpConverter>,
+static BOOT_ENTRY: AtomicPtr<c_char> = AtomicPtr::new(null_mut());
+
+/// Remember which boot menu entry is being booted, so it can be passed on in `/chosen`.
+pub fn set_boot_entry(label: &str) {
+    // The handler that passes it on is linked in along with this.
+    core::hint::black_box(&BOOT_ENTRY_DT_UPDATE);
+    let Ok(label) = CString::new(label) else {
+        return;
+    };
+    let old = BOOT_ENTRY.swap(label.into_raw(), Ordering::AcqRel);
+    if !old.is_null() {
+        drop(unsafe { CString::from_raw(old) });
+    }
+}
+
+fn lk2nd_boot_entry_dt_update(fdt: &mut FdtMut, _cmdline: &str, boot_type: BootType) -> Result<(), FdtError> {
+    let entry = BOOT_ENTRY.load(Ordering::Acquire);
+    if entry.is_null() || boot_type.intersects(BootType::DOWNSTREAM | BootType::LK2ND) {
+        return Ok(());
+    }
+    let Ok(entry) = unsafe { CStr::from_ptr(entry) }.to_str() else {
+        return Ok(());
+    };
+
+    let chosen = fdt.subnode_or_add(0, "chosen")?;
+    fdt.set_property_str(chosen, "lk2nd,boot-entry", entry)
+}
+dt_update_handler!(BOOT_ENTRY_DT_UPDATE, lk2nd_boot_entry_dt_update);
+
+/// Trait glue to allow the object crate to read from a payload.
+struct PayloadReadCacheOps<'a> {
+    payload: Box<dyn Payload + 'a>,
+    pos: u64,
 }
-impl<'a> ReadCacheOps for FatFileReadCacheOps<'a> {
+impl<'a> ReadCacheOps for PayloadReadCacheOps<'a> {
     fn len(&mut self) -> Result<u64, ()> {
-        self.file.seek(SeekFrom::End(0)).map_err(|_| ())
+        self.payload.len().map_err(|_| ())
     }
     fn seek(&mut self, pos: u64) -> Result<u64, ()> {
-        self.file.seek(SeekFrom::Start(pos)).map_err(|_| ())
+        self.pos = pos;
+        Ok(pos)
     }
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
-        self.file.read(buf).map_err(|_| ())
+        let len = self.payload.len().map_err(|_| ())?;
+        let n = buf.len().min(len.saturating_sub(self.pos) as usize);
+        self.read_exact(&mut buf[..n])?;
+        Ok(n)
     }
     fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), ()> {
-        self.file.read_exact(buf).map_err(|_| ())
+        self.payload.read_at(self.pos, buf).map_err(|_| ())?;
+        self.pos += buf.len() as u64;
+        Ok(())
     }
 }
 
 mod sys {
-    use core::ffi::{c_char, c_uint, c_void};
+    use core::ffi::{c_char, c_int, c_uint, c_ulonglong, c_void};
 
     extern "C" {
+        // Linker symbols delimiting lk itself, including its heap.
+        pub static _start: u8;
+        pub static _end_of_ram: u8;
+
         pub fn get_ddr_start() -> c_uint;
+        pub fn smem_get_ddr_size() -> c_ulonglong;
+        pub fn target_get_scratch_address() -> *mut c_void;
+        pub fn target_get_max_flash_size() -> c_uint;
+
+        pub fn fdt_open_into(fdt: *const c_void, buf: *mut c_void, bufsize: c_int) -> c_int;
+        pub fn fdt_overlay_apply(fdt: *mut c_void, fdto: *mut c_void) -> c_int;
+        pub fn fdt_pack(fdt: *mut c_void) -> c_int;
+
+        pub fn arch_clean_invalidate_cache_range(start: usize, len: usize);
+
+        pub fn dev_tree_appended(kernel: *mut c_void, kernel_size: c_uint, dtb_offset: c_uint, tags: *mut c_void) -> *mut c_void;
+        pub fn lk2nd_boot_have_atags() -> bool;
 
         pub fn boot_linux(
             kernel: *mut c_void,
@@ -0,0 +1,48 @@
+//! Key input for the boot menu.
+
+use core::time::Duration;
+use crate::lk_thread::sleep;
+
+pub const KEY_VOLUMEUP: u16 = 0x115;
+pub const KEY_VOLUMEDOWN: u16 = 0x116;
+pub const KEY_POWER: u16 = 0x119;
+
+/// Wait for a key to be pressed and released, like wait_key() in lk2nd/device/menu/menu.c.
+/// Gives up and returns `None` once `timeout` has passed without any key being pressed.
+pub fn wait(timeout: Option<Duration>) -> Option<u16> {
+    let start = unsafe { sys::current_time() };
+    let expired = || match timeout {
+        Some(timeout) => unsafe { sys::current_time() }.wrapping_sub(start) as u128 >= timeout.as_millis(),
+        None => false,
+    };
+
+    let keycode = loop {
+        let keycode = unsafe { sys::lk2nd_boot_pressed_key() };
+        if keycode != 0 {
+            break keycode;
+        }
+        if expired() {
+            return None;
+        }
+        sleep(Duration::from_millis(1));
+    };
+
+    while unsafe { sys::lk2nd_keys_pressed(keycode as u32) } {
+        sleep(Duration::from_millis(1));
+    }
+
+    // A small debounce delay
+    sleep(Duration::from_millis(5));
+
+    Some(keycode)
+}
+
+mod sys {
+    use core::ffi::c_ulong;
+
+    extern "C" {
+        pub fn current_time() -> c_ulong;
+        pub fn lk2nd_boot_pressed_key() -> u16;
+        pub fn lk2nd_keys_pressed(keycode: u32) -> bool;
+    }
+}
@@ -5,13 +5,14 @@ extern crate alloc;
 use alloc::string::{ToString};
 use alloc::{format, vec};
 use alloc::boxed::Box;
-use alloc::sync::Arc;
+use alloc::rc::Rc;
 use alloc::vec::Vec;
+use core::time::Duration;
 use anyhow::Error;
 
 use byteorder::{ByteOrder};
 use embedded_graphics::image::Image;
-use embedded_graphics::mono_font::MonoTextStyle;
+use embedded_graphics::mono_font::{MonoFont, MonoTextStyle};
 use embedded_graphics::pixelcolor::Rgb888;
 use embedded_graphics::prelude::*;
 use embedded_graphics::text::Text;
@@ -19,14 +20,26 @@ use embedded_layout::layout::linear::{FixedMargin, LinearLayout};
 use embedded_layout::prelude::*;
 use fatfs::{DefaultTimeProvider, FileSystem, LossyOemCpConverter, Read, Seek, SeekFrom};
 use object::{Object, ObjectSection, ReadCacheOps};
-use profont::PROFONT_24_POINT;
+use profont::{PROFONT_14_POINT, PROFONT_18_POINT, PROFONT_24_POINT};
 use tinybmp::Bmp;
 
+use crate::bcb::BcbError;
 use crate::bio::OpenDevice;
+use crate::btrfs::Btrfs;
+use crate::erofs::Erofs;
+use crate::exfat::ExFat;
+use crate::ext4::Ext4;
+use crate::f2fs::F2fs;
 use crate::fbcon::FbCon888;
+use crate::fs::{BootFs, Subdir};
+use crate::keys::{KEY_POWER, KEY_VOLUMEDOWN, KEY_VOLUMEUP};
 use crate::lk_thread::sleep;
+use crate::loader::{ConsoleMode, EntryState, LoaderConfig, Timeout};
+use crate::probe::FsType;
 
+mod bcb;
 mod bio;
+mod decompress;
 mod fbcon;
 mod fmt;
 mod lk_alloc;
@@ -35,65 +48,237 @@ mod lk_mutex;
 mod lk_thread;
 mod panic;
 mod kernel_boot;
-mod lk_fs;
 mod extlinux;
+mod bls;
+mod boot_counting;
+mod bootimg;
+mod keys;
+mod loader;
+mod fdt;
+mod guid;
+mod liblp;
+mod partition;
+mod probe;
+mod fs;
+mod ext4;
+mod exfat;
+mod btrfs;
+mod f2fs;
+mod erofs;
+mod lk2nd_device;
 
 trait BootOption {
+    /// Stable identifier that `default` in loader.conf or extlinux.conf is matched against.
+    fn id(&self) -> &str;
     fn label(&self) -> &str;
     fn splash(&self, display: &mut FbCon888) -> Result<(), ()>;
     fn boot(&mut self) -> !;
-}
 
-extern "C" {
-    fn wait_key() -> u16;
-}
+    /// Whether the option used up its boot counting tries without being marked good.
+    fn is_bad(&self) -> bool {
+        false
+    }
 
-const KEY_VOLUMEUP: u16 = 0x115;
-const KEY_VOLUMEDOWN: u16 = 0x116;
-const KEY_POWER: u16 = 0x119;
+    /// Whether the option boots Android recovery, for the misc partition's `boot-recovery`.
+    fn is_recovery(&self) -> bool {
+        false
+    }
+
+    /// The A/B slot suffix of the partition the option boots from, e.g. `_a`.
+    fn slot(&self) -> Option<&str> {
+        None
+    }
+}
 
 pub type FatFS = FileSystem<OpenDevice, DefaultTimeProvider, LossyOemCpConverter>;
+pub type FatFile<'a> = fatfs::File<'a, OpenDevice, DefaultTimeProvider, LossyOemCpConverter>;
 
+/// Returns true if the bootloader should stay in fastboot instead.
 #[no_mangle]
-pub extern "C" fn boot_scan() {
+pub extern "C" fn boot_scan() -> bool {
+    // What Android or recovery asked for on the last reboot.
+    let mut misc = match bcb::read() {
+        Ok(misc) => Some(misc),
+        Err(BcbError::NotFound) => None,
+        Err(err) => {
+            println!("failed to read misc: {}", err);
+            None
+        }
+    };
+    if let Some(misc) = misc.as_mut().filter(|v| v.command == bcb::COMMAND_BOOTONCE_BOOTLOADER) {
+        println!("misc asks for the bootloader");
+        if let Err(err) = misc.clear_command() {
+            println!("failed to clear misc command: {}", err);
+        }
+        return true;
+    }
+
     // lk_thread::spawn("boot-scan", || {
     let mut options: Vec<Box<dyn BootOption>> = Vec::new();
+    if let Err(err) = partition::publish_nested() {
+        println!("scanning for nested partitions failed: {}", err);
+    }
 
-    for dev in bio::get_bdevs().unwrap().iter().filter(|dev| dev.is_leaf) {
-        // TODO: expose type GUID in bdev and use that to check for ESP instead.
-        if let Some(esp_dev) = dev.label.clone().filter(|label| label.eq("esp")).and_then(|_| bio::open(&dev.name).ok()) {
-            println!("found ESP partition: {:?}", dev.name);
-            match FatFS::new(esp_dev, fatfs::FsOptions::new()) {
-                Ok(fs) => {
-                    scan_esp(Arc::new(fs), "/EFI", &mut options);
+    // The ESP's loader.conf takes precedence, then the first extlinux.conf that has a say. Kept
+    // apart until the scan is done, the ESP isn't necessarily the first device.
+    let mut esp_config = LoaderConfig::default();
+    let mut config = LoaderConfig::default();
+    // Where the entry selection is kept across boots.
+    let mut esp: Option<Rc<dyn BootFs>> = None;
+
+    for dev in bio::get_bdevs().unwrap().iter().filter(|dev| dev.is_leaf && !dev.is_hidden()) {
+        // Partitions without a type GUID, e.g. from MBR, can only go by their name.
+        let is_esp = match dev.type_guid {
+            Some(guid) => guid == guid::ESP,
+            None => dev.label.as_deref() == Some("esp"),
+        };
+        let is_xbootldr = dev.type_guid == Some(guid::XBOOTLDR);
+
+        // Boot loader partitions are opened for writing, so that FAT can be written to.
+        let bdev = if is_esp || is_xbootldr {
+            bio::open_writable(&dev.name).or_else(|_| bio::open(&dev.name))
+        } else {
+            bio::open(&dev.name)
+        };
+        let Ok(mut bdev) = bdev else {
+            continue;
+        };
+
+        match probe::probe(&mut bdev) {
+            Some(FsType::Fat) if is_esp || is_xbootldr => {
+                println!("found {} partition: {:?}", if is_esp { "ESP" } else { "XBOOTLDR" }, dev.name);
+                match FatFS::new(bdev, fatfs::FsOptions::new()) {
+                    Ok(fs) => {
+                        let fs: Rc<dyn BootFs> = Rc::new(fs);
+                        // loader.conf only ever lives on the ESP.
+                        if is_esp {
+                            if let Some(loader_conf) = loader::read(fs.as_ref()) {
+                                esp_config = esp_config.or(loader_conf);
+                            }
+                            esp.get_or_insert(fs.clone());
+                        }
+                        if let Err(err) = scan_esp(fs.clone(), "/EFI", &mut options) {
+                            println!("{}: failed to scan for UKIs: {}", dev.name, err);
+                        }
+                        if let Ok(opts) = bls::scan(fs.clone(), "") {
+                            options.extend(opts);
+                        }
+                        if let Some(extlinux_conf) = scan_images(fs, "", &mut options) {
+                            config = config.or(extlinux_conf);
+                        }
+                    }
+                    Err(e) => println!("noes! {:?}", e),
                 }
-                Err(e) => println!("noes! {:?}", e),
             }
-        }
-
-        if let Ok(opt) = extlinux::scan(&dev.name) {
-            options.push(opt);
+            Some(FsType::Ext) => match Ext4::new(bdev) {
+                Ok(fs) => {
+                    let fs: Rc<dyn BootFs> = Rc::new(fs);
+                    if let Some(extlinux_conf) = scan_linux(fs, partition_suffix(&dev.name), &mut options) {
+                        config = config.or(extlinux_conf);
+                    }
+                }
+                Err(err) => println!("{}: failed to read ext4: {}", dev.name, err),
+            },
+            // exFAT is what large SD cards come with, so anything could be on there.
+            Some(FsType::Exfat) => match ExFat::new(bdev) {
+                Ok(fs) => scan_fs(Rc::new(fs), is_esp, dev, &mut options, &mut config, &mut esp_config),
+                Err(err) => println!("{}: failed to read exFAT: {}", dev.name, err),
+            },
+            // Android's userdata, the one big partition that other OSes get to put their kernels on.
+            Some(FsType::F2fs) => match F2fs::new(bdev) {
+                Ok(fs) => scan_fs(Rc::new(fs), is_esp, dev, &mut options, &mut config, &mut esp_config),
+                Err(err) => println!("{}: failed to read f2fs: {}", dev.name, err),
+            },
+            Some(FsType::Btrfs) => match Btrfs::new(bdev) {
+                Ok(fs) => {
+                    let fs: Rc<dyn BootFs> = Rc::new(fs);
+                    for dir in btrfs::BOOT_DIRS {
+                        if fs.read_dir(dir).is_ok() {
+                            let boot = Rc::new(Subdir::new(fs.clone(), dir));
+                            scan_fs(boot, is_esp, dev, &mut options, &mut config, &mut esp_config);
+                        }
+                    }
+                }
+                Err(err) => println!("{}: failed to read btrfs: {}", dev.name, err),
+            },
+            Some(FsType::Erofs) => match Erofs::new(bdev) {
+                Ok(fs) => scan_fs(Rc::new(fs), is_esp, dev, &mut options, &mut config, &mut esp_config),
+                Err(err) => println!("{}: failed to read EROFS: {}", dev.name, err),
+            },
+            Some(fs) => println!("{}: nothing to scan {:?} with", dev.name, fs),
+            None => {}
         }
     }
 
-        // TODO: check for magic in boot partition
+    options.extend(bootimg::scan(&bio::get_bdevs().unwrap_or_default()));
     //     lk_thread::exit()
     // });
+    let config = esp_config.or(config);
+
+    // Bad entries go last, so they're only booted when picked by hand. So do those on a slot
+    // other than the active one.
+    let active_slot = misc.as_ref().and_then(|v| v.active_slot()).map(bcb::slot_suffix);
+    options.sort_by_key(|v| (v.is_bad(), v.slot().is_some_and(|slot| Some(slot) != active_slot.as_deref())));
+
+    if let Some(misc) = misc.as_ref().filter(|v| v.command == bcb::COMMAND_BOOT_RECOVERY) {
+        println!("misc asks for recovery: {:?}", misc.recovery);
+        // Recovery clears the command itself once it's done.
+        match options.iter_mut().find(|v| v.is_recovery()) {
+            Some(option) => boot(option.as_mut()),
+            None => {
+                println!("no recovery option, leaving it to aboot");
+                return false;
+            }
+        }
+    }
+
+    if options.is_empty() {
+        println!("no boot options found");
+        return false;
+    }
 
     let mut display = fbcon::get().unwrap();
     display.clear(Rgb888::CSS_BLACK).unwrap();
 
-    let mut selected = 0;
+    let mut state = esp.as_deref().map(EntryState::read).unwrap_or_default();
+    let mut selected = select_default(&options, &config, &state);
+    // A oneshot entry is only ever tried once, whether it exists or not.
+    if state.oneshot.take().is_some() {
+        save_state(esp.as_deref(), &state);
+    }
+    // Counts down until the selected option is booted, stopped by any key press.
+    let mut countdown = match config.timeout {
+        Some(Timeout::After(timeout)) => Some(timeout),
+        Some(Timeout::Never) | None => None,
+    };
+    let font = menu_font(config.console_mode);
 
     loop {
+        if countdown == Some(Duration::ZERO) {
+            boot(options[selected].as_mut());
+        }
+
         display.clear(Rgb888::CSS_BLACK).unwrap();
-        print_menu(selected, &options, &mut display);
+        print_menu(selected, &options, countdown, font, &mut display);
 
         options[selected].splash(&mut display);
 
-        match unsafe { wait_key() } {
+        // Wake up every second to update the countdown.
+        let key = keys::wait(countdown.map(|v| v.min(Duration::from_secs(1))));
+        let Some(key) = key else {
+            countdown = countdown.map(|v| v.saturating_sub(Duration::from_secs(1)));
+            continue;
+        };
+        countdown = None;
+
+        match key {
             KEY_POWER => {
-                options[selected].boot();
+                let id = options[selected].id().to_string();
+                if state.saved.as_ref() != Some(&id) {
+                    state.saved = Some(id);
+                    save_state(esp.as_deref(), &state);
+                }
+                boot(options[selected].as_mut());
             }
             KEY_VOLUMEUP => {
                 if selected == 0 {
@@ -112,12 +297,118 @@ pub extern "C" fn boot_scan() {
     }
 }
 
-fn print_menu<DT: DrawTarget<Color = Rgb888>>(selected: usize, options: &Vec<Box<dyn BootOption>>, display: &mut DT) {
-    let text_style = MonoTextStyle::new(&PROFONT_24_POINT, Rgb888::CSS_SLATE_GRAY);
-    let selected_text_style = MonoTextStyle::new(&PROFONT_24_POINT, Rgb888::CSS_HOT_PINK);
+/// Pick the option to start out with: the oneshot entry, then the default from the entry state,
+/// then the one from loader.conf or extlinux.conf, and failing all that the first one.
+fn select_default(options: &[Box<dyn BootOption>], config: &LoaderConfig, state: &EntryState) -> usize {
+    let by_id = |id: &str| options.iter().position(|v| v.id() == id);
+    let by_pattern = |pattern: &str| options.iter().position(|v| loader::glob_match(pattern, v.id()));
+
+    state.oneshot.as_deref().and_then(by_id)
+        .or_else(|| state.default.as_deref().and_then(by_pattern))
+        .or_else(|| match config.default.as_deref()? {
+            "@saved" => state.saved.as_deref().and_then(by_id),
+            pattern => by_pattern(pattern),
+        })
+        .unwrap_or(0)
+}
+
+fn save_state(esp: Option<&dyn BootFs>, state: &EntryState) {
+    if let Some(Err(err)) = esp.map(|fs| state.write(fs)) {
+        println!("failed to save entry state: {}", err);
+    }
+}
+
+fn boot(option: &mut dyn BootOption) -> ! {
+    kernel_boot::set_boot_entry(option.label());
+    option.boot()
+}
+
+/// Pick the menu font for a loader.conf console-mode. Higher modes get smaller text, so more of
+/// it fits on screen.
+fn menu_font(mode: Option<ConsoleMode>) -> &'static MonoFont<'static> {
+    match mode {
+        None | Some(ConsoleMode::Keep) | Some(ConsoleMode::Mode(0)) => &PROFONT_24_POINT,
+        Some(ConsoleMode::Mode(1)) => &PROFONT_18_POINT,
+        Some(ConsoleMode::Mode(_)) | Some(ConsoleMode::Max) => &PROFONT_14_POINT,
+    }
+}
+
+/// Add the extlinux.conf labels and BLS entries on `fs` and in EROFS images on it, returning the
+/// menu settings from the first extlinux.conf.
+fn scan_linux(fs: Rc<dyn BootFs>, suffix: &str, options: &mut Vec<Box<dyn BootOption>>) -> Option<LoaderConfig> {
+    let mut config = None;
+    if let Ok((opts, extlinux_conf)) = extlinux::scan(fs.clone(), suffix) {
+        options.extend(opts);
+        config = Some(extlinux_conf);
+    }
+    if let Ok(opts) = bls::scan(fs.clone(), suffix) {
+        options.extend(opts);
+    }
+    config.or(scan_images(fs, suffix, options))
+}
+
+/// Scan a filesystem that isn't only there for booting, so may have anything on it: UKIs,
+/// extlinux.conf, BLS entries or EROFS images.
+fn scan_fs(
+    fs: Rc<dyn BootFs>,
+    is_esp: bool,
+    dev: &bio::BlockDev,
+    options: &mut Vec<Box<dyn BootOption>>,
+    config: &mut LoaderConfig,
+    esp_config: &mut LoaderConfig,
+) {
+    // loader.conf only ever lives on the ESP.
+    if is_esp {
+        if let Some(loader_conf) = loader::read(fs.as_ref()) {
+            *esp_config = core::mem::take(esp_config).or(loader_conf);
+        }
+    }
+    if let Err(err) = scan_esp(fs.clone(), "/EFI", options) {
+        println!("{}: failed to scan for UKIs: {}", dev.name, err);
+    }
+    if let Some(extlinux_conf) = scan_linux(fs, partition_suffix(&dev.name), options) {
+        *config = core::mem::take(config).or(extlinux_conf);
+    }
+}
+
+/// Scan the `*.erofs` images in the root of `fs` like partitions of their own, for OSes that ship
+/// their boot files as a read-only image.
+fn scan_images(fs: Rc<dyn BootFs>, suffix: &str, options: &mut Vec<Box<dyn BootOption>>) -> Option<LoaderConfig> {
+    let mut config = None;
+    for entry in fs.read_dir("/").unwrap_or_default() {
+        if entry.is_dir || !entry.name.ends_with(".erofs") {
+            continue;
+        }
+        let image = fs::open_image(fs.clone(), &entry.name).map_err(Error::msg).and_then(|v| Erofs::new(v).map_err(Error::msg));
+        match image {
+            Ok(image) => config = config.or(scan_linux(Rc::new(image), suffix, options)),
+            Err(err) => println!("{}: failed to read EROFS image: {}", entry.name, err),
+        }
+    }
+    config
+}
+
+/// Tell apart boot options found on ext2 partitions by where they came from.
+fn partition_suffix(partition: &str) -> &'static str {
+    // TODO: properly detect where devices are coming from, somehow...
+    if partition.starts_with("wrp0") { " (internal)" } else { " (SD card)" }
+}
+
+fn print_menu<DT: DrawTarget<Color = Rgb888>>(
+    selected: usize,
+    options: &Vec<Box<dyn BootOption>>,
+    countdown: Option<Duration>,
+    font: &MonoFont,
+    display: &mut DT,
+) {
+    let text_style = MonoTextStyle::new(font, Rgb888::CSS_SLATE_GRAY);
+    let selected_text_style = MonoTextStyle::new(font, Rgb888::CSS_HOT_PINK);
+
+    let countdown = countdown.map(|v| format!("Booting in {}s", v.as_millis().div_ceil(1000)));
 
     let mut views: Vec<_> = options.iter().enumerate()
         .map(|(idx, option)| Text::new(option.label(), Point::zero(), if idx == selected { selected_text_style } else { text_style }))
+        .chain(countdown.as_deref().map(|v| Text::new(v, Point::zero(), text_style)))
         .collect();
 
     // let layout = LinearLayout::vertical(Chain::new(Text::new("Select a boot option", Point::zero(), text_style)).append(Views::new(&mut views)));
@@ -130,24 +421,91 @@ fn print_menu<DT: DrawTarget<Color = Rgb888>>(selected: usize, options: &Vec<Box
 
 }
 
-fn scan_esp(fs: Arc<FatFS>, root: &str, options: &mut Vec<Box<dyn BootOption>>) -> anyhow::Result<()> {
-    let dir = fs.root_dir().open_dir(root).map_err(Error::msg)?;
-    for entry in dir.iter().flatten() {
-        let name = entry.file_name();
-        if name != ".." && name != "." {
-            if entry.is_dir() {
-                scan_esp(fs.clone(), &format!("{}/{}", root, entry.file_name()), options)?;
-            } else if name.ends_with(".efi") {
-                println!("parsing {} of size {}", name, entry.len());
-                match kernel_boot::parse_uki(fs.clone(), &format!("{}/{}", root, name)) {
-                    Ok(config) => {
-                        options.push(Box::new(config));
-                    }
-                    Err(err) => println!("oof: {:?}", err),
+fn scan_esp(fs: Rc<dyn BootFs>, root: &str, options: &mut Vec<Box<dyn BootOption>>) -> anyhow::Result<()> {
+    for entry in fs.read_dir(root).map_err(Error::msg)? {
+        let name = entry.name;
+        if entry.is_dir {
+            // Drop-in directories hold overlays and addons for a UKI, not UKIs themselves.
+            if name.ends_with(".extra.d") {
+                continue;
+            }
+            scan_esp(fs.clone(), &format!("{}/{}", root, name), options)?;
+        } else if name.ends_with(".efi") {
+            println!("parsing {}", name);
+            match kernel_boot::parse_uki(fs.clone(), &format!("{}/{}", root, name)) {
+                Ok(profiles) => {
+                    options.extend(profiles.into_iter().map(|v| Box::new(v) as Box<dyn BootOption>));
                 }
+                Err(err) => println!("oof: {:?}", err),
             }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestOption(&'static str);
+
+    impl BootOption for TestOption {
+        fn id(&self) -> &str {
+            self.0
+        }
+
+        fn label(&self) -> &str {
+            self.0
+        }
+
+        fn splash(&self, _display: &mut FbCon888) -> Result<(), ()> {
+            Err(())
+        }
+
+        fn boot(&mut self) -> ! {
+            unreachable!()
+        }
+    }
+
+    fn options() -> Vec<Box<dyn BootOption>> {
+        ["arch.conf", "arch-lts.conf", "fedora.conf"].into_iter()
+            .map(|v| Box::new(TestOption(v)) as Box<dyn BootOption>)
+            .collect()
+    }
+
+    fn config(default: &str) -> LoaderConfig {
+        LoaderConfig { default: Some(default.into()), ..Default::default() }
+    }
+
+    fn saved(id: &str) -> EntryState {
+        EntryState { saved: Some(id.into()), ..Default::default() }
+    }
+
+    #[test]
+    fn default_pattern() {
+        let options = options();
+        assert_eq!(select_default(&options, &config("arch-*"), &EntryState::default()), 1);
+        assert_eq!(select_default(&options, &config("*.conf"), &EntryState::default()), 0);
+        assert_eq!(select_default(&options, &config("debian*"), &EntryState::default()), 0);
+        assert_eq!(select_default(&options, &LoaderConfig::default(), &EntryState::default()), 0);
+
+        // The entry state's default and oneshot come first.
+        let state = EntryState { default: Some("fedora*".into()), ..Default::default() };
+        assert_eq!(select_default(&options, &config("arch-*"), &state), 2);
+        let state = EntryState { oneshot: Some("arch-lts.conf".into()), ..state };
+        assert_eq!(select_default(&options, &config("arch-*"), &state), 1);
+    }
+
+    #[test]
+    fn default_saved() {
+        let options = options();
+        assert_eq!(select_default(&options, &config("@saved"), &saved("fedora.conf")), 2);
+        // The saved id is matched as is, not as a glob.
+        assert_eq!(select_default(&options, &config("@saved"), &saved("fedora*")), 0);
+        assert_eq!(select_default(&options, &config("@saved"), &saved("debian.conf")), 0);
+        assert_eq!(select_default(&options, &config("@saved"), &EntryState::default()), 0);
+        // The saved id only matters with `default @saved`.
+        assert_eq!(select_default(&options, &config("arch-*"), &saved("fedora.conf")), 1);
+    }
+}
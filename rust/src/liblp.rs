@@ -0,0 +1,455 @@
+//! Android dynamic partitions: the liblp metadata in the `super` partition, and the logical
+//! partitions it describes.
+//!
+//! Logical partitions are published as linear devices named after themselves, so `system_a`
+//! can be opened like any other partition.
+
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::ffi::c_int;
+use sha2::{Digest, Sha256};
+use snafu::Snafu;
+use crate::bio::{self, BioError, BlockDev, LinearExtent, OpenDevice};
+use crate::println;
+
+#[derive(Debug, Snafu)]
+pub enum LiblpError {
+    #[snafu(display("failed to open device"))]
+    Open,
+    #[snafu(display("I/O error: {source}"))]
+    Io { source: BioError },
+    #[snafu(display("no valid geometry"))]
+    InvalidGeometry,
+    #[snafu(display("no valid metadata"))]
+    InvalidMetadata,
+    #[snafu(display("{name}: no block device {device}"))]
+    MissingDevice { name: String, device: String },
+    #[snafu(display("failed to publish {name}"))]
+    Publish { name: String },
+    #[snafu(display("failed to list devices"))]
+    ListDevices,
+}
+
+impl From<BioError> for LiblpError {
+    fn from(source: BioError) -> Self {
+        LiblpError::Io { source }
+    }
+}
+
+/// The GPT name of the partition that has the metadata in it.
+const SUPER_LABEL: &str = "super";
+
+const SECTOR_SIZE: u64 = 512;
+/// Left alone for a boot sector or partition table.
+const RESERVED_BYTES: u64 = 4096;
+/// The geometry is stored twice, each copy padded to this size.
+const GEOMETRY_SIZE: u64 = 4096;
+const GEOMETRY_MAGIC: u32 = 0x616c4467;
+const GEOMETRY_STRUCT_SIZE: usize = 52;
+
+const HEADER_MAGIC: u32 = 0x414c5030;
+const HEADER_MAJOR_VERSION: u16 = 10;
+const HEADER_MAX_MINOR_VERSION: u16 = 2;
+/// Size of the header up to version 10.1. 10.2 adds flags and padding.
+const HEADER_MIN_SIZE: usize = 128;
+
+const PARTITION_SIZE: usize = 52;
+const EXTENT_SIZE: usize = 24;
+const BLOCK_DEVICE_SIZE: usize = 64;
+const NAME_LEN: usize = 36;
+
+const PARTITION_ATTR_DISABLED: u32 = 1 << 3;
+const TARGET_TYPE_LINEAR: u32 = 0;
+const TARGET_TYPE_ZERO: u32 = 1;
+
+// Metadata is 64KB in practice, anything much bigger is not liblp.
+const METADATA_MAX_SIZE: u32 = 1024 * 1024;
+
+struct Geometry {
+    metadata_max_size: u64,
+    metadata_slot_count: u64,
+}
+
+#[derive(Debug)]
+pub struct Extent {
+    pub sectors: u64,
+    /// Sector on the block device, `None` if the extent reads as zeroes.
+    pub target: Option<(usize, u64)>,
+}
+
+#[derive(Debug)]
+pub struct LogicalPartition {
+    pub name: String,
+    pub attributes: u32,
+    pub extents: Vec<Extent>,
+}
+
+pub struct Metadata {
+    pub partitions: Vec<LogicalPartition>,
+    /// Names of the partitions the extents are on. The first one is the super partition itself.
+    pub block_devices: Vec<String>,
+}
+
+/// A table descriptor: where in the tables the entries are, how many there are and how big.
+struct Table<'a> {
+    data: &'a [u8],
+    count: usize,
+    entry_size: usize,
+}
+
+impl<'a> Table<'a> {
+    fn parse(tables: &'a [u8], desc: &[u8], min_entry_size: usize) -> Option<Self> {
+        let offset = LittleEndian::read_u32(desc) as usize;
+        let count = LittleEndian::read_u32(&desc[4..]) as usize;
+        let entry_size = LittleEndian::read_u32(&desc[8..]) as usize;
+        if entry_size < min_entry_size {
+            return None;
+        }
+        let data = tables.get(offset..offset.checked_add(count.checked_mul(entry_size)?)?)?;
+        Some(Table { data, count, entry_size })
+    }
+
+    fn entry(&self, idx: usize) -> &'a [u8] {
+        &self.data[idx * self.entry_size..][..self.entry_size]
+    }
+}
+
+fn sha256_zeroed(data: &[u8], checksum: core::ops::Range<usize>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(&data[..checksum.start]);
+    hasher.update([0; 32]);
+    hasher.update(&data[checksum.end..]);
+    hasher.finalize().into()
+}
+
+fn name(data: &[u8]) -> Option<String> {
+    let len = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    core::str::from_utf8(&data[..len]).ok().filter(|v| !v.is_empty()).map(|v| v.to_string())
+}
+
+fn read_geometry(dev: &mut OpenDevice) -> Result<Geometry, LiblpError> {
+    let mut block = vec![0; GEOMETRY_STRUCT_SIZE];
+    for copy in 0..2 {
+        dev.read_at(RESERVED_BYTES + copy * GEOMETRY_SIZE, &mut block)?;
+        if LittleEndian::read_u32(&block) != GEOMETRY_MAGIC
+            || LittleEndian::read_u32(&block[4..]) as usize != GEOMETRY_STRUCT_SIZE
+            || sha256_zeroed(&block, 8..40) != block[8..40]
+        {
+            continue;
+        }
+        let metadata_max_size = LittleEndian::read_u32(&block[40..]);
+        let metadata_slot_count = LittleEndian::read_u32(&block[44..]);
+        let logical_block_size = LittleEndian::read_u32(&block[48..]) as u64;
+        if metadata_max_size as usize <= HEADER_MIN_SIZE || metadata_max_size > METADATA_MAX_SIZE
+            || metadata_max_size as u64 % SECTOR_SIZE != 0 || metadata_slot_count == 0
+            || logical_block_size == 0 || logical_block_size % SECTOR_SIZE != 0
+        {
+            continue;
+        }
+        return Ok(Geometry {
+            metadata_max_size: metadata_max_size as u64,
+            metadata_slot_count: metadata_slot_count as u64,
+        });
+    }
+    Err(LiblpError::InvalidGeometry)
+}
+
+/// Parse the metadata at `offset`, returning `None` if it is damaged.
+fn read_metadata(dev: &mut OpenDevice, geometry: &Geometry, offset: u64) -> Option<Metadata> {
+    let mut data = vec![0; geometry.metadata_max_size as usize];
+    dev.read_at(offset, &mut data).ok()?;
+
+    if LittleEndian::read_u32(&data) != HEADER_MAGIC
+        || LittleEndian::read_u16(&data[4..]) != HEADER_MAJOR_VERSION
+        || LittleEndian::read_u16(&data[6..]) > HEADER_MAX_MINOR_VERSION
+    {
+        return None;
+    }
+    let header_size = LittleEndian::read_u32(&data[8..]) as usize;
+    if header_size < HEADER_MIN_SIZE || header_size > data.len() {
+        return None;
+    }
+    let header = &data[..header_size];
+    if sha256_zeroed(header, 12..44) != header[12..44] {
+        return None;
+    }
+    let tables_size = LittleEndian::read_u32(&header[44..]) as usize;
+    let tables = data.get(header_size..header_size.checked_add(tables_size)?)?;
+    if Sha256::digest(tables)[..] != header[48..80] {
+        return None;
+    }
+
+    let partition_table = Table::parse(tables, &header[80..], PARTITION_SIZE)?;
+    let extent_table = Table::parse(tables, &header[92..], EXTENT_SIZE)?;
+    let block_device_table = Table::parse(tables, &header[116..], BLOCK_DEVICE_SIZE)?;
+    let block_devices = (0..block_device_table.count)
+        .map(|i| name(&block_device_table.entry(i)[24..24 + NAME_LEN]))
+        .collect::<Option<Vec<_>>>()?;
+
+    let partitions = (0..partition_table.count).map(|i| {
+        let entry = partition_table.entry(i);
+        let first = LittleEndian::read_u32(&entry[40..]) as usize;
+        let count = LittleEndian::read_u32(&entry[44..]) as usize;
+        let extents = (first..first.checked_add(count)?).map(|i| {
+            if i >= extent_table.count {
+                return None;
+            }
+            let entry = extent_table.entry(i);
+            let target = match LittleEndian::read_u32(&entry[8..]) {
+                TARGET_TYPE_LINEAR => {
+                    let device = LittleEndian::read_u32(&entry[20..]) as usize;
+                    if device >= block_devices.len() {
+                        return None;
+                    }
+                    Some((device, LittleEndian::read_u64(&entry[12..])))
+                }
+                TARGET_TYPE_ZERO => None,
+                _ => return None,
+            };
+            Some(Extent { sectors: LittleEndian::read_u64(entry), target })
+        }).collect::<Option<Vec<_>>>()?;
+        Some(LogicalPartition {
+            name: name(&entry[..NAME_LEN])?,
+            attributes: LittleEndian::read_u32(&entry[NAME_LEN..]),
+            extents,
+        })
+    }).collect::<Option<Vec<_>>>()?;
+
+    Some(Metadata { partitions, block_devices })
+}
+
+/// Read the metadata of the super partition `dev`. Every slot has its own copy, plus a backup of
+/// it. Slot 0 is preferred, as the logical partitions of both slots are listed in each.
+pub fn parse(dev: &mut OpenDevice) -> Result<Metadata, LiblpError> {
+    let geometry = read_geometry(dev)?;
+    let primary = RESERVED_BYTES + GEOMETRY_SIZE * 2;
+    let backup = primary + geometry.metadata_max_size * geometry.metadata_slot_count;
+    for slot in 0..geometry.metadata_slot_count {
+        for base in [primary, backup] {
+            if let Some(metadata) = read_metadata(dev, &geometry, base + slot * geometry.metadata_max_size) {
+                return Ok(metadata);
+            }
+        }
+    }
+    Err(LiblpError::InvalidMetadata)
+}
+
+/// Publish the logical partitions in the super partition `device`, skipping empty and disabled
+/// ones. Returns the number of partitions published.
+pub fn publish(device: &str, devs: &[BlockDev]) -> Result<usize, LiblpError> {
+    let mut dev = bio::open(device).map_err(|_| LiblpError::Open)?;
+    let metadata = parse(&mut dev)?;
+    drop(dev);
+
+    // The other block devices only show up on devices retrofitted with dynamic partitions.
+    let block_devices = metadata.block_devices.iter().enumerate().map(|(i, name)| {
+        if i == 0 {
+            return Some(device);
+        }
+        devs.iter().find(|v| v.label.as_deref() == Some(name.as_str())).map(|v| v.name.as_str())
+    }).collect::<Vec<_>>();
+
+    let mut count = 0;
+    for partition in &metadata.partitions {
+        if partition.extents.is_empty() || partition.attributes & PARTITION_ATTR_DISABLED != 0 {
+            continue;
+        }
+        let extents = partition.extents.iter().map(|extent| {
+            let (parent, start) = match extent.target {
+                Some((device, sector)) => match block_devices[device] {
+                    Some(parent) => (Some(parent), sector),
+                    None => return Err(LiblpError::MissingDevice {
+                        name: partition.name.clone(),
+                        device: metadata.block_devices[device].clone(),
+                    }),
+                },
+                None => (None, 0),
+            };
+            Ok(LinearExtent { parent, start, count: extent.sectors })
+        }).collect::<Result<Vec<_>, _>>()?;
+
+        bio::publish_linear(&partition.name, &partition.name, &extents)
+            .map_err(|_| LiblpError::Publish { name: partition.name.clone() })?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Publish the logical partitions of all super partitions. Returns the number of partitions
+/// published.
+pub fn publish_all() -> Result<usize, LiblpError> {
+    let devs = bio::get_bdevs().map_err(|_| LiblpError::ListDevices)?;
+    let mut count = 0;
+    for dev in devs.iter().filter(|v| v.is_leaf && v.label.as_deref() == Some(SUPER_LABEL)) {
+        match publish(&dev.name, &devs) {
+            Ok(n) => {
+                println!("found {} logical partitions in {}", n, dev.name);
+                count += n;
+            }
+            Err(err) => println!("{}: {}", dev.name, err),
+        }
+    }
+    Ok(count)
+}
+
+/// C entry point for publish_all().
+#[no_mangle]
+pub extern "C" fn lk2nd_liblp_publish() -> c_int {
+    match publish_all() {
+        Ok(count) => count as c_int,
+        Err(err) => {
+            println!("publishing logical partitions failed: {}", err);
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use super::*;
+
+    const METADATA_SIZE: u32 = 4096;
+    const PRIMARY: usize = (RESERVED_BYTES + GEOMETRY_SIZE * 2) as usize;
+    const BACKUP: usize = PRIMARY + METADATA_SIZE as usize * 2;
+
+    /// An extent as (sectors, target sector and block device, or `None` for zeroes).
+    type TestExtent = (u64, Option<(u64, u32)>);
+
+    fn put_name(entry: &mut [u8], name: &str) {
+        entry[..name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    fn geometry(disk: &mut [u8]) {
+        let block = &mut disk[RESERVED_BYTES as usize..][..GEOMETRY_STRUCT_SIZE];
+        LittleEndian::write_u32(block, GEOMETRY_MAGIC);
+        LittleEndian::write_u32(&mut block[4..], GEOMETRY_STRUCT_SIZE as u32);
+        LittleEndian::write_u32(&mut block[40..], METADATA_SIZE);
+        LittleEndian::write_u32(&mut block[44..], 2);
+        LittleEndian::write_u32(&mut block[48..], 4096);
+        let checksum = sha256_zeroed(block, 8..40);
+        block[8..40].copy_from_slice(&checksum);
+        let block = block.to_vec();
+        disk[(RESERVED_BYTES + GEOMETRY_SIZE) as usize..][..GEOMETRY_STRUCT_SIZE].copy_from_slice(&block);
+    }
+
+    fn metadata(partitions: &[(&str, u32, &[TestExtent])], block_devices: &[&str]) -> Vec<u8> {
+        let mut tables = Vec::new();
+        let mut extents = Vec::new();
+        for &(name, attributes, part_extents) in partitions {
+            let mut entry = [0; PARTITION_SIZE];
+            put_name(&mut entry, name);
+            LittleEndian::write_u32(&mut entry[36..], attributes);
+            LittleEndian::write_u32(&mut entry[40..], (extents.len() / EXTENT_SIZE) as u32);
+            LittleEndian::write_u32(&mut entry[44..], part_extents.len() as u32);
+            tables.extend(entry);
+            for &(sectors, target) in part_extents {
+                let mut entry = [0; EXTENT_SIZE];
+                LittleEndian::write_u64(&mut entry, sectors);
+                match target {
+                    Some((sector, device)) => {
+                        LittleEndian::write_u32(&mut entry[8..], TARGET_TYPE_LINEAR);
+                        LittleEndian::write_u64(&mut entry[12..], sector);
+                        LittleEndian::write_u32(&mut entry[20..], device);
+                    }
+                    None => LittleEndian::write_u32(&mut entry[8..], TARGET_TYPE_ZERO),
+                }
+                extents.extend(entry);
+            }
+        }
+        let extents_offset = tables.len();
+        tables.extend(extents);
+        let devices_offset = tables.len();
+        for name in block_devices {
+            let mut entry = [0; BLOCK_DEVICE_SIZE];
+            put_name(&mut entry[24..], name);
+            tables.extend(entry);
+        }
+
+        let mut header = vec![0; HEADER_MIN_SIZE];
+        LittleEndian::write_u32(&mut header, HEADER_MAGIC);
+        LittleEndian::write_u16(&mut header[4..], HEADER_MAJOR_VERSION);
+        LittleEndian::write_u32(&mut header[8..], HEADER_MIN_SIZE as u32);
+        LittleEndian::write_u32(&mut header[44..], tables.len() as u32);
+        header[48..80].copy_from_slice(&Sha256::digest(&tables));
+        for (desc, offset, count, size) in [
+            (80, 0, partitions.len(), PARTITION_SIZE),
+            (92, extents_offset, (devices_offset - extents_offset) / EXTENT_SIZE, EXTENT_SIZE),
+            (104, devices_offset, 0, 48),
+            (116, devices_offset, block_devices.len(), BLOCK_DEVICE_SIZE),
+        ] {
+            LittleEndian::write_u32(&mut header[desc..], offset as u32);
+            LittleEndian::write_u32(&mut header[desc + 4..], count as u32);
+            LittleEndian::write_u32(&mut header[desc + 8..], size as u32);
+        }
+        let checksum = sha256_zeroed(&header, 12..44);
+        header[12..44].copy_from_slice(&checksum);
+        header.extend(tables);
+        header
+    }
+
+    /// A super partition with `metadata` in the primary and backup copies of both slots.
+    fn super_image(metadata: &[u8]) -> Vec<u8> {
+        let mut disk = vec![0; 64 * 1024];
+        geometry(&mut disk);
+        for slot in 0..2 {
+            for base in [PRIMARY, BACKUP] {
+                disk[base + slot * METADATA_SIZE as usize..][..metadata.len()].copy_from_slice(metadata);
+            }
+        }
+        disk
+    }
+
+    fn open(disk: Vec<u8>) -> OpenDevice {
+        let size = disk.len() as u64;
+        OpenDevice::from_image(Box::new(disk), size)
+    }
+
+    fn example() -> Vec<u8> {
+        metadata(&[
+            ("system_a", 0, &[(2048, Some((2048, 0))), (1024, None), (512, Some((0, 1)))]),
+            ("vendor_a", PARTITION_ATTR_DISABLED, &[(256, Some((8192, 0)))]),
+            ("system_b", 0, &[]),
+        ], &["super", "super_ext"])
+    }
+
+    #[test]
+    fn metadata_parse() {
+        let metadata = parse(&mut open(super_image(&example()))).unwrap();
+        assert_eq!(metadata.block_devices, ["super", "super_ext"]);
+        let names: Vec<_> = metadata.partitions.iter().map(|v| v.name.as_str()).collect();
+        assert_eq!(names, ["system_a", "vendor_a", "system_b"]);
+
+        let system = &metadata.partitions[0];
+        let extents: Vec<_> = system.extents.iter().map(|v| (v.sectors, v.target)).collect();
+        assert_eq!(extents, [(2048, Some((0, 2048))), (1024, None), (512, Some((1, 0)))]);
+        assert_eq!(metadata.partitions[1].attributes, PARTITION_ATTR_DISABLED);
+        assert!(metadata.partitions[2].extents.is_empty());
+    }
+
+    #[test]
+    fn metadata_backup() {
+        let mut disk = super_image(&example());
+        // Corrupt the primary copy of slot 0, and drop the first geometry copy.
+        disk[PRIMARY + HEADER_MIN_SIZE] ^= 0xff;
+        disk[RESERVED_BYTES as usize] ^= 0xff;
+        let metadata = parse(&mut open(disk)).unwrap();
+        assert_eq!(metadata.partitions.len(), 3);
+    }
+
+    #[test]
+    fn invalid_extent_device() {
+        let metadata = metadata(&[("system_a", 0, &[(2048, Some((2048, 1)))])], &["super"]);
+        assert!(matches!(parse(&mut open(super_image(&metadata))), Err(LiblpError::InvalidMetadata)));
+    }
+
+    #[test]
+    fn invalid_geometry() {
+        let mut disk = super_image(&example());
+        disk[RESERVED_BYTES as usize + 40] ^= 0xff;
+        disk[(RESERVED_BYTES + GEOMETRY_SIZE) as usize + 40] ^= 0xff;
+        assert!(matches!(parse(&mut open(disk)), Err(LiblpError::InvalidGeometry)));
+    }
+}
@@ -0,0 +1,79 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::ffi::{c_char, CStr};
+
+fn to_string(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(ptr) }.to_str().ok().map(|v| v.to_string())
+}
+
+/// DTB file names (without extension) that lk2nd thinks match this device, most specific first.
+pub fn dtb_hints() -> Vec<String> {
+    let mut hints = Vec::new();
+    let mut ptr = unsafe { sys::lk2nd_device_get_dtb_hints() };
+    if ptr.is_null() {
+        return hints;
+    }
+    unsafe {
+        while !(*ptr).is_null() {
+            hints.extend(to_string(*ptr));
+            ptr = ptr.add(1);
+        }
+    }
+    hints
+}
+
+/// Where a DTB for this device could be in `dir`, a directory of DTBs as installed by the kernel,
+/// most likely first.
+pub fn dtb_paths(dir: &str) -> Vec<String> {
+    dtb_hints().iter()
+        .flat_map(|hint| [
+            // NOTE: Try aarch64 path, then aarch32 one.
+            format!("{}/qcom/{}.dtb", dir, hint),
+            format!("{}/qcom-{}.dtb", dir, hint),
+            // boot-deploy drops the vendor dir when copying dtbs.
+            format!("{}/{}.dtb", dir, hint),
+        ])
+        .collect()
+}
+
+/// Compatible of the device lk2nd detected.
+pub fn compatible() -> Option<String> {
+    to_string(unsafe { sys::lk2nd_device_get_compatible() })
+}
+
+/// Model name of the device lk2nd detected.
+pub fn model() -> Option<String> {
+    to_string(unsafe { sys::lk2nd_device_get_model() })
+}
+
+/// Compatibles that the root node of a kernel DTB for this device is expected to carry, best match
+/// first. This is the compatible lk2nd detected, followed by guesses derived from the DTB hints.
+/// Mainline DTBs are named `<soc>-<vendor>-<board>` and (nearly always) have `<vendor>,<board>` as
+/// their most specific compatible.
+pub fn compatibles() -> Vec<String> {
+    let mut compatibles: Vec<String> = compatible().into_iter().collect();
+    for hint in dtb_hints() {
+        let hint = hint.rsplit('/').next().unwrap_or(&hint);
+        if let Some((vendor, board)) = hint.split_once('-').and_then(|(_, rest)| rest.split_once('-')) {
+            let guess = format!("{},{}", vendor, board);
+            if !compatibles.contains(&guess) {
+                compatibles.push(guess);
+            }
+        }
+    }
+    compatibles
+}
+
+mod sys {
+    use core::ffi::c_char;
+
+    extern "C" {
+        pub fn lk2nd_device_get_dtb_hints() -> *const *const c_char;
+        pub fn lk2nd_device_get_compatible() -> *const c_char;
+        pub fn lk2nd_device_get_model() -> *const c_char;
+    }
+}
@@ -0,0 +1,261 @@
+//! Boot menu settings, from systemd-boot's `/loader/loader.conf` or extlinux.conf, and the entry
+//! selection kept on the ESP between boots.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use core::time::Duration;
+use crate::fs::BootFs;
+use crate::kernel_boot::BootError;
+
+const LOADER_CONF: &str = "/loader/loader.conf";
+const ENTRY_STATE: &str = "/loader/entry-state";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timeout {
+    /// Boot the default entry unless a key is pressed before this runs out.
+    After(Duration),
+    /// Wait for the user to pick an entry.
+    Never,
+}
+
+/// How big the menu text should be. systemd-boot picks a text mode with these, and higher modes
+/// have more rows, so here they pick smaller fonts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsoleMode {
+    Mode(u32),
+    Max,
+    Keep,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LoaderConfig {
+    /// Glob matched against the id of each boot option.
+    pub default: Option<String>,
+    pub timeout: Option<Timeout>,
+    pub console_mode: Option<ConsoleMode>,
+}
+
+impl LoaderConfig {
+    /// Fill in whatever isn't set here from `other`.
+    pub fn or(self, other: LoaderConfig) -> LoaderConfig {
+        LoaderConfig {
+            default: self.default.or(other.default),
+            timeout: self.timeout.or(other.timeout),
+            console_mode: self.console_mode.or(other.console_mode),
+        }
+    }
+}
+
+/// Entry selection that persists across boots, in `/loader/entry-state` on the ESP. It stands in
+/// for the EFI variables systemd-boot keeps these in, and has the same `key value` lines as
+/// loader.conf, so userspace can set the next boot target by writing it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EntryState {
+    /// Glob for the entry to boot by default, taking precedence over loader.conf.
+    pub default: Option<String>,
+    /// Id of the entry to boot next time only. Cleared as soon as it has been read.
+    pub oneshot: Option<String>,
+    /// Id of the entry last picked in the menu, for `default @saved` in loader.conf.
+    pub saved: Option<String>,
+}
+
+impl EntryState {
+    /// Read the entry state on an ESP. A missing file is the same as an empty one.
+    pub fn read(fs: &dyn BootFs) -> EntryState {
+        let mut state = EntryState::default();
+        let Ok(data) = fs.read(ENTRY_STATE) else {
+            return state;
+        };
+        for (key, value) in key_values(&String::from_utf8_lossy(&data)) {
+            let value = Some(value.to_string()).filter(|v| !v.is_empty());
+            match key {
+                "default" => state.default = value,
+                "oneshot" => state.oneshot = value,
+                "saved" => state.saved = value,
+                _ => {}
+            }
+        }
+        state
+    }
+
+    pub fn write(&self, fs: &dyn BootFs) -> Result<(), BootError> {
+        let mut data = String::new();
+        for (key, value) in [("default", &self.default), ("oneshot", &self.oneshot), ("saved", &self.saved)] {
+            if let Some(value) = value {
+                data += &format!("{} {}\n", key, value);
+            }
+        }
+        fs.write(ENTRY_STATE, data.as_bytes())
+    }
+}
+
+/// The `key value` pairs of a loader.conf style file, leaving out empty lines and comments.
+fn key_values(data: &str) -> impl Iterator<Item = (&str, &str)> {
+    data.lines().map(str::trim).filter(|v| !v.is_empty() && !v.starts_with('#')).map(|line| {
+        match line.split_once(|c: char| c.is_ascii_whitespace()) {
+            Some((key, value)) => (key, value.trim()),
+            None => (line, ""),
+        }
+    })
+}
+
+/// Parse a loader.conf. Unknown keys and values are ignored.
+pub fn parse(data: &str) -> LoaderConfig {
+    let mut config = LoaderConfig::default();
+
+    for (key, value) in key_values(data) {
+        match key {
+            "default" => config.default = Some(value.to_string()),
+            "timeout" => config.timeout = match value {
+                "menu-force" => Some(Timeout::Never),
+                // We can't show the menu on a key press after the fact, so these are the same as 0.
+                "menu-hidden" | "menu-disabled" => Some(Timeout::After(Duration::ZERO)),
+                value => value.parse().ok().map(|v| Timeout::After(Duration::from_secs(v))),
+            },
+            "console-mode" => config.console_mode = match value {
+                "max" => Some(ConsoleMode::Max),
+                "keep" | "auto" => Some(ConsoleMode::Keep),
+                value => value.parse().ok().map(ConsoleMode::Mode),
+            },
+            _ => {}
+        }
+    }
+
+    config
+}
+
+/// Read the loader.conf on an ESP, if there is one.
+pub fn read(fs: &dyn BootFs) -> Option<LoaderConfig> {
+    let data = fs.read(LOADER_CONF).ok()?;
+    Some(parse(&String::from_utf8_lossy(&data)))
+}
+
+/// Match `name` against a shell-style glob with `*`, `?` and `[...]`, like fnmatch().
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let (pattern, name) = (pattern.as_bytes(), name.as_bytes());
+    // Position to resume from after the last `*`, in the pattern and the name.
+    let mut backtrack = None;
+    let (mut p, mut n) = (0, 0);
+
+    while n < name.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    backtrack = Some((p, n));
+                    p += 1;
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    n += 1;
+                    continue;
+                }
+                b'[' => if let Some((matched, len)) = match_class(&pattern[p..], name[n]) {
+                    if matched {
+                        p += len;
+                        n += 1;
+                        continue;
+                    }
+                } else if name[n] == b'[' {
+                    p += 1;
+                    n += 1;
+                    continue;
+                },
+                c => if c == name[n] {
+                    p += 1;
+                    n += 1;
+                    continue;
+                },
+            }
+        }
+        match backtrack {
+            // Let the last `*` eat one more character and try again.
+            Some((bp, bn)) => {
+                backtrack = Some((bp, bn + 1));
+                p = bp + 1;
+                n = bn + 1;
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Match `c` against the `[...]` class at the start of `pattern`, returning whether it matched
+/// and the length of the class, or `None` if the class isn't terminated.
+fn match_class(pattern: &[u8], c: u8) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negate = matches!(pattern.get(i), Some(b'!') | Some(b'^'));
+    if negate {
+        i += 1;
+    }
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let start = *pattern.get(i)?;
+        if start == b']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+        first = false;
+        if pattern.get(i + 1) == Some(&b'-') && pattern.get(i + 2).is_some_and(|&v| v != b']') {
+            let end = pattern[i + 2];
+            matched |= start <= c && c <= end;
+            i += 3;
+        } else {
+            matched |= start == c;
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_star() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "arch.conf"));
+        assert!(glob_match("arch*", "arch-lts.conf"));
+        assert!(glob_match("*.conf", "arch.conf"));
+        // The first `*` can't stop at the first `-`.
+        assert!(glob_match("*-lts*-6", "arch-lts-lts-6"));
+        assert!(glob_match("a**b", "ab"));
+        assert!(!glob_match("*.conf", "arch.efi"));
+        assert!(!glob_match("arch*", "xarch"));
+    }
+
+    #[test]
+    fn glob_question_mark() {
+        assert!(glob_match("linux-6.?", "linux-6.1"));
+        assert!(glob_match("??", "ab"));
+        assert!(!glob_match("?", ""));
+        assert!(!glob_match("??", "abc"));
+    }
+
+    #[test]
+    fn glob_class() {
+        assert!(glob_match("[a-z]", "m"));
+        assert!(!glob_match("[a-z]", "M"));
+        assert!(glob_match("linux-[0-9][0-9]", "linux-42"));
+        assert!(glob_match("[abc-]", "-"));
+        // A `]` right after the `[` is part of the class.
+        assert!(glob_match("[]x]", "]"));
+
+        assert!(glob_match("[!x]", "y"));
+        assert!(!glob_match("[!x]", "x"));
+        assert!(glob_match("[^a-c]", "d"));
+        assert!(!glob_match("[!a-c]", "b"));
+    }
+
+    #[test]
+    fn glob_unterminated_class() {
+        // Without a `]`, the `[` is an ordinary character.
+        assert!(glob_match("[a", "[a"));
+        assert!(!glob_match("[a", "a"));
+        assert!(glob_match("*[", "linux["));
+        assert!(match_class(b"[!a-", b'b').is_none());
+    }
+}
@@ -0,0 +1,471 @@
+//! GPT and MBR partition tables.
+//!
+//! Unlike lib/partition this understands GPT, including falling back to the backup GPT when the
+//! primary one is damaged, and the logical partitions in an MBR extended partition.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, LittleEndian};
+use core::ffi::{c_char, c_int, CStr};
+use crc::{Crc, CRC_32_ISO_HDLC};
+use snafu::Snafu;
+use crate::bio::{self, BioError, OpenDevice};
+use crate::guid::{self, Guid};
+use crate::println;
+
+#[derive(Debug, Snafu)]
+pub enum PartitionError {
+    #[snafu(display("failed to open device"))]
+    Open,
+    #[snafu(display("I/O error: {source}"))]
+    Io { source: BioError },
+    #[snafu(display("no partition table"))]
+    NoTable,
+    #[snafu(display("primary and backup GPT are both invalid"))]
+    InvalidGpt,
+    #[snafu(display("failed to publish {name}"))]
+    Publish { name: String },
+    #[snafu(display("failed to list devices"))]
+    ListDevices,
+}
+
+impl From<BioError> for PartitionError {
+    fn from(source: BioError) -> Self {
+        PartitionError::Io { source }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Partition {
+    pub start_lba: u64,
+    pub lba_count: u64,
+    /// GPT partition name. MBR partitions don't have one.
+    pub name: Option<String>,
+    /// GPT type GUID. MBR ESP and XBOOTLDR types are mapped to their GPT equivalent.
+    pub type_guid: Option<Guid>,
+    pub partuuid: Option<Guid>,
+    pub attributes: u64,
+}
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_STATUS_ACTIVE: u8 = 0x80;
+const MBR_TYPE_PROTECTIVE: u8 = 0xee;
+const MBR_TYPE_ESP: u8 = 0xef;
+const MBR_TYPE_XBOOTLDR: u8 = 0xea;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0f, 0x85];
+// Guards against loops in the EBR chain.
+const MBR_MAX_LOGICAL: usize = 128;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_HEADER_MIN_SIZE: usize = 92;
+const GPT_ENTRY_MIN_SIZE: usize = 128;
+// The spec asks for at least 16KB of entries, no real table comes close to 1MB.
+const GPT_ENTRIES_MAX_SIZE: usize = 1024 * 1024;
+
+// Same as LK2ND_BOOT_MIN_SIZE, anything smaller won't have a disk image in it.
+const NESTED_MIN_SIZE: usize = 16 * 1024 * 1024;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Parse the partition table of `dev`. Partitions are returned in table order, leaving out empty
+/// GPT entries and MBR extended partitions themselves.
+pub fn parse(dev: &mut OpenDevice) -> Result<Vec<Partition>, PartitionError> {
+    let block_size = dev.block_size() as usize;
+    let mut mbr = vec![0; block_size];
+    dev.read_at(0, &mut mbr)?;
+    if mbr[510..512] != MBR_SIGNATURE || is_boot_sector(&mbr) {
+        return Err(PartitionError::NoTable);
+    }
+
+    if (0..4).any(|i| mbr[446 + i * 16 + 4] == MBR_TYPE_PROTECTIVE) {
+        return parse_gpt(dev);
+    }
+    parse_mbr(dev, &mbr)
+}
+
+/// FAT, exFAT and NTFS boot sectors end in the MBR signature too, but have boot code or BPB
+/// fields where the partition entries would be.
+fn is_boot_sector(block: &[u8]) -> bool {
+    &block[3..11] == b"EXFAT   " || &block[3..11] == b"NTFS    "
+        || &block[54..59] == b"FAT12" || &block[54..59] == b"FAT16" || &block[82..87] == b"FAT32"
+}
+
+struct GptHeader {
+    alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    entries_lba: u64,
+    entry_count: usize,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+fn parse_gpt(dev: &mut OpenDevice) -> Result<Vec<Partition>, PartitionError> {
+    let last_lba = (dev.block_count() as u64).checked_sub(1).ok_or(PartitionError::NoTable)?;
+
+    match read_gpt(dev, 1) {
+        Ok(partitions) => return Ok(partitions),
+        Err((err, alternate_lba)) => {
+            println!("primary GPT invalid ({}), trying backup", err);
+            for lba in [alternate_lba, Some(last_lba)].into_iter().flatten() {
+                if let Ok(partitions) = read_gpt(dev, lba) {
+                    return Ok(partitions);
+                }
+            }
+        }
+    }
+    Err(PartitionError::InvalidGpt)
+}
+
+/// Read the GPT whose header is at `lba`. On failure, also returns where the header says its
+/// alternate is, if the header itself could be trusted.
+fn read_gpt(dev: &mut OpenDevice, lba: u64) -> Result<Vec<Partition>, (&'static str, Option<u64>)> {
+    let header = read_gpt_header(dev, lba)?;
+    let alternate = Some(header.alternate_lba);
+    let last_usable_lba = header.last_usable_lba.min((dev.block_count() as u64).saturating_sub(1));
+
+    let mut entries = vec![0; header.entry_count * header.entry_size];
+    dev.read_at(header.entries_lba * dev.block_size(), &mut entries).map_err(|_| ("I/O error", alternate))?;
+    if CRC32.checksum(&entries) != header.entries_crc {
+        return Err(("entries CRC mismatch", alternate));
+    }
+
+    Ok(entries.chunks_exact(header.entry_size).filter_map(|entry| {
+        let type_guid = Guid(entry[0..16].try_into().unwrap());
+        if type_guid.is_zero() {
+            return None;
+        }
+        let first_lba = LittleEndian::read_u64(&entry[32..]);
+        let last_lba = LittleEndian::read_u64(&entry[40..]);
+        if last_lba < first_lba || first_lba < header.first_usable_lba || last_lba > last_usable_lba {
+            return None;
+        }
+
+        let name: Vec<u16> = entry[56..128].chunks_exact(2)
+            .map(LittleEndian::read_u16)
+            .take_while(|&c| c != 0)
+            .collect();
+        let name = char::decode_utf16(name).map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)).collect::<String>();
+
+        Some(Partition {
+            start_lba: first_lba,
+            lba_count: last_lba - first_lba + 1,
+            name: Some(name).filter(|v| !v.is_empty()),
+            type_guid: Some(type_guid),
+            partuuid: Some(Guid(entry[16..32].try_into().unwrap())),
+            attributes: LittleEndian::read_u64(&entry[48..]),
+        })
+    }).collect())
+}
+
+fn read_gpt_header(dev: &mut OpenDevice, lba: u64) -> Result<GptHeader, (&'static str, Option<u64>)> {
+    let block_size = dev.block_size() as usize;
+    let mut block = vec![0; block_size];
+    dev.read_at(lba * block_size as u64, &mut block).map_err(|_| ("I/O error", None))?;
+
+    if &block[0..8] != GPT_SIGNATURE {
+        return Err(("bad signature", None));
+    }
+    let header_size = LittleEndian::read_u32(&block[12..]) as usize;
+    if !(GPT_HEADER_MIN_SIZE..=block_size).contains(&header_size) {
+        return Err(("bad header size", None));
+    }
+    let crc = LittleEndian::read_u32(&block[16..]);
+    LittleEndian::write_u32(&mut block[16..], 0);
+    if CRC32.checksum(&block[..header_size]) != crc {
+        return Err(("header CRC mismatch", None));
+    }
+    if LittleEndian::read_u64(&block[24..]) != lba {
+        return Err(("header in the wrong place", None));
+    }
+
+    let header = GptHeader {
+        alternate_lba: LittleEndian::read_u64(&block[32..]),
+        first_usable_lba: LittleEndian::read_u64(&block[40..]),
+        last_usable_lba: LittleEndian::read_u64(&block[48..]),
+        entries_lba: LittleEndian::read_u64(&block[72..]),
+        entry_count: LittleEndian::read_u32(&block[80..]) as usize,
+        entry_size: LittleEndian::read_u32(&block[84..]) as usize,
+        entries_crc: LittleEndian::read_u32(&block[88..]),
+    };
+    let alternate = Some(header.alternate_lba);
+    if header.entry_size < GPT_ENTRY_MIN_SIZE || header.entry_size % 8 != 0 {
+        return Err(("bad entry size", alternate));
+    }
+    if header.entry_count.saturating_mul(header.entry_size) > GPT_ENTRIES_MAX_SIZE {
+        return Err(("too many entries", alternate));
+    }
+    Ok(header)
+}
+
+fn mbr_partition(entry: &[u8], base_lba: u64) -> Option<(u8, Partition)> {
+    let status = entry[0];
+    let kind = entry[4];
+    let start = LittleEndian::read_u32(&entry[8..]) as u64;
+    let count = LittleEndian::read_u32(&entry[12..]) as u64;
+    if kind == 0 || count == 0 || (status != 0 && status != MBR_STATUS_ACTIVE) {
+        return None;
+    }
+
+    let type_guid = match kind {
+        MBR_TYPE_ESP => Some(guid::ESP),
+        MBR_TYPE_XBOOTLDR => Some(guid::XBOOTLDR),
+        _ => None,
+    };
+    Some((kind, Partition {
+        start_lba: base_lba + start,
+        lba_count: count,
+        name: None,
+        type_guid,
+        partuuid: None,
+        attributes: 0,
+    }))
+}
+
+fn parse_mbr(dev: &mut OpenDevice, mbr: &[u8]) -> Result<Vec<Partition>, PartitionError> {
+    let block_size = dev.block_size();
+    let block_count = dev.block_count() as u64;
+    let in_bounds = |p: &Partition| p.start_lba + p.lba_count <= block_count;
+
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for i in 0..4 {
+        let Some((kind, partition)) = mbr_partition(&mbr[446 + i * 16..], 0) else {
+            continue;
+        };
+        if !in_bounds(&partition) {
+            continue;
+        }
+        if MBR_TYPES_EXTENDED.contains(&kind) {
+            extended.get_or_insert(partition.start_lba);
+        } else {
+            partitions.push(partition);
+        }
+    }
+
+    // Each EBR has a logical partition relative to itself, and a link to the next EBR relative
+    // to the start of the extended partition.
+    if let Some(extended_lba) = extended {
+        let mut ebr = vec![0; block_size as usize];
+        let mut ebr_lba = extended_lba;
+        for _ in 0..MBR_MAX_LOGICAL {
+            dev.read_at(ebr_lba * block_size, &mut ebr)?;
+            if ebr[510..512] != MBR_SIGNATURE {
+                break;
+            }
+            if let Some((_, partition)) = mbr_partition(&ebr[446..], ebr_lba) {
+                if in_bounds(&partition) {
+                    partitions.push(partition);
+                }
+            }
+            match mbr_partition(&ebr[462..], extended_lba) {
+                Some((kind, next)) if MBR_TYPES_EXTENDED.contains(&kind) && next.start_lba > ebr_lba => {
+                    ebr_lba = next.start_lba;
+                }
+                _ => break,
+            }
+        }
+    }
+
+    Ok(partitions)
+}
+
+/// Parse the partition table on `device` and publish its partitions as `<device>p<N>`, numbered
+/// in table order. Returns the number of partitions published.
+///
+/// Either all partitions are published or none are, so that the caller can fall back to another
+/// partition parser using the same names.
+pub fn publish(device: &str) -> Result<usize, PartitionError> {
+    let mut dev = bio::open(device).map_err(|_| PartitionError::Open)?;
+    let partitions = parse(&mut dev)?;
+    drop(dev);
+
+    let mut published = Vec::new();
+    for (idx, partition) in partitions.iter().enumerate() {
+        let name = format!("{}p{}", device, idx);
+        if bio::publish_subdevice(device, &name, partition).is_err() {
+            bio::unpublish_subdevices(device, &published);
+            return Err(PartitionError::Publish { name });
+        }
+        published.push(name);
+    }
+    Ok(partitions.len())
+}
+
+/// Look for disk images inside partitions, like postmarketOS puts in userdata, and publish the
+/// partitions inside them. Only goes one level deep. Returns the number of partitions published.
+pub fn publish_nested() -> Result<usize, PartitionError> {
+    let devs = bio::get_bdevs().map_err(|_| PartitionError::ListDevices)?;
+    let mut count = 0;
+    for dev in devs.iter().filter(|v| v.is_leaf && v.size >= NESTED_MIN_SIZE) {
+        match publish(&dev.name) {
+            Ok(0) | Err(PartitionError::NoTable) => {}
+            Ok(n) => {
+                println!("found {} partitions inside {} ({:?})", n, dev.name, dev.label);
+                count += n;
+            }
+            Err(err) => println!("{}: {}", dev.name, err),
+        }
+    }
+    Ok(count)
+}
+
+/// C entry point for publish(). Returns the number of partitions, or a negative value if there
+/// is no partition table that could be read.
+#[no_mangle]
+pub extern "C" fn lk2nd_partition_publish(device: *const c_char) -> c_int {
+    let Ok(device) = unsafe { CStr::from_ptr(device) }.to_str() else {
+        return -1;
+    };
+    match publish(device) {
+        Ok(count) => count as c_int,
+        Err(err) => {
+            println!("{}: {}", device, err);
+            -1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use super::*;
+
+    const BLOCK: usize = 512;
+    const DISK_BLOCKS: usize = 2048;
+    const LINUX: Guid = Guid::new(0x0fc63daf, 0x8483, 0x4772, [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0xe4, 0x7d, 0xe4]);
+
+    fn open(disk: Vec<u8>) -> OpenDevice {
+        let size = disk.len() as u64;
+        OpenDevice::from_image(Box::new(disk), size)
+    }
+
+    fn mbr_entry(block: &mut [u8], idx: usize, kind: u8, start: u32, count: u32) {
+        let entry = &mut block[446 + idx * 16..];
+        entry[4] = kind;
+        LittleEndian::write_u32(&mut entry[8..], start);
+        LittleEndian::write_u32(&mut entry[12..], count);
+        block[510..512].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    /// Write a GPT header at `lba`, with 128 entries at `entries_lba`.
+    fn gpt_header(disk: &mut [u8], lba: usize, alternate: usize, entries_lba: usize) {
+        let entries = &disk[entries_lba * BLOCK..][..128 * GPT_ENTRY_MIN_SIZE];
+        let entries_crc = CRC32.checksum(entries);
+        let header = &mut disk[lba * BLOCK..][..BLOCK];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        LittleEndian::write_u32(&mut header[12..], GPT_HEADER_MIN_SIZE as u32);
+        LittleEndian::write_u64(&mut header[24..], lba as u64);
+        LittleEndian::write_u64(&mut header[32..], alternate as u64);
+        LittleEndian::write_u64(&mut header[40..], 34);
+        LittleEndian::write_u64(&mut header[48..], DISK_BLOCKS as u64 - 34);
+        LittleEndian::write_u64(&mut header[72..], entries_lba as u64);
+        LittleEndian::write_u32(&mut header[80..], 128);
+        LittleEndian::write_u32(&mut header[84..], GPT_ENTRY_MIN_SIZE as u32);
+        LittleEndian::write_u32(&mut header[88..], entries_crc);
+        let crc = CRC32.checksum(&header[..GPT_HEADER_MIN_SIZE]);
+        LittleEndian::write_u32(&mut header[16..], crc);
+    }
+
+    /// A disk with a protective MBR and primary and backup GPTs holding `partitions`.
+    fn gpt_disk(partitions: &[(Guid, u64, u64, &str)]) -> Vec<u8> {
+        let mut disk = vec![0; DISK_BLOCKS * BLOCK];
+        mbr_entry(&mut disk, 0, MBR_TYPE_PROTECTIVE, 1, DISK_BLOCKS as u32 - 1);
+
+        let mut entries = vec![0; 128 * GPT_ENTRY_MIN_SIZE];
+        for (entry, &(type_guid, first, last, name)) in entries.chunks_exact_mut(GPT_ENTRY_MIN_SIZE).zip(partitions) {
+            entry[..16].copy_from_slice(&type_guid.0);
+            entry[16] = 1;
+            LittleEndian::write_u64(&mut entry[32..], first);
+            LittleEndian::write_u64(&mut entry[40..], last);
+            for (i, c) in name.encode_utf16().enumerate() {
+                LittleEndian::write_u16(&mut entry[56 + i * 2..], c);
+            }
+        }
+        let backup_entries = DISK_BLOCKS - 33;
+        disk[2 * BLOCK..][..entries.len()].copy_from_slice(&entries);
+        disk[backup_entries * BLOCK..][..entries.len()].copy_from_slice(&entries);
+        gpt_header(&mut disk, 1, DISK_BLOCKS - 1, 2);
+        gpt_header(&mut disk, DISK_BLOCKS - 1, 1, backup_entries);
+        disk
+    }
+
+    #[test]
+    fn gpt() {
+        let disk = gpt_disk(&[(guid::ESP, 34, 99, "esp"), (LINUX, 100, 2000, "rootfs")]);
+        let partitions = parse(&mut open(disk)).unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[0].name.as_deref(), Some("esp"));
+        assert_eq!(partitions[0].type_guid, Some(guid::ESP));
+        assert_eq!((partitions[0].start_lba, partitions[0].lba_count), (34, 66));
+        assert_eq!(partitions[1].name.as_deref(), Some("rootfs"));
+        assert_eq!(partitions[1].type_guid, Some(LINUX));
+        assert_eq!((partitions[1].start_lba, partitions[1].lba_count), (100, 1901));
+    }
+
+    #[test]
+    fn gpt_out_of_bounds() {
+        let disk = gpt_disk(&[
+            (LINUX, 2, 99, "overlaps-gpt"),
+            (LINUX, 100, 200, "ok"),
+            (LINUX, 1000, DISK_BLOCKS as u64 - 1, "overlaps-backup"),
+            (LINUX, 1000, 1 << 40, "past-end"),
+        ]);
+        let partitions = parse(&mut open(disk)).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].name.as_deref(), Some("ok"));
+    }
+
+    #[test]
+    fn gpt_backup() {
+        let mut disk = gpt_disk(&[(guid::ESP, 34, 99, "esp")]);
+        disk[2 * BLOCK] ^= 0xff;
+        let partitions = parse(&mut open(disk)).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].name.as_deref(), Some("esp"));
+    }
+
+    #[test]
+    fn gpt_both_invalid() {
+        let mut disk = gpt_disk(&[(guid::ESP, 34, 99, "esp")]);
+        disk[BLOCK] ^= 0xff;
+        disk[(DISK_BLOCKS - 1) * BLOCK] ^= 0xff;
+        assert!(matches!(parse(&mut open(disk)), Err(PartitionError::InvalidGpt)));
+    }
+
+    #[test]
+    fn mbr_logical() {
+        let mut disk = vec![0; DISK_BLOCKS * BLOCK];
+        mbr_entry(&mut disk, 0, MBR_TYPE_ESP, 1, 99);
+        mbr_entry(&mut disk, 1, 0x05, 100, 1000);
+        // Two logical partitions, the second EBR linked from the first relative to the extended
+        // partition.
+        mbr_entry(&mut disk[100 * BLOCK..], 0, 0x83, 1, 199);
+        mbr_entry(&mut disk[100 * BLOCK..], 1, 0x05, 200, 800);
+        mbr_entry(&mut disk[300 * BLOCK..], 0, 0x83, 1, 799);
+
+        let partitions = parse(&mut open(disk)).unwrap();
+        let layout: Vec<_> = partitions.iter().map(|v| (v.start_lba, v.lba_count, v.type_guid)).collect();
+        assert_eq!(layout, [(1, 99, Some(guid::ESP)), (101, 199, None), (301, 799, None)]);
+    }
+
+    #[test]
+    fn mbr_out_of_bounds() {
+        let mut disk = vec![0; DISK_BLOCKS * BLOCK];
+        mbr_entry(&mut disk, 0, 0x83, 1, DISK_BLOCKS as u32);
+        mbr_entry(&mut disk, 1, 0x83, 1, 99);
+        let partitions = parse(&mut open(disk)).unwrap();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].lba_count, 99);
+    }
+
+    #[test]
+    fn fat_boot_sector() {
+        let mut disk = vec![0; DISK_BLOCKS * BLOCK];
+        disk[82..87].copy_from_slice(b"FAT32");
+        disk[510..512].copy_from_slice(&MBR_SIGNATURE);
+        assert!(matches!(parse(&mut open(disk)), Err(PartitionError::NoTable)));
+    }
+}
@@ -0,0 +1,79 @@
+//! Filesystem detection by superblock magic, so scanners are only pointed at filesystems they
+//! can actually read.
+
+use alloc::vec;
+use byteorder::{ByteOrder, LittleEndian};
+use crate::bio::OpenDevice;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsType {
+    Fat,
+    Exfat,
+    Ext,
+    Erofs,
+    Squashfs,
+    F2fs,
+    Btrfs,
+}
+
+const EXT_SUPERBLOCK: u64 = 1024;
+const EXT_MAGIC: u16 = 0xef53;
+const EROFS_SUPERBLOCK: u64 = 1024;
+const EROFS_MAGIC: u32 = 0xe0f5e1e2;
+const F2FS_SUPERBLOCK: u64 = 1024;
+const F2FS_MAGIC: u32 = 0xf2f52010;
+const EXFAT_NAME: &[u8; 8] = b"EXFAT   ";
+const SQUASHFS_MAGIC: &[u8; 4] = b"hsqs";
+const BTRFS_SUPERBLOCK: u64 = 64 * 1024;
+const BTRFS_MAGIC: &[u8; 8] = b"_BHRfS_M";
+
+/// Figure out what filesystem is on `dev`, if it's one we know about.
+pub fn probe(dev: &mut OpenDevice) -> Option<FsType> {
+    // Covers the first sector as well as the superblocks at 1KB.
+    let mut head = vec![0; 2048];
+    dev.read_at(0, &mut head).ok()?;
+
+    if LittleEndian::read_u16(&head[EXT_SUPERBLOCK as usize + 0x38..]) == EXT_MAGIC {
+        return Some(FsType::Ext);
+    }
+    if LittleEndian::read_u32(&head[EROFS_SUPERBLOCK as usize..]) == EROFS_MAGIC {
+        return Some(FsType::Erofs);
+    }
+    if LittleEndian::read_u32(&head[F2FS_SUPERBLOCK as usize..]) == F2FS_MAGIC {
+        return Some(FsType::F2fs);
+    }
+    if &head[..4] == SQUASHFS_MAGIC {
+        return Some(FsType::Squashfs);
+    }
+    // exFAT zeroes the BPB, it can only be recognized by its file system name.
+    if &head[3..11] == EXFAT_NAME {
+        return Some(FsType::Exfat);
+    }
+    if is_fat(&head[..512]) {
+        return Some(FsType::Fat);
+    }
+
+    let mut magic = [0; 8];
+    if dev.read_at(BTRFS_SUPERBLOCK + 0x40, &mut magic).is_ok() && &magic == BTRFS_MAGIC {
+        return Some(FsType::Btrfs);
+    }
+    None
+}
+
+/// Sanity check the BIOS parameter block. This is about what Linux' msdos driver checks, the
+/// "FAT16" style strings are only informational and not always there.
+fn is_fat(sector: &[u8]) -> bool {
+    let jump = sector[0] == 0xeb || sector[0] == 0xe9;
+    let bytes_per_sector = LittleEndian::read_u16(&sector[11..]);
+    let sectors_per_cluster = sector[13];
+    let reserved_sectors = LittleEndian::read_u16(&sector[14..]);
+    let fats = sector[16];
+    let media = sector[21];
+
+    jump && sector[510..512] == [0x55, 0xaa]
+        && bytes_per_sector.is_power_of_two() && (512..=4096).contains(&bytes_per_sector)
+        && sectors_per_cluster.is_power_of_two()
+        && reserved_sectors != 0
+        && fats != 0
+        && (media == 0xf0 || media >= 0xf8)
+}